@@ -1,4 +1,14 @@
-use etcd_client::{Client, ConnectOptions, TlsOptions, Identity, Certificate, Error, DeleteOptions, GetOptions, KeyValue, PutOptions, SortTarget, SortOrder};
+use etcd_client::{Channel, Client, ConnectOptions, Compare, CompareOp, Error, DeleteOptions, EventType, GetOptions, GetResponse, KeyValue, LockOptions, PutOptions, SortTarget, SortOrder, Txn, TxnOp, TxnOpResponse, WatchOptions};
+#[cfg(feature = "tls-rustls")]
+use etcd_client::{Certificate, Identity, TlsOptions};
+#[cfg(feature = "tls-openssl")]
+use etcd_client::OpenSslClientConfig;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::ffi::{CStr, CString};
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::Duration;
 use pgrx::pg_sys::panic::ErrorReport;
 use pgrx::PgSqlErrorCode;
@@ -8,949 +18,11298 @@ use thiserror::Error;
 
 pgrx::pg_module_magic!();
 
-#[wrappers_fdw(
-    version = "0.0.1",
-    author = "Cybertec PostgreSQL International GmbH",
-    error_type = "EtcdFdwError"
-)]
-pub(crate) struct EtcdFdw {
-    client: Client,
-    rt: Runtime,
-    fetch_results: Vec<KeyValue>,
-    tgt_cols: Vec<Column>,
-    fetch_key: bool,
-    fetch_value: bool,
+// etcd-client's OpenSSL transport takes over the sockets, tonic's rustls would run TLS over TLS
+#[cfg(all(feature = "tls-rustls", feature = "tls-openssl"))]
+compile_error!("Only one of the features tls-rustls and tls-openssl can be enabled");
+
+/// Percentage of keys returned by scans, 0 disables sampling
+static SAMPLE_PERCENT: GucSetting<f64> = GucSetting::<f64>::new(0.0);
+
+/// Session level overrides of server and table options, unset by default
+static CONSISTENCY: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+static KEYS_ONLY: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+static CONNECT_TIMEOUT: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+static REQUEST_TIMEOUT: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+static FETCH_SIZE: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+/// Revision scans read at instead of the `revision` table option, see `read_revision`
+static READ_REVISION: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+
+/// Foreign server and prefix of the configuration store of `etcd_config_get()` and `etcd_config_set()`
+static CONFIG_SERVER: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+static CONFIG_PREFIX: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(Some(c"/config/"));
+/// Seconds configuration values are cached by the backend, 0 disables the cache
+static CONFIG_CACHE_TTL: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// Delays in milliseconds between reconnection attempts of dropped watches
+static WATCH_RETRY_MIN: GucSetting<i32> = GucSetting::<i32>::new(100);
+static WATCH_RETRY_MAX: GucSetting<i32> = GucSetting::<i32>::new(30000);
+
+/// Whether identical scans of a statement share one request, see `statement_cached`
+static STATEMENT_CACHE_ENABLED: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+/// Number of etcd requests a backend has in flight at once, 0 doesn't limit them
+static MAX_INFLIGHT_REQUESTS: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// Prefix below which the service discovery functions register services
+static SERVICE_PREFIX: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(Some(c"/services/"));
+
+/// Options that can be set on the server, overridden per table and overridden again per session
+const LAYERED_OPTIONS: [&str; 5] = ["consistency", "keys_only", "connect_timeout", "request_timeout", "fetch_size"];
+
+/// Options accepted by the validator for each catalog
+const SERVER_OPTIONS: [&str; 15] = [
+    "connstr",
+    "ssl_key",
+    "ssl_cert",
+    "ssl_ca",
+    "ssl_servername",
+    "tls_backend",
+    "load_balancing",
+    "hedge_delay",
+    "max_inflight_requests",
+    "connect_timeout",
+    "request_timeout",
+    "consistency",
+    "keys_only",
+    "fetch_size",
+    "namespace",
+];
+const TABLE_OPTIONS: [&str; 40] = [
+    "rowid_column",
+    "layout",
+    "prefix",
+    "prefixes",
+    "key",
+    "range_end",
+    "keys_only",
+    "revision",
+    "consistency",
+    "modify_batch_size",
+    "default_limit",
+    "default_sort",
+    "on_scan_error",
+    "on_unavailable",
+    "on_conflict",
+    "point_cache",
+    "cache_ttl",
+    "write_lease_ttl",
+    "lease_id",
+    "modify_lock",
+    "n_rows",
+    "startup_cost",
+    "key_pad",
+    "key_encoding",
+    "strip_prefix",
+    "namespace",
+    "exclude_prefix",
+    "key_regex",
+    "empty_as_null",
+    "key_column",
+    "value_column",
+    "key_delimiter",
+    "value_compression",
+    "value_format",
+    "proto_descriptor",
+    "proto_message",
+    "k8s_mode",
+    "connect_timeout",
+    "request_timeout",
+    "fetch_size",
+];
+const USER_MAPPING_OPTIONS: [&str; 3] = ["user", "password", "prefix"];
+const COLUMN_OPTIONS: [&str; 1] = ["jsonpath"];
+
+#[pg_guard]
+pub extern "C-unwind" fn _PG_init() {
+    GucRegistry::define_float_guc(
+        c"etcd_fdw.sample_percent",
+        c"Percentage of keys returned by scans on etcd foreign tables.",
+        c"TABLESAMPLE can't be used on foreign tables. A value between 0 and 100 samples that percentage of the keys instead, 0 disables sampling.",
+        &SAMPLE_PERCENT,
+        0.0,
+        100.0,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        c"etcd_fdw.config_server",
+        c"Foreign server of the configuration store of etcd_config_get() and etcd_config_set().",
+        c"",
+        &CONFIG_SERVER,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        c"etcd_fdw.config_prefix",
+        c"Prefix of the keys of the configuration store of etcd_config_get() and etcd_config_set().",
+        c"",
+        &CONFIG_PREFIX,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_int_guc(
+        c"etcd_fdw.config_cache_ttl",
+        c"Time configuration values read by etcd_config_get() are cached by the backend.",
+        c"0 reads every value from etcd.",
+        &CONFIG_CACHE_TTL,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_S,
+    );
+    GucRegistry::define_int_guc(
+        c"etcd_fdw.watch_retry_min",
+        c"Delay before the first reconnection attempt of a dropped watch.",
+        c"Further attempts wait exponentially longer, up to etcd_fdw.watch_retry_max, each with random jitter.",
+        &WATCH_RETRY_MIN,
+        1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_MS,
+    );
+    GucRegistry::define_int_guc(
+        c"etcd_fdw.watch_retry_max",
+        c"Longest delay between reconnection attempts of a dropped watch.",
+        c"",
+        &WATCH_RETRY_MAX,
+        1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_MS,
+    );
+    GucRegistry::define_bool_guc(
+        c"etcd_fdw.statement_cache",
+        c"Share the result of identical etcd scans within a statement.",
+        c"Tables over the same prefix, or a table referenced several times, are fetched once per statement.",
+        &STATEMENT_CACHE_ENABLED,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_int_guc(
+        c"etcd_fdw.max_inflight_requests",
+        c"Maximum number of etcd requests a backend has in flight at once.",
+        c"Further requests wait until one of them finished. 0 doesn't limit the number of requests.",
+        &MAX_INFLIGHT_REQUESTS,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        c"etcd_fdw.service_prefix",
+        c"Prefix of the keys of services registered with etcd_service_register().",
+        c"Services are registered as <prefix><name>/<address>, as used by etcd based service discovery.",
+        &SERVICE_PREFIX,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        c"etcd_fdw.consistency",
+        c"Overrides the consistency option of etcd servers and foreign tables.",
+        c"",
+        &CONSISTENCY,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        c"etcd_fdw.keys_only",
+        c"Overrides the keys_only option of etcd servers and foreign tables.",
+        c"",
+        &KEYS_ONLY,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        c"etcd_fdw.connect_timeout",
+        c"Overrides the connect_timeout option of etcd servers.",
+        c"",
+        &CONNECT_TIMEOUT,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        c"etcd_fdw.request_timeout",
+        c"Overrides the request_timeout option of etcd servers.",
+        c"",
+        &REQUEST_TIMEOUT,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        c"etcd_fdw.fetch_size",
+        c"Overrides the fetch_size option of etcd servers and foreign tables.",
+        c"",
+        &FETCH_SIZE,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        c"etcd_fdw.read_revision",
+        c"Overrides the revision option of etcd foreign tables.",
+        c"Scans read the keys as of this etcd revision. Empty or 0 reads the table's revision.",
+        &READ_REVISION,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+
+    unsafe {
+        let _ = PREV_GET_RELATION_INFO_HOOK.set(pg_sys::get_relation_info_hook);
+        pg_sys::get_relation_info_hook = Some(get_relation_info);
+    }
 }
-pub struct EtcdConfig {
-    pub endpoints: Vec<String>,
-    pub ca_cert_path: Option<String>,
-    pub client_cert_path: Option<String>,
-    pub client_key_path: Option<String>,
-    pub user: Option<String>,
-    pub password: Option<String>,
-    pub servername: Option<String>,
-    pub connect_timeout: Duration,
-    pub request_timeout: Duration,
+
+/// Properties of the foreign table being planned, which the framework doesn't pass to `EtcdFdw::new`
+#[derive(Clone, Copy)]
+struct PlannedTable {
+    /// whether the key and value columns sort bytewise
+    key_order_bytewise: bool,
+    value_order_bytewise: bool,
+    /// collation of the key column, for matching patterns on keys
+    key_collation: pg_sys::Oid,
+    /// the foreign table, for caching its scans
+    relid: pg_sys::Oid,
+    /// whether a range condition compares a value with the key or a revision, see `range_commuted`
+    key_range_commuted: bool,
+    revision_range_commuted: bool,
+    /// whether conditions evaluated by PostgreSQL refer to the value column
+    value_in_conditions: bool,
+    /// number of conditions of the scan, including the ones the framework doesn't pass on as quals
+    conditions: usize,
+    /// whether the LIMIT of the query applies to the rows of the scan, see `limit_applies_to_scan`
+    limit_applies: bool,
+    /// number of sort keys of the query and whether the first one is a column of the table, see `query_sort_keys`
+    sort_keys: usize,
+    first_sort_on_table: bool,
+    /// `on_unavailable 'empty'`
+    empty_when_unavailable: bool,
+    /// whether the table is scanned for a SELECT rather than an UPDATE or DELETE
+    select: bool,
+    /// whether the table is scanned for a DELETE, see `RangeDelete`
+    delete: bool,
 }
 
-impl Default for EtcdConfig {
-    fn default() -> Self {
-        Self {
-            endpoints: Vec::new(),
-            ca_cert_path: None,
-            client_cert_path: None,
-            client_key_path: None,
-            user: None,
-            password: None,
-            servername: None,
-            connect_timeout: Duration::from_secs(10),
-            request_timeout: Duration::from_secs(30),
+impl PlannedTable {
+    const DEFAULT: PlannedTable = PlannedTable {
+        key_order_bytewise: true,
+        value_order_bytewise: true,
+        key_collation: pg_sys::DEFAULT_COLLATION_OID,
+        relid: pg_sys::InvalidOid,
+        key_range_commuted: false,
+        revision_range_commuted: false,
+        value_in_conditions: true,
+        conditions: 0,
+        limit_applies: false,
+        sort_keys: 0,
+        first_sort_on_table: true,
+        empty_when_unavailable: false,
+        select: true,
+        delete: false,
+    };
+}
+
+thread_local! {
+    /// Set before the framework creates the scan's `EtcdFdw` instance, taken by `EtcdFdw::new`
+    /// and cleared once the framework returns
+    static PLANNED_TABLE: std::cell::Cell<PlannedTable> = const { std::cell::Cell::new(PlannedTable::DEFAULT) };
+}
+
+/// Take the planned table for an instance of the given server
+/// A table left over from planning another relation, e.g. after an error, isn't used
+unsafe fn take_planned_table(server_oid: pg_sys::Oid) -> PlannedTable {
+    let planned = PLANNED_TABLE.replace(PlannedTable::DEFAULT);
+    if planned.relid == pg_sys::InvalidOid || (*pg_sys::GetForeignTable(planned.relid)).serverid != server_oid {
+        return PlannedTable::DEFAULT;
+    }
+    planned
+}
+
+/// The framework's `GetForeignRelSize` callback, wrapped by `get_foreign_rel_size`
+static FRAMEWORK_GET_FOREIGN_REL_SIZE: OnceLock<pg_sys::GetForeignRelSize_function> = OnceLock::new();
+
+/// Records the column collations and options of the planned foreign table before handing over to the framework
+#[pg_guard]
+extern "C-unwind" fn get_foreign_rel_size(
+    root: *mut pg_sys::PlannerInfo,
+    baserel: *mut pg_sys::RelOptInfo,
+    foreigntableid: pg_sys::Oid,
+) {
+    unsafe {
+        rewrite_starts_with(baserel, foreigntableid);
+        let table = pg_sys::GetForeignTable(foreigntableid);
+        let on_unavailable = def_elem_options((*table).options).remove("on_unavailable");
+        let (sort_keys, first_sort_on_table) = query_sort_keys(root, baserel);
+        PLANNED_TABLE.set(PlannedTable {
+            key_order_bytewise: column_order_is_bytewise(foreigntableid, &table_column(foreigntableid, "key"))
+                && key_type_sorts_as_key(foreigntableid, &def_elem_options((*table).options)),
+            value_order_bytewise: column_order_is_bytewise(foreigntableid, &table_column(foreigntableid, "value"))
+                && type_sorts_as_text(column_type(foreigntableid, &table_column(foreigntableid, "value"))),
+            key_collation: match column_collation(foreigntableid, &table_column(foreigntableid, "key")) {
+                pg_sys::InvalidOid => pg_sys::DEFAULT_COLLATION_OID,
+                collid => collid,
+            },
+            relid: foreigntableid,
+            key_range_commuted: range_commuted(baserel, foreigntableid, &table_column(foreigntableid, "key")),
+            revision_range_commuted: range_commuted(baserel, foreigntableid, c"mod_revision")
+                || range_commuted(baserel, foreigntableid, c"create_revision"),
+            value_in_conditions: value_in_conditions(baserel, foreigntableid),
+            conditions: condition_count(baserel),
+            limit_applies: limit_applies_to_scan(root),
+            sort_keys,
+            first_sort_on_table,
+            empty_when_unavailable: on_unavailable.as_deref() == Some("empty"),
+            select: (*(*root).parse).commandType == pg_sys::CmdType::CMD_SELECT,
+            delete: (*(*root).parse).commandType == pg_sys::CmdType::CMD_DELETE,
+        });
+        if let Some(Some(framework_fn)) = FRAMEWORK_GET_FOREIGN_REL_SIZE.get() {
+            framework_fn(root, baserel, foreigntableid);
         }
+        PLANNED_TABLE.set(PlannedTable::DEFAULT);
+        // The framework takes the row count of `get_rel_size` as is, the conditions of the scan reduce it
+        // The keys of the table are kept for costing the scan, see `scan_cost`
+        (*baserel).tuples = (*baserel).rows;
+        let selectivity = pg_sys::clauselist_selectivity(
+            root,
+            (*baserel).baserestrictinfo,
+            0,
+            pg_sys::JoinType::JOIN_INNER,
+            std::ptr::null_mut(),
+        );
+        (*baserel).rows = pg_sys::clamp_row_est((*baserel).rows * selectivity);
     }
 }
 
-#[derive(Error, Debug)]
-pub enum EtcdFdwError {
-    #[error("Failed to fetch from etcd: {0}")]
-    FetchError(String),
+/// `starts_with(text, text)` and the `^@` operator it implements
+const STARTS_WITH_FUNCTION: pg_sys::Oid = pg_sys::Oid::from_u32(3696);
+const STARTS_WITH_OPERATOR: pg_sys::Oid = pg_sys::Oid::from_u32(3877);
 
-    #[error("Failed to send update to etcd: {0}")]
-    UpdateError(String),
+/// Rewrites conditions `starts_with(key, 'x')` to `key ^@ 'x'`, which the framework passes on as quals
+/// Both call the same function, so the rows PostgreSQL returns are the same
+unsafe fn rewrite_starts_with(baserel: *mut pg_sys::RelOptInfo, relid: pg_sys::Oid) {
+    let attnum = pg_sys::get_attnum(relid, table_column(relid, "key").as_ptr());
+    if attnum == pg_sys::InvalidAttrNumber as pg_sys::AttrNumber {
+        return;
+    }
+    let unnest = |node: *mut pg_sys::Node| {
+        if is_a(node, pg_sys::NodeTag::T_RelabelType) {
+            (*(node as *mut pg_sys::RelabelType)).arg as *mut pg_sys::Node
+        } else {
+            node
+        }
+    };
 
-    #[error("Failed to connect to client: {0}")]
-    ClientConnectionError(String),
+    pgrx::memcx::current_context(|mcx| {
+        let Some(clauses) =
+            pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx((*baserel).baserestrictinfo, mcx)
+        else {
+            return;
+        };
+        for ri in clauses.iter() {
+            let ri = *ri as *mut pg_sys::RestrictInfo;
+            let clause = (*ri).clause as *mut pg_sys::Node;
+            if !is_a(clause, pg_sys::NodeTag::T_FuncExpr) {
+                continue;
+            }
+            let func = clause as *mut pg_sys::FuncExpr;
+            if (*func).funcid != STARTS_WITH_FUNCTION {
+                continue;
+            }
+            let Some(args) = pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx((*func).args, mcx) else {
+                continue;
+            };
+            let [string, prefix] = args.iter().map(|a| *a as *mut pg_sys::Node).collect::<Vec<_>>()[..] else {
+                continue;
+            };
+            // only conditions on the key are pushed down, `^@` has no commutator for `starts_with('/a/b', key)`
+            let key = unnest(string);
+            if !is_a(key, pg_sys::NodeTag::T_Var)
+                || (*(key as *mut pg_sys::Var)).varattno != attnum
+                || is_a(unnest(prefix), pg_sys::NodeTag::T_Var)
+            {
+                continue;
+            }
+            (*ri).clause = pg_sys::make_opclause(
+                STARTS_WITH_OPERATOR,
+                pg_sys::BOOLOID,
+                false,
+                string.cast(),
+                prefix.cast(),
+                pg_sys::InvalidOid,
+                (*func).inputcollid,
+            );
+        }
+    })
+}
 
-    #[error("No connection string option was specified. Specify it with connstr")]
-    NoConnStr(()),
+/// Whether a range condition of the scan has the column on the right, as in `'/b' > key`
+/// The framework swaps the operands of such conditions without commuting the operator,
+/// so range conditions on the column aren't pushed down for these scans
+unsafe fn range_commuted(baserel: *mut pg_sys::RelOptInfo, relid: pg_sys::Oid, column: &CStr) -> bool {
+    let attnum = pg_sys::get_attnum(relid, column.as_ptr());
+    if attnum == pg_sys::InvalidAttrNumber as pg_sys::AttrNumber {
+        return false;
+    }
+    let unnest = |node: *mut pg_sys::Node| {
+        if is_a(node, pg_sys::NodeTag::T_RelabelType) {
+            (*(node as *mut pg_sys::RelabelType)).arg as *mut pg_sys::Node
+        } else {
+            node
+        }
+    };
 
-    #[error("KeyFile and CertFile must both be present.")]
-    CertKeyMismatch(()),
+    pgrx::memcx::current_context(|mcx| {
+        let Some(clauses) =
+            pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx((*baserel).baserestrictinfo, mcx)
+        else {
+            return false;
+        };
+        clauses.iter().any(|ri| {
+            let clause = (*(*ri as *mut pg_sys::RestrictInfo)).clause as *mut pg_sys::Node;
+            if !is_a(clause, pg_sys::NodeTag::T_OpExpr) {
+                return false;
+            }
+            let expr = clause as *mut pg_sys::OpExpr;
+            let Some(args) = pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx((*expr).args, mcx) else {
+                return false;
+            };
+            let operands: Vec<*mut pg_sys::Node> = args.iter().map(|a| unnest(*a as *mut pg_sys::Node)).collect();
+            let [left, right] = operands[..] else {
+                return false;
+            };
+            let key_on_right = is_a(right, pg_sys::NodeTag::T_Var)
+                && !is_a(left, pg_sys::NodeTag::T_Var)
+                && (*(right as *mut pg_sys::Var)).varattno == attnum;
+            let opname = pg_sys::get_opname((*expr).opno);
+            key_on_right
+                && !opname.is_null()
+                && matches!(CStr::from_ptr(opname).to_bytes(), b"<" | b"<=" | b">" | b">=")
+        })
+    })
+}
 
-    #[error("User and Password must both be specified through user mappings.")]
-    UserPassMismatch(()),
+/// Whether a condition of the scan refers to the value column, a column projected from it or the whole row
+unsafe fn value_in_conditions(baserel: *mut pg_sys::RelOptInfo, relid: pg_sys::Oid) -> bool {
+    let attnum = pg_sys::get_attnum(relid, table_column(relid, "value").as_ptr());
+    pgrx::memcx::current_context(|mcx| {
+        let Some(clauses) =
+            pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx((*baserel).baserestrictinfo, mcx)
+        else {
+            return false;
+        };
+        clauses.iter().any(|ri| {
+            let clause = (*(*ri as *mut pg_sys::RestrictInfo)).clause as *mut pg_sys::Node;
+            let flags = (pg_sys::PVC_RECURSE_AGGREGATES | pg_sys::PVC_RECURSE_PLACEHOLDERS) as i32;
+            let vars = pg_sys::pull_var_clause(clause, flags);
+            pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx(vars, mcx).is_some_and(|vars| {
+                vars.iter().any(|var| {
+                    let varattno = (*(*var as *mut pg_sys::Var)).varattno;
+                    varattno == attnum || varattno == 0 || column_options(relid, varattno).contains_key("jsonpath")
+                })
+            })
+        })
+    })
+}
 
-    #[error("Column {0} is not contained in the input dataset")]
-    MissingColumn(String),
+/// Number of conditions PostgreSQL evaluates on the rows of the scan
+unsafe fn condition_count(baserel: *mut pg_sys::RelOptInfo) -> usize {
+    pgrx::memcx::current_context(|mcx| {
+        pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx((*baserel).baserestrictinfo, mcx)
+            .map_or(0, |clauses| clauses.len())
+    })
+}
 
-    #[error("Key {0} already exists in etcd. No duplicates allowed")]
-    KeyAlreadyExists(String),
+/// Whether the LIMIT of the query only counts rows of this scan, so it can be pushed down
+/// The framework passes the LIMIT on to every scan without aggregates, but joins, DISTINCT,
+/// window functions and set-returning functions change the number of rows it counts,
+/// and `WITH TIES` returns more rows than the limit
+unsafe fn limit_applies_to_scan(root: *mut pg_sys::PlannerInfo) -> bool {
+    let parse = (*root).parse;
+    pg_sys::bms_membership((*root).all_baserels) == pg_sys::BMS_Membership::BMS_SINGLETON
+        && (*parse).distinctClause.is_null()
+        && !(*parse).hasWindowFuncs
+        && !(*parse).hasTargetSRFs
+        && (*parse).limitOption != pg_sys::LimitOption::LIMIT_OPTION_WITH_TIES
+}
 
-    #[error("Options 'prefix' and 'range_end' cannot be used together")]
-    ConflictingPrefixAndRange,
+/// The framework's `GetForeignPaths` callback, wrapped by `get_foreign_paths`
+static FRAMEWORK_GET_FOREIGN_PATHS: OnceLock<pg_sys::GetForeignPaths_function> = OnceLock::new();
+
+/// Costs the scan path by the requests and keys it reads from etcd, see `scan_cost`
+/// Marks the scan path of queries ordered by the key as sorted if etcd returns the keys in that order
+/// PostgreSQL doesn't sort the rows again then, so `WHERE key > $1 ORDER BY key LIMIT n`
+/// reads and returns a single page of n keys
+#[pg_guard]
+extern "C-unwind" fn get_foreign_paths(
+    root: *mut pg_sys::PlannerInfo,
+    baserel: *mut pg_sys::RelOptInfo,
+    foreigntableid: pg_sys::Oid,
+) {
+    unsafe {
+        if let Some(Some(framework_fn)) = FRAMEWORK_GET_FOREIGN_PATHS.get() {
+            framework_fn(root, baserel, foreigntableid);
+        }
+        let (startup_cost, total_cost) = scan_cost(root, baserel, foreigntableid);
+        let scan_path = pgrx::memcx::current_context(|mcx| {
+            let paths = pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx((*baserel).pathlist, mcx)?;
+            for path in paths.iter() {
+                (*(*path as *mut pg_sys::Path)).startup_cost = startup_cost;
+                (*(*path as *mut pg_sys::Path)).total_cost = total_cost;
+            }
+            paths.get(0).map(|path| *path as *mut pg_sys::ForeignPath)
+        });
+        if let Some(scan_path) = scan_path {
+            add_key_join_paths(root, baserel, foreigntableid, scan_path);
+        }
+        let Some(pathkey) = key_pathkey(root, baserel, foreigntableid) else {
+            return;
+        };
+        // Only the key is sorted by etcd, later sort keys are left to an incremental sort
+        let pathkeys = pg_sys::lappend(std::ptr::null_mut(), pathkey.cast());
+        pgrx::memcx::current_context(|mcx| {
+            if let Some(paths) = pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx((*baserel).pathlist, mcx) {
+                for path in paths.iter().filter(|path| (*(**path as *mut pg_sys::Path)).param_info.is_null()) {
+                    (*(*path as *mut pg_sys::Path)).pathkeys = pathkeys;
+                }
+            }
+        });
+    }
+}
 
-    #[error("Options 'prefix' and 'key' should not be used together")]
-    ConflictingPrefixAndKey,
+/// The other side of a join condition `key = outer.column` on the key of the scanned relation
+unsafe fn key_join_operand(
+    clause: *mut pg_sys::Node,
+    baserel: *mut pg_sys::RelOptInfo,
+    key_attnum: pg_sys::AttrNumber,
+) -> Option<*mut pg_sys::Node> {
+    let unnest = |node: *mut pg_sys::Node| {
+        if is_a(node, pg_sys::NodeTag::T_RelabelType) {
+            (*(node as *mut pg_sys::RelabelType)).arg as *mut pg_sys::Node
+        } else {
+            node
+        }
+    };
+    if !is_a(clause, pg_sys::NodeTag::T_OpExpr) {
+        return None;
+    }
+    let expr = clause as *mut pg_sys::OpExpr;
+    let opname = pg_sys::get_opname((*expr).opno);
+    if opname.is_null() || CStr::from_ptr(opname).to_bytes() != b"=" {
+        return None;
+    }
+    pgrx::memcx::current_context(|mcx| {
+        let args = pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx((*expr).args, mcx)?;
+        let operands: Vec<*mut pg_sys::Node> = args.iter().map(|a| unnest(*a as *mut pg_sys::Node)).collect();
+        let [left, right] = operands[..] else {
+            return None;
+        };
+        let is_key = |node: *mut pg_sys::Node| {
+            is_a(node, pg_sys::NodeTag::T_Var)
+                && (*(node as *mut pg_sys::Var)).varno as pg_sys::Index == (*baserel).relid
+                && (*(node as *mut pg_sys::Var)).varattno == key_attnum
+        };
+        let is_outer = |node: *mut pg_sys::Node| {
+            is_a(node, pg_sys::NodeTag::T_Var) && (*(node as *mut pg_sys::Var)).varno as pg_sys::Index != (*baserel).relid
+        };
+        match (is_key(left), is_key(right)) {
+            (true, false) if is_outer(right) => Some(right),
+            (false, true) if is_outer(left) => Some(left),
+            _ => None,
+        }
+    })
+}
 
-    #[error("Key {0} doesn't exist in etcd")]
-    KeyDoesntExist(String),
+/// Equivalence class callback of `add_key_join_paths`, matches the key column of the scanned relation
+#[pg_guard]
+extern "C-unwind" fn ec_member_is_key(
+    _root: *mut pg_sys::PlannerInfo,
+    rel: *mut pg_sys::RelOptInfo,
+    _ec: *mut pg_sys::EquivalenceClass,
+    em: *mut pg_sys::EquivalenceMember,
+    arg: *mut std::ffi::c_void,
+) -> bool {
+    unsafe {
+        let mut expr = (*em).em_expr as *mut pg_sys::Node;
+        if is_a(expr, pg_sys::NodeTag::T_RelabelType) {
+            expr = (*(expr as *mut pg_sys::RelabelType)).arg as *mut pg_sys::Node;
+        }
+        is_a(expr, pg_sys::NodeTag::T_Var)
+            && (*(expr as *mut pg_sys::Var)).varno as pg_sys::Index == (*rel).relid
+            && (*(expr as *mut pg_sys::Var)).varattno == *(arg as *mut pg_sys::AttrNumber)
+    }
+}
 
-    #[error("Invalid option '{0}' with value '{1}'")]
-    InvalidOption(String, String),
+/// Adds a parameterized path for each set of outer relations the key is joined with by equality,
+/// so a nested loop join can read the matching key with a point get per outer row
+/// The paths are copies of the framework's scan path, the values are passed in by `re_scan_foreign_scan`
+unsafe fn add_key_join_paths(
+    root: *mut pg_sys::PlannerInfo,
+    baserel: *mut pg_sys::RelOptInfo,
+    relid: pg_sys::Oid,
+    scan_path: *mut pg_sys::ForeignPath,
+) {
+    let mut key_attnum = pg_sys::get_attnum(relid, table_column(relid, "key").as_ptr());
+    if key_attnum == pg_sys::InvalidAttrNumber as pg_sys::AttrNumber {
+        return;
+    }
+    let options = def_elem_options((*pg_sys::GetForeignTable(relid)).options);
+    if options.get("layout").is_some_and(|v| v == "sub_keys") {
+        return;
+    }
 
-    #[error("Invalid sort field value '{0}'")]
-    InvalidSortField(String),
+    // Join conditions implied by equivalence classes, like `e.key = l.name`, and the other ones on the key
+    let mut clauses = pg_sys::generate_implied_equalities_for_column(
+        root,
+        baserel,
+        Some(ec_member_is_key),
+        (&mut key_attnum as *mut pg_sys::AttrNumber).cast(),
+        std::ptr::null_mut(),
+    );
+    pgrx::memcx::current_context(|mcx| {
+        if let Some(joininfo) = pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx((*baserel).joininfo, mcx) {
+            for ri in joininfo.iter() {
+                let ri = *ri as *mut pg_sys::RestrictInfo;
+                if pg_sys::join_clause_is_movable_to(ri, baserel)
+                    && key_join_operand((*ri).clause.cast(), baserel, key_attnum).is_some()
+                {
+                    clauses = pg_sys::lappend(clauses, ri.cast());
+                }
+            }
+        }
+    });
 
-    #[error("{0}")]
-    OptionsError(#[from] OptionsError),
+    let mut required: Vec<pg_sys::Relids> = Vec::new();
+    pgrx::memcx::current_context(|mcx| {
+        let Some(clauses) = pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx(clauses, mcx) else {
+            return;
+        };
+        for ri in clauses.iter() {
+            let ri = *ri as *mut pg_sys::RestrictInfo;
+            if key_join_operand((*ri).clause.cast(), baserel, key_attnum).is_none() {
+                continue;
+            }
+            let required_outer = pg_sys::bms_difference((*ri).clause_relids, (*baserel).relids);
+            if pg_sys::bms_num_members(required_outer) == 0
+                || required.iter().any(|r| pg_sys::bms_equal(*r, required_outer))
+            {
+                continue;
+            }
+            required.push(required_outer);
+        }
+    });
+
+    for required_outer in required {
+        let param_info = pg_sys::get_baserel_parampathinfo(root, baserel, required_outer);
+        let rows = (*param_info).ppi_rows;
+        let path = pg_sys::palloc(std::mem::size_of::<pg_sys::ForeignPath>()) as *mut pg_sys::ForeignPath;
+        *path = *scan_path;
+        (*path).path.param_info = param_info;
+        (*path).path.rows = rows;
+        (*path).path.pathkeys = std::ptr::null_mut();
+        (*path).path.startup_cost = REQUEST_COST;
+        (*path).path.total_cost = REQUEST_COST + rows * (KEY_COST + pg_sys::cpu_tuple_cost);
+        pg_sys::add_path(baserel, path.cast());
+    }
 }
 
-impl From<EtcdFdwError> for ErrorReport {
-    fn from(value: EtcdFdwError) -> Self {
-        ErrorReport::new(PgSqlErrorCode::ERRCODE_FDW_ERROR, format!("{}", value), "")
+/// The framework's `GetForeignPlan` callback, wrapped by `get_foreign_plan`
+static FRAMEWORK_GET_FOREIGN_PLAN: OnceLock<pg_sys::GetForeignPlan_function> = OnceLock::new();
+
+/// Passes the outer columns of the key's join conditions of parameterized scans to the executor as `fdw_exprs`
+/// PostgreSQL replaces them by the parameters of the nested loop join, and still rechecks the conditions
+#[pg_guard]
+extern "C-unwind" fn get_foreign_plan(
+    root: *mut pg_sys::PlannerInfo,
+    baserel: *mut pg_sys::RelOptInfo,
+    foreigntableid: pg_sys::Oid,
+    best_path: *mut pg_sys::ForeignPath,
+    tlist: *mut pg_sys::List,
+    scan_clauses: *mut pg_sys::List,
+    outer_plan: *mut pg_sys::Plan,
+) -> *mut pg_sys::ForeignScan {
+    unsafe {
+        let scan = match FRAMEWORK_GET_FOREIGN_PLAN.get() {
+            Some(Some(framework_fn)) => {
+                framework_fn(root, baserel, foreigntableid, best_path, tlist, scan_clauses, outer_plan)
+            }
+            _ => return std::ptr::null_mut(),
+        };
+        let param_info = (*best_path).path.param_info;
+        if param_info.is_null() {
+            return scan;
+        }
+        let key_attnum = pg_sys::get_attnum(foreigntableid, table_column(foreigntableid, "key").as_ptr());
+        pgrx::memcx::current_context(|mcx| {
+            if let Some(clauses) =
+                pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx((*param_info).ppi_clauses, mcx)
+            {
+                for ri in clauses.iter() {
+                    let clause = (*(*ri as *mut pg_sys::RestrictInfo)).clause as *mut pg_sys::Node;
+                    if let Some(outer) = key_join_operand(clause, baserel, key_attnum) {
+                        (*scan).fdw_exprs = pg_sys::lappend((*scan).fdw_exprs, outer.cast());
+                    }
+                }
+            }
+        });
+        scan
     }
 }
 
-/// Check whether dependent options exits
-/// i.e user & pass, cert & key
-fn require_pair(
-    a: bool,
-    b: bool,
-    err: EtcdFdwError,
-) -> Result<(), EtcdFdwError> {
-    match (a, b) {
-        (true, false) | (false, true) => Err(err),
-        _ => Ok(()),
-    }
+/// Values of the key's join conditions for the scan the framework is about to start
+enum JoinParams {
+    /// the scan waits for the first outer row of the nested loop
+    Pending,
+    /// the values of the current outer row, `None` for NULL, which matches no key
+    Values(Vec<Option<Cell>>),
 }
 
-/// Helper function for parsing timeouts
-fn parse_timeout(
-    options: &std::collections::HashMap<String, String>,
-    key: &str,
-    default: Duration,
-) -> Result<Duration, EtcdFdwError> {
-    if let Some(val) = options.get(key) {
-        match val.parse::<u64>() {
-            Ok(secs) => Ok(Duration::from_secs(secs)),
-            Err(_) => Err(EtcdFdwError::InvalidOption(key.to_string(), val.clone())),
+thread_local! {
+    /// Set by `begin_foreign_scan` and `re_scan_foreign_scan` around the framework's callbacks
+    static JOIN_PARAMS: RefCell<Option<JoinParams>> = const { RefCell::new(None) };
+}
+
+/// The framework's `BeginForeignScan` callback, wrapped by `begin_foreign_scan`
+static FRAMEWORK_BEGIN_FOREIGN_SCAN: OnceLock<pg_sys::BeginForeignScan_function> = OnceLock::new();
+
+/// Parameterized scans wait for the values of the outer row, which are only set before the first rescan
+#[pg_guard]
+extern "C-unwind" fn begin_foreign_scan(node: *mut pg_sys::ForeignScanState, eflags: std::ffi::c_int) {
+    unsafe {
+        let plan = (*node).ss.ps.plan as *mut pg_sys::ForeignScan;
+        if !(*plan).fdw_exprs.is_null() {
+            JOIN_PARAMS.set(Some(JoinParams::Pending));
         }
-    } else {
-        Ok(default)
+        // The framework doesn't begin scans that are only explained,
+        // they are begun anyway and stop after describing their request, see `describe_scan`
+        let explain_only = eflags & pg_sys::EXEC_FLAG_EXPLAIN_ONLY as std::ffi::c_int != 0;
+        EXPLAIN_ONLY.set(explain_only);
+        SCAN_REQUEST.set(None);
+        if let Some(Some(framework_fn)) = FRAMEWORK_BEGIN_FOREIGN_SCAN.get() {
+            framework_fn(node, eflags & !(pg_sys::EXEC_FLAG_EXPLAIN_ONLY as std::ffi::c_int));
+        }
+        EXPLAIN_ONLY.set(false);
+        JOIN_PARAMS.set(None);
+        record_scan_request(node);
+        let stats = BEGUN_SCAN_STATS.take();
+        SCAN_STATS.with_borrow_mut(|scans| match stats {
+            Some(stats) => scans.insert(node as usize, stats),
+            None => scans.remove(&(node as usize)),
+        });
     }
 }
 
+thread_local! {
+    /// Requests of the running foreign scans, by the address of their `ForeignScanState`
+    static SCAN_REQUESTS: RefCell<HashMap<usize, String>> = RefCell::new(HashMap::new());
+    /// Statistics of the running foreign scans, by the address of their `ForeignScanState`
+    static SCAN_STATS: RefCell<HashMap<usize, Rc<std::cell::Cell<ScanStats>>>> = RefCell::new(HashMap::new());
+}
 
+/// Keeps the description of the request the scan of `node` sent last for `explain_foreign_scan`
+fn record_scan_request(node: *mut pg_sys::ForeignScanState) {
+    let request = SCAN_REQUEST.take();
+    SCAN_REQUESTS.with_borrow_mut(|requests| match request {
+        Some(request) => requests.insert(node as usize, request),
+        None => requests.remove(&(node as usize)),
+    });
+}
 
-/// Use this to connect to etcd.
-/// Parse the certs/key paths and read them as bytes
-/// Sets the `TlsOptions` if available to support sll connection
-pub async fn connect_etcd(config: EtcdConfig) -> Result<Client, Error> {
-    let mut connect_options = ConnectOptions::new()
-        .with_connect_timeout(config.connect_timeout)
-        .with_timeout(config.request_timeout);
+/// The framework's `ExplainForeignScan` callback, wrapped by `explain_foreign_scan`
+static FRAMEWORK_EXPLAIN_FOREIGN_SCAN: OnceLock<pg_sys::ExplainForeignScan_function> = OnceLock::new();
 
-    let use_tls = config.ca_cert_path.is_some() || config.client_cert_path.is_some();
+/// Adds the etcd request of the scan to EXPLAIN (VERBOSE), so it shows which conditions were pushed down
+#[pg_guard]
+extern "C-unwind" fn explain_foreign_scan(node: *mut pg_sys::ForeignScanState, es: *mut pg_sys::ExplainState) {
+    unsafe {
+        if let Some(Some(framework_fn)) = FRAMEWORK_EXPLAIN_FOREIGN_SCAN.get() {
+            framework_fn(node, es);
+        }
+        let stats = SCAN_STATS.with_borrow(|scans| scans.get(&(node as usize)).map(|s| s.get()));
+        if let Some(stats) = stats.filter(|_| (*es).analyze) {
+            pg_sys::ExplainPropertyInteger(c"etcd Requests".as_ptr(), std::ptr::null(), stats.requests, es);
+            pg_sys::ExplainPropertyInteger(c"etcd Keys".as_ptr(), std::ptr::null(), stats.keys, es);
+            pg_sys::ExplainPropertyInteger(c"etcd Bytes".as_ptr(), c"bytes".as_ptr(), stats.bytes, es);
+            if (*es).timing {
+                let ms = stats.time.as_secs_f64() * 1000.0;
+                pg_sys::ExplainPropertyFloat(c"etcd Time".as_ptr(), c"ms".as_ptr(), ms, 3, es);
+            }
+        }
+        if !(*es).verbose {
+            return;
+        }
+        let request = SCAN_REQUESTS.with_borrow(|requests| requests.get(&(node as usize)).cloned());
+        if let Some(request) = request.and_then(|r| CString::new(r).ok()) {
+            pg_sys::ExplainPropertyText(c"etcd Request".as_ptr(), request.as_ptr(), es);
+        }
+    }
+}
 
-    if use_tls {
-        let mut tls_options = TlsOptions::new();
+/// The framework's `EndForeignScan` callback, wrapped by `end_foreign_scan`
+static FRAMEWORK_END_FOREIGN_SCAN: OnceLock<pg_sys::EndForeignScan_function> = OnceLock::new();
+
+/// Forgets the request of the ended scan
+#[pg_guard]
+extern "C-unwind" fn end_foreign_scan(node: *mut pg_sys::ForeignScanState) {
+    unsafe {
+        SCAN_REQUESTS.with_borrow_mut(|requests| requests.remove(&(node as usize)));
+        SCAN_STATS.with_borrow_mut(|scans| scans.remove(&(node as usize)));
+        if let Some(Some(framework_fn)) = FRAMEWORK_END_FOREIGN_SCAN.get() {
+            framework_fn(node);
+        }
+    }
+}
 
-        // Load CA cert if provided
-        if let Some(ca_path) = &config.ca_cert_path {
-            let ca_bytes = std::fs::read(ca_path).map_err(Error::IoError)?;
-            let ca_cert = Certificate::from_pem(ca_bytes);
-            tls_options = tls_options.ca_certificate(ca_cert);
+/// The framework's `ReScanForeignScan` callback, wrapped by `re_scan_foreign_scan`
+static FRAMEWORK_RE_SCAN_FOREIGN_SCAN: OnceLock<pg_sys::ReScanForeignScan_function> = OnceLock::new();
+
+/// Restarts the scan with the values the nested loop join set for the current outer row
+#[pg_guard]
+extern "C-unwind" fn re_scan_foreign_scan(node: *mut pg_sys::ForeignScanState) {
+    unsafe {
+        let plan = (*node).ss.ps.plan as *mut pg_sys::ForeignScan;
+        JOIN_PARAMS.set(join_param_values(node, (*plan).fdw_exprs).map(JoinParams::Values));
+        EXPLAIN_ONLY.set(false);
+        SCAN_REQUEST.set(None);
+        if let Some(Some(framework_fn)) = FRAMEWORK_RE_SCAN_FOREIGN_SCAN.get() {
+            framework_fn(node);
         }
+        JOIN_PARAMS.set(None);
+        record_scan_request(node);
+    }
+}
 
-        // Load client cert and key if both provided
-        if let (Some(cert_path), Some(key_path)) = (&config.client_cert_path, &config.client_key_path) {
-            let cert_bytes = std::fs::read(cert_path).map_err(Error::IoError)?;
-            let key_bytes  = std::fs::read(key_path).map_err(Error::IoError)?;
-            let identity = Identity::from_pem(cert_bytes, key_bytes);
-            tls_options = tls_options.identity(identity);
+/// Current values of the nested loop parameters in `fdw_exprs`
+/// `None` if there are none or one can't be read, the scan then reads all keys and the join conditions are rechecked
+unsafe fn join_param_values(node: *mut pg_sys::ForeignScanState, fdw_exprs: *mut pg_sys::List) -> Option<Vec<Option<Cell>>> {
+    let estate = (*node).ss.ps.state;
+    pgrx::memcx::current_context(|mcx| {
+        let exprs = pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx(fdw_exprs, mcx)?;
+        let mut values = Vec::new();
+        for expr in exprs.iter() {
+            let expr = *expr as *mut pg_sys::Node;
+            if !is_a(expr, pg_sys::NodeTag::T_Param) {
+                return None;
+            }
+            let param = expr as *mut pg_sys::Param;
+            if (*param).paramkind != pg_sys::ParamKind::PARAM_EXEC {
+                return None;
+            }
+            let prm = (*estate).es_param_exec_vals.add((*param).paramid as usize);
+            if !(*prm).execPlan.is_null() {
+                return None;
+            }
+            if (*prm).isnull {
+                values.push(None);
+                continue;
+            }
+            values.push(Some(Cell::from_polymorphic_datum((*prm).value, false, (*param).paramtype)?));
         }
+        Some(values)
+    })
+}
 
-        // Load domain name if provided
-        if let Some(domain) = &config.servername {
-            tls_options = tls_options.domain_name(domain);
+/// Cost of a request to etcd, dominated by the network round trip
+const REQUEST_COST: f64 = 100.0;
+/// Cost of transferring a key and its value from etcd
+const KEY_COST: f64 = 0.01;
+
+/// Startup and total cost of scanning the table
+/// Conditions on the key narrow the range etcd reads, so only their selectivity reduces the keys transferred.
+/// Scans of more than `fetch_size` keys take a request per page
+unsafe fn scan_cost(
+    root: *mut pg_sys::PlannerInfo,
+    baserel: *mut pg_sys::RelOptInfo,
+    relid: pg_sys::Oid,
+) -> (pg_sys::Cost, pg_sys::Cost) {
+    let attnum = pg_sys::get_attnum(relid, table_column(relid, "key").as_ptr());
+    let key_clauses = pgrx::memcx::current_context(|mcx| {
+        let mut key_clauses: *mut pg_sys::List = std::ptr::null_mut();
+        let Some(clauses) =
+            pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx((*baserel).baserestrictinfo, mcx)
+        else {
+            return key_clauses;
+        };
+        for ri in clauses.iter() {
+            let clause = (*(*ri as *mut pg_sys::RestrictInfo)).clause as *mut pg_sys::Node;
+            let flags = (pg_sys::PVC_RECURSE_AGGREGATES | pg_sys::PVC_RECURSE_PLACEHOLDERS) as i32;
+            let vars = pg_sys::pull_var_clause(clause, flags);
+            let only_key = pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx(vars, mcx)
+                .is_some_and(|vars| vars.iter().all(|var| (*(*var as *mut pg_sys::Var)).varattno == attnum));
+            if only_key {
+                key_clauses = pg_sys::lappend(key_clauses, *ri);
+            }
         }
+        key_clauses
+    });
+    let key_selectivity =
+        pg_sys::clauselist_selectivity(root, key_clauses, 0, pg_sys::JoinType::JOIN_INNER, std::ptr::null_mut());
+    let keys = pg_sys::clamp_row_est((*baserel).tuples * key_selectivity);
+
+    let options = def_elem_options((*pg_sys::GetForeignTable(relid)).options);
+    let fetch_size = options
+        .get("fetch_size")
+        .and_then(|v| parse_fetch_size(v).ok())
+        .unwrap_or(DEFAULT_FETCH_SIZE);
+    let pages = if fetch_size > 0 {
+        (keys / fetch_size as f64).ceil().max(1.0)
+    } else {
+        1.0
+    };
 
-        connect_options = connect_options.with_tls(tls_options);
-    }
+    // The framework's `startup_cost` table option replaces the cost of the first request
+    let startup_cost = options
+        .get("startup_cost")
+        .and_then(|v| parse_startup_cost(v).ok())
+        .unwrap_or(REQUEST_COST);
+    let total_cost = startup_cost
+        + (pages - 1.0) * REQUEST_COST
+        + keys * KEY_COST
+        + (*baserel).rows * pg_sys::cpu_tuple_cost;
+    (startup_cost, total_cost)
+}
 
-    // Load User and Password
-    if let (Some(user), Some(pass)) = (&config.user, &config.password) {
-        connect_options = connect_options.with_user(user, pass);
+/// The first sort key of the query, if it orders by the key column in etcd's bytewise order
+/// Mirrors the conditions under which `start_scan` pushes the sort down
+unsafe fn key_pathkey(
+    root: *mut pg_sys::PlannerInfo,
+    baserel: *mut pg_sys::RelOptInfo,
+    relid: pg_sys::Oid,
+) -> Option<*mut pg_sys::PathKey> {
+    let options = def_elem_options((*pg_sys::GetForeignTable(relid)).options);
+    if options.get("layout").is_some_and(|v| v == "sub_keys") {
+        return None;
+    }
+    if !KeyEncoding::from_options(&options).ok()?.as_ref().is_none_or(KeyEncoding::preserves_order) {
+        return None;
+    }
+    if !key_type_sorts_as_key(relid, &options) {
+        return None;
+    }
+    let attnum = pg_sys::get_attnum(relid, table_column(relid, "key").as_ptr());
+    if attnum == pg_sys::InvalidAttrNumber as pg_sys::AttrNumber {
+        return None;
     }
 
-    let endpoints: Vec<&str> = config.endpoints.iter().map(|s| s.as_str()).collect();
-    Client::connect(endpoints, Some(connect_options)).await
+    pgrx::memcx::current_context(|mcx| {
+        let pathkeys = pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx((*root).query_pathkeys, mcx)?;
+        let pathkey = *pathkeys.iter().next()? as *mut pg_sys::PathKey;
+        let orders_by_key = pathkey_column(pathkey, baserel, mcx) == Some(attnum);
+
+        // An explicit collation is pushed down if it's "C" or "POSIX", the column's if it sorts bytewise
+        let collid = (*(*pathkey).pk_eclass).ec_collation;
+        let bytewise = collid == pg_sys::C_COLLATION_OID
+            || collid == pg_sys::POSIX_COLLATION_OID
+            || (collid == column_collation(relid, &table_column(relid, "key")) && collation_is_bytewise(collid));
+        (orders_by_key && bytewise).then_some(pathkey)
+    })
 }
 
+/// Column of the scanned table a sort key of the query refers to
+/// Found the way the framework extracts the sorts it passes to `begin_scan`
+unsafe fn pathkey_column(
+    pathkey: *mut pg_sys::PathKey,
+    baserel: *mut pg_sys::RelOptInfo,
+    mcx: &pgrx::memcx::MemCx<'_>,
+) -> Option<pg_sys::AttrNumber> {
+    let ec = (*pathkey).pk_eclass;
+    if (*ec).ec_has_volatile {
+        return None;
+    }
+    let members = pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx((*ec).ec_members, mcx)?;
+    let member = members
+        .iter()
+        .map(|em| *em as *mut pg_sys::EquivalenceMember)
+        .find(|em| pg_sys::bms_equal((**em).em_relids, (*baserel).relids))?;
+    // ORDER BY key COLLATE ... relabels the column
+    let mut expr = (*member).em_expr as *mut pg_sys::Node;
+    if is_a(expr, pg_sys::NodeTag::T_RelabelType) {
+        expr = (*(expr as *mut pg_sys::RelabelType)).arg as *mut pg_sys::Node;
+    }
+    is_a(expr, pg_sys::NodeTag::T_Var).then(|| (*(expr as *mut pg_sys::Var)).varattno)
+}
 
-type EtcdFdwResult<T> = std::result::Result<T, EtcdFdwError>;
+/// Number of sort keys of the query and whether the first one is a column of the scanned table
+/// Sorts of expressions, or of other tables, aren't passed to `begin_scan`,
+/// so the sorts it gets don't tell whether the query orders its rows at all
+unsafe fn query_sort_keys(root: *mut pg_sys::PlannerInfo, baserel: *mut pg_sys::RelOptInfo) -> (usize, bool) {
+    pgrx::memcx::current_context(|mcx| {
+        let Some(pathkeys) = pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx((*root).query_pathkeys, mcx)
+        else {
+            return (0, true);
+        };
+        let first_on_table = pathkeys
+            .iter()
+            .next()
+            .is_none_or(|pathkey| pathkey_column(*pathkey as *mut pg_sys::PathKey, baserel, mcx).is_some());
+        (pathkeys.len(), first_on_table)
+    })
+}
 
-impl ForeignDataWrapper<EtcdFdwError> for EtcdFdw {
-    fn new(server: ForeignServer) -> EtcdFdwResult<EtcdFdw> {
-        let mut config = EtcdConfig::default();
+/// The `get_relation_info_hook` installed before etcd_fdw's
+static PREV_GET_RELATION_INFO_HOOK: OnceLock<pg_sys::get_relation_info_hook_type> = OnceLock::new();
+
+/// Declares the key column of etcd foreign tables unique, as etcd keys are
+/// The planner uses unique indexes to remove unneeded joins, to skip de-duplication of
+/// semi joins and to estimate the number of distinct values
+#[pg_guard]
+extern "C-unwind" fn get_relation_info(
+    root: *mut pg_sys::PlannerInfo,
+    relation_object_id: pg_sys::Oid,
+    inhparent: bool,
+    rel: *mut pg_sys::RelOptInfo,
+) {
+    unsafe {
+        if let Some(Some(prev_hook)) = PREV_GET_RELATION_INFO_HOOK.get() {
+            prev_hook(root, relation_object_id, inhparent, rel);
+        }
 
-        // Open connection to etcd specified through the server parameter
-        let rt = tokio::runtime::Runtime::new().expect("Tokio runtime should be initialized");
+        // Rows of inheritance children can share keys
+        let fdwroutine = (*rel).fdwroutine;
+        if inhparent || fdwroutine.is_null() {
+            return;
+        }
+        let is_etcd_table = (*fdwroutine)
+            .GetForeignRelSize
+            .is_some_and(|f| f as usize == get_foreign_rel_size as usize);
+        if is_etcd_table {
+            if let Some(index) = key_unique_index(relation_object_id, rel) {
+                (*rel).indexlist = pg_sys::lappend((*rel).indexlist, index.cast());
+            }
+        }
+    }
+}
 
-        // Add parsing for the multi host connection string things here
-        let connstr = match server.options.get("connstr") {
-            Some(x) => x.clone(),
-            None => return Err(EtcdFdwError::NoConnStr(())),
-        };
+/// A hypothetical unique btree index on the key column of an etcd foreign table
+/// Hypothetical indexes aren't used for scans or probed for the actual range of values
+/// None if there's no key column or its equality doesn't imply equal keys, i.e. with case insensitive collations
+unsafe fn key_unique_index(relid: pg_sys::Oid, rel: *mut pg_sys::RelOptInfo) -> Option<*mut pg_sys::IndexOptInfo> {
+    let attnum = pg_sys::get_attnum(relid, table_column(relid, "key").as_ptr());
+    if attnum == pg_sys::InvalidAttrNumber as pg_sys::AttrNumber {
+        return None;
+    }
 
-        let cacert_path = server.options.get("ssl_ca").cloned();
-        let cert_path = server.options.get("ssl_cert").cloned();
-        let key_path  = server.options.get("ssl_key").cloned();
-        let servername  = server.options.get("ssl_servername").cloned();
+    let mut typid = pg_sys::InvalidOid;
+    let mut typmod = -1;
+    let mut collid = pg_sys::InvalidOid;
+    pg_sys::get_atttypetypmodcoll(relid, attnum, &mut typid, &mut typmod, &mut collid);
+    if collid != pg_sys::InvalidOid && !pg_sys::get_collation_isdeterministic(collid) {
+        return None;
+    }
+    let opclass = pg_sys::GetDefaultOpClass(typid, pg_sys::BTREE_AM_OID);
+    if opclass == pg_sys::InvalidOid {
+        return None;
+    }
 
-        // Parse timeouts with defaults
-        let connect_timeout = parse_timeout(&server.options, "connect_timeout", config.connect_timeout)?;
-        let request_timeout = parse_timeout(&server.options, "request_timeout", config.request_timeout)?;
+    let mut index = PgBox::<pg_sys::IndexOptInfo>::alloc_node(pg_sys::NodeTag::T_IndexOptInfo);
+    index.rel = rel;
+    index.tuples = (*rel).tuples;
+    index.tree_height = -1;
+    index.ncolumns = 1;
+    index.nkeycolumns = 1;
+    index.indexkeys = palloc_value(attnum as std::ffi::c_int);
+    index.indexcollations = palloc_value(collid);
+    index.opfamily = palloc_value(pg_sys::get_opclass_family(opclass));
+    index.opcintype = palloc_value(pg_sys::get_opclass_input_type(opclass));
+    index.relam = pg_sys::BTREE_AM_OID;
+    index.unique = true;
+    index.immediate = true;
+    index.hypothetical = true;
+    Some(index.into_pg())
+}
 
-        // ssl_cert + ssl_key must be both present or both absent
-        require_pair(cert_path.is_some(), key_path.is_some(), EtcdFdwError::CertKeyMismatch(()))?;
+/// A single element array in the current memory context
+unsafe fn palloc_value<T: Copy>(value: T) -> *mut T {
+    let ptr = pg_sys::palloc(std::mem::size_of::<T>()).cast::<T>();
+    ptr.write(value);
+    ptr
+}
 
-        let mut user = None;
-        let mut password = None;
+/// The framework's `PlanForeignModify` and `ExecForeignInsert` callbacks, wrapped for INSERT ... RETURNING
+static FRAMEWORK_PLAN_FOREIGN_MODIFY: OnceLock<pg_sys::PlanForeignModify_function> = OnceLock::new();
+static FRAMEWORK_EXEC_FOREIGN_INSERT: OnceLock<pg_sys::ExecForeignInsert_function> = OnceLock::new();
+static FRAMEWORK_EXEC_FOREIGN_UPDATE: OnceLock<pg_sys::ExecForeignUpdate_function> = OnceLock::new();
+static FRAMEWORK_EXEC_FOREIGN_DELETE: OnceLock<pg_sys::ExecForeignDelete_function> = OnceLock::new();
+static FRAMEWORK_BEGIN_FOREIGN_MODIFY: OnceLock<pg_sys::BeginForeignModify_function> = OnceLock::new();
+static FRAMEWORK_END_FOREIGN_MODIFY: OnceLock<pg_sys::EndForeignModify_function> = OnceLock::new();
+
+/// Range of keys a DELETE's scan read with all conditions evaluated by etcd
+/// If the DELETE deletes all of them, they are deleted by one range delete at the end of the statement
+struct RangeDelete {
+    key: Vec<u8>,
+    range_end: Vec<u8>,
+    /// revision the range was read at and the number of its keys
+    revision: i64,
+    count: i64,
+}
 
-        unsafe {
-            let usermapping =  pg_sys::GetUserMapping(pg_sys::GetUserId(), server.server_oid);
-            let options = (*usermapping).options;
-            pgrx::memcx::current_context(|mcx| {
-                let list = pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx(options, mcx).unwrap();
-                for option in list.iter() {
-                    let option = *option as *mut pg_sys::DefElem;
-                    let name_cstr = std::ffi::CStr::from_ptr((*option).defname);
-                    let value_cstr = std::ffi::CStr::from_ptr(pg_sys::defGetString(option));
-                    let name = name_cstr.to_str().map_err(|_| {
-                        OptionsError::OptionNameIsInvalidUtf8(
-                            String::from_utf8_lossy(name_cstr.to_bytes()).to_string(),
-                        )
-                    });
-                    let value = value_cstr.to_str().map_err(|_| {
-                        OptionsError::OptionValueIsInvalidUtf8 {
-                            option_name: name_cstr.to_string_lossy().into_owned(),
-                        }
-                    });
-                    if let (Ok(name), Ok(value)) = (name, value) {
-                        match name {
-                            "user" => user = Some(value.to_string()),
-                            "password" => password = Some(value.to_string()),
-                            _ => {}
-                        }
-                    }
-                }
-            });
+impl RangeDelete {
+    fn contains(&self, key: &[u8]) -> bool {
+        key >= self.key.as_slice() && key < self.range_end.as_slice()
+    }
+}
+
+/// Conditions of an UPDATE or DELETE that its writes compare in etcd again
+/// A key changed since the scan read it isn't written, e.g. for compare-and-swap with `mod_revision = N`
+/// or `value = '...'`
+struct WriteConditions {
+    relid: pg_sys::Oid,
+    mod_revision: Option<i64>,
+    /// the stored value the condition on the value column matches
+    value: Option<Vec<u8>>,
+}
+
+impl WriteConditions {
+    fn compares(&self, key: &[u8]) -> Vec<Compare> {
+        let mut compares = Vec::new();
+        if let Some(revision) = self.mod_revision {
+            compares.push(Compare::mod_revision(key, CompareOp::Equal, revision));
+        }
+        if let Some(value) = &self.value {
+            compares.push(Compare::value(key, CompareOp::Equal, value.clone()));
         }
+        compares
+    }
+}
 
-        config = EtcdConfig {
-            endpoints: vec![connstr],
-            ca_cert_path: cacert_path,
-            client_cert_path: cert_path,
-            client_key_path: key_path,
-            user: user,
-            password: password,
-            servername: servername,
-            connect_timeout: connect_timeout,
-            request_timeout: request_timeout,
-        };
+/// Metadata of the key written by the latest insert or update
+#[derive(Clone, Copy)]
+struct WrittenKey {
+    create_revision: i64,
+    mod_revision: i64,
+    version: i64,
+    lease: i64,
+    /// Whether the insert created the key, false if it existed already with `on_conflict 'ignore'`
+    created: bool,
+}
 
-        let client = match rt.block_on(connect_etcd(config)) {
-            Ok(x) => x,
-            Err(e) => return Err(EtcdFdwError::ClientConnectionError(e.to_string())),
+/// Metadata of a key written by a put, from the key etcd replaced if it existed
+fn replaced_key(previous: Option<&KeyValue>, revision: i64, lease: i64) -> WrittenKey {
+    WrittenKey {
+        create_revision: previous.map(|kv| kv.create_revision()).unwrap_or(revision),
+        mod_revision: revision,
+        version: previous.map(|kv| kv.version() + 1).unwrap_or(1),
+        lease,
+        created: previous.is_none(),
+    }
+}
+
+thread_local! {
+    static LAST_WRITE: std::cell::Cell<Option<WrittenKey>> = const { std::cell::Cell::new(None) };
+    /// Whether the current INSERT, UPDATE or DELETE has a RETURNING clause
+    /// Without it the rows of an INSERT are written at the end
+    static MODIFY_RETURNING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    /// New row of the current UPDATE, with the key column the framework leaves out of it
+    static UPDATED_ROW: RefCell<Option<Row>> = const { RefCell::new(None) };
+    /// Row of the key the latest UPDATE or DELETE ... RETURNING wrote or deleted
+    static RETURNED_ROW: RefCell<Option<Vec<(String, Option<Cell>)>>> = const { RefCell::new(None) };
+    /// Range read by the scan of the current DELETE
+    static RANGE_DELETE: RefCell<Option<RangeDelete>> = const { RefCell::new(None) };
+    /// Conditions of the scan of the current UPDATE or DELETE
+    static WRITE_CONDITIONS: RefCell<Option<WriteConditions>> = const { RefCell::new(None) };
+}
+
+/// The framework rejects RETURNING, hide it from the framework
+/// The slots of inserted and updated rows are completed with the metadata columns by `exec_foreign_insert`
+/// and `exec_foreign_update`, `exec_foreign_delete` fills the slot with the deleted row
+#[pg_guard]
+extern "C-unwind" fn plan_foreign_modify(
+    root: *mut pg_sys::PlannerInfo,
+    plan: *mut pg_sys::ModifyTable,
+    result_relation: pg_sys::Index,
+    subplan_index: std::ffi::c_int,
+) -> *mut pg_sys::List {
+    unsafe {
+        // The framework creates the instance of the modified table here, e.g. for its column options
+        let rte = pg_sys::planner_rt_fetch(result_relation, root);
+        PLANNED_TABLE.set(PlannedTable {
+            relid: (*rte).relid,
+            ..PlannedTable::DEFAULT
+        });
+        let returning = (*plan).returningLists;
+        (*plan).returningLists = std::ptr::null_mut();
+        let fdw_private = match FRAMEWORK_PLAN_FOREIGN_MODIFY.get() {
+            Some(Some(framework_fn)) => framework_fn(root, plan, result_relation, subplan_index),
+            _ => std::ptr::null_mut(),
         };
+        PLANNED_TABLE.set(PlannedTable::DEFAULT);
+        (*plan).returningLists = returning;
+        fdw_private
+    }
+}
 
-        let fetch_results = vec![];
+/// Inserts the row through the framework, then fills the metadata columns of the slot for RETURNING
+#[pg_guard]
+extern "C-unwind" fn exec_foreign_insert(
+    estate: *mut pg_sys::EState,
+    rinfo: *mut pg_sys::ResultRelInfo,
+    slot: *mut pg_sys::TupleTableSlot,
+    plan_slot: *mut pg_sys::TupleTableSlot,
+) -> *mut pg_sys::TupleTableSlot {
+    unsafe {
+        LAST_WRITE.set(None);
+        MODIFY_RETURNING.set(!(*rinfo).ri_projectReturning.is_null());
+        let slot = match FRAMEWORK_EXEC_FOREIGN_INSERT.get() {
+            Some(Some(framework_fn)) => framework_fn(estate, rinfo, slot, plan_slot),
+            _ => slot,
+        };
 
-        Ok(Self {
-            client,
-            rt,
-            fetch_results,
-            tgt_cols: Vec::new(),
-            fetch_key: false,
-            fetch_value: false,
-        })
+        if let (Some(written), false) = (LAST_WRITE.get(), (*rinfo).ri_projectReturning.is_null()) {
+            store_metadata_columns(slot, written);
+        }
+        slot
     }
+}
 
-    fn begin_scan(
-        &mut self,
-        _quals: &[Qual],
-        columns: &[Column],
-        sort: &[Sort],
-        limit: &Option<Limit>,
-        options: &std::collections::HashMap<String, String>,
-    ) -> Result<(), EtcdFdwError> {
-        // parse the options defined when `CREATE FOREIGN TABLE`
-        let prefix = options.get("prefix").cloned();
-        let range_end = options.get("range_end").cloned();
-        let key_start = options.get("key").cloned();
-        let keys_only = options.get("keys_only").map(|v| v == "true").unwrap_or(false);
-        let revision = options.get("revision").and_then(|v| v.parse::<i64>().ok()).unwrap_or(0);
-        let serializable = options.get("consistency").map(|v| v == "s").unwrap_or(false);
-        let mut qual_key_start: Option<String> = None;
-        let mut qual_prefix: Option<String> = None;
-        let mut qual_range_end: Option<String> = None;
-        let mut get_options = GetOptions::new();
+/// Updates the row through the framework, keeping the whole new row for renaming keys
+/// For RETURNING the slot is filled with the row of the key as etcd holds it after the update
+#[pg_guard]
+extern "C-unwind" fn exec_foreign_update(
+    estate: *mut pg_sys::EState,
+    rinfo: *mut pg_sys::ResultRelInfo,
+    slot: *mut pg_sys::TupleTableSlot,
+    plan_slot: *mut pg_sys::TupleTableSlot,
+) -> *mut pg_sys::TupleTableSlot {
+    unsafe {
+        LAST_WRITE.set(None);
+        RETURNED_ROW.set(None);
+        MODIFY_RETURNING.set(!(*rinfo).ri_projectReturning.is_null());
+        UPDATED_ROW.set(Some(slot_row(slot)));
+        let slot = match FRAMEWORK_EXEC_FOREIGN_UPDATE.get() {
+            Some(Some(framework_fn)) => framework_fn(estate, rinfo, slot, plan_slot),
+            _ => slot,
+        };
+        UPDATED_ROW.set(None);
 
-        if let Some(x) = limit {
-            get_options = get_options.with_limit(x.count);
+        if let Some(cells) = RETURNED_ROW.take() {
+            store_row(slot, cells);
         }
+        if let (Some(written), false) = (LAST_WRITE.get(), (*rinfo).ri_projectReturning.is_null()) {
+            store_metadata_columns(slot, written);
+        }
+        slot
+    }
+}
 
-        if keys_only {
-            get_options = get_options.with_keys_only();
+/// Deletes the row through the framework, then fills the slot with the deleted row for RETURNING
+#[pg_guard]
+extern "C-unwind" fn exec_foreign_delete(
+    estate: *mut pg_sys::EState,
+    rinfo: *mut pg_sys::ResultRelInfo,
+    slot: *mut pg_sys::TupleTableSlot,
+    plan_slot: *mut pg_sys::TupleTableSlot,
+) -> *mut pg_sys::TupleTableSlot {
+    unsafe {
+        RETURNED_ROW.set(None);
+        MODIFY_RETURNING.set(!(*rinfo).ri_projectReturning.is_null());
+        let slot = match FRAMEWORK_EXEC_FOREIGN_DELETE.get() {
+            Some(Some(framework_fn)) => framework_fn(estate, rinfo, slot, plan_slot),
+            _ => slot,
+        };
+
+        if let Some(cells) = RETURNED_ROW.take() {
+            store_row(slot, cells);
         }
+        slot
+    }
+}
 
-        if revision > 0 {
-            get_options = get_options.with_revision(revision);
+/// Store a row in a slot, columns without a cell are NULL
+unsafe fn store_row(slot: *mut pg_sys::TupleTableSlot, cells: Vec<(String, Option<Cell>)>) {
+    let tupdesc = (*slot).tts_tupleDescriptor;
+    let natts = (*tupdesc).natts as usize;
+    let mut values = vec![pg_sys::Datum::from(0); natts];
+    let mut nulls = vec![true; natts];
+    for (i, attr) in PgTupleDesc::from_pg_unchecked(tupdesc).iter().enumerate() {
+        if attr.is_dropped() {
+            continue;
+        }
+        let name = pgrx::name_data_to_str(&attr.attname);
+        let cell = cells.iter().find(|(n, _)| n == name).and_then(|(_, cell)| cell.clone());
+        if let Some(datum) = cell.and_then(|cell| cell.into_datum()) {
+            values[i] = datum;
+            nulls[i] = false;
         }
+    }
 
-        if serializable {
-            get_options = get_options.with_serializable();
+    let tuple = pg_sys::heap_form_tuple(tupdesc, values.as_mut_ptr(), nulls.as_mut_ptr());
+    pg_sys::ExecForceStoreHeapTuple(tuple, slot, false);
+}
+
+/// TRUNCATE deletes the keys below the prefix of each table
+#[cfg(not(feature = "pg13"))]
+#[pg_guard]
+extern "C-unwind" fn exec_foreign_truncate(
+    rels: *mut pg_sys::List,
+    _behavior: pg_sys::DropBehavior::Type,
+    _restart_seqs: bool,
+) {
+    unsafe {
+        for rel in PgList::<pg_sys::RelationData>::from_pg(rels).iter_ptr() {
+            report_error(truncate_table((*rel).rd_id));
         }
+    }
+}
 
-        // WHERE clause pushdown
-        for q in _quals {
-            // only pushdown "key"
-            if q.field != "key" {
-                continue;
+/// Delete all keys of a foreign table with one range delete per prefix
+/// Tables without a prefix would delete the whole keyspace and aren't truncated
+#[cfg(not(feature = "pg13"))]
+unsafe fn truncate_table(relid: pg_sys::Oid) -> EtcdFdwResult<()> {
+    let table = pg_sys::GetForeignTable(relid);
+    let fserver = pg_sys::GetForeignServer((*table).serverid);
+    let mut fdw = EtcdFdw::new(ForeignServer {
+        server_name: CStr::from_ptr((*fserver).servername).to_string_lossy().into_owned(),
+        server_oid: (*fserver).serverid,
+        options: def_elem_options((*fserver).options),
+        ..Default::default()
+    })?;
+    let options = expand_prefix(&resolve_options(&fdw.server_options, &def_elem_options((*table).options)))?;
+
+    if options.contains_key("exclude_prefix") || options.contains_key("key_regex") {
+        return Err(EtcdFdwError::TruncateUnsupported(
+            "keys left out by exclude_prefix or key_regex would be deleted too".to_string(),
+        ));
+    }
+    let prefixes = option_prefixes(&options);
+    if prefixes.is_empty() || prefixes.iter().any(String::is_empty) {
+        return Err(EtcdFdwError::TruncateUnsupported("it has no prefix".to_string()));
+    }
+    for prefix in &prefixes {
+        fdw.check_role_prefix(prefix.as_bytes())?;
+    }
+    for prefix in prefixes {
+        forget_cached_prefix(prefix.as_bytes());
+        fdw.rt
+            .block_on(fdw.client.delete(prefix.as_str(), Some(DeleteOptions::new().with_prefix())))
+            .map_err(|e| EtcdFdwError::UpdateError(e.to_string()))?;
+        // keys inserted with a write lease earlier in the transaction are gone, the commit doesn't detach them
+        PENDING_LEASES.with_borrow_mut(|pending| {
+            for lease in pending.iter_mut() {
+                lease.keys.retain(|key| !key.starts_with(prefix.as_bytes()));
             }
+        });
+    }
+    Ok(())
+}
 
-            // extract string value
-            let v = match &q.value {
-                Value::Cell(Cell::String(s)) => s.clone(),
-                _ => continue,
-            };
+/// All columns of a slot as a row
+unsafe fn slot_row(slot: *mut pg_sys::TupleTableSlot) -> Row {
+    let tupdesc = (*slot).tts_tupleDescriptor;
+    let natts = (*tupdesc).natts;
+    if ((*slot).tts_nvalid as std::ffi::c_int) < natts {
+        pg_sys::slot_getsomeattrs_int(slot, natts);
+    }
 
-            match q.operator.as_str() {
-                "=" => {
-                    // equal: start at v, end at v+"\0"
-                    qual_key_start = Some(v.clone());
-                    qual_range_end = Some(format!("{}\0", v));
-                }
-                ">=" => {
-                    // greater or equal: start at v
-                    qual_key_start = Some(v.clone());
-                }
-                ">" => {
-                    // greater than: start at v+"\0"
-                    qual_key_start = Some(format!("{}\0", v));
-                }
-                "<" => {
-                    // less than: end at v
-                    qual_range_end = Some(v.clone());
-                }
-                "<=" => {
-                    // less or equal: end at v+"\0"
-                    qual_range_end = Some(format!("{}\0", v));
-                }
-                "~~" => {
-                    // LIKE operator with % suffix only
-                    if let Some(pref) = v.strip_suffix('%') {
-                        qual_prefix = Some(pref.to_string());
-                    }
-                }
-                _ => {}
-            }
+    let values = std::slice::from_raw_parts((*slot).tts_values, natts as usize);
+    let nulls = std::slice::from_raw_parts((*slot).tts_isnull, natts as usize);
+    let mut row = Row::new();
+    for (i, attr) in PgTupleDesc::from_pg_unchecked(tupdesc).iter().enumerate() {
+        if attr.is_dropped() {
+            continue;
         }
+        row.push(
+            pgrx::name_data_to_str(&attr.attname),
+            Cell::from_polymorphic_datum(values[i], nulls[i], attr.atttypid),
+        );
+    }
+    row
+}
 
-        // Determine the effective prefix based on FDW and WHERE clause options
-        // If both are present, ensure one is a prefix of the other
-        // Otherwise, no data will be fetched
-        // If only one is present, use that as the prefix
-        let eff_prefix = match (prefix.as_ref(), qual_prefix.as_ref()) {
-            (Some(_fdw), Some(_where)) => {
-                if _where.starts_with(_fdw) {
-                    Some(_where.clone())
-                } else if _fdw.starts_with(_where) {
-                    Some(_fdw.clone())
-                } else {
-                    return Ok(());
-                }
-            }
-            (Some(_fdw), None) => Some(_fdw.clone()),
-            (None, Some(_where)) => Some(_where.clone()),
-            (None, None) => None,
+/// Replace the metadata columns of a slot by the metadata of a written key
+unsafe fn store_metadata_columns(slot: *mut pg_sys::TupleTableSlot, written: WrittenKey) {
+    let tupdesc = (*slot).tts_tupleDescriptor;
+    let natts = (*tupdesc).natts;
+    if ((*slot).tts_nvalid as std::ffi::c_int) < natts {
+        pg_sys::slot_getsomeattrs_int(slot, natts);
+    }
+
+    let mut values = std::slice::from_raw_parts((*slot).tts_values, natts as usize).to_vec();
+    let mut nulls = std::slice::from_raw_parts((*slot).tts_isnull, natts as usize).to_vec();
+    let attrs = PgTupleDesc::from_pg_unchecked(tupdesc);
+    for (i, attr) in attrs.iter().enumerate() {
+        if attr.is_dropped() {
+            continue;
+        }
+        let meta = match (pgrx::name_data_to_str(&attr.attname), attr.atttypid) {
+            ("create_revision", pg_sys::INT8OID) => pg_sys::Datum::from(written.create_revision),
+            ("mod_revision", pg_sys::INT8OID) => pg_sys::Datum::from(written.mod_revision),
+            ("version", pg_sys::INT8OID) => pg_sys::Datum::from(written.version),
+            ("lease", pg_sys::INT8OID) => pg_sys::Datum::from(written.lease),
+            ("created", pg_sys::BOOLOID) => pg_sys::Datum::from(written.created),
+            _ => continue,
         };
+        values[i] = meta;
+        nulls[i] = false;
+    }
 
-        // Determine the effective key start based on FDW and WHERE clause options
-        // If both are present, take the larger one
-        // Otherwise, take whichever is present
-        // If neither is present, start from the beginning
-        let eff_key_start = match (&qual_key_start, &key_start) {
-            (Some(_where), Some(_fdw)) => {
-                if _where > _fdw {
-                    _where.clone()
-                } else {
-                    _fdw.clone()
-                }
+    let tuple = pg_sys::heap_form_tuple(tupdesc, values.as_mut_ptr(), nulls.as_mut_ptr());
+    pg_sys::ExecForceStoreHeapTuple(tuple, slot, false);
+}
+
+/// COPY FROM inserts without planning a modify, the framework's modify state is planned here
+/// Without RETURNING the rows are buffered, so COPY writes them in transactions of `MAX_TXN_OPS` rows
+#[pg_guard]
+extern "C-unwind" fn begin_foreign_insert(_mtstate: *mut pg_sys::ModifyTableState, rinfo: *mut pg_sys::ResultRelInfo) {
+    unsafe {
+        let mut rte = PgBox::<pg_sys::RangeTblEntry>::alloc0();
+        rte.relid = (*(*rinfo).ri_RelationDesc).rd_id;
+        let mut rte_array = [std::ptr::null_mut(), rte.into_pg()];
+        let mut root = PgBox::<pg_sys::PlannerInfo>::alloc0();
+        root.simple_rte_array = rte_array.as_mut_ptr();
+        root.parse = PgBox::<pg_sys::Query>::alloc0().into_pg();
+        let mut plan = PgBox::<pg_sys::ModifyTable>::alloc0();
+        plan.operation = pg_sys::CmdType::CMD_INSERT;
+        let fdw_private = plan_foreign_modify(root.into_pg(), plan.into_pg(), 1, 0);
+
+        // The framework looks for the rowid in the target list of the subplan, COPY has none
+        let mut subplan = PgBox::<pg_sys::PlanState>::alloc0();
+        subplan.plan = PgBox::<pg_sys::Plan>::alloc0().into_pg();
+        let subplan = subplan.into_pg();
+        let mut mtstate = PgBox::<pg_sys::ModifyTableState>::alloc0();
+        mtstate.ps.lefttree = subplan;
+        // PostgreSQL 13 keeps the subplans in an array
+        #[cfg(feature = "pg13")]
+        let mut subplans = [subplan];
+        #[cfg(feature = "pg13")]
+        {
+            mtstate.mt_plans = subplans.as_mut_ptr();
+        }
+        if let Some(Some(framework_fn)) = FRAMEWORK_BEGIN_FOREIGN_MODIFY.get() {
+            framework_fn(mtstate.into_pg(), rinfo, fdw_private, 0, 0);
+        }
+    }
+}
+
+/// Ends the modify state of `begin_foreign_insert`, writing the buffered rows
+#[pg_guard]
+extern "C-unwind" fn end_foreign_insert(estate: *mut pg_sys::EState, rinfo: *mut pg_sys::ResultRelInfo) {
+    if let Some(Some(framework_fn)) = FRAMEWORK_END_FOREIGN_MODIFY.get() {
+        unsafe { framework_fn(estate, rinfo) };
+    }
+}
+
+/// Check whether a column of a foreign table sorts like etcd does, i.e. bytewise
+/// Range quals, ORDER BY and LIMIT can only be pushed down for such columns
+unsafe fn column_order_is_bytewise(relid: pg_sys::Oid, column: &CStr) -> bool {
+    // jsonb orders by the JSON values, not by their text
+    if column_type(relid, column) == pg_sys::JSONBOID {
+        return false;
+    }
+    collation_is_bytewise(column_collation(relid, column))
+}
+
+/// Whether the values of a type sort like their text, numbers, booleans and timestamps of cast values don't
+fn type_sorts_as_text(type_oid: pg_sys::Oid) -> bool {
+    matches!(
+        type_oid,
+        pg_sys::TEXTOID | pg_sys::VARCHAROID | pg_sys::BPCHAROID | pg_sys::BYTEAOID | pg_sys::InvalidOid
+    )
+}
+
+/// Whether the key column sorts like the etcd keys, keys of other types than text sort by their values
+/// unless `key_pad` pads the numbers
+unsafe fn key_type_sorts_as_key(relid: pg_sys::Oid, options: &HashMap<String, String>) -> bool {
+    options.contains_key("key_pad") || type_sorts_as_text(column_type(relid, &table_column(relid, "key")))
+}
+
+/// Names of the key and value columns, `key` and `value` unless the `key_column` and `value_column` table options rename them
+fn column_names(options: &HashMap<String, String>) -> (String, String) {
+    (
+        options.get("key_column").cloned().unwrap_or_else(|| "key".to_string()),
+        options.get("value_column").cloned().unwrap_or_else(|| "value".to_string()),
+    )
+}
+
+/// Name of the `"key"` or `"value"` column of a foreign table, see `column_names`
+unsafe fn table_column(relid: pg_sys::Oid, column: &str) -> CString {
+    let (key, value) = column_names(&def_elem_options((*pg_sys::GetForeignTable(relid)).options));
+    // catalog names can't contain NUL bytes
+    CString::new(if column == "value" { value } else { key }).unwrap_or_default()
+}
+
+/// Columns of a relation with their types, like the target columns of a scan of all of them
+unsafe fn relation_target_columns(relid: pg_sys::Oid) -> Vec<Column> {
+    relation_columns(relid)
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, name)| {
+            let type_oid = column_type(relid, &CString::new(name.clone()).ok()?);
+            Some(Column { name, num: i + 1, type_oid })
+        })
+        .collect()
+}
+
+/// Names of the columns of a relation in their order, without dropped columns
+unsafe fn relation_columns(relid: pg_sys::Oid) -> Vec<String> {
+    let mut columns = Vec::new();
+    for attnum in 1..pg_sys::AttrNumber::MAX {
+        let name = pg_sys::get_attname(relid, attnum, true);
+        if name.is_null() {
+            break;
+        }
+        // dropped columns keep a name that doesn't resolve
+        if pg_sys::get_attnum(relid, name) == attnum {
+            columns.push(CStr::from_ptr(name).to_string_lossy().into_owned());
+        }
+    }
+    columns
+}
+
+/// Options of a column of a foreign table
+unsafe fn column_options(relid: pg_sys::Oid, attnum: pg_sys::AttrNumber) -> HashMap<String, String> {
+    def_elem_options(pg_sys::GetForeignColumnOptions(relid, attnum))
+}
+
+/// The columns of a foreign table with a `jsonpath` option
+unsafe fn column_jsonpaths(relid: pg_sys::Oid) -> EtcdFdwResult<HashMap<String, JsonPath>> {
+    let mut paths = HashMap::new();
+    if relid == pg_sys::InvalidOid {
+        return Ok(paths);
+    }
+    for column in relation_columns(relid) {
+        let name = CString::new(column.as_str()).unwrap_or_default();
+        let attnum = pg_sys::get_attnum(relid, name.as_ptr());
+        if let Some(path) = column_options(relid, attnum).get("jsonpath") {
+            paths.insert(column, parse_jsonpath(path)?);
+        }
+    }
+    Ok(paths)
+}
+
+/// Type of a column, invalid if the column doesn't exist
+unsafe fn column_type(relid: pg_sys::Oid, column: &CStr) -> pg_sys::Oid {
+    let attnum = pg_sys::get_attnum(relid, column.as_ptr());
+    if attnum == pg_sys::InvalidAttrNumber as pg_sys::AttrNumber {
+        return pg_sys::InvalidOid;
+    }
+    pg_sys::get_atttype(relid, attnum)
+}
+
+/// Collation of a column, invalid if the column doesn't exist or isn't collatable
+unsafe fn column_collation(relid: pg_sys::Oid, column: &CStr) -> pg_sys::Oid {
+    let attnum = pg_sys::get_attnum(relid, column.as_ptr());
+    if attnum == pg_sys::InvalidAttrNumber as pg_sys::AttrNumber {
+        return pg_sys::InvalidOid;
+    }
+
+    let mut typid = pg_sys::InvalidOid;
+    let mut typmod = -1;
+    let mut collid = pg_sys::InvalidOid;
+    pg_sys::get_atttypetypmodcoll(relid, attnum, &mut typid, &mut typmod, &mut collid);
+    collid
+}
+
+/// Check whether a collation orders text bytewise
+unsafe fn collation_is_bytewise(collid: pg_sys::Oid) -> bool {
+    if collid == pg_sys::InvalidOid {
+        // not a collatable type
+        return true;
+    }
+    if collid == pg_sys::C_COLLATION_OID || collid == pg_sys::POSIX_COLLATION_OID {
+        return true;
+    }
+    if !pg_sys::get_collation_isdeterministic(collid) {
+        return false;
+    }
+    // e.g. the database default collation: linguistic collations sort "a" before "B",
+    // bytewise ordering puts all upper case letters first
+    pg_sys::varstr_cmp(c"a".as_ptr(), 1, c"B".as_ptr(), 1, collid) > 0
+}
+
+/// Keys inserted by the current transaction under a write lease, see the `write_lease_ttl` table option
+/// Right before commit the keys are detached from the lease so they persist,
+/// aborting revokes the lease and with it the keys
+#[derive(Clone)]
+struct PendingLease {
+    config: EtcdConfig,
+    lease: i64,
+    keys: PendingKeys,
+}
+
+/// Keys written under a write lease, each with the subtransaction that wrote it
+/// Rolling back a subtransaction deletes the keys it wrote
+#[derive(Clone, Default)]
+struct PendingKeys(Vec<(pg_sys::SubTransactionId, Vec<u8>)>);
+
+impl PendingKeys {
+    fn push(&mut self, key: Vec<u8>) {
+        let subxact = unsafe { pg_sys::GetCurrentSubTransactionId() };
+        self.0.push((subxact, key));
+    }
+
+    fn extend(&mut self, keys: impl IntoIterator<Item = Vec<u8>>) {
+        for key in keys {
+            self.push(key);
+        }
+    }
+
+    fn retain(&mut self, mut f: impl FnMut(&Vec<u8>) -> bool) {
+        self.0.retain(|(_, key)| f(key));
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.0.iter().map(|(_, key)| key)
+    }
+
+    /// Remove the keys written by `subxact` and the subtransactions within it
+    /// Subtransaction IDs grow, the ones after `subxact` are nested in it while it's still open
+    fn take_subxact(&mut self, subxact: pg_sys::SubTransactionId) -> Vec<Vec<u8>> {
+        let (taken, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.0).into_iter().partition(|(id, _)| *id >= subxact);
+        self.0 = kept;
+        taken.into_iter().map(|(_, key)| key).collect()
+    }
+}
+
+thread_local! {
+    /// Revision of the etcd store the latest scan of the session read at
+    static LAST_READ_REVISION: std::cell::Cell<i64> = const { std::cell::Cell::new(0) };
+    /// Header of the latest response a scan of the session received, see `etcd_last_read_header()`
+    static LAST_READ_HEADER: std::cell::Cell<Option<ReadHeader>> = const { std::cell::Cell::new(None) };
+}
+
+/// The fields of an etcd response header
+#[derive(Clone, Copy)]
+struct ReadHeader {
+    cluster_id: u64,
+    member_id: u64,
+    raft_term: u64,
+    revision: i64,
+}
+
+/// Remember the header of a response to a scan's request
+fn record_read_header(header: Option<&etcd_client::ResponseHeader>) {
+    if let Some(h) = header {
+        LAST_READ_HEADER.set(Some(ReadHeader {
+            cluster_id: h.cluster_id(),
+            member_id: h.member_id(),
+            raft_term: h.raft_term(),
+            revision: h.revision(),
+        }));
+    }
+}
+
+thread_local! {
+    static PENDING_LEASES: RefCell<Vec<PendingLease>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Track a write lease granted in the current transaction
+/// The commit and abort callbacks are registered with the transaction's first lease
+fn track_write_lease(config: EtcdConfig, lease: i64) {
+    let first = PENDING_LEASES.with_borrow_mut(|pending| {
+        pending.push(PendingLease {
+            config,
+            lease,
+            keys: PendingKeys::default(),
+        });
+        pending.len() == 1
+    });
+
+    if first {
+        register_xact_callback(PgXactCallbackEvent::PreCommit, || {
+            if let Err(e) = commit_write_leases() {
+                error!("{}", e);
             }
-            (Some(_where), None) => _where.clone(),
-            (None, Some(_fdw)) => _fdw.clone(),
-            (None, None) => "\0".to_string(), // start from the beginning
-        };
+        });
+        register_xact_callback(PgXactCallbackEvent::Abort, revoke_write_leases);
+        register_subxact_callback(PgSubXactCallbackEvent::AbortSub, |subxact, _parent| {
+            delete_subxact_keys(subxact)
+        });
+    }
+}
 
-        // Determine the effective range end based on FDW and WHERE clause options
-        // If both are present, take the smaller one
-        // Otherwise, take whichever is present
-        // If neither is present, go to the end
-        let mut eff_range_end = match (&qual_range_end, &range_end) {
-            (Some(_where), Some(_fdw)) => {
-                if _where < _fdw {
-                    _where.clone()
-                } else {
-                    _fdw.clone()
-                }
+/// Run `f` on the keys written under a lease of the current transaction
+fn with_pending_lease<R>(lease: i64, f: impl FnOnce(&mut PendingKeys) -> R) -> Option<R> {
+    PENDING_LEASES.with_borrow_mut(|pending| {
+        pending
+            .iter_mut()
+            .find(|p| p.lease == lease)
+            .map(|p| f(&mut p.keys))
+    })
+}
+
+/// Connect once per configuration and credentials for the callbacks handling several leases
+async fn lease_client(clients: &mut HashMap<LeaseClientKey, Client>, config: EtcdConfig) -> Result<Client, Error> {
+    let key = (config.channel_key(), config.user.clone(), config.password.clone());
+    if let Some(client) = clients.get(&key) {
+        return Ok(client.clone());
+    }
+    let client = connect_etcd(config).await?;
+    clients.insert(key, client.clone());
+    Ok(client)
+}
+
+type LeaseClientKey = (ChannelKey, Option<String>, Option<String>);
+
+/// Transaction operations applying `op` to each key still attached to `lease`
+/// Keys deleted or attached to another lease meanwhile are skipped
+fn leased_key_ops(keys: &[Vec<u8>], lease: i64, op: impl Fn(&[u8]) -> TxnOp) -> Vec<TxnOp> {
+    keys.iter()
+        .map(|k| {
+            TxnOp::txn(
+                Txn::new()
+                    .when([Compare::lease(k.as_slice(), CompareOp::Equal, lease)])
+                    .and_then([op(k)]),
+            )
+        })
+        .collect()
+}
+
+/// Detach the keys of the transaction's write leases and release the leases
+/// Keys whose lease already expired can't be detached, which fails the commit,
+/// keys deleted or attached to another lease since they were written are skipped
+fn commit_write_leases() -> Result<(), EtcdFdwError> {
+    let pending = PENDING_LEASES.with_borrow(|pending| pending.clone());
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    runtime().block_on(async {
+        let mut clients = HashMap::new();
+        for p in pending {
+            let mut client = lease_client(&mut clients, p.config)
+                .await
+                .map_err(|e| EtcdFdwError::ClientConnectionError(e.to_string()))?;
+            let keys: Vec<Vec<u8>> = p.keys.iter().cloned().collect();
+            for chunk in keys.chunks(MAX_TXN_OPS) {
+                // a put without lease keeping the value detaches the key
+                let ops = leased_key_ops(chunk, p.lease, |k| {
+                    TxnOp::put(k, "", Some(PutOptions::new().with_ignore_value()))
+                });
+                client
+                    .txn(Txn::new().and_then(ops))
+                    .await
+                    .map_err(|e| EtcdFdwError::UpdateError(e.to_string()))?;
             }
-            (Some(_where), None) => _where.clone(),
-            (None, Some(_fdw)) => _fdw.clone(),
+            client
+                .lease_revoke(p.lease)
+                .await
+                .map_err(|e| EtcdFdwError::UpdateError(e.to_string()))?;
+        }
+        Ok(())
+    })?;
+
+    PENDING_LEASES.with_borrow_mut(|pending| pending.clear());
+    Ok(())
+}
+
+/// Revoke the transaction's write leases, deleting the keys written under them
+/// Errors are ignored as nothing may be raised after abort, the leases expire on their own then
+fn revoke_write_leases() {
+    let pending = PENDING_LEASES.take();
+    if pending.is_empty() {
+        return;
+    }
+
+    runtime().block_on(async {
+        let mut clients = HashMap::new();
+        for p in pending {
+            if let Ok(mut client) = lease_client(&mut clients, p.config).await {
+                let _ = client.lease_revoke(p.lease).await;
+            }
+        }
+    });
+}
+
+/// Delete the keys a rolled back subtransaction wrote under the transaction's write leases
+/// Errors are ignored like on abort, keys that can't be deleted are detached at commit with the others
+fn delete_subxact_keys(subxact: pg_sys::SubTransactionId) {
+    let rolled_back: Vec<(EtcdConfig, i64, Vec<Vec<u8>>)> = PENDING_LEASES.with_borrow_mut(|pending| {
+        pending
+            .iter_mut()
+            .map(|p| (p.config.clone(), p.lease, p.keys.take_subxact(subxact)))
+            .filter(|(_, _, keys)| !keys.is_empty())
+            .collect()
+    });
+    if rolled_back.is_empty() {
+        return;
+    }
+
+    runtime().block_on(async {
+        let mut clients = HashMap::new();
+        for (config, lease, keys) in rolled_back {
+            let Ok(mut client) = lease_client(&mut clients, config).await else {
+                continue;
+            };
+            for chunk in keys.chunks(MAX_TXN_OPS) {
+                let ops = leased_key_ops(chunk, lease, |k| TxnOp::delete(k, None));
+                let _ = client.txn(Txn::new().and_then(ops)).await;
+            }
+        }
+    });
+}
+
+/// TTL in seconds of the leases of modify locks, the lease is kept alive while the lock is held
+const MODIFY_LOCK_TTL: i64 = 10;
+
+/// Prefix of the names of modify locks, followed by the prefix of the locked table
+const MODIFY_LOCK_PREFIX: &str = "/etcd_fdw/locks";
+
+/// An etcd lock held by a modify statement, see the `modify_lock` table option
+/// The lock is bound to a lease, revoking the lease releases the lock
+struct HeldLock {
+    config: EtcdConfig,
+    lease: i64,
+    keep_alive: tokio::task::JoinHandle<()>,
+}
+
+thread_local! {
+    static HELD_LOCKS: RefCell<Vec<HeldLock>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Track a modify lock, it's released by `end_modify` or when the transaction aborts
+fn track_held_lock(lock: HeldLock) {
+    let first = HELD_LOCKS.with_borrow_mut(|held| {
+        held.push(lock);
+        held.len() == 1
+    });
+    if first {
+        register_xact_callback(PgXactCallbackEvent::Abort, release_held_locks);
+    }
+}
+
+/// Release the modify lock bound to `lease`
+fn release_lock(lease: i64) -> Result<(), EtcdFdwError> {
+    let lock = HELD_LOCKS.with_borrow_mut(|held| {
+        let i = held.iter().position(|l| l.lease == lease)?;
+        Some(held.remove(i))
+    });
+    let Some(lock) = lock else {
+        return Ok(());
+    };
+
+    lock.keep_alive.abort();
+    runtime().block_on(async {
+        let mut client = connect_etcd(lock.config)
+            .await
+            .map_err(|e| EtcdFdwError::ClientConnectionError(e.to_string()))?;
+        client
+            .lease_revoke(lock.lease)
+            .await
+            .map_err(|e| EtcdFdwError::UpdateError(e.to_string()))?;
+        Ok(())
+    })
+}
+
+/// Release the modify locks of a failed statement
+/// Errors are ignored as nothing may be raised after abort, the leases expire on their own then
+fn release_held_locks() {
+    let held = HELD_LOCKS.take();
+    runtime().block_on(async {
+        for lock in held {
+            lock.keep_alive.abort();
+            if let Ok(mut client) = connect_etcd(lock.config).await {
+                let _ = client.lease_revoke(lock.lease).await;
+            }
+        }
+    });
+}
+
+/// Session override of a layered option, `None` if unset
+fn session_option(name: &str) -> Option<String> {
+    let setting = match name {
+        "consistency" => &CONSISTENCY,
+        "keys_only" => &KEYS_ONLY,
+        "connect_timeout" => &CONNECT_TIMEOUT,
+        "request_timeout" => &REQUEST_TIMEOUT,
+        "fetch_size" => &FETCH_SIZE,
+        _ => return None,
+    };
+    setting
+        .get()
+        .map(|v| v.to_string_lossy().into_owned())
+        .filter(|v| !v.is_empty())
+}
+
+/// Revision set by `etcd_fdw.read_revision`, `None` if unset or 0
+fn read_revision() -> Result<Option<i64>, EtcdFdwError> {
+    let Some(value) = READ_REVISION.get() else {
+        return Ok(None);
+    };
+    let value = value.to_string_lossy();
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    match value.parse::<i64>() {
+        Ok(0) => Ok(None),
+        Ok(r) if r > 0 => Ok(Some(r)),
+        _ => Err(EtcdFdwError::InvalidOption(
+            "etcd_fdw.read_revision".to_string(),
+            value.to_string(),
+        )),
+    }
+}
+
+/// Replace the `{name}` placeholders of a template by the values `resolve` returns for them
+fn expand_placeholders(
+    template: &str,
+    resolve: impl Fn(&str) -> EtcdFdwResult<String>,
+) -> EtcdFdwResult<String> {
+    let invalid = || EtcdFdwError::InvalidOption("prefix".to_string(), template.to_string());
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        let end = match rest[start..].find('}') {
+            Some(end) if rest.as_bytes()[start] == b'{' => start + end,
+            _ => return Err(invalid()),
+        };
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&resolve(&rest[start + 1..end])?);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Value of a placeholder of the `prefix` and `prefixes` options in the current session
+/// `current_user` and `current_database` are the names of the current role and database,
+/// any other name is looked up as configuration parameter the current role may read.
+/// Values containing a `/` are rejected, so a placeholder can't reach into another subtree,
+/// as are values containing a `,`, which would add prefixes to the `prefixes` list
+fn placeholder_value(name: &str) -> EtcdFdwResult<String> {
+    let unresolved = || EtcdFdwError::UnresolvedPlaceholder(name.to_string());
+    let value = unsafe {
+        match name {
+            "current_user" => pg_sys::GetUserNameFromId(pg_sys::GetUserId(), false) as *const std::ffi::c_char,
+            "current_database" => pg_sys::get_database_name(pg_sys::MyDatabaseId) as *const std::ffi::c_char,
+            _ => {
+                let guc = CString::new(name).map_err(|_| unresolved())?;
+                pg_sys::GetConfigOption(guc.as_ptr(), true, true)
+            }
+        }
+    };
+    if value.is_null() {
+        return Err(unresolved());
+    }
+    match unsafe { CStr::from_ptr(value) }.to_str() {
+        Ok(v) if !v.is_empty() && !v.contains(['/', ',']) => Ok(v.to_string()),
+        _ => Err(unresolved()),
+    }
+}
+
+/// Options with the placeholders of the `prefix` and `prefixes` options expanded for the current session
+/// With a `namespace`, the keys of the `prefix`, `prefixes`, `key` and `range_end` options are taken within it
+/// and tables without any of them cover the namespace.
+/// A `prefixes` list that comes down to a single prefix is turned into the `prefix` option
+fn expand_prefix(options: &HashMap<String, String>) -> EtcdFdwResult<HashMap<String, String>> {
+    let mut expanded = options.clone();
+    if let Some(prefix) = options.get("prefix") {
+        expanded.insert("prefix".to_string(), expand_placeholders(prefix, placeholder_value)?);
+    }
+    if let Some(prefixes) = options.get("prefixes") {
+        // the list is split before the placeholders are expanded, so their values don't add prefixes
+        let parts = prefixes
+            .split(',')
+            .map(|p| expand_placeholders(p, placeholder_value))
+            .collect::<EtcdFdwResult<Vec<_>>>()?;
+        expanded.insert("prefixes".to_string(), parts.join(","));
+    }
+    if let Some(namespace) = options.get("namespace") {
+        if namespace.contains(',') && (expanded.contains_key("prefixes") || expanded.contains_key("exclude_prefix")) {
+            return Err(EtcdFdwError::InvalidOption("namespace".to_string(), namespace.clone()));
+        }
+        for name in ["prefixes", "exclude_prefix"] {
+            if let Some(prefixes) = expanded.get_mut(name) {
+                // each of several prefixes is taken within the namespace
+                *prefixes = prefixes.split(',').map(|p| format!("{}{}", namespace, p)).collect::<Vec<_>>().join(",");
+            }
+        }
+        for name in ["prefix", "key", "range_end"] {
+            if let Some(key) = expanded.get_mut(name) {
+                key.insert_str(0, namespace);
+            }
+        }
+        if !expanded.contains_key("prefix") && !expanded.contains_key("prefixes") && !expanded.contains_key("key") {
+            expanded.insert("prefix".to_string(), namespace.clone());
+        }
+    }
+    if let Some(prefixes) = expanded.remove("prefixes") {
+        match split_prefixes(&prefixes).as_slice() {
+            [prefix] => expanded.insert("prefix".to_string(), prefix.clone()),
+            prefixes => expanded.insert("prefixes".to_string(), prefixes.join(",")),
+        };
+    }
+    Ok(expanded)
+}
+
+/// Resolve the effective options of a foreign table
+/// Layered options are taken from the session settings first, then the table and then the server.
+/// All other table options are passed through as is
+fn resolve_options(
+    server: &HashMap<String, String>,
+    table: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    let mut resolved = table.clone();
+    // the namespace of a table replaces the server's, there's no session setting for it
+    if let Some(namespace) = table.get("namespace").or(server.get("namespace")) {
+        resolved.insert("namespace".to_string(), namespace.clone());
+    }
+    for name in LAYERED_OPTIONS {
+        let value = session_option(name)
+            .or_else(|| table.get(name).cloned())
+            .or_else(|| server.get(name).cloned());
+        if let Some(v) = value {
+            resolved.insert(name.to_string(), v);
+        }
+    }
+    resolved
+}
+
+#[wrappers_fdw(
+    version = "0.0.1",
+    author = "Cybertec PostgreSQL International GmbH",
+    error_type = "EtcdFdwError"
+)]
+pub(crate) struct EtcdFdw {
+    client: Client,
+    rt: &'static Runtime,
+    fetch_results: Vec<KeyValue>,
+    /// pages of the scan left to read once `fetch_results` is drained
+    scan_pages: Option<ScanPages>,
+    /// scans of the other prefixes of a table with several ones, see `next_prefix_scan`
+    prefix_scans: Option<PrefixScans>,
+    /// rows without columns left to return, for scans that only count the keys
+    counted_rows: i64,
+    /// requests of the scan, shared with `explain_foreign_scan`
+    stats: Rc<std::cell::Cell<ScanStats>>,
+    /// arguments of the last `begin_scan`, to start the scan over in `re_scan`
+    scan_args: Option<(Vec<Qual>, Vec<Column>, Vec<Sort>, Option<Limit>, HashMap<String, String>)>,
+    tgt_cols: Vec<Column>,
+    /// names of the key and value columns, see `column_names`
+    key_column: String,
+    value_column: String,
+    /// `key_delimiter` and the columns of the key segments in scans
+    key_segments: Option<KeySegments>,
+    segment_columns: Vec<String>,
+    /// whether `rowid_column` names the key column, UPDATE and DELETE of split keys need the whole key
+    rowid_is_key: bool,
+    /// columns projected from the value document, see the `jsonpath` column option
+    jsonpaths: HashMap<String, JsonPath>,
+    /// `value_compression 'gzip'`
+    gzip_values: bool,
+    /// `empty_as_null`, empty values read as NULL and NULL is written as the empty value
+    empty_as_null: bool,
+    /// keys left out of scans, see the `exclude_prefix` and `key_regex` table options
+    exclude_prefixes: Vec<String>,
+    key_regex: Option<KeyPattern>,
+    /// rendering of the values into the value column, see the `value_format` table option
+    value_format: ValueFormat,
+    fetch_key: bool,
+    fetch_value: bool,
+    server_options: HashMap<String, String>,
+    key_order_bytewise: bool,
+    value_order_bytewise: bool,
+    key_collation: pg_sys::Oid,
+    key_encoding: Option<KeyEncoding>,
+    role_prefix: Option<String>,
+    config: EtcdConfig,
+    write_lease: Option<i64>,
+    on_conflict: OnConflict,
+    hedge_delay: Option<Duration>,
+    skip_scan_errors: bool,
+    unavailable: Option<String>,
+    planned_for_select: bool,
+    planned_for_delete: bool,
+    modify_batch_size: usize,
+    pending_writes: Vec<(Vec<u8>, TxnOp)>,
+    /// key, value and lease of the rows of an INSERT
+    pending_inserts: Vec<(Vec<u8>, Vec<u8>, Option<i64>)>,
+    lease_id: Option<i64>,
+    /// leases granted for the `ttl` column of the written rows, by TTL
+    ttl_leases: HashMap<i64, i64>,
+    range_deleted: Vec<Vec<u8>>,
+    sub_keys: bool,
+    pivoted_rows: Vec<Vec<(String, Option<Cell>)>>,
+    /// remaining seconds of the leases of the scanned keys, -1 for expired leases
+    lease_ttls: RefCell<HashMap<i64, i64>>,
+    modify_lock: Option<i64>,
+    relid: pg_sys::Oid,
+    key_range_commuted: bool,
+    revision_range_commuted: bool,
+    value_in_conditions: bool,
+    conditions: usize,
+    limit_applies: bool,
+    sort_keys: usize,
+    first_sort_on_table: bool,
+}
+
+#[derive(Clone)]
+pub struct EtcdConfig {
+    pub endpoints: Vec<String>,
+    pub ca_cert_path: Option<String>,
+    pub client_cert_path: Option<String>,
+    pub client_key_path: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub servername: Option<String>,
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    /// Send all requests to the first reachable endpoint instead of spreading them
+    pub pick_first: bool,
+    /// Number of requests to the server in flight at once, 0 doesn't limit them
+    pub max_inflight_requests: usize,
+    pub tls_backend: TlsBackend,
+}
+
+/// TLS implementation of connections to etcd, see the `tls_backend` server option
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TlsBackend {
+    Rustls,
+    Openssl,
+}
+
+impl Default for TlsBackend {
+    /// The backend etcd_fdw was built with
+    fn default() -> Self {
+        if cfg!(feature = "tls-openssl") {
+            TlsBackend::Openssl
+        } else {
+            TlsBackend::Rustls
+        }
+    }
+}
+
+impl Default for EtcdConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            ca_cert_path: None,
+            client_cert_path: None,
+            client_key_path: None,
+            user: None,
+            password: None,
+            servername: None,
+            connect_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            pick_first: false,
+            max_inflight_requests: 0,
+            tls_backend: TlsBackend::default(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum EtcdFdwError {
+    #[error("Failed to fetch from etcd: {0}")]
+    FetchError(String),
+
+    #[error("Failed to send update to etcd: {0}")]
+    UpdateError(String),
+
+    #[error("Failed to connect to client: {0}")]
+    ClientConnectionError(String),
+
+    #[error("No connection string option was specified. Specify it with connstr")]
+    NoConnStr(()),
+
+    #[error("KeyFile and CertFile must both be present.")]
+    CertKeyMismatch(()),
+
+    #[error("User and Password must both be specified through user mappings.")]
+    UserPassMismatch(()),
+
+    #[error("Column {0} is not contained in the input dataset")]
+    MissingColumn(String),
+
+    #[error("Column {0} can't be NULL, etcd values can only be empty with the table option empty_as_null")]
+    NullValue(String),
+
+    #[error("Invalid value '{1}' of column {0}")]
+    InvalidColumnValue(String, String),
+
+    #[error("Key {0} already exists in etcd. No duplicates allowed")]
+    KeyAlreadyExists(String),
+
+    #[error("Options 'prefix' and 'range_end' cannot be used together")]
+    ConflictingPrefixAndRange,
+
+    #[error("Options 'prefix' and 'key' should not be used together")]
+    ConflictingPrefixAndKey,
+
+    #[error("Options 'prefix' and 'prefixes' cannot be used together")]
+    ConflictingPrefixAndPrefixes,
+
+    #[error("Key {0} doesn't exist in etcd")]
+    KeyDoesntExist(String),
+
+    #[error("Invalid option '{0}' with value '{1}'")]
+    InvalidOption(String, String),
+
+    #[error("Unknown option '{0}'")]
+    UnknownOption(String),
+
+    #[error("Key {0} doesn't fit the key_pad encoding, only non-negative numbers of at most key_pad digits are supported")]
+    InvalidPaddedKey(String),
+
+    #[error("Key {0} is outside of the prefix '{1}' of the user mapping")]
+    KeyOutsideRolePrefix(String, String),
+
+    #[error("Key {0} doesn't fit the percent key_encoding, keys have to start with the table's prefix and use valid escapes")]
+    InvalidPercentKey(String),
+
+    #[error("Key {0} is outside of the table's prefix or namespace, it has no relative key")]
+    InvalidRelativeKey(String),
+
+    #[error("Placeholder {{{0}}} of the prefix has no value that can be used in a key")]
+    UnresolvedPlaceholder(String),
+
+    #[error("{0} requires etcd >= {1}, server reports {2}")]
+    UnsupportedVersion(String, String, String),
+
+    #[error("etcd_fdw.config_server has to name the foreign server of the configuration store")]
+    NoConfigServer,
+
+    #[error("tls_backend '{0}' isn't available, etcd_fdw was built without the tls-{0} feature")]
+    TlsBackendUnavailable(String),
+
+    #[error("Entry with key {0} can't be read: {1}")]
+    UndecodableEntry(String, String),
+
+    #[error("etcd is unavailable: {0}")]
+    EtcdUnavailable(String),
+
+    #[error("Invalid sort field value '{0}'")]
+    InvalidSortField(String),
+
+    #[error("Segment column {0} can't contain the key_delimiter, its value is '{1}'")]
+    InvalidKeySegment(String, String),
+
+    #[error("UPDATE and DELETE of tables with key_delimiter need rowid_column to name the key column")]
+    SegmentRowid,
+
+    #[error("The value of a table with jsonpath columns has to be a JSON document: {0}")]
+    InvalidJsonValue(String),
+
+    #[error("The value doesn't fit the table's value_format: {0}")]
+    InvalidValue(String),
+
+    #[error("Tables with k8s_mode are read-only")]
+    KubernetesReadOnly,
+
+    #[error("UPDATE can't change the key of a row of a table with layout 'sub_keys'")]
+    SubKeyRename,
+
+    #[error("Key {0} of a row of a table with layout 'sub_keys' isn't valid UTF-8")]
+    InvalidSubKeyRow(String),
+
+    #[error("TRUNCATE can't delete the keys of the table, {0}")]
+    TruncateUnsupported(String),
+
+    #[error("Key {0} was changed concurrently, the conditions of the statement don't hold anymore")]
+    ConcurrentUpdate(String),
+
+    #[error("{0}")]
+    OptionsError(#[from] OptionsError),
+}
+
+impl From<EtcdFdwError> for ErrorReport {
+    fn from(value: EtcdFdwError) -> Self {
+        let code = match value {
+            // The statement can be retried like a serialization failure of PostgreSQL
+            EtcdFdwError::ConcurrentUpdate(_) => PgSqlErrorCode::ERRCODE_T_R_SERIALIZATION_FAILURE,
+            _ => PgSqlErrorCode::ERRCODE_FDW_ERROR,
+        };
+        ErrorReport::new(code, format!("{}", value), "")
+    }
+}
+
+/// Check whether dependent options exits
+/// i.e user & pass, cert & key
+fn require_pair(
+    a: bool,
+    b: bool,
+    err: EtcdFdwError,
+) -> Result<(), EtcdFdwError> {
+    match (a, b) {
+        (true, false) | (false, true) => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Helper function for parsing timeouts
+fn parse_timeout(
+    options: &std::collections::HashMap<String, String>,
+    key: &str,
+    default: Duration,
+) -> Result<Duration, EtcdFdwError> {
+    if let Some(val) = options.get(key) {
+        match val.parse::<u64>() {
+            Ok(secs) => Ok(Duration::from_secs(secs)),
+            Err(_) => Err(EtcdFdwError::InvalidOption(key.to_string(), val.clone())),
+        }
+    } else {
+        Ok(default)
+    }
+}
+
+/// Reject options that aren't in `known`, so typos in CREATE and ALTER statements don't go unnoticed
+fn check_known_options(options: &[Option<String>], known: &[&str]) -> Result<(), EtcdFdwError> {
+    for option in options.iter().flatten() {
+        let name = option.split_once('=').map_or(option.as_str(), |(name, _)| name);
+        if !known.contains(&name) {
+            return Err(EtcdFdwError::UnknownOption(name.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Check the value formats of the options that can be set on servers as well as tables
+fn check_layered_options(options: &[Option<String>]) -> Result<(), EtcdFdwError> {
+    for name in ["connect_timeout", "request_timeout"] {
+        if let Some(val) = option_value(options, name) {
+            if val.parse::<u64>().is_err() {
+                return Err(EtcdFdwError::InvalidOption(name.to_string(), val.to_string()));
+            }
+        }
+    }
+
+    if let Some(val) = option_value(options, "consistency") {
+        if val != "l" && val != "s" {
+            return Err(EtcdFdwError::InvalidOption("consistency".to_string(), val.to_string()));
+        }
+    }
+
+    if let Some(val) = option_value(options, "keys_only") {
+        if val != "true" && val != "false" {
+            return Err(EtcdFdwError::InvalidOption("keys_only".to_string(), val.to_string()));
+        }
+    }
+
+    if let Some(val) = option_value(options, "fetch_size") {
+        parse_fetch_size(val)?;
+    }
+    Ok(())
+}
+
+/// Number of keys scans read per request unless `fetch_size` is set
+const DEFAULT_FETCH_SIZE: i64 = 1000;
+
+/// Parse the `fetch_size` option, a non-negative number of keys, 0 reads all keys at once
+fn parse_fetch_size(val: &str) -> Result<i64, EtcdFdwError> {
+    match val.parse::<i64>() {
+        Ok(size) if size >= 0 => Ok(size),
+        _ => Err(EtcdFdwError::InvalidOption("fetch_size".to_string(), val.to_string())),
+    }
+}
+
+/// Parse the `n_rows` table option, a non-negative row count
+fn parse_n_rows(val: &str) -> Result<i64, EtcdFdwError> {
+    match val.parse::<i64>() {
+        Ok(rows) if rows >= 0 => Ok(rows),
+        _ => Err(EtcdFdwError::InvalidOption("n_rows".to_string(), val.to_string())),
+    }
+}
+
+/// Parse the framework's `startup_cost` table option, a non-negative cost
+fn parse_startup_cost(val: &str) -> Result<f64, EtcdFdwError> {
+    match val.parse::<f64>() {
+        Ok(cost) if cost >= 0.0 => Ok(cost),
+        _ => Err(EtcdFdwError::InvalidOption("startup_cost".to_string(), val.to_string())),
+    }
+}
+
+/// Parse the `key_pad` table option, the number of digits of numeric keys
+/// i64::MAX has 19 digits
+fn parse_key_pad(val: &str) -> Result<usize, EtcdFdwError> {
+    match val.parse::<usize>() {
+        Ok(width) if (1..=19).contains(&width) => Ok(width),
+        _ => Err(EtcdFdwError::InvalidOption("key_pad".to_string(), val.to_string())),
+    }
+}
+
+/// Zero-padded encoding of numeric keys, see the `key_pad` table option
+/// A number is stored as the table's prefix followed by the number padded to `width` digits,
+/// so etcd's lexicographic key order matches the numeric order
+#[derive(Clone, Debug)]
+struct PaddedKeys {
+    prefix: String,
+    width: usize,
+}
+
+impl PaddedKeys {
+    fn from_options(options: &HashMap<String, String>) -> Result<Option<Self>, EtcdFdwError> {
+        match options.get("key_pad") {
+            Some(v) => Ok(Some(Self {
+                prefix: options.get("prefix").cloned().unwrap_or_default(),
+                width: parse_key_pad(v)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Negative numbers and numbers wider than the padding have no encoding
+    fn encode(&self, n: i64) -> Option<String> {
+        let digits = format!("{:0width$}", n, width = self.width);
+        if n >= 0 && digits.len() == self.width {
+            Some(format!("{}{}", self.prefix, digits))
+        } else {
+            None
+        }
+    }
+
+    fn decode(&self, key: &str) -> Option<i64> {
+        key.strip_prefix(self.prefix.as_str())
+            .filter(|d| d.len() == self.width && d.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|d| d.parse().ok())
+    }
+}
+
+/// Parse the `value_compression` table option, whether values are gzip-compressed
+fn parse_value_compression(val: &str) -> Result<bool, EtcdFdwError> {
+    match val {
+        "none" => Ok(false),
+        "gzip" => Ok(true),
+        _ => Err(EtcdFdwError::InvalidOption("value_compression".to_string(), val.to_string())),
+    }
+}
+
+/// Magic bytes gzip streams start with
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Compress a value for `value_compression 'gzip'`
+fn gzip(value: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(value).expect("writing to a Vec can't fail");
+    encoder.finish().expect("writing to a Vec can't fail")
+}
+
+/// Decompress a value of a table with `value_compression 'gzip'`
+fn gunzip(value: &[u8]) -> Result<Vec<u8>, String> {
+    use std::io::Read;
+    let mut decompressed = Vec::new();
+    flate2::read::GzDecoder::new(value)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| format!("invalid gzip stream: {}", e))?;
+    Ok(decompressed)
+}
+
+/// Rendering of the values into the value column, see the `value_format` table option
+#[derive(Debug, Clone, Default)]
+enum ValueFormat {
+    #[default]
+    Text,
+    Json,
+    Base64,
+    Hex,
+    /// protobuf messages of the `proto_message` type, rendered as JSON
+    Protobuf(Box<prost_reflect::MessageDescriptor>),
+    /// Kubernetes objects, see the `k8s_mode` table option
+    /// The types of protobuf objects are looked up in the `proto_descriptor` descriptor set, if there is one
+    Kubernetes(Option<Box<prost_reflect::DescriptorPool>>),
+}
+
+/// Parse the `value_format` table option
+/// `protobuf` needs the `proto_descriptor` and `proto_message` options
+fn parse_value_format(val: &str, descriptor: Option<&str>, message: Option<&str>) -> Result<ValueFormat, EtcdFdwError> {
+    match val {
+        "text" => Ok(ValueFormat::Text),
+        "json" => Ok(ValueFormat::Json),
+        "base64" => Ok(ValueFormat::Base64),
+        "hex" => Ok(ValueFormat::Hex),
+        "protobuf" => Ok(ValueFormat::Protobuf(Box::new(proto_message(descriptor, message)?))),
+        _ => Err(EtcdFdwError::InvalidOption("value_format".to_string(), val.to_string())),
+    }
+}
+
+/// Parse the `proto_descriptor` option, a base64-encoded FileDescriptorSet
+fn proto_pool(descriptor: &str) -> Result<prost_reflect::DescriptorPool, EtcdFdwError> {
+    use base64::Engine;
+    let invalid_descriptor = || EtcdFdwError::InvalidOption("proto_descriptor".to_string(), descriptor.to_string());
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(descriptor.trim())
+        .map_err(|_| invalid_descriptor())?;
+    prost_reflect::DescriptorPool::decode(bytes.as_slice()).map_err(|_| invalid_descriptor())
+}
+
+/// Find the `proto_message` type in the `proto_descriptor` descriptor set
+fn proto_message(descriptor: Option<&str>, message: Option<&str>) -> Result<prost_reflect::MessageDescriptor, EtcdFdwError> {
+    let descriptor = descriptor.ok_or_else(|| EtcdFdwError::InvalidOption("proto_descriptor".to_string(), String::new()))?;
+    let message = message.ok_or_else(|| EtcdFdwError::InvalidOption("proto_message".to_string(), String::new()))?;
+    let pool = proto_pool(descriptor)?;
+    pool.get_message_by_name(message)
+        .ok_or_else(|| EtcdFdwError::InvalidOption("proto_message".to_string(), message.to_string()))
+}
+
+/// Parse the `empty_as_null` table option
+fn parse_empty_as_null(val: &str) -> Result<bool, EtcdFdwError> {
+    match val {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(EtcdFdwError::InvalidOption("empty_as_null".to_string(), val.to_string())),
+    }
+}
+
+/// Parse the `k8s_mode` table option
+fn parse_k8s_mode(val: &str) -> Result<bool, EtcdFdwError> {
+    match val {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(EtcdFdwError::InvalidOption("k8s_mode".to_string(), val.to_string())),
+    }
+}
+
+/// Prefix of the protobuf-encoded objects Kubernetes stores
+const K8S_MAGIC: &[u8] = b"k8s\x00";
+
+/// Length-delimited fields of a protobuf message, other wire types are skipped
+fn proto_fields(mut bytes: &[u8]) -> Result<Vec<(u64, &[u8])>, String> {
+    fn varint(bytes: &mut &[u8]) -> Result<u64, String> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&b, rest) = bytes.split_first().ok_or("truncated varint")?;
+            *bytes = rest;
+            n |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err("invalid varint".to_string())
+    }
+
+    let mut fields = Vec::new();
+    while !bytes.is_empty() {
+        let tag = varint(&mut bytes)?;
+        let len = match tag & 7 {
+            0 => {
+                varint(&mut bytes)?;
+                continue;
+            }
+            1 => 8,
+            2 => varint(&mut bytes)? as usize,
+            5 => 4,
+            wire_type => return Err(format!("unsupported wire type {}", wire_type)),
+        };
+        if len > bytes.len() {
+            return Err("truncated field".to_string());
+        }
+        let (field, rest) = bytes.split_at(len);
+        if tag & 7 == 2 {
+            fields.push((tag >> 3, field));
+        }
+        bytes = rest;
+    }
+    Ok(fields)
+}
+
+/// A Kubernetes object as JSON
+/// Protobuf objects are wrapped in a runtime.Unknown message behind `K8S_MAGIC`, other objects are stored as JSON
+/// Objects of types missing in `pool` are rendered as their envelope with the base64-encoded message in `raw`
+fn k8s_object(value: &[u8], pool: Option<&prost_reflect::DescriptorPool>) -> Result<serde_json::Value, String> {
+    use base64::Engine;
+    let Some(envelope) = value.strip_prefix(K8S_MAGIC) else {
+        return serde_json::from_slice(value).map_err(|e| format!("neither a Kubernetes protobuf object nor JSON: {}", e));
+    };
+
+    let text = |b: &[u8]| String::from_utf8_lossy(b).into_owned();
+    let (mut api_version, mut kind, mut raw) = (String::new(), String::new(), &[][..]);
+    let mut envelope_fields = serde_json::Map::new();
+    for (number, field) in proto_fields(envelope)? {
+        match number {
+            1 => {
+                for (number, field) in proto_fields(field)? {
+                    match number {
+                        1 => api_version = text(field),
+                        2 => kind = text(field),
+                        _ => {}
+                    }
+                }
+            }
+            2 => raw = field,
+            3 => {
+                envelope_fields.insert("contentEncoding".to_string(), text(field).into());
+            }
+            4 => {
+                envelope_fields.insert("contentType".to_string(), text(field).into());
+            }
+            _ => {}
+        }
+    }
+
+    // apps/v1 objects are in the k8s.io.api.apps.v1 package, v1 objects in k8s.io.api.core.v1
+    let (group, version) = api_version.split_once('/').unwrap_or(("core", api_version.as_str()));
+    let package_suffix = format!(".{}.{}", group.split('.').next().unwrap_or(group), version);
+    let message = pool.and_then(|p| p.all_messages().find(|m| m.name() == kind && m.package_name().ends_with(&package_suffix)));
+    let mut object = match message {
+        Some(message) => {
+            let decoded = prost_reflect::DynamicMessage::decode(message, raw)
+                .map_err(|e| format!("invalid {} object: {}", kind, e))?;
+            match serde_json::to_value(&decoded).map_err(|e| format!("invalid {} object: {}", kind, e))? {
+                serde_json::Value::Object(fields) => fields,
+                _ => serde_json::Map::new(),
+            }
+        }
+        None => {
+            envelope_fields.insert("raw".to_string(), base64::engine::general_purpose::STANDARD.encode(raw).into());
+            envelope_fields
+        }
+    };
+    object.insert("apiVersion".to_string(), api_version.into());
+    object.insert("kind".to_string(), kind.into());
+    Ok(serde_json::Value::Object(object))
+}
+
+impl ValueFormat {
+    fn from_options(options: &HashMap<String, String>) -> Result<Self, EtcdFdwError> {
+        if options.get("k8s_mode").map(|v| parse_k8s_mode(v)).transpose()? == Some(true) {
+            let pool = options.get("proto_descriptor").map(|d| proto_pool(d)).transpose()?;
+            return Ok(ValueFormat::Kubernetes(pool.map(Box::new)));
+        }
+        match options.get("value_format") {
+            Some(v) => parse_value_format(
+                v,
+                options.get("proto_descriptor").map(String::as_str),
+                options.get("proto_message").map(String::as_str),
+            ),
+            None => Ok(ValueFormat::Text),
+        }
+    }
+
+    /// The text of a stored value, `json` values have to be JSON documents
+    fn render<'a>(&self, value: &'a [u8]) -> Result<std::borrow::Cow<'a, [u8]>, String> {
+        use base64::Engine;
+        match self {
+            ValueFormat::Text => Ok(std::borrow::Cow::Borrowed(value)),
+            ValueFormat::Json => {
+                serde_json::from_slice::<serde_json::Value>(value).map_err(|e| format!("invalid JSON: {}", e))?;
+                Ok(std::borrow::Cow::Borrowed(value))
+            }
+            ValueFormat::Base64 => Ok(base64::engine::general_purpose::STANDARD.encode(value).into_bytes().into()),
+            ValueFormat::Hex => Ok(value.iter().map(|b| format!("{:02x}", b)).collect::<String>().into_bytes().into()),
+            ValueFormat::Protobuf(message) => {
+                let decoded = prost_reflect::DynamicMessage::decode((**message).clone(), value)
+                    .map_err(|e| format!("invalid protobuf message: {}", e))?;
+                serde_json::to_vec(&decoded)
+                    .map(std::borrow::Cow::Owned)
+                    .map_err(|e| format!("invalid protobuf message: {}", e))
+            }
+            ValueFormat::Kubernetes(pool) => {
+                let object = k8s_object(value, pool.as_deref())?;
+                Ok(object.to_string().into_bytes().into())
+            }
+        }
+    }
+
+    /// The stored value of a text written to the value column
+    fn parse(&self, text: Vec<u8>) -> Result<Vec<u8>, String> {
+        use base64::Engine;
+        match self {
+            ValueFormat::Text => Ok(text),
+            ValueFormat::Json => {
+                serde_json::from_slice::<serde_json::Value>(&text).map_err(|e| format!("invalid JSON: {}", e))?;
+                Ok(text)
+            }
+            ValueFormat::Base64 => base64::engine::general_purpose::STANDARD
+                .decode(&text)
+                .map_err(|e| format!("invalid base64: {}", e)),
+            ValueFormat::Hex => {
+                if text.len() % 2 != 0 {
+                    return Err("invalid hex: odd number of digits".to_string());
+                }
+                // decoded bytewise, non-ASCII input is no hex digit rather than a split character
+                let digit = |b: u8| char::from(b).to_digit(16).map(|d| d as u8);
+                text.chunks(2)
+                    .map(|pair| match (digit(pair[0]), digit(pair[1])) {
+                        (Some(high), Some(low)) => Ok(high << 4 | low),
+                        _ => Err("invalid hex: not a hex digit".to_string()),
+                    })
+                    .collect()
+            }
+            ValueFormat::Protobuf(message) => {
+                use prost::Message;
+                let mut deserializer = serde_json::Deserializer::from_slice(&text);
+                let decoded = prost_reflect::DynamicMessage::deserialize((**message).clone(), &mut deserializer)
+                    .map_err(|e| format!("invalid {} message: {}", message.full_name(), e))?;
+                Ok(decoded.encode_to_vec())
+            }
+            // begin_modify refuses to modify tables in k8s_mode
+            ValueFormat::Kubernetes(_) => Err("tables in k8s_mode are read-only".to_string()),
+        }
+    }
+
+    /// Whether the rendered values sort like the stored ones
+    fn preserves_order(&self) -> bool {
+        !matches!(self, ValueFormat::Base64 | ValueFormat::Protobuf(_) | ValueFormat::Kubernetes(_))
+    }
+}
+
+/// Parse the `key_encoding` table option, whether keys are percent-encoded
+fn parse_key_encoding(val: &str) -> Result<bool, EtcdFdwError> {
+    match val {
+        "none" => Ok(false),
+        "percent" => Ok(true),
+        _ => Err(EtcdFdwError::InvalidOption("key_encoding".to_string(), val.to_string())),
+    }
+}
+
+/// Percent-encoded keys, see the `key_encoding` table option
+/// The part of a key following the table's prefix is one percent-encoded component,
+/// all characters but ASCII letters, digits and `-._~` are escaped
+#[derive(Clone, Debug)]
+struct PercentKeys {
+    prefix: String,
+}
+
+impl PercentKeys {
+    /// Keys outside of the prefix have no encoding
+    fn encode(&self, key: &str) -> Option<String> {
+        let component = key.strip_prefix(self.prefix.as_str())?;
+        let mut encoded = self.prefix.clone();
+        for b in component.bytes() {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                encoded.push(b as char);
+            } else {
+                encoded.push_str(&format!("%{:02X}", b));
+            }
+        }
+        Some(encoded)
+    }
+
+    /// Escapes that aren't followed by two hex digits or don't decode to UTF-8 are invalid
+    fn decode(&self, key: &str) -> Option<String> {
+        let component = key.strip_prefix(self.prefix.as_str())?.as_bytes();
+        let mut decoded = self.prefix.clone().into_bytes();
+        let mut i = 0;
+        while i < component.len() {
+            if component[i] == b'%' {
+                let hex = std::str::from_utf8(component.get(i + 1..i + 3)?).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            } else {
+                decoded.push(component[i]);
+                i += 1;
+            }
+        }
+        String::from_utf8(decoded).ok()
+    }
+}
+
+/// Parse the `strip_prefix` table option, whether the key column holds keys relative to the prefix
+fn parse_strip_prefix(val: &str) -> Result<bool, EtcdFdwError> {
+    match val {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(EtcdFdwError::InvalidOption("strip_prefix".to_string(), val.to_string())),
+    }
+}
+
+/// Keys relative to the table's prefix, see the `strip_prefix` table option
+#[derive(Clone, Debug)]
+struct RelativeKeys {
+    prefix: String,
+}
+
+impl RelativeKeys {
+    fn encode(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    /// Keys outside of the prefix have no relative key
+    fn decode(&self, key: &str) -> Option<String> {
+        key.strip_prefix(self.prefix.as_str()).map(str::to_string)
+    }
+}
+
+/// Encoding of the key column into etcd keys, see the `key_pad`, `key_encoding` and `strip_prefix` table options
+#[derive(Clone, Debug)]
+enum KeyEncoding {
+    Padded(PaddedKeys),
+    Percent(PercentKeys),
+    Relative(RelativeKeys),
+}
+
+impl KeyEncoding {
+    fn from_options(options: &HashMap<String, String>) -> Result<Option<Self>, EtcdFdwError> {
+        let percent = match options.get("key_encoding") {
+            Some(v) => parse_key_encoding(v)?,
+            None => false,
+        };
+        let strip = match options.get("strip_prefix") {
+            Some(v) => parse_strip_prefix(v)?,
+            None => false,
+        };
+        match PaddedKeys::from_options(options)? {
+            Some(_) if percent => Err(EtcdFdwError::InvalidOption(
+                "key_encoding".to_string(),
+                "percent".to_string(),
+            )),
+            // padded and percent-encoded keys are already relative to the prefix
+            Some(_) if strip => Err(EtcdFdwError::InvalidOption("strip_prefix".to_string(), "true".to_string())),
+            None if percent && strip => Err(EtcdFdwError::InvalidOption("strip_prefix".to_string(), "true".to_string())),
+            Some(p) => Ok(Some(Self::Padded(p))),
+            None if percent => Ok(Some(Self::Percent(PercentKeys {
+                prefix: options.get("prefix").cloned().unwrap_or_default(),
+            }))),
+            None if strip => Ok(Some(Self::Relative(RelativeKeys {
+                prefix: options.get("prefix").cloned().unwrap_or_default(),
+            }))),
+            // keys are shown within the namespace
+            None => Ok(options
+                .get("namespace")
+                .map(|namespace| Self::Relative(RelativeKeys { prefix: namespace.clone() }))),
+        }
+    }
+
+    /// The etcd key a key column value is stored under
+    fn encode(&self, cell: &Cell) -> EtcdFdwResult<String> {
+        match (self, cell) {
+            (Self::Padded(p), cell) => cell_number(cell)
+                .and_then(|n| p.encode(n))
+                .ok_or_else(|| EtcdFdwError::InvalidPaddedKey(cell.to_string())),
+            (Self::Percent(p), Cell::String(s)) => p
+                .encode(s)
+                .ok_or_else(|| EtcdFdwError::InvalidPercentKey(s.clone())),
+            (Self::Percent(_), cell) => Err(EtcdFdwError::InvalidPercentKey(cell.to_string())),
+            (Self::Relative(r), Cell::String(s)) => Ok(r.encode(s)),
+            (Self::Relative(r), cell) => Ok(r.encode(cell.to_string().trim_matches('\''))),
+        }
+    }
+
+    /// The key column value of an etcd key
+    fn decode(&self, key: &str) -> EtcdFdwResult<Cell> {
+        match self {
+            Self::Padded(p) => p
+                .decode(key)
+                .map(Cell::I64)
+                .ok_or_else(|| EtcdFdwError::InvalidPaddedKey(key.to_string())),
+            Self::Percent(p) => p
+                .decode(key)
+                .map(Cell::String)
+                .ok_or_else(|| EtcdFdwError::InvalidPercentKey(key.to_string())),
+            Self::Relative(r) => r
+                .decode(key)
+                .map(Cell::String)
+                .ok_or_else(|| EtcdFdwError::InvalidRelativeKey(key.to_string())),
+        }
+    }
+
+    /// Whether etcd's key order matches the order of the key column
+    fn preserves_order(&self) -> bool {
+        matches!(self, Self::Padded(_) | Self::Relative(_))
+    }
+}
+
+/// Keys split into path segments, see the `key_delimiter` table option
+/// The part of a key following the table's prefix and a delimiter is split, the last segment takes the rest
+#[derive(Clone)]
+struct KeySegments {
+    delimiter: String,
+    prefix: String,
+}
+
+impl KeySegments {
+    fn from_options(options: &HashMap<String, String>) -> Option<Self> {
+        Some(Self {
+            delimiter: options.get("key_delimiter")?.clone(),
+            prefix: options.get("prefix").cloned().unwrap_or_default(),
+        })
+    }
+
+    /// The `count` segments of a key, NULL for the ones a shorter key lacks
+    fn split(&self, key: &str, count: usize) -> Vec<Option<String>> {
+        let rest = key.strip_prefix(self.prefix.as_str()).unwrap_or(key);
+        let rest = rest.strip_prefix(self.delimiter.as_str()).unwrap_or(rest);
+        let mut segments: Vec<Option<String>> = rest
+            .splitn(count, self.delimiter.as_str())
+            .map(|s| Some(s.to_string()))
+            .collect();
+        segments.resize(count, None);
+        segments
+    }
+
+    /// The key of a row's segments, or the start of the keys below the leading segments
+    fn join(&self, segments: &[String]) -> String {
+        let separator = if self.prefix.ends_with(self.delimiter.as_str()) { "" } else { self.delimiter.as_str() };
+        format!("{}{}{}", self.prefix, separator, segments.join(self.delimiter.as_str()))
+    }
+}
+
+/// Step of a `JsonPath`
+#[derive(Clone, Debug, PartialEq)]
+enum JsonPathStep {
+    Member(String),
+    Index(usize),
+}
+
+/// Path of a column's field in the value document, see the `jsonpath` column option
+/// Only member accessors and array subscripts, e.g. `$.hosts[0]."host name"`, as writes follow the path too
+#[derive(Clone, Debug, PartialEq)]
+struct JsonPath(Vec<JsonPathStep>);
+
+impl JsonPath {
+    /// The field at the path, None if the document lacks it
+    fn get<'a>(&self, doc: &'a serde_json::Value) -> Option<&'a serde_json::Value> {
+        self.0.iter().try_fold(doc, |v, step| match step {
+            JsonPathStep::Member(name) => v.get(name.as_str()),
+            JsonPathStep::Index(i) => v.get(*i),
+        })
+    }
+
+    /// Store a field at the path, creating the objects and arrays leading to it
+    fn set(&self, doc: &mut serde_json::Value, value: serde_json::Value) {
+        let mut target = doc;
+        for step in &self.0 {
+            target = match step {
+                JsonPathStep::Member(name) => {
+                    if !target.is_object() {
+                        *target = serde_json::Value::Object(serde_json::Map::new());
+                    }
+                    &mut target[name.as_str()]
+                }
+                JsonPathStep::Index(i) => {
+                    if !target.is_array() {
+                        *target = serde_json::Value::Array(Vec::new());
+                    }
+                    if let serde_json::Value::Array(array) = &mut *target {
+                        if array.len() <= *i {
+                            array.resize(*i + 1, serde_json::Value::Null);
+                        }
+                    }
+                    &mut target[*i]
+                }
+            };
+        }
+        *target = value;
+    }
+}
+
+fn parse_jsonpath(val: &str) -> Result<JsonPath, EtcdFdwError> {
+    let invalid = || EtcdFdwError::InvalidOption("jsonpath".to_string(), val.to_string());
+    let mut rest = val.trim().strip_prefix('$').ok_or_else(invalid)?;
+    let mut steps = Vec::new();
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix(".\"") {
+            let end = r.find('"').ok_or_else(invalid)?;
+            steps.push(JsonPathStep::Member(r[..end].to_string()));
+            rest = &r[end + 1..];
+        } else if let Some(r) = rest.strip_prefix('.') {
+            let end = r.find(['.', '[']).unwrap_or(r.len());
+            let name = &r[..end];
+            if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                return Err(invalid());
+            }
+            steps.push(JsonPathStep::Member(name.to_string()));
+            rest = &r[end..];
+        } else if let Some(r) = rest.strip_prefix('[') {
+            let end = r.find(']').ok_or_else(invalid)?;
+            let index = r[..end].trim().parse::<usize>().map_err(|_| invalid())?;
+            steps.push(JsonPathStep::Index(index));
+            rest = &r[end + 1..];
+        } else {
+            return Err(invalid());
+        }
+    }
+    // `$` alone is the value column
+    if steps.is_empty() {
+        return Err(invalid());
+    }
+    Ok(JsonPath(steps))
+}
+
+/// The cell of a column of type `type_oid` holding a field of a JSON document
+fn json_cell(value: &serde_json::Value, type_oid: pg_sys::Oid) -> Result<Cell, String> {
+    let text = || match value {
+        serde_json::Value::String(s) => s.clone(),
+        v => v.to_string(),
+    };
+    let mismatch = || format!("field {} doesn't fit the column type", value);
+    let cell = match type_oid {
+        pg_sys::TEXTOID | pg_sys::VARCHAROID => Cell::String(text()),
+        pg_sys::JSONBOID => Cell::Json(JsonB(value.clone())),
+        pg_sys::BOOLOID => Cell::Bool(value.as_bool().or_else(|| text().parse().ok()).ok_or_else(mismatch)?),
+        pg_sys::INT2OID | pg_sys::INT4OID | pg_sys::INT8OID => {
+            let n = value.as_i64().or_else(|| text().parse().ok()).ok_or_else(mismatch)?;
+            match type_oid {
+                pg_sys::INT2OID => Cell::I16(i16::try_from(n).map_err(|_| mismatch())?),
+                pg_sys::INT4OID => Cell::I32(i32::try_from(n).map_err(|_| mismatch())?),
+                _ => Cell::I64(n),
+            }
+        }
+        pg_sys::FLOAT4OID => Cell::F32(value.as_f64().or_else(|| text().parse().ok()).ok_or_else(mismatch)? as f32),
+        pg_sys::FLOAT8OID => Cell::F64(value.as_f64().or_else(|| text().parse().ok()).ok_or_else(mismatch)?),
+        pg_sys::NUMERICOID => Cell::Numeric(AnyNumeric::try_from(text().as_str()).map_err(|_| mismatch())?),
+        _ => return Err("jsonpath columns have to be of type text, varchar, jsonb, boolean or a number".to_string()),
+    };
+    Ok(cell)
+}
+
+/// The cell of a value column of a type other than text, e.g. bigint, numeric, boolean or timestamptz
+/// The value is parsed by the type's input function, values it rejects are reported with the key they belong to
+fn typed_cell(value: &str, type_oid: pg_sys::Oid) -> Result<Cell, String> {
+    let text = CString::new(value).map_err(|e| e.to_string())?;
+    let datum = PgTryBuilder::new(|| unsafe {
+        let mut input = pg_sys::InvalidOid;
+        let mut ioparam = pg_sys::InvalidOid;
+        pg_sys::getTypeInputInfo(type_oid, &mut input, &mut ioparam);
+        Ok(pg_sys::OidInputFunctionCall(input, text.as_ptr() as *mut std::ffi::c_char, ioparam, -1))
+    })
+    .catch_others(|e| match e {
+        pg_sys::panic::CaughtError::PostgresError(report) | pg_sys::panic::CaughtError::ErrorReport(report) => {
+            Err(report.message().to_string())
+        }
+        e => e.rethrow(),
+    })
+    .execute()?;
+    unsafe { Cell::from_polymorphic_datum(datum, false, type_oid) }
+        .ok_or_else(|| format!("values can't be cast to type {}", type_oid.to_u32()))
+}
+
+/// The cell of a column holding text read from etcd, a key, a key segment, a sub-key or a value,
+/// of whatever type the column is declared as
+fn text_cell(value: &str, type_oid: pg_sys::Oid) -> Result<Cell, String> {
+    match type_oid {
+        pg_sys::TEXTOID | pg_sys::VARCHAROID | pg_sys::BPCHAROID => Ok(Cell::String(value.to_string())),
+        pg_sys::JSONBOID => serde_json::from_str(value)
+            .map(|json| Cell::Json(JsonB(json)))
+            .map_err(|e| format!("invalid JSON: {}", e)),
+        // other types are cast from the text
+        type_oid => typed_cell(value, type_oid),
+    }
+}
+
+/// The cell of a metadata column, a bigint unless the column is declared with another type, e.g. integer or numeric
+fn number_cell(n: i64, type_oid: pg_sys::Oid) -> Result<Cell, String> {
+    match type_oid {
+        pg_sys::INT8OID => Ok(Cell::I64(n)),
+        type_oid => typed_cell(&n.to_string(), type_oid),
+    }
+}
+
+/// The JSON field a cell of a `jsonpath` column is stored as
+fn cell_json(cell: &Cell) -> serde_json::Value {
+    match cell {
+        Cell::Bool(b) => serde_json::Value::Bool(*b),
+        Cell::I8(n) => serde_json::Value::from(*n),
+        Cell::I16(n) => serde_json::Value::from(*n),
+        Cell::I32(n) => serde_json::Value::from(*n),
+        Cell::I64(n) => serde_json::Value::from(*n),
+        Cell::F32(n) => serde_json::Value::from(*n),
+        Cell::F64(n) => serde_json::Value::from(*n),
+        Cell::Numeric(n) => serde_json::from_str(&n.to_string()).unwrap_or_else(|_| serde_json::Value::String(n.to_string())),
+        Cell::String(s) => serde_json::Value::String(s.clone()),
+        Cell::Json(json) => json.0.clone(),
+        cell => serde_json::Value::String(cell.to_string().trim_matches('\'').to_string()),
+    }
+}
+
+/// Columns that are filled with the metadata of a key rather than its name or value
+const METADATA_COLUMNS: [&str; 9] = [
+    "create_revision",
+    "mod_revision",
+    "version",
+    "lease",
+    "created",
+    "ttl_remaining",
+    "ttl",
+    "expires_at",
+    "value_md5",
+];
+
+/// The columns holding the segments of keys split by `key_delimiter`: all but the key, value and metadata columns
+fn segment_columns(columns: &[String], key_column: &str, value_column: &str) -> Vec<String> {
+    columns
+        .iter()
+        .filter(|c| *c != key_column && *c != value_column && !METADATA_COLUMNS.contains(&c.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// The etcd value of a cell of the value column
+/// bytea values are stored as they are, jsonb values as their JSON text
+fn value_bytes(cell: &Cell) -> Vec<u8> {
+    match cell {
+        Cell::Bytea(bytes) => unsafe { pgrx::varlena::varlena_to_byte_slice(*bytes as *const pg_sys::varlena) }.to_vec(),
+        Cell::Json(json) => json.0.to_string().into_bytes(),
+        cell => cell.to_string().trim_matches(|x| x == '\'').as_bytes().to_vec(),
+    }
+}
+
+/// Integer value of a cell, if it holds one
+fn cell_number(cell: &Cell) -> Option<i64> {
+    match cell {
+        Cell::I16(n) => Some(*n as i64),
+        Cell::I32(n) => Some(*n as i64),
+        Cell::I64(n) => Some(*n),
+        _ => None,
+    }
+}
+
+/// Integer operand of a qual, None for other types, NULL and arrays
+fn qual_number(q: &Qual) -> Option<i64> {
+    match &q.value {
+        Value::Cell(cell) => cell_number(cell),
+        _ => None,
+    }
+}
+
+/// Translate a key column value into the etcd key it is stored under
+fn cell_key(cell: &Cell, key_encoding: Option<&KeyEncoding>) -> Option<String> {
+    match (cell, key_encoding) {
+        (Cell::String(s), None) => Some(s.clone()),
+        // bytea keys that aren't UTF-8 can't be used as bounds of the scan
+        (Cell::Bytea(bytes), None) => {
+            let bytes = unsafe { pgrx::varlena::varlena_to_byte_slice(*bytes as *const pg_sys::varlena) };
+            std::str::from_utf8(bytes).ok().map(str::to_string)
+        }
+        (cell, Some(e)) => e.encode(cell).ok(),
+        _ => None,
+    }
+}
+
+/// Parse the `default_limit` table option, a positive row count
+fn parse_default_limit(val: &str) -> Result<i64, EtcdFdwError> {
+    match val.parse::<i64>() {
+        Ok(rows) if rows > 0 => Ok(rows),
+        _ => Err(EtcdFdwError::InvalidOption("default_limit".to_string(), val.to_string())),
+    }
+}
+
+/// etcd sort target of a column, None for columns etcd can't sort by
+fn sort_target(column: &str) -> Option<SortTarget> {
+    match column {
+        "key" => Some(SortTarget::Key),
+        "value" => Some(SortTarget::Value),
+        "create_revision" => Some(SortTarget::Create),
+        "mod_revision" => Some(SortTarget::Mod),
+        "version" => Some(SortTarget::Version),
+        // etcd can't sort by lease
+        _ => None,
+    }
+}
+
+/// Parse the `default_sort` table option, a sort target or column followed by an optional `asc` or `desc`
+/// i.e. `key desc` or `mod_revision desc`
+fn parse_default_sort(val: &str) -> Result<(SortTarget, SortOrder), EtcdFdwError> {
+    let invalid = || EtcdFdwError::InvalidOption("default_sort".to_string(), val.to_string());
+    let mut words = val.split_whitespace();
+    let target = words
+        .next()
+        .and_then(|field| SortTarget::from_str_name(&field.to_ascii_uppercase()).or_else(|| sort_target(field)))
+        .ok_or_else(invalid)?;
+    let order = match words.next().map(|o| o.to_ascii_lowercase()).as_deref() {
+        None | Some("asc") => SortOrder::Ascend,
+        Some("desc") => SortOrder::Descend,
+        Some(_) => return Err(invalid()),
+    };
+    if words.next().is_some() {
+        return Err(invalid());
+    }
+    Ok((target, order))
+}
+
+/// Parse the `modify_batch_size` table option, the number of writes per etcd transaction
+fn parse_modify_batch_size(val: &str) -> Result<usize, EtcdFdwError> {
+    match val.parse::<usize>() {
+        Ok(size) if (1..=MAX_TXN_OPS).contains(&size) => Ok(size),
+        _ => Err(EtcdFdwError::InvalidOption("modify_batch_size".to_string(), val.to_string())),
+    }
+}
+
+/// Parse the `on_scan_error` table option, whether undecodable entries are skipped
+fn parse_on_scan_error(val: &str) -> Result<bool, EtcdFdwError> {
+    match val {
+        "error" => Ok(false),
+        "warn_skip" => Ok(true),
+        _ => Err(EtcdFdwError::InvalidOption("on_scan_error".to_string(), val.to_string())),
+    }
+}
+
+/// Parse the `load_balancing` server option, whether requests are pinned to the first reachable endpoint
+fn parse_load_balancing(val: &str) -> Result<bool, EtcdFdwError> {
+    match val {
+        "round_robin" => Ok(false),
+        "pick_first" => Ok(true),
+        _ => Err(EtcdFdwError::InvalidOption("load_balancing".to_string(), val.to_string())),
+    }
+}
+
+/// Parse the `point_cache` table option
+fn parse_point_cache(val: &str) -> Result<bool, EtcdFdwError> {
+    match val {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(EtcdFdwError::InvalidOption("point_cache".to_string(), val.to_string())),
+    }
+}
+
+/// Parse the `tls_backend` server option, only the backend etcd_fdw was built with is accepted
+fn parse_tls_backend(val: &str) -> Result<TlsBackend, EtcdFdwError> {
+    match val {
+        "rustls" if cfg!(feature = "tls-rustls") => Ok(TlsBackend::Rustls),
+        "openssl" if cfg!(feature = "tls-openssl") => Ok(TlsBackend::Openssl),
+        "rustls" | "openssl" => Err(EtcdFdwError::TlsBackendUnavailable(val.to_string())),
+        _ => Err(EtcdFdwError::InvalidOption("tls_backend".to_string(), val.to_string())),
+    }
+}
+
+/// Parse the `hedge_delay` server option, in milliseconds
+fn parse_hedge_delay(val: &str) -> Result<Duration, EtcdFdwError> {
+    match val.parse::<u64>() {
+        Ok(ms) => Ok(Duration::from_millis(ms)),
+        _ => Err(EtcdFdwError::InvalidOption("hedge_delay".to_string(), val.to_string())),
+    }
+}
+
+/// Parse the `cache_ttl` table option, seconds with an optional `s` or `ms` unit
+fn parse_cache_ttl(val: &str) -> Result<Duration, EtcdFdwError> {
+    let parsed = if let Some(ms) = val.strip_suffix("ms") {
+        ms.trim().parse::<u64>().map(Duration::from_millis)
+    } else {
+        val.strip_suffix('s').unwrap_or(val).trim().parse::<u64>().map(Duration::from_secs)
+    };
+    parsed.map_err(|_| EtcdFdwError::InvalidOption("cache_ttl".to_string(), val.to_string()))
+}
+
+/// Parse the `layout` table option, whether the columns of a row are stored as sub-keys of the row key
+fn parse_layout(val: &str) -> Result<bool, EtcdFdwError> {
+    match val {
+        "key_value" => Ok(false),
+        "sub_keys" => Ok(true),
+        _ => Err(EtcdFdwError::InvalidOption("layout".to_string(), val.to_string())),
+    }
+}
+
+/// Parse the `modify_lock` table option, whether modify statements lock the table's prefix
+fn parse_modify_lock(val: &str) -> Result<bool, EtcdFdwError> {
+    match val {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(EtcdFdwError::InvalidOption("modify_lock".to_string(), val.to_string())),
+    }
+}
+
+/// Parse the `max_inflight_requests` server option
+fn parse_max_inflight_requests(val: &str) -> Result<usize, EtcdFdwError> {
+    val.parse::<usize>()
+        .map_err(|_| EtcdFdwError::InvalidOption("max_inflight_requests".to_string(), val.to_string()))
+}
+
+/// What an insert of an existing key does, from the `on_conflict` table option
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum OnConflict {
+    #[default]
+    Error,
+    Ignore,
+    /// Plain put without checking whether the key exists
+    Overwrite,
+}
+
+/// Parse the `on_conflict` table option
+fn parse_on_conflict(val: &str) -> Result<OnConflict, EtcdFdwError> {
+    match val {
+        "error" => Ok(OnConflict::Error),
+        "ignore" => Ok(OnConflict::Ignore),
+        "overwrite" => Ok(OnConflict::Overwrite),
+        _ => Err(EtcdFdwError::InvalidOption("on_conflict".to_string(), val.to_string())),
+    }
+}
+
+/// Parse the `on_unavailable` table option, whether scans return no rows if etcd can't be reached
+fn parse_on_unavailable(val: &str) -> Result<bool, EtcdFdwError> {
+    match val {
+        "error" => Ok(false),
+        "empty" => Ok(true),
+        _ => Err(EtcdFdwError::InvalidOption("on_unavailable".to_string(), val.to_string())),
+    }
+}
+
+/// Whether a request failed because the etcd cluster couldn't be reached
+fn is_unavailable(e: &Error) -> bool {
+    match e {
+        Error::TransportError(_) => true,
+        Error::GRpcStatus(status) => matches!(
+            status.code(),
+            tonic::Code::Unavailable | tonic::Code::DeadlineExceeded
+        ),
+        _ => false,
+    }
+}
+
+/// Error of a failed read request
+fn fetch_error(e: Error) -> EtcdFdwError {
+    if is_unavailable(&e) {
+        EtcdFdwError::EtcdUnavailable(e.to_string())
+    } else {
+        EtcdFdwError::FetchError(e.to_string())
+    }
+}
+
+/// Parse the `lease_id` table option, the ID of an etcd lease
+fn parse_lease_id(val: &str) -> Result<i64, EtcdFdwError> {
+    match val.parse::<i64>() {
+        Ok(lease) if lease > 0 => Ok(lease),
+        _ => Err(EtcdFdwError::InvalidOption("lease_id".to_string(), val.to_string())),
+    }
+}
+
+/// Parse the `write_lease_ttl` table option, a lease TTL in seconds
+fn parse_write_lease_ttl(val: &str) -> Result<i64, EtcdFdwError> {
+    match val.parse::<i64>() {
+        Ok(ttl) if ttl > 0 => Ok(ttl),
+        _ => Err(EtcdFdwError::InvalidOption("write_lease_ttl".to_string(), val.to_string())),
+    }
+}
+
+/// Look up the value of an option in the validator's `name=value` option list
+fn option_value<'a>(options: &'a [Option<String>], name: &str) -> Option<&'a str> {
+    let search_key = format!("{}=", name);
+    options
+        .iter()
+        .flatten()
+        .find_map(|o| o.strip_prefix(search_key.as_str()))
+}
+
+/// Literal prefix of a LIKE pattern and the rest of the pattern, starting at the first wildcard
+/// The rest is empty for patterns without wildcards and only `%` for pure prefix patterns like `'/a/%'`
+fn like_prefix(pattern: &str) -> (String, &str) {
+    let mut prefix = String::new();
+    let mut rest = pattern;
+    let mut chars = pattern.char_indices();
+    while let Some((i, c)) = chars.next() {
+        rest = &pattern[i..];
+        match c {
+            '\\' => match chars.next() {
+                Some((_, escaped)) => prefix.push(escaped),
+                // a trailing escape is rejected by the recheck
+                None => return (prefix, rest),
+            },
+            '%' | '_' => return (prefix, rest),
+            c => prefix.push(c),
+        }
+    }
+    (prefix, "")
+}
+
+/// Narrow the start of the key range of the conditions, several lower bounds only match above the largest one
+fn raise_key_start(start: &mut Option<String>, bound: String) {
+    if start.as_ref().is_none_or(|s| bound > *s) {
+        *start = Some(bound);
+    }
+}
+
+/// Narrow the end of the key range of the conditions, several upper bounds only match below the smallest one
+fn lower_range_end(end: &mut Option<String>, bound: String) {
+    if end.as_ref().is_none_or(|e| bound < *e) {
+        *end = Some(bound);
+    }
+}
+
+/// Rest of a scan that reads the keys in pages of `fetch_size` keys, see `EtcdFdw::fetch_next_page`
+/// The pages continue after the last key of the previous page, in the key order of the scan
+struct ScanPages {
+    /// range of the keys not read yet
+    key: Vec<u8>,
+    range_end: Vec<u8>,
+    descending: bool,
+    /// options of the scan, at the revision of the first page
+    options: GetOptions,
+    fetch_size: i64,
+    /// rows left to read for the limit of the scan
+    remaining: Option<i64>,
+}
+
+/// Scans left of a table with several prefixes, one per prefix in the key order of the scan
+struct PrefixScans {
+    quals: Vec<Qual>,
+    columns: Vec<Column>,
+    sort: Vec<Sort>,
+    /// options of each scan, with one of the prefixes and at the revision of the first scan
+    options: Vec<HashMap<String, String>>,
+    /// rows left to return for the `default_limit` of the table
+    rows_left: Option<i64>,
+}
+
+/// The prefixes of the `prefixes` option, a comma-separated list
+/// Prefixes below another one are left out, the others are sorted
+fn split_prefixes(list: &str) -> Vec<String> {
+    let mut prefixes: Vec<String> = list.split(',').map(str::to_string).collect();
+    prefixes.sort();
+    prefixes.dedup();
+    let all = prefixes.clone();
+    prefixes.retain(|p| !all.iter().any(|q| q != p && p.starts_with(q.as_str())));
+    prefixes
+}
+
+/// The prefixes of a table with expanded options, those of `prefixes` or the single `prefix`
+fn option_prefixes(options: &HashMap<String, String>) -> Vec<String> {
+    match options.get("prefixes") {
+        Some(list) => split_prefixes(list),
+        None => options.get("prefix").cloned().into_iter().collect(),
+    }
+}
+
+/// The etcd request a scan sends, as shown by EXPLAIN (VERBOSE)
+#[derive(Clone, Default)]
+struct ScanRequest {
+    /// `get`, `range` or `multi-get`, `none` for scans that can't match any key
+    operation: &'static str,
+    key: String,
+    range_end: Option<String>,
+    prefix: Option<String>,
+    /// number of keys of a multi-get
+    keys: Option<usize>,
+    limit: Option<i64>,
+    fetch_size: Option<i64>,
+    sort: Option<(SortTarget, SortOrder)>,
+    revision: i64,
+    serializable: bool,
+    keys_only: bool,
+    count_only: bool,
+    /// request sent with the keys the first one listed
+    then: Option<&'static str>,
+    /// cache the scan is answered from
+    cache: Option<&'static str>,
+}
+
+impl std::fmt::Display for ScanRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.operation == "none" {
+            return write!(f, "none");
+        }
+        write!(f, "{}", self.operation)?;
+        if let Some(n) = self.keys {
+            write!(f, " of {} keys", n)?;
+        } else {
+            write!(f, " key {:?}", self.key)?;
+        }
+        if let Some(range_end) = &self.range_end {
+            write!(f, " range_end {:?}", range_end)?;
+        }
+        if let Some(prefix) = &self.prefix {
+            write!(f, " prefix {:?}", prefix)?;
+        }
+        if let Some(n) = self.limit {
+            write!(f, " limit {}", n)?;
+        }
+        if let Some(n) = self.fetch_size {
+            write!(f, " fetch_size {}", n)?;
+        }
+        if let Some((target, order)) = self.sort {
+            write!(f, " sort {} {}", format!("{:?}", target).to_lowercase(), format!("{:?}", order).to_lowercase())?;
+        }
+        if self.revision > 0 {
+            write!(f, " revision {}", self.revision)?;
+        }
+        write!(f, " consistency {}", if self.serializable { "s" } else { "l" })?;
+        if self.keys_only {
+            write!(f, " keys_only")?;
+        }
+        if self.count_only {
+            write!(f, " count_only")?;
+        }
+        if let Some(then) = self.then {
+            write!(f, ", then {}", then)?;
+        }
+        if let Some(cache) = self.cache {
+            write!(f, " using {}", cache)?;
+        }
+        Ok(())
+    }
+}
+
+/// Requests a scan sent to etcd and what they returned, shown by EXPLAIN ANALYZE
+#[derive(Clone, Copy, Default)]
+struct ScanStats {
+    requests: i64,
+    keys: i64,
+    /// bytes of the returned keys and values
+    bytes: i64,
+    /// time spent waiting for etcd
+    time: Duration,
+}
+
+impl ScanStats {
+    fn add(self, other: ScanStats) -> ScanStats {
+        ScanStats {
+            requests: self.requests + other.requests,
+            keys: self.keys + other.keys,
+            bytes: self.bytes + other.bytes,
+            time: self.time + other.time,
+        }
+    }
+}
+
+thread_local! {
+    /// Set by `begin_foreign_scan` for EXPLAIN without ANALYZE, scans then describe their request without sending it
+    static EXPLAIN_ONLY: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    /// Description of the request of the scan started last, taken by `begin_foreign_scan` and `re_scan_foreign_scan`
+    static SCAN_REQUEST: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// Statistics of the scan begun last, taken by `begin_foreign_scan`
+    static BEGUN_SCAN_STATS: RefCell<Option<Rc<std::cell::Cell<ScanStats>>>> = const { RefCell::new(None) };
+}
+
+/// Records the request of the scan for EXPLAIN (VERBOSE), returns whether the scan is only explained
+/// Scans that are only explained stop before sending it
+fn describe_scan(request: &ScanRequest) -> bool {
+    SCAN_REQUEST.set(Some(request.to_string()));
+    EXPLAIN_ONLY.get()
+}
+
+/// LIKE or regular expression condition on the key, that can't be expressed as an etcd range
+enum KeyPattern {
+    Like(String),
+    Regex(String),
+}
+
+impl KeyPattern {
+    /// Whether `key` matches, evaluated by PostgreSQL's own operators so the result is the same as the recheck
+    fn matches(&self, key: &str, collation: pg_sys::Oid) -> bool {
+        let (func, pattern): (unsafe extern "C-unwind" fn(pg_sys::FunctionCallInfo) -> pg_sys::Datum, &str) =
+            match self {
+                KeyPattern::Like(p) => (pg_sys::textlike, p),
+                KeyPattern::Regex(p) => (pg_sys::textregexeq, p),
+            };
+        unsafe {
+            let matched = pg_sys::DirectFunctionCall2Coll(
+                Some(func),
+                collation,
+                key.into_datum().expect("text isn't null"),
+                pattern.into_datum().expect("text isn't null"),
+            );
+            bool::from_datum(matched, false).unwrap_or(false)
+        }
+    }
+}
+
+/// Extract the etcd key operand of a qual on the key column
+/// Quals comparing against a query parameter (`key = $1` in a generic plan)
+/// carry a placeholder until the parameter is resolved at `begin_scan` time.
+/// Only the resolved text value, or number for `key_pad` tables, is returned
+fn qual_key_value(q: &Qual, key_encoding: Option<&KeyEncoding>) -> Option<String> {
+    match &q.value {
+        Value::Cell(cell) => cell_key(cell, key_encoding),
+        _ => None,
+    }
+}
+
+/// Keep roughly `percent` of the keys
+/// Each key is chosen independently by hashing it with a fixed seed, so repeated
+/// scans of unchanged keys return the same sample
+fn sample_keys(keys: Vec<String>, percent: f64) -> Vec<String> {
+    use std::hash::BuildHasher;
+
+    let state = std::hash::BuildHasherDefault::<std::hash::DefaultHasher>::default();
+    let threshold = (percent / 100.0 * u64::MAX as f64) as u64;
+    keys.into_iter()
+        .filter(|k| state.hash_one(k) <= threshold)
+        .collect()
+}
+
+/// Collect a `DefElem` option list of the catalog, options that aren't valid UTF-8 are skipped
+unsafe fn def_elem_options(options: *mut pg_sys::List) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    pgrx::memcx::current_context(|mcx| {
+        let list = pgrx::list::List::<*mut std::ffi::c_void>::downcast_ptr_in_memcx(options, mcx).unwrap();
+        for option in list.iter() {
+            let option = *option as *mut pg_sys::DefElem;
+            let name_cstr = std::ffi::CStr::from_ptr((*option).defname);
+            let value_cstr = std::ffi::CStr::from_ptr(pg_sys::defGetString(option));
+            let name = name_cstr.to_str().map_err(|_| {
+                OptionsError::OptionNameIsInvalidUtf8(
+                    String::from_utf8_lossy(name_cstr.to_bytes()).to_string(),
+                )
+            });
+            let value = value_cstr.to_str().map_err(|_| {
+                OptionsError::OptionValueIsInvalidUtf8 {
+                    option_name: name_cstr.to_string_lossy().into_owned(),
+                }
+            });
+            if let (Ok(name), Ok(value)) = (name, value) {
+                map.insert(name.to_string(), value.to_string());
+            }
+        }
+    });
+    map
+}
+
+/// Runtime shared by all etcd connections of the backend
+/// The shared gRPC channels run their connections on it, so it lives as long as the backend
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| tokio::runtime::Runtime::new().expect("Tokio runtime should be initialized"))
+}
+
+/// Everything about a connection but the authentication
+type ChannelKey = (Vec<String>, Option<String>, Option<String>, Option<String>, Option<String>, Duration, Duration, bool, usize);
+
+/// Counts the requests in flight against a limit, see `etcd_fdw.max_inflight_requests`
+struct RequestLimit {
+    in_flight: AtomicUsize,
+    /// 0 doesn't limit the requests
+    limit: AtomicUsize,
+    released: tokio::sync::Notify,
+}
+
+impl RequestLimit {
+    fn new(limit: usize) -> Self {
+        RequestLimit {
+            in_flight: AtomicUsize::new(0),
+            limit: AtomicUsize::new(limit),
+            released: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Wait until another request may be sent
+    async fn acquire(self: Arc<Self>) -> RequestPermit {
+        loop {
+            // Registered before checking, so a release in between isn't missed
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            let limit = self.limit.load(Ordering::Relaxed);
+            let acquired = self
+                .in_flight
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (limit == 0 || n < limit).then_some(n + 1))
+                .is_ok();
+            if acquired {
+                return RequestPermit(self);
+            }
+            released.await;
+        }
+    }
+}
+
+/// A request in flight, released when dropped
+struct RequestPermit(Arc<RequestLimit>);
+
+impl Drop for RequestPermit {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::AcqRel);
+        self.0.released.notify_waiters();
+    }
+}
+
+/// The limit of all requests of the backend
+fn backend_request_limit() -> Arc<RequestLimit> {
+    static LIMIT: OnceLock<Arc<RequestLimit>> = OnceLock::new();
+    LIMIT.get_or_init(|| Arc::new(RequestLimit::new(0))).clone()
+}
+
+/// Apply the current `etcd_fdw.max_inflight_requests` to the backend's requests
+/// GUCs can only be read by the backend's thread, not by the runtime's
+fn sync_request_limit() {
+    let limit = MAX_INFLIGHT_REQUESTS.get().max(0) as usize;
+    let backend = backend_request_limit();
+    if backend.limit.swap(limit, Ordering::Relaxed) != limit {
+        backend.released.notify_waiters();
+    }
+}
+
+/// gRPC channel that holds back requests exceeding the limits of the backend and the server
+/// A request counts until its response arrives, the streams of watches don't count
+/// The limit of the server applies to each channel, servers and user mappings sharing a channel share it
+#[derive(Clone)]
+struct LimitedChannel {
+    inner: tonic::transport::Channel,
+    limits: Vec<Arc<RequestLimit>>,
+}
+
+/// gRPC methods of requests that aren't held back by a `LimitedChannel`
+const UNLIMITED_LEASE_KEEP_ALIVE_PATH: &str = "/etcdserverpb.Lease/LeaseKeepAlive";
+const UNLIMITED_LOCK_PATH: &str = "/v3lockpb.Lock/Lock";
+
+type GrpcRequest = tonic::codegen::http::Request<tonic::body::Body>;
+type GrpcResponse = tonic::codegen::http::Response<tonic::body::Body>;
+
+impl tower::Service<GrpcRequest> for LimitedChannel {
+    type Response = GrpcResponse;
+    type Error = tower::BoxError;
+    type Future = std::pin::Pin<Box<dyn std::future::Future<Output = Result<GrpcResponse, tower::BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        // Readiness of the inner channel is awaited once the request may be sent
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: GrpcRequest) -> Self::Future {
+        use tower::ServiceExt;
+
+        let inner = self.inner.clone();
+        // Keep-alives of held leases and lock requests waiting for a lock could be starved by
+        // the requests they protect, or hold a permit while waiting, they aren't limited
+        let limits = match request.uri().path() {
+            UNLIMITED_LEASE_KEEP_ALIVE_PATH | UNLIMITED_LOCK_PATH => Vec::new(),
+            _ => self.limits.clone(),
+        };
+        Box::pin(async move {
+            let mut permits = Vec::with_capacity(limits.len());
+            for limit in limits {
+                permits.push(limit.acquire().await);
+            }
+            let response = inner.oneshot(request).await?;
+            drop(permits);
+            Ok(response)
+        })
+    }
+}
+
+/// Wrap a channel to respect the request limits, `server_limit` 0 only applies the backend's limit
+fn limited_channel(channel: tonic::transport::Channel, server_limit: usize) -> Channel {
+    let mut limits = vec![backend_request_limit()];
+    if server_limit > 0 {
+        limits.push(Arc::new(RequestLimit::new(server_limit)));
+    }
+    Channel::Custom(tower::util::BoxCloneSyncService::new(LimitedChannel {
+        inner: channel,
+        limits,
+    }))
+}
+
+thread_local! {
+    /// gRPC channels of the backend, shared by all servers and user mappings
+    /// that connect to the same endpoints with the same TLS settings and timeouts
+    static CHANNELS: RefCell<HashMap<ChannelKey, Channel>> = RefCell::new(HashMap::new());
+}
+
+thread_local! {
+    /// Versions the etcd clusters behind the channels of the backend reported
+    static SERVER_VERSIONS: RefCell<HashMap<ChannelKey, String>> = RefCell::new(HashMap::new());
+}
+
+/// Parse the major and minor number of an etcd version like `3.5.12`
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Options of the current user's mapping for a foreign server
+fn user_mapping_options(server_oid: pg_sys::Oid) -> HashMap<String, String> {
+    unsafe {
+        let usermapping =  pg_sys::GetUserMapping(pg_sys::GetUserId(), server_oid);
+        def_elem_options((*usermapping).options)
+    }
+}
+
+impl EtcdConfig {
+    /// Connection settings of a foreign server and a user mapping
+    fn from_options(
+        server_options: &HashMap<String, String>,
+        user_mapping: &HashMap<String, String>,
+    ) -> EtcdFdwResult<Self> {
+        let defaults = EtcdConfig::default();
+
+        // A connection string lists one or more endpoints separated by commas
+        let endpoints: Vec<String> = match server_options.get("connstr") {
+            Some(x) => x.split(',').map(|e| e.trim().to_string()).filter(|e| !e.is_empty()).collect(),
+            None => return Err(EtcdFdwError::NoConnStr(())),
+        };
+        let pick_first = match server_options.get("load_balancing") {
+            Some(v) => parse_load_balancing(v)?,
+            None => false,
+        };
+        let tls_backend = match server_options.get("tls_backend") {
+            Some(v) => parse_tls_backend(v)?,
+            None => TlsBackend::default(),
+        };
+        let max_inflight_requests = match server_options.get("max_inflight_requests") {
+            Some(v) => parse_max_inflight_requests(v)?,
+            None => 0,
+        };
+
+        let cacert_path = server_options.get("ssl_ca").cloned();
+        let cert_path = server_options.get("ssl_cert").cloned();
+        let key_path  = server_options.get("ssl_key").cloned();
+        let servername  = server_options.get("ssl_servername").cloned();
+
+        // Parse timeouts with defaults
+        // The connection is made before any table options are known, so only the server and session layers apply
+        let conn_options = resolve_options(server_options, &HashMap::new());
+        let connect_timeout = parse_timeout(&conn_options, "connect_timeout", defaults.connect_timeout)?;
+        let request_timeout = parse_timeout(&conn_options, "request_timeout", defaults.request_timeout)?;
+
+        // ssl_cert + ssl_key must be both present or both absent
+        require_pair(cert_path.is_some(), key_path.is_some(), EtcdFdwError::CertKeyMismatch(()))?;
+
+        let user = user_mapping.get("user").cloned();
+        let password = user_mapping.get("password").cloned();
+
+        Ok(EtcdConfig {
+            endpoints: endpoints,
+            ca_cert_path: cacert_path,
+            client_cert_path: cert_path,
+            client_key_path: key_path,
+            user: user,
+            password: password,
+            servername: servername,
+            connect_timeout: connect_timeout,
+            request_timeout: request_timeout,
+            pick_first: pick_first,
+            max_inflight_requests: max_inflight_requests,
+            tls_backend: tls_backend,
+        })
+    }
+
+    fn uses_tls(&self) -> bool {
+        self.ca_cert_path.is_some() || self.client_cert_path.is_some()
+    }
+
+    fn channel_key(&self) -> ChannelKey {
+        (
+            self.endpoints.clone(),
+            self.ca_cert_path.clone(),
+            self.client_cert_path.clone(),
+            self.client_key_path.clone(),
+            self.servername.clone(),
+            self.connect_timeout,
+            self.request_timeout,
+            self.pick_first,
+            self.max_inflight_requests,
+        )
+    }
+}
+
+/// Build a load balanced gRPC channel to the endpoints
+/// Parse the certs/key paths and read them as bytes
+/// Sets the `TlsOptions` if available to support sll connection
+async fn build_channel(config: &EtcdConfig) -> Result<Channel, Error> {
+    let use_tls = config.uses_tls();
+
+    #[cfg(not(feature = "tls-rustls"))]
+    if use_tls {
+        return Err(Error::InvalidArgs("etcd_fdw was built without the tls-rustls feature".to_string()));
+    }
+
+    #[cfg(feature = "tls-rustls")]
+    let mut tls_options = None;
+    #[cfg(feature = "tls-rustls")]
+    if use_tls {
+        let mut tls = TlsOptions::new();
+
+        // Load CA cert if provided
+        if let Some(ca_path) = &config.ca_cert_path {
+            let ca_bytes = std::fs::read(ca_path).map_err(Error::IoError)?;
+            let ca_cert = Certificate::from_pem(ca_bytes);
+            tls = tls.ca_certificate(ca_cert);
+        }
+
+        // Load client cert and key if both provided
+        if let (Some(cert_path), Some(key_path)) = (&config.client_cert_path, &config.client_key_path) {
+            let cert_bytes = std::fs::read(cert_path).map_err(Error::IoError)?;
+            let key_bytes  = std::fs::read(key_path).map_err(Error::IoError)?;
+            let identity = Identity::from_pem(cert_bytes, key_bytes);
+            tls = tls.identity(identity);
+        }
+
+        // Load domain name if provided
+        if let Some(domain) = &config.servername {
+            tls = tls.domain_name(domain);
+        }
+
+        tls_options = Some(tls);
+    }
+
+    let mut endpoints = Vec::new();
+    for url in &config.endpoints {
+        let url = if url.starts_with("http://") || url.starts_with("https://") {
+            url.clone()
+        } else if use_tls {
+            format!("https://{}", url)
+        } else {
+            format!("http://{}", url)
+        };
+
+        #[allow(unused_mut)]
+        let mut endpoint = tonic::transport::Endpoint::from_shared(url)?
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.request_timeout);
+        #[cfg(feature = "tls-rustls")]
+        if let Some(tls) = &tls_options {
+            endpoint = endpoint.tls_config(tls.clone())?;
+        }
+        endpoints.push(endpoint);
+    }
+
+    let limit = config.max_inflight_requests;
+    if !config.pick_first {
+        return Ok(limited_channel(tonic::transport::Channel::balance_list(endpoints.into_iter()), limit));
+    }
+
+    // Pin the channel to the first endpoint that accepts a connection
+    // If none does, connect to the first one lazily, so the outage is reported by the first request
+    for endpoint in &endpoints {
+        if let Ok(channel) = endpoint.connect().await {
+            return Ok(limited_channel(channel, limit));
+        }
+    }
+    match endpoints.first() {
+        Some(endpoint) => Ok(limited_channel(endpoint.connect_lazy(), limit)),
+        None => Err(Error::InvalidArgs("connstr doesn't contain an endpoint".to_string())),
+    }
+}
+
+/// Use this to connect to etcd.
+/// The gRPC channel is shared with other connections to the same endpoints,
+/// only the authentication is done per connection
+/// Has to run on the shared runtime
+pub async fn connect_etcd(config: EtcdConfig) -> Result<Client, Error> {
+    // etcd-client builds OpenSSL channels itself, so they aren't shared
+    if config.tls_backend == TlsBackend::Openssl && config.uses_tls() {
+        return connect_etcd_openssl(config).await;
+    }
+
+    let key = config.channel_key();
+    let channel = match CHANNELS.with_borrow(|channels| channels.get(&key).cloned()) {
+        Some(channel) => channel,
+        None => {
+            let channel = build_channel(&config).await?;
+            CHANNELS.with_borrow_mut(|channels| channels.insert(key, channel.clone()));
+            channel
+        }
+    };
+
+    // Load User and Password
+    let mut connect_options = ConnectOptions::new();
+    if let (Some(user), Some(pass)) = (&config.user, &config.password) {
+        connect_options = connect_options.with_user(user, pass);
+    }
+
+    Client::from_channel(channel, Some(connect_options)).await
+}
+
+/// Client of an unreachable cluster, every request fails with the connection error
+/// It doesn't connect or authenticate, so no request reaches etcd without the credentials of the user mapping
+async fn unavailable_client(reason: String) -> Result<Client, Error> {
+    let channel = tower::service_fn(move |_: GrpcRequest| {
+        let reason = reason.clone();
+        async move { Err::<GrpcResponse, tower::BoxError>(reason.into()) }
+    });
+    Client::from_channel(Channel::Custom(tower::util::BoxCloneSyncService::new(channel)), None).await
+}
+
+/// Connect to etcd with TLS implemented by OpenSSL
+#[cfg(feature = "tls-openssl")]
+async fn connect_etcd_openssl(config: EtcdConfig) -> Result<Client, Error> {
+    if config.servername.is_some() {
+        return Err(Error::InvalidArgs("ssl_servername isn't supported with tls_backend 'openssl'".to_string()));
+    }
+
+    let mut tls = OpenSslClientConfig::default();
+    if let Some(ca_path) = &config.ca_cert_path {
+        tls = tls.ca_cert_pem(&std::fs::read(ca_path).map_err(Error::IoError)?);
+    }
+    if let (Some(cert_path), Some(key_path)) = (&config.client_cert_path, &config.client_key_path) {
+        let cert_bytes = std::fs::read(cert_path).map_err(Error::IoError)?;
+        let key_bytes = std::fs::read(key_path).map_err(Error::IoError)?;
+        tls = tls.client_cert_pem_and_key(&cert_bytes, &key_bytes);
+    }
+
+    let mut connect_options = ConnectOptions::new()
+        .with_openssl_tls(tls)
+        .with_connect_timeout(config.connect_timeout)
+        .with_timeout(config.request_timeout);
+    if let (Some(user), Some(pass)) = (&config.user, &config.password) {
+        connect_options = connect_options.with_user(user, pass);
+    }
+
+    let endpoints: Vec<String> = config
+        .endpoints
+        .iter()
+        .map(|url| if url.starts_with("https://") { url.clone() } else { format!("https://{}", url.trim_start_matches("http://")) })
+        .collect();
+    Client::connect(endpoints, Some(connect_options)).await
+}
+
+#[cfg(not(feature = "tls-openssl"))]
+async fn connect_etcd_openssl(_config: EtcdConfig) -> Result<Client, Error> {
+    Err(Error::InvalidArgs("etcd_fdw was built without the tls-openssl feature".to_string()))
+}
+
+/// Default upper bound of operations per transaction enforced by etcd (`--max-txn-ops`)
+const MAX_TXN_OPS: usize = 128;
+
+thread_local! {
+    /// Clients of single endpoints used for hedged reads, by connection and authentication
+    static HEDGE_CLIENTS: RefCell<HashMap<(ChannelKey, Option<String>, Option<String>), Client>> = RefCell::new(HashMap::new());
+}
+
+/// Send a read to `first`, and after `delay` without a response also to `second`
+/// The first successful response wins, the read only fails if both requests failed
+async fn hedged_get(
+    mut first: Client,
+    mut second: Client,
+    key: String,
+    options: GetOptions,
+    delay: Duration,
+) -> Result<GetResponse, Error> {
+    let first_get = first.get(key.clone(), Some(options.clone()));
+    tokio::pin!(first_get);
+    match tokio::time::timeout(delay, &mut first_get).await {
+        Ok(Ok(resp)) => return Ok(resp),
+        Ok(Err(_)) => return second.get(key, Some(options)).await,
+        Err(_) => {}
+    }
+
+    let second_get = second.get(key, Some(options));
+    tokio::pin!(second_get);
+    tokio::select! {
+        resp = &mut first_get => match resp {
+            Ok(resp) => Ok(resp),
+            Err(_) => second_get.await,
+        },
+        resp = &mut second_get => match resp {
+            Ok(resp) => Ok(resp),
+            Err(_) => first_get.await,
+        },
+    }
+}
+
+/// Most keys a point cache holds, lookups of further keys aren't cached
+const POINT_CACHE_MAX_KEYS: usize = 10_000;
+
+/// Point lookups of a prefix cached by the backend, see the `point_cache` table option
+/// A watch on the prefix applies the changes of other clients to the cached keys
+#[derive(Default)]
+struct PointCache {
+    /// Cached keys with their key-value, None if the key doesn't exist, and the revision of that state
+    entries: HashMap<String, (Option<KeyValue>, i64)>,
+    /// Whether the watch is connected, cached keys aren't used while it reconnects
+    connected: bool,
+    /// Set once the watch can't resume, the cache is dropped by the next lookup
+    broken: bool,
+}
+
+/// Delays between reconnection attempts of a watch, see `etcd_fdw.watch_retry_min` and `etcd_fdw.watch_retry_max`
+#[derive(Clone, Copy)]
+struct Backoff {
+    min: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    fn from_settings() -> Self {
+        let min = Duration::from_millis(WATCH_RETRY_MIN.get() as u64);
+        let max = Duration::from_millis(WATCH_RETRY_MAX.get() as u64);
+        Self { min, max: max.max(min) }
+    }
+
+    /// Random delay up to the exponentially growing limit of the attempt ("full jitter")
+    fn delay(&self, attempt: u32) -> Duration {
+        use std::hash::BuildHasher;
+
+        let jitter = std::collections::hash_map::RandomState::new().hash_one(attempt) as f64 / u64::MAX as f64;
+        self.jittered_delay(attempt, jitter)
+    }
+
+    /// Delay of the attempt at `jitter` between 0 and 1 of the way to its limit
+    fn jittered_delay(&self, attempt: u32, jitter: f64) -> Duration {
+        let limit = self.min.saturating_mul(2u32.saturating_pow(attempt)).min(self.max);
+        self.min + (limit - self.min).mul_f64(jitter)
+    }
+}
+
+/// Counters of the watches of the backend
+static WATCHES_ACTIVE: AtomicI64 = AtomicI64::new(0);
+static WATCH_RECONNECTS: AtomicI64 = AtomicI64::new(0);
+static WATCHES_LOST: AtomicI64 = AtomicI64::new(0);
+
+/// Connection, etcd user and watched prefix of a point cache
+type PointCacheKey = (ChannelKey, Option<String>, String);
+
+thread_local! {
+    static POINT_CACHES: RefCell<HashMap<PointCacheKey, Arc<Mutex<PointCache>>>> = RefCell::new(HashMap::new());
+}
+
+/// Apply the changes of the keys below `prefix` after `revision` to the cached keys
+/// A dropped watch is reconnected with `backoff` and resumes after the last revision it was sent.
+/// The watch ends once the history it has to resume from was compacted or the cache was replaced
+/// The watch is counted as active by the caller, so it is counted as soon as it is spawned
+async fn watch_point_cache(
+    mut client: Client,
+    prefix: String,
+    revision: i64,
+    cache: Arc<Mutex<PointCache>>,
+    backoff: Backoff,
+) {
+    let mut next_revision = revision + 1;
+    let mut attempt = 0;
+    'reconnect: while Arc::strong_count(&cache) > 1 {
+        let watch_options = WatchOptions::new()
+            .with_prefix()
+            .with_start_revision(next_revision);
+        if let Ok((_watcher, mut stream)) = client.watch(prefix.clone(), Some(watch_options)).await {
+            while let Ok(Some(resp)) = stream.message().await {
+                if resp.canceled() {
+                    break 'reconnect;
+                }
+                attempt = 0;
+                // The response carries all changes up to the revision of its header
+                if let Some(header) = resp.header() {
+                    next_revision = next_revision.max(header.revision() + 1);
+                }
+                let mut cache = cache.lock().expect("The point cache lock isn't poisoned");
+                cache.connected = true;
+                for event in resp.events() {
+                    let Some(kv) = event.kv() else {
+                        continue;
+                    };
+                    next_revision = next_revision.max(kv.mod_revision() + 1);
+                    let Some(entry) = kv.key_str().ok().and_then(|k| cache.entries.get_mut(k)) else {
+                        continue;
+                    };
+                    if kv.mod_revision() > entry.1 {
+                        let state = if event.event_type() == EventType::Delete { None } else { Some(kv.clone()) };
+                        *entry = (state, kv.mod_revision());
+                    }
+                }
+            }
+        }
+
+        // Changes are missed while the watch is down, so the cached keys are read again
+        // Keys read meanwhile are updated by the changes the resumed watch replays
+        {
+            let mut cache = cache.lock().expect("The point cache lock isn't poisoned");
+            cache.connected = false;
+            cache.entries.clear();
+        }
+        tokio::time::sleep(backoff.delay(attempt)).await;
+        attempt += 1;
+        WATCH_RECONNECTS.fetch_add(1, Ordering::Relaxed);
+    }
+    cache.lock().expect("The point cache lock isn't poisoned").broken = true;
+    WATCHES_ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    WATCHES_LOST.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Forget the keys below a prefix deleted by this backend in all point caches, like `forget_cached_key`
+fn forget_cached_prefix(prefix: &[u8]) {
+    POINT_CACHES.with_borrow(|caches| {
+        for cache in caches.values() {
+            let mut cache = cache.lock().expect("The point cache lock isn't poisoned");
+            cache.entries.retain(|key, _| !key.as_bytes().starts_with(prefix));
+        }
+    });
+    SCAN_CACHE.with_borrow_mut(|cache| cache.clear());
+    STATEMENT_CACHE.with_borrow_mut(|(_, cache)| cache.clear());
+}
+
+/// Forget a key written by this backend in all point caches, so the next lookup reads it from etcd
+/// Cached scan results may contain the key as well, so they are all dropped
+fn forget_cached_key(key: &[u8]) {
+    // Only UTF-8 keys are cached
+    if let Ok(key) = std::str::from_utf8(key) {
+        POINT_CACHES.with_borrow(|caches| {
+            for cache in caches.values() {
+                cache.lock().expect("The point cache lock isn't poisoned").entries.remove(key);
+            }
+        });
+    }
+    SCAN_CACHE.with_borrow_mut(|cache| cache.clear());
+    STATEMENT_CACHE.with_borrow_mut(|(_, cache)| cache.clear());
+}
+
+/// Foreign table, connection, etcd user and request of a cached scan
+type ScanCacheKey = (pg_sys::Oid, ChannelKey, Option<String>, String);
+
+thread_local! {
+    /// Results of scans of tables with `cache_ttl`, with their read revision and the time they were read
+    static SCAN_CACHE: RefCell<HashMap<ScanCacheKey, (Vec<KeyValue>, i64, std::time::Instant)>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Connection, etcd user and request of a scan shared within a statement
+type StatementCacheKey = (ChannelKey, Option<String>, String);
+
+/// Start time and command of a statement, statements of the same function call may share them
+type StatementStamp = (pg_sys::TimestampTz, pg_sys::CommandId);
+
+thread_local! {
+    /// Results of the scans of the current statement with `etcd_fdw.statement_cache`, with their read revision
+    static STATEMENT_CACHE: RefCell<(Option<StatementStamp>, HashMap<StatementCacheKey, (Vec<KeyValue>, i64)>)> =
+        RefCell::new((None, HashMap::new()));
+}
+
+/// Results of an identical scan of the current statement, the results of earlier statements are dropped
+fn statement_cached(key: &StatementCacheKey) -> Option<(Vec<KeyValue>, i64)> {
+    let current = unsafe { (pg_sys::GetCurrentStatementStartTimestamp(), pg_sys::GetCurrentCommandId(false)) };
+    STATEMENT_CACHE.with_borrow_mut(|(stamp, cache)| {
+        if *stamp != Some(current) {
+            *stamp = Some(current);
+            cache.clear();
+        }
+        cache.get(key).cloned()
+    })
+}
+
+/// Drop the cached scan results of a table, or of all tables, returns the number of dropped results
+fn flush_scan_cache(relid: Option<pg_sys::Oid>) -> i64 {
+    SCAN_CACHE.with_borrow_mut(|cache| {
+        let before = cache.len();
+        cache.retain(|key, _| relid.is_some_and(|relid| key.0 != relid));
+        (before - cache.len()) as i64
+    })
+}
+
+/// Fetch a set of individual keys with as few round trips as possible
+/// Every key becomes a get operation, batched into etcd transactions of at most `MAX_TXN_OPS` operations
+/// Returns the key-values and the revision of the first transaction
+async fn multi_get<K: AsRef<[u8]>>(
+    client: &mut Client,
+    keys: Vec<K>,
+    options: GetOptions,
+) -> Result<(Vec<KeyValue>, i64), Error> {
+    let mut kvs = Vec::new();
+    let mut revision = 0;
+    for chunk in keys.chunks(MAX_TXN_OPS) {
+        let ops: Vec<TxnOp> = chunk
+            .iter()
+            .map(|k| TxnOp::get(k.as_ref(), Some(options.clone())))
+            .collect();
+        let resp = client.txn(Txn::new().and_then(ops)).await?;
+        if revision == 0 {
+            record_read_header(resp.header());
+            revision = resp.header().map(|h| h.revision()).unwrap_or(0);
+        }
+
+        for op in resp.op_responses() {
+            if let TxnOpResponse::Get(mut get) = op {
+                kvs.extend(get.take_kvs());
+            }
+        }
+    }
+    Ok((kvs, revision))
+}
+
+/// Put `key` attached to a new lease with `ttl` seconds and revoke the lease of a previous registration
+/// Returns the new lease
+async fn register_service(client: &mut Client, key: &str, address: &str, ttl: i64) -> Result<i64, EtcdFdwError> {
+    if ttl <= 0 {
+        return Err(EtcdFdwError::InvalidOption("ttl".to_string(), ttl.to_string()));
+    }
+    let lease = client
+        .lease_grant(ttl, None)
+        .await
+        .map_err(|e| EtcdFdwError::UpdateError(e.to_string()))?
+        .id();
+    let put_options = PutOptions::new().with_lease(lease).with_prev_key();
+    let resp = client
+        .put(key, address, Some(put_options))
+        .await
+        .map_err(|e| EtcdFdwError::UpdateError(e.to_string()))?;
+
+    // The previous lease only held this key, it may have expired already
+    if let Some(prev) = resp.prev_key().filter(|kv| kv.lease() != 0) {
+        let _ = client.lease_revoke(prev.lease()).await;
+    }
+    Ok(lease)
+}
+
+/// Delete `key` along with its lease, returns whether it existed
+async fn deregister_service(client: &mut Client, key: &str) -> Result<bool, EtcdFdwError> {
+    let resp = client
+        .delete(key, Some(DeleteOptions::new().with_prev_key()))
+        .await
+        .map_err(|e| EtcdFdwError::UpdateError(e.to_string()))?;
+    let Some(prev) = resp.prev_kvs().first() else {
+        return Ok(false);
+    };
+    if prev.lease() != 0 {
+        let _ = client.lease_revoke(prev.lease()).await;
+    }
+    Ok(true)
+}
+
+/// Apply `write` to `key` in a single transaction if `compare` holds, a `None` write deletes the key
+/// Returns whether the comparison held and the write was applied
+async fn compare_and_write(
+    client: &mut Client,
+    key: &str,
+    compare: Compare,
+    write: Option<&str>,
+) -> Result<bool, EtcdFdwError> {
+    let op = match write {
+        Some(value) => TxnOp::put(key, value, None),
+        None => TxnOp::delete(key, None),
+    };
+    let resp = client
+        .txn(Txn::new().when([compare]).and_then([op]))
+        .await
+        .map_err(|e| EtcdFdwError::UpdateError(e.to_string()))?;
+    Ok(resp.succeeded())
+}
+
+/// Keys `estimate_rel_size` reads to measure the mean size of the rows
+const ESTIMATE_SAMPLE_SIZE: i64 = 16;
+
+/// Keys listed per request by `keyspace_report`
+const REPORT_PAGE_SIZE: i64 = 1000;
+
+/// Group of a key by its first `depth` path segments, i.e. `/app/` for `/app/db/url` with depth 1
+/// Keys with fewer segments form a group of their own
+fn key_group(key: &str, depth: usize) -> &str {
+    let mut end = usize::from(key.starts_with('/'));
+    for _ in 0..depth {
+        match key[end..].find('/') {
+            Some(i) => end += i + 1,
+            None => return key,
+        }
+    }
+    &key[..end]
+}
+
+/// The keys a SQL function covers without a prefix of its own: the namespace of the server,
+/// or the prefix of the user mapping if that lies within it
+fn function_scope(namespace: &str, role_prefix: &str) -> String {
+    match role_prefix.starts_with(namespace) {
+        true => role_prefix.to_string(),
+        false => namespace.to_string(),
+    }
+}
+
+/// Number of keys and bytes of their values below `prefix`, grouped by their first `depth` path segments
+/// The keyspace is read in pages at the revision of the first page, so huge keyspaces don't have to fit in memory
+/// Keys are grouped within `namespace`, which `prefix` starts with
+async fn keyspace_report(
+    client: &mut Client,
+    namespace: &str,
+    prefix: &str,
+    depth: i32,
+) -> Result<BTreeMap<String, (i64, i64)>, EtcdFdwError> {
+    if depth < 0 {
+        return Err(EtcdFdwError::InvalidOption("depth".to_string(), depth.to_string()));
+    }
+    let range_end = prefix_range_end(prefix.as_bytes());
+
+    let mut groups: BTreeMap<String, (i64, i64)> = BTreeMap::new();
+    let mut start = match prefix {
+        "" => vec![0],
+        _ => prefix.as_bytes().to_vec(),
+    };
+    let mut revision = 0;
+    loop {
+        let mut options = GetOptions::new()
+            .with_range(range_end.clone())
+            .with_sort(SortTarget::Key, SortOrder::Ascend)
+            .with_limit(REPORT_PAGE_SIZE);
+        if revision > 0 {
+            options = options.with_revision(revision);
+        }
+        let resp = client.get(start.clone(), Some(options)).await.map_err(fetch_error)?;
+        if revision == 0 {
+            revision = resp.header().map(|h| h.revision()).unwrap_or(0);
+        }
+        for kv in resp.kvs() {
+            let key = String::from_utf8_lossy(&kv.key()[namespace.len()..]);
+            let group = groups.entry(key_group(&key, depth as usize).to_string()).or_default();
+            group.0 += 1;
+            group.1 += kv.value().len() as i64;
+        }
+        match resp.kvs().last() {
+            Some(last) if resp.more() => {
+                start = last.key().to_vec();
+                start.push(0);
+            }
+            _ => break,
+        }
+    }
+    Ok(groups)
+}
+
+/// End of the range of the keys starting with `prefix`, `\0` (all keys) for the empty prefix
+fn prefix_range_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while end.last() == Some(&0xFF) {
+        end.pop();
+    }
+    match end.last_mut() {
+        Some(last) => {
+            *last += 1;
+            end
+        }
+        None => vec![0],
+    }
+}
+
+/// The immediate children of `prefix`, like a directory listing
+/// Keys with a `delimiter` after the prefix are listed as their first segment, up to and including the delimiter
+/// Each request reads one key, the next one skips the keys below the child just found
+/// Returns the children in key order with whether they have keys below them
+async fn list_children(client: &mut Client, prefix: &str, delimiter: &str) -> Result<Vec<(String, bool)>, EtcdFdwError> {
+    if delimiter.is_empty() {
+        return Err(EtcdFdwError::InvalidOption("delimiter".to_string(), delimiter.to_string()));
+    }
+    let range_end = prefix_range_end(prefix.as_bytes());
+    let mut children = Vec::new();
+    let mut start = match prefix {
+        "" => vec![0],
+        _ => prefix.as_bytes().to_vec(),
+    };
+    let mut revision = 0;
+    loop {
+        let mut options = GetOptions::new().with_range(range_end.clone()).with_limit(1).with_keys_only();
+        if revision > 0 {
+            options = options.with_revision(revision);
+        }
+        let resp = client.get(start.clone(), Some(options)).await.map_err(fetch_error)?;
+        if revision == 0 {
+            revision = resp.header().map(|h| h.revision()).unwrap_or(0);
+        }
+        let Some(kv) = resp.kvs().first() else {
+            break;
+        };
+        let key = String::from_utf8_lossy(kv.key()).into_owned();
+        let rest = key.get(prefix.len()..).unwrap_or_default();
+        match rest.find(delimiter) {
+            Some(i) => {
+                let child = format!("{}{}", prefix, &rest[..i + delimiter.len()]);
+                start = prefix_range_end(child.as_bytes());
+                children.push((child, true));
+            }
+            None => {
+                start = kv.key().to_vec();
+                start.push(0);
+                children.push((key, false));
+            }
+        }
+    }
+    Ok(children)
+}
+
+/// List the keys below `prefix` that were modified or deleted after `revision`
+/// The changes are replayed from etcd's history with a watch, which ends once the watch
+/// has caught up with the store revision at the time of the call
+/// Returns the latest change of each key as its mod revision and whether it was deleted
+async fn modified_since(
+    client: &mut Client,
+    prefix: &str,
+    revision: i64,
+) -> Result<BTreeMap<String, (i64, bool)>, EtcdFdwError> {
+    let mut changes = BTreeMap::new();
+
+    let head = client
+        .get(prefix, Some(GetOptions::new().with_prefix().with_count_only()))
+        .await
+        .map_err(|e| EtcdFdwError::FetchError(e.to_string()))?;
+    let current = head.header().map(|h| h.revision()).unwrap_or(0);
+    if current <= revision {
+        return Ok(changes);
+    }
+
+    let watch_options = WatchOptions::new()
+        .with_prefix()
+        .with_start_revision(revision + 1);
+    let (mut watcher, mut stream) = client
+        .watch(prefix, Some(watch_options))
+        .await
+        .map_err(|e| EtcdFdwError::FetchError(e.to_string()))?;
+
+    loop {
+        // etcd only answers progress requests once the watcher replayed the history,
+        // so keep asking until a notification covers the current revision
+        let message = tokio::select! {
+            message = stream.message() => message.map_err(|e| EtcdFdwError::FetchError(e.to_string()))?,
+            _ = tokio::time::sleep(Duration::from_millis(100)) => {
+                watcher
+                    .request_progress()
+                    .await
+                    .map_err(|e| EtcdFdwError::FetchError(e.to_string()))?;
+                continue;
+            }
+        };
+        let Some(resp) = message else {
+            break;
+        };
+
+        if resp.canceled() {
+            return Err(EtcdFdwError::FetchError(format!(
+                "revision {} is no longer available, history was compacted up to revision {}",
+                revision,
+                resp.compact_revision()
+            )));
+        }
+
+        for event in resp.events() {
+            if let Some(kv) = event.kv() {
+                if kv.mod_revision() > current {
+                    continue;
+                }
+                if let Ok(key) = kv.key_str() {
+                    let deleted = event.event_type() == EventType::Delete;
+                    changes.insert(key.to_string(), (kv.mod_revision(), deleted));
+                }
+            }
+        }
+
+        let resp_revision = resp.header().map(|h| h.revision()).unwrap_or(0);
+        let progress = !resp.created() && resp.events().is_empty();
+        let caught_up = resp
+            .events()
+            .last()
+            .and_then(|e| e.kv())
+            .is_some_and(|kv| kv.mod_revision() >= current);
+        if (progress && resp_revision >= current) || caught_up {
+            break;
+        }
+    }
+
+    let _ = watcher.cancel().await;
+    Ok(changes)
+}
+
+type EtcdFdwResult<T> = std::result::Result<T, EtcdFdwError>;
+
+impl EtcdFdw {
+    /// Fetch the key-values of a scan
+    fn start_scan(
+        &mut self,
+        _quals: &[Qual],
+        columns: &[Column],
+        sort: &[Sort],
+        limit: &Option<Limit>,
+        options: &std::collections::HashMap<String, String>,
+    ) -> Result<(), EtcdFdwError> {
+        if let Some(e) = &self.unavailable {
+            // EXPLAIN doesn't need etcd
+            if EXPLAIN_ONLY.get() {
+                return Ok(());
+            }
+            return Err(EtcdFdwError::EtcdUnavailable(e.clone()));
+        }
+        self.scan_pages = None;
+        self.lease_ttls.borrow_mut().clear();
+
+        // parse the options defined when `CREATE FOREIGN TABLE`
+        // layered options fall back to the server and can be overridden by the session
+        let options = &expand_prefix(&resolve_options(&self.server_options, options))?;
+
+        // Tables with several prefixes scan one prefix after the other, in the order of the keys
+        // As the prefixes don't overlap, the rows keep the order of a sort on the key
+        self.prefix_scans = None;
+        let mut prefixes = option_prefixes(options);
+        if prefixes.len() > 1 {
+            let (key_column, _) = column_names(options);
+            if sort.first().is_some_and(|s| s.field == key_column && s.reversed) {
+                prefixes.reverse();
+            }
+            // LIMIT and OFFSET aren't pushed down, PostgreSQL applies them to the rows of all prefixes
+            // The default_limit bounds the rows of all prefixes together, see `iter_scan`
+            let rows_left = match (limit, options.get("default_limit")) {
+                (None, Some(v)) if self.planned_for_select => Some(parse_default_limit(v)?),
+                _ => None,
+            };
+            let mut scans: Vec<HashMap<String, String>> = prefixes
+                .into_iter()
+                .map(|p| {
+                    let mut scan_options = options.clone();
+                    scan_options.remove("prefixes");
+                    scan_options.insert("prefix".to_string(), p);
+                    if limit.is_some() {
+                        scan_options.remove("default_limit");
+                    }
+                    scan_options
+                })
+                .collect();
+            let first = scans.remove(0);
+
+            // the other prefixes are read at the revision of the first one
+            let previous_header = LAST_READ_HEADER.take();
+            let result = self.scan_range(_quals, columns, sort, &None, &first);
+            match LAST_READ_HEADER.get() {
+                Some(header) if !options.contains_key("revision") => {
+                    for scan_options in &mut scans {
+                        scan_options.insert("revision".to_string(), header.revision.to_string());
+                    }
+                }
+                Some(_) => {}
+                None => LAST_READ_HEADER.set(previous_header),
+            }
+            self.prefix_scans = Some(PrefixScans {
+                quals: _quals.to_vec(),
+                columns: columns.to_vec(),
+                sort: sort.to_vec(),
+                options: scans,
+                rows_left,
+            });
+            return result;
+        }
+        self.scan_range(_quals, columns, sort, limit, options)
+    }
+
+    /// Start the scan of the range or prefix of the table, `options` are the table's effective options
+    fn scan_range(
+        &mut self,
+        _quals: &[Qual],
+        columns: &[Column],
+        sort: &[Sort],
+        limit: &Option<Limit>,
+        options: &HashMap<String, String>,
+    ) -> Result<(), EtcdFdwError> {
+        let prefix = options.get("prefix").cloned();
+        let range_end = options.get("range_end").cloned();
+        let key_start = options.get("key").cloned();
+        let keys_only = options.get("keys_only").map(|v| v == "true").unwrap_or(false);
+        let revision = match read_revision()? {
+            Some(r) => r,
+            None => options.get("revision").and_then(|v| v.parse::<i64>().ok()).unwrap_or(0),
+        };
+        // Without an explicit consistency, read-only transactions read serializable from the local member
+        let serializable = match options.get("consistency") {
+            Some(v) => v == "s",
+            None => unsafe { pg_sys::XactReadOnly },
+        };
+        self.key_encoding = KeyEncoding::from_options(options)?;
+        (self.key_column, self.value_column) = column_names(options);
+        self.gzip_values = match options.get("value_compression") {
+            Some(v) => parse_value_compression(v)?,
+            None => false,
+        };
+        self.value_format = ValueFormat::from_options(options)?;
+        self.empty_as_null = match options.get("empty_as_null") {
+            Some(v) => parse_empty_as_null(v)?,
+            None => false,
+        };
+        self.exclude_prefixes = match options.get("exclude_prefix") {
+            Some(p) => p.split(',').map(str::to_string).collect(),
+            None => Vec::new(),
+        };
+        self.key_regex = options.get("key_regex").cloned().map(KeyPattern::Regex);
+        self.jsonpaths = unsafe { column_jsonpaths(self.relid)? };
+        self.key_segments = KeySegments::from_options(options);
+        self.segment_columns = match self.key_segments {
+            Some(_) => segment_columns(&unsafe { relation_columns(self.relid) }, &self.key_column, &self.value_column),
+            None => Vec::new(),
+        };
+        self.segment_columns.retain(|c| !self.jsonpaths.contains_key(c));
+        self.skip_scan_errors = match options.get("on_scan_error") {
+            Some(v) => parse_on_scan_error(v)?,
+            None => false,
+        };
+        if self.planned_for_delete {
+            RANGE_DELETE.set(None);
+        }
+        if !self.planned_for_select {
+            WRITE_CONDITIONS.set(None);
+        }
+        let mut qual_key_start: Option<String> = None;
+        let mut qual_prefixes: Vec<String> = Vec::new();
+        let mut qual_range_end: Option<String> = None;
+        let mut qual_keys: Option<Vec<String>> = None;
+        let mut qual_point: Option<String> = None;
+        let mut key_patterns: Vec<KeyPattern> = Vec::new();
+        let mut get_options = GetOptions::new();
+
+        if keys_only {
+            get_options = get_options.with_keys_only();
+        }
+
+        if revision > 0 {
+            get_options = get_options.with_revision(revision);
+        }
+
+        if serializable {
+            get_options = get_options.with_serializable();
+        }
+
+        self.sub_keys = match options.get("layout") {
+            Some(v) => parse_layout(v)?,
+            None => false,
+        };
+        let request = ScanRequest {
+            operation: "range",
+            revision,
+            serializable,
+            keys_only,
+            ..Default::default()
+        };
+
+        if self.sub_keys {
+            return self.start_sub_key_scan(_quals, columns, prefix.unwrap_or_default(), get_options, request);
+        }
+        if !self.planned_for_select {
+            WRITE_CONDITIONS.set(Some(self.write_conditions(_quals)));
+        }
+
+        // Check if columns contains key and value
+        // Without the value column, e.g. `SELECT key FROM t`, etcd doesn't send the values
+        let colnames: Vec<String> = columns.iter().map(|x| x.name.clone()).collect();
+        self.fetch_key = colnames.contains(&self.key_column);
+        self.fetch_value = colnames.contains(&self.value_column)
+            || colnames.iter().any(|c| self.jsonpaths.contains_key(c) || c == "value_md5");
+        let keys_only = keys_only || !self.fetch_value;
+        if keys_only {
+            get_options = get_options.with_keys_only();
+        }
+        let request = ScanRequest { keys_only, ..request };
+
+        // Number of conditions etcd evaluates exactly, PostgreSQL rechecks all of them anyway
+        // A remote limit is only correct if none is left to drop rows after it, see `start_scan`'s LIMIT pushdown
+        let mut pushed_quals = 0;
+
+        // Conditions on the revisions filter the keys in etcd, e.g. `mod_revision > 12345` for the changes since then
+        if !self.revision_range_commuted {
+            let mut mod_range = (0, i64::MAX);
+            let mut create_range = (0, i64::MAX);
+            for q in _quals {
+                let range = match q.field.as_str() {
+                    "mod_revision" => &mut mod_range,
+                    "create_revision" => &mut create_range,
+                    _ => continue,
+                };
+                let Some(n) = qual_number(q) else {
+                    continue;
+                };
+                // bounds below 1 aren't sent to etcd below
+                let pushed = match q.operator.as_str() {
+                    "=" => {
+                        *range = (range.0.max(n), range.1.min(n));
+                        n > 0
+                    }
+                    ">" => {
+                        range.0 = range.0.max(n.saturating_add(1));
+                        n >= 0
+                    }
+                    ">=" => {
+                        range.0 = range.0.max(n);
+                        n > 0
+                    }
+                    "<" => {
+                        range.1 = range.1.min(n.saturating_sub(1));
+                        n > 1
+                    }
+                    "<=" => {
+                        range.1 = range.1.min(n);
+                        n > 0
+                    }
+                    _ => false,
+                };
+                if pushed {
+                    pushed_quals += 1;
+                }
+            }
+            // etcd treats 0 as no filter, upper bounds below 1 are left to the recheck
+            if mod_range.0 > 0 {
+                get_options = get_options.with_min_mod_revision(mod_range.0);
+            }
+            if mod_range.1 > 0 && mod_range.1 < i64::MAX {
+                get_options = get_options.with_max_mod_revision(mod_range.1);
+            }
+            if create_range.0 > 0 {
+                get_options = get_options.with_min_create_revision(create_range.0);
+            }
+            if create_range.1 > 0 && create_range.1 < i64::MAX {
+                get_options = get_options.with_max_create_revision(create_range.1);
+            }
+        }
+
+        // Equality conditions on the leading segment columns restrict the scan to the keys below them
+        if let Some(segments) = &self.key_segments {
+            let leading: Vec<String> = self
+                .segment_columns
+                .iter()
+                .map_while(|c| {
+                    _quals
+                        .iter()
+                        .filter(|q| q.field == *c && q.operator == "=" && !q.use_or)
+                        .find_map(|q| qual_key_value(q, None))
+                })
+                .collect();
+            if leading.len() == self.segment_columns.len() {
+                qual_prefixes.push(segments.join(&leading));
+            } else if !leading.is_empty() {
+                qual_prefixes.push(format!("{}{}", segments.join(&leading), segments.delimiter));
+            }
+        }
+
+        // WHERE clause pushdown
+        for q in _quals {
+            // only pushdown "key"
+            if q.field != self.key_column {
+                continue;
+            }
+
+            // Keys are never NULL, e.g. the `key IS NOT NULL` of the subqueries PostgreSQL plans
+            // MIN(key) and MAX(key) as holds for all of them, so their LIMIT 1 is still pushed down
+            if q.operator == "is not" {
+                pushed_quals += 1;
+                continue;
+            }
+
+            // IN lists and constant semi-joins arrive as `key = ANY(array)`
+            // Collect the keys so they can be fetched individually
+            if let Value::Array(cells) = &q.value {
+                // bytea keys that aren't UTF-8 can't be fetched by name, PostgreSQL filters them
+                let binary = cells
+                    .iter()
+                    .any(|c| matches!(c, Cell::Bytea(_)) && cell_key(c, self.key_encoding.as_ref()).is_none());
+                if q.operator == "=" && q.use_or && !binary {
+                    let keys: Vec<String> = cells
+                        .iter()
+                        .filter_map(|c| cell_key(c, self.key_encoding.as_ref()))
+                        .collect();
+                    // Several IN lists on the key only match their intersection
+                    qual_keys = Some(match qual_keys {
+                        Some(prev) => prev.into_iter().filter(|k| keys.contains(k)).collect(),
+                        None => keys,
+                    });
+                }
+                continue;
+            }
+
+            // extract string value
+            let v = match qual_key_value(q, self.key_encoding.as_ref()) {
+                Some(s) => s,
+                None => continue,
+            };
+
+            match q.operator.as_str() {
+                "=" => {
+                    // equal: start at v, end at v+"\0"
+                    pushed_quals += 1;
+                    qual_point = Some(v.clone());
+                    raise_key_start(&mut qual_key_start, v.clone());
+                    lower_range_end(&mut qual_range_end, format!("{}\0", v));
+                }
+                // etcd compares keys bytewise, other collations and encodings can't push down ranges
+                ">=" | ">" | "<" | "<=" if !self.key_order_preserved() => {}
+                // the framework reports `'/b' > key` as `key > '/b'`
+                ">=" | ">" | "<" | "<=" if self.key_range_commuted => {}
+                ">=" => {
+                    // greater or equal: start at v
+                    pushed_quals += 1;
+                    raise_key_start(&mut qual_key_start, v.clone());
+                }
+                ">" => {
+                    // greater than: start at v+"\0"
+                    pushed_quals += 1;
+                    raise_key_start(&mut qual_key_start, format!("{}\0", v));
+                }
+                "<" => {
+                    // less than: end at v
+                    pushed_quals += 1;
+                    lower_range_end(&mut qual_range_end, v.clone());
+                }
+                "<=" => {
+                    // less or equal: end at v+"\0"
+                    pushed_quals += 1;
+                    lower_range_end(&mut qual_range_end, format!("{}\0", v));
+                }
+                // the pattern or prefix was encoded, it no longer matches the encoded keys bytewise
+                "~~" | "~" | "^@" if self.key_encoding.is_some() => {}
+                // bytea patterns are matched by PostgreSQL, the keys needn't be UTF-8
+                "~~" if matches!(q.value, Value::Cell(Cell::Bytea(_))) => {}
+                "^@" => {
+                    // starts with: the keys below the prefix, `starts_with(key, v)` is rewritten to it
+                    pushed_quals += 1;
+                    qual_prefixes.push(v);
+                }
+                "~~" => {
+                    // LIKE operator: the literal start of the pattern is a prefix of all matching keys
+                    // Other patterns are matched against the keys before the values are fetched
+                    let (pref, rest) = like_prefix(&v);
+                    if rest.chars().all(|c| c == '%') {
+                        pushed_quals += 1;
+                    }
+                    if rest.is_empty() {
+                        // without wildcards the pattern only matches itself
+                        qual_point = Some(pref.clone());
+                        raise_key_start(&mut qual_key_start, pref.clone());
+                        lower_range_end(&mut qual_range_end, format!("{}\0", pref));
+                        continue;
+                    }
+                    if !pref.is_empty() {
+                        qual_prefixes.push(pref);
+                    }
+                    if !rest.chars().all(|c| c == '%') {
+                        key_patterns.push(KeyPattern::Like(v));
+                    }
+                }
+                "~" => key_patterns.push(KeyPattern::Regex(v)),
+                _ => {}
+            }
+        }
+
+        // Determine the effective prefix based on FDW, user mapping and WHERE clause options
+        // If several are present, ensure each is a prefix of the longest one
+        // Otherwise, no data will be fetched
+        // If only one is present, use that as the prefix
+        let mut eff_prefix: Option<String> = None;
+        for p in [prefix, self.role_prefix.clone()].into_iter().flatten().chain(qual_prefixes) {
+            eff_prefix = match eff_prefix {
+                Some(cur) if p.starts_with(&cur) => Some(p),
+                Some(cur) if cur.starts_with(&p) => Some(cur),
+                Some(_) => {
+                    describe_scan(&ScanRequest { operation: "none", ..request });
+                    return Ok(());
+                }
+                None => Some(p),
+            };
+        }
+
+        // Determine the effective key start based on FDW and WHERE clause options
+        // If both are present, take the larger one
+        // Otherwise, take whichever is present
+        // If neither is present, start from the beginning
+        let eff_key_start = match (&qual_key_start, &key_start) {
+            (Some(_where), Some(_fdw)) => {
+                if _where > _fdw {
+                    _where.clone()
+                } else {
+                    _fdw.clone()
+                }
+            }
+            (Some(_where), None) => _where.clone(),
+            (None, Some(_fdw)) => _fdw.clone(),
+            (None, None) => "\0".to_string(), // start from the beginning
+        };
+
+        // Determine the effective range end based on FDW and WHERE clause options
+        // If both are present, take the smaller one
+        // Otherwise, take whichever is present
+        // If neither is present, go to the end
+        let mut eff_range_end = match (&qual_range_end, &range_end) {
+            (Some(_where), Some(_fdw)) => {
+                if _where < _fdw {
+                    _where.clone()
+                } else {
+                    _fdw.clone()
+                }
+            }
+            (Some(_where), None) => _where.clone(),
+            (None, Some(_fdw)) => _fdw.clone(),
             (None, None) => "\u{10FFFF}".to_string(), // go to the end
         };
 
-        // Compute range_end for prefix
-        // If a prefix is provided, calculate the range_end by incrementing the last byte of the prefix
-        // This ensures that the range_end is exclusive and covers all keys starting with the prefix
-        if let Some(p) = &eff_prefix {
-            let mut bytes = p.as_bytes().to_vec();
-            for i in (0..bytes.len()).rev() {
-                if bytes[i] < 0xFF {
-                    bytes[i] += 1;
-                    bytes.truncate(i + 1);
-                    let prefix_range_end = String::from_utf8(bytes).unwrap();
-                    // Ensure the calculated range_end does not exceed the effective range_end
-                    if prefix_range_end < eff_range_end {
-                        eff_range_end = prefix_range_end;
-                    }
-                    break;
-                }
-            }
-        }
+        // Compute range_end for prefix
+        // If a prefix is provided, calculate the range_end by incrementing the last byte of the prefix
+        // This ensures that the range_end is exclusive and covers all keys starting with the prefix
+        if let Some(p) = &eff_prefix {
+            let mut bytes = p.as_bytes().to_vec();
+            for i in (0..bytes.len()).rev() {
+                if bytes[i] < 0xFF {
+                    bytes[i] += 1;
+                    bytes.truncate(i + 1);
+                    let prefix_range_end = String::from_utf8(bytes).unwrap();
+                    // Ensure the calculated range_end does not exceed the effective range_end
+                    if prefix_range_end < eff_range_end {
+                        eff_range_end = prefix_range_end;
+                    }
+                    break;
+                }
+            }
+        }
+
+        // Determine the effective key to start the scan
+        // If a prefix is provided, use it as the base key and enable prefix-based scanning
+        // Otherwise, use the effective key start
+        let key = match &eff_prefix {
+            Some(p) => {
+                get_options = get_options.with_prefix();
+                // Ensure the key starts from the larger of the prefix or the effective key start
+                std::cmp::max(eff_key_start.clone(), p.clone())
+            }
+            None => eff_key_start.clone(),
+        };
+
+        // Conditions with disjoint bounds, like `key > 'b' AND key < 'a'`, match no key
+        if key >= eff_range_end {
+            describe_scan(&ScanRequest { operation: "none", ..request });
+            self.tgt_cols = columns.to_vec();
+            return Ok(());
+        }
+        let request = ScanRequest {
+            key: key.clone(),
+            range_end: Some(eff_range_end.clone()),
+            prefix: eff_prefix.clone(),
+            ..request
+        };
+
+        // Sampled scans and scans with patterns on the key list the keys of the range without values first,
+        // then only the sampled and matching keys are fetched, see `etcd_fdw.sample_percent`
+        // With keys_only there are no values to save, the patterns are left to the recheck
+        let sample_percent = SAMPLE_PERCENT.get();
+        let filter_keys = !key_patterns.is_empty() && !keys_only;
+        let mut listed_revision = 0;
+        let list_first = (sample_percent > 0.0 || filter_keys) && qual_keys.is_none();
+        if list_first {
+            let then = if sample_percent > 0.0 {
+                "multi-get of the sampled keys"
+            } else {
+                "multi-get of the matching keys"
+            };
+            if describe_scan(&ScanRequest { keys_only: true, then: Some(then), ..request.clone() }) {
+                return Ok(());
+            }
+            let mut list_options = GetOptions::new()
+                .with_keys_only()
+                .with_range(eff_range_end.clone());
+            if revision > 0 {
+                list_options = list_options.with_revision(revision);
+            }
+            if serializable {
+                list_options = list_options.with_serializable();
+            }
+
+            let started = std::time::Instant::now();
+            let listed = match self.rt.block_on(self.client.get(key.clone(), Some(list_options))) {
+                Ok(x) => x,
+                Err(e) => return Err(fetch_error(e)),
+            };
+            self.count_requests(1, listed.kvs(), started);
+            record_read_header(listed.header());
+            listed_revision = listed.header().map(|h| h.revision()).unwrap_or(0);
+            let listed_keys: Vec<String> = listed
+                .kvs()
+                .iter()
+                .filter_map(|kv| kv.key_str().ok().map(|k| k.to_string()))
+                .filter(|k| key_patterns.iter().all(|p| p.matches(k, self.key_collation)))
+                .collect();
+            qual_keys = Some(if sample_percent > 0.0 {
+                sample_keys(listed_keys, sample_percent)
+            } else {
+                listed_keys
+            });
+        }
+
+        // Single key lookups of tables with point_cache are answered from the cache if possible
+        let point_cache = match options.get("point_cache") {
+            Some(v) => parse_point_cache(v)?,
+            None => false,
+        };
+        if point_cache && revision == 0 && !keys_only && qual_keys.is_none() {
+            if let Some(point) = qual_point.clone().filter(|k| *k >= key && *k < eff_range_end) {
+                let point_request = ScanRequest {
+                    operation: "get",
+                    key: point.clone(),
+                    range_end: None,
+                    prefix: None,
+                    cache: Some("point_cache"),
+                    ..request.clone()
+                };
+                if describe_scan(&point_request) {
+                    return Ok(());
+                }
+                let watched = prefix.clone().unwrap_or_default();
+                let (kv, read_revision) = self.cached_point_lookup(&watched, &point, get_options)?;
+                LAST_READ_REVISION.set(read_revision);
+                self.fetch_results = kv.into_iter().collect();
+                self.tgt_cols = columns.to_vec();
+                return Ok(());
+            }
+        }
+
+        // Batched multi-get for a known set of keys
+        // Only the keys inside the effective range are requested, batched into etcd transactions
+        if let Some(mut keys) = qual_keys {
+            keys.retain(|k| *k >= key && *k < eff_range_end);
+            keys.sort();
+            keys.dedup();
+            // Listed keys keep the description of the listing
+            let multi_get_request = ScanRequest {
+                operation: if keys.is_empty() { "none" } else { "multi-get" },
+                keys: Some(keys.len()),
+                range_end: None,
+                prefix: None,
+                ..request.clone()
+            };
+            if !list_first && describe_scan(&multi_get_request) {
+                return Ok(());
+            }
+
+            let mut point_options = GetOptions::new();
+            if keys_only {
+                point_options = point_options.with_keys_only();
+            }
+            // Listed keys are fetched at the revision they were listed at
+            if revision > 0 {
+                point_options = point_options.with_revision(revision);
+            } else if listed_revision > 0 {
+                point_options = point_options.with_revision(listed_revision);
+            }
+            if serializable {
+                point_options = point_options.with_serializable();
+            }
+
+            self.fetch_results = if keys.is_empty() {
+                vec![]
+            } else {
+                let started = std::time::Instant::now();
+                let requests = keys.len().div_ceil(MAX_TXN_OPS) as i64;
+                match self.rt.block_on(multi_get(&mut self.client, keys, point_options)) {
+                    Ok((kvs, read_revision)) => {
+                        self.count_requests(requests, &kvs, started);
+                        LAST_READ_REVISION.set(if revision > 0 { revision } else { read_revision });
+                        kvs
+                    }
+                    Err(e) => return Err(fetch_error(e)),
+                }
+            };
+            // The keys are fetched in ascending order, the scan may be planned as sorted by `get_foreign_paths`
+            if sort.first().is_some_and(|s| s.field == self.key_column && s.reversed) {
+                self.fetch_results.reverse();
+            }
+            self.tgt_cols = columns.to_vec();
+            return Ok(());
+        }
+
+        // A single key is read with a plain get instead of a range read
+        if let Some(point) = qual_point.filter(|k| *k >= key && *k < eff_range_end) {
+            let point_request = ScanRequest {
+                operation: "get",
+                key: point.clone(),
+                range_end: None,
+                prefix: None,
+                ..request
+            };
+            if describe_scan(&point_request) {
+                return Ok(());
+            }
+            let mut point_options = GetOptions::new();
+            if keys_only {
+                point_options = point_options.with_keys_only();
+            }
+            if revision > 0 {
+                point_options = point_options.with_revision(revision);
+            }
+            if serializable {
+                point_options = point_options.with_serializable();
+            }
+
+            let started = std::time::Instant::now();
+            let result = match self.hedge_delay {
+                Some(delay) if serializable && self.config.endpoints.len() > 1 => {
+                    self.hedged_get(point, point_options, delay)
+                }
+                _ => self.rt.block_on(self.client.get(point, Some(point_options))),
+            };
+            let mut resp = result.map_err(fetch_error)?;
+            self.count_requests(1, resp.kvs(), started);
+            record_read_header(resp.header());
+            let read_revision = resp.header().map(|h| h.revision()).unwrap_or(0);
+            LAST_READ_REVISION.set(if revision > 0 { revision } else { read_revision });
+            self.fetch_results = resp.take_kvs();
+            self.tgt_cols = columns.to_vec();
+            return Ok(());
+        }
+
+        get_options = get_options.with_range(eff_range_end.clone());
+
+        // Scans without columns, e.g. for `SELECT count(*) FROM t`, only need the number of keys
+        // No column means no condition is rechecked locally, so etcd's count is exact unless keys are left out
+        let keys_left_out = !self.exclude_prefixes.is_empty() || self.key_regex.is_some();
+
+        // A DELETE whose conditions etcd evaluates exactly, e.g. `key LIKE '/tmp/%'`, deletes the whole range
+        // The revision filters don't apply to range deletes
+        let range_delete = (self.planned_for_delete
+            && pushed_quals == self.conditions
+            && !keys_left_out
+            && revision == 0
+            && sample_percent <= 0.0
+            && !_quals.iter().any(|q| q.field == "mod_revision" || q.field == "create_revision"))
+        .then(|| (key.clone().into_bytes(), eff_range_end.clone().into_bytes()));
+        if columns.is_empty() && sample_percent <= 0.0 && !keys_left_out {
+            if describe_scan(&ScanRequest { count_only: true, ..request }) {
+                return Ok(());
+            }
+            let started = std::time::Instant::now();
+            let resp = self
+                .rt
+                .block_on(self.client.get(key, Some(get_options.with_count_only())))
+                .map_err(fetch_error)?;
+            self.count_requests(1, resp.kvs(), started);
+            record_read_header(resp.header());
+            let read_revision = resp.header().map(|h| h.revision()).unwrap_or(0);
+            LAST_READ_REVISION.set(if revision > 0 { revision } else { read_revision });
+            self.counted_rows = resp.count();
+            if let (None, Some(default_limit)) = (limit, options.get("default_limit")) {
+                if self.planned_for_select {
+                    self.counted_rows = self.counted_rows.min(parse_default_limit(default_limit)?);
+                }
+            }
+            self.tgt_cols = vec![];
+            return Ok(());
+        }
+
+        // sort pushdown
+        // only the first sort key is pushed down, further ones and columns etcd can't sort by are sorted locally
+        // text columns are only sorted remotely if their collation orders bytewise like etcd
+        // PostgreSQL sorts the result locally in any case
+        // The sorts only include columns of the table, an ORDER BY on expressions isn't among them
+        let mut sort_pushed = self.sort_keys == 0;
+        let mut pushed_sort = None;
+        if let Some(first_sort) = sort.first().filter(|_| self.first_sort_on_table) {
+            if let Some(target) = sort_target(self.etcd_field(&first_sort.field)) {
+                let column_bytewise = match target {
+                    SortTarget::Key => self.key_order_preserved(),
+                    SortTarget::Value => {
+                        self.value_order_bytewise && !self.gzip_values && self.value_format.preserves_order()
+                    }
+                    _ => true,
+                };
+                let bytewise = match first_sort.collate.as_deref() {
+                    Some(collation) => collation == "C" || collation == "POSIX",
+                    None => column_bytewise,
+                };
+
+                if bytewise {
+                    let order = if first_sort.reversed {
+                        SortOrder::Descend
+                    } else {
+                        SortOrder::Ascend
+                    };
+
+                    get_options = get_options.with_sort(target, order);
+                    pushed_sort = Some((target, order));
+                    // rows with the same first sort key are returned in any order,
+                    // a limit would cut them off before the later sort keys are applied
+                    sort_pushed = self.sort_keys == 1 || target == SortTarget::Key;
+                }
+            }
+        } else if let Some(default_sort) = options.get("default_sort").filter(|_| self.sort_keys == 0) {
+            // without ORDER BY the rows are returned in etcd's order
+            let (target, order) = parse_default_sort(default_sort)?;
+            get_options = get_options.with_sort(target, order);
+            pushed_sort = Some((target, order));
+        }
+
+        // A remote limit is only correct if etcd returns the rows in the requested order
+        // and applied all conditions, rows PostgreSQL drops afterwards would be missing from the result
+        // Otherwise all rows are fetched and PostgreSQL applies the limit
+        // PostgreSQL skips the OFFSET rows itself, so they are fetched as well
+        let mut offset = 0;
+        let mut pushed_limit = None;
+        if let Some(x) = limit {
+            if self.limit_applies && sort_pushed && pushed_quals == self.conditions && !keys_left_out {
+                get_options = get_options.with_limit(x.count + x.offset);
+                pushed_limit = Some(x.count + x.offset);
+                offset = x.offset;
+            }
+        } else if let Some(default_limit) = options.get("default_limit") {
+            // bounds queries without LIMIT, UPDATE and DELETE still see all rows
+            if self.planned_for_select {
+                let default_limit = parse_default_limit(default_limit)?;
+                get_options = get_options.with_limit(default_limit);
+                pushed_limit = Some(default_limit);
+            }
+        }
+
+        // The OFFSET rows are only listed without their values, PostgreSQL skips them anyway
+        // The values of the rows after them are read at the revision they were listed at
+        // Not if a condition on the value, which is evaluated before skipping, needs the values of all rows
+        let request = ScanRequest {
+            limit: pushed_limit,
+            sort: pushed_sort,
+            ..request
+        };
+        if offset > 0 && !keys_only && !self.value_in_conditions {
+            let offset_request = ScanRequest {
+                keys_only: true,
+                then: Some("multi-get of the keys after the offset"),
+                ..request
+            };
+            if describe_scan(&offset_request) {
+                return Ok(());
+            }
+            let started = std::time::Instant::now();
+            let mut listed = self
+                .rt
+                .block_on(self.client.get(key, Some(get_options.with_keys_only())))
+                .map_err(fetch_error)?;
+            self.count_requests(1, listed.kvs(), started);
+            record_read_header(listed.header());
+            let listed_revision = if revision > 0 {
+                revision
+            } else {
+                listed.header().map(|h| h.revision()).unwrap_or(0)
+            };
+            let mut kvs = listed.take_kvs();
+            let page: Vec<Vec<u8>> = kvs.iter().skip(offset as usize).map(|kv| kv.key().to_vec()).collect();
+            kvs.truncate(offset as usize);
+
+            let mut page_options = GetOptions::new().with_revision(listed_revision);
+            if serializable {
+                page_options = page_options.with_serializable();
+            }
+            if !page.is_empty() {
+                let started = std::time::Instant::now();
+                let requests = page.len().div_ceil(MAX_TXN_OPS) as i64;
+                let (values, _) = self
+                    .rt
+                    .block_on(multi_get(&mut self.client, page, page_options))
+                    .map_err(fetch_error)?;
+                self.count_requests(requests, &values, started);
+                kvs.extend(values);
+            }
+            LAST_READ_REVISION.set(listed_revision);
+            self.fetch_results = kvs;
+            self.tgt_cols = columns.to_vec();
+            return Ok(());
+        }
+
+        // Scans of tables with cache_ttl are answered from the results of an identical scan within the TTL
+        // UPDATE and DELETE always read from etcd
+        let cache_ttl = match options.get("cache_ttl") {
+            Some(v) if self.planned_for_select => Some(parse_cache_ttl(v)?),
+            _ => None,
+        };
+        let cache_key = cache_ttl.map(|_| {
+            (
+                self.relid,
+                self.config.channel_key(),
+                self.config.user.clone(),
+                format!("{:?} {:?}", key, get_options),
+            )
+        });
+        // With etcd_fdw.statement_cache, scans of any table sending the same request share its result
+        let statement_key = (self.planned_for_select && STATEMENT_CACHE_ENABLED.get()).then(|| {
+            (
+                self.config.channel_key(),
+                self.config.user.clone(),
+                format!("{:?} {:?}", key, get_options),
+            )
+        });
+        if let Some((kvs, read_revision)) = statement_key.as_ref().and_then(statement_cached) {
+            if describe_scan(&ScanRequest { cache: Some("etcd_fdw.statement_cache"), ..request }) {
+                return Ok(());
+            }
+            LAST_READ_REVISION.set(read_revision);
+            self.fetch_results = kvs;
+            self.tgt_cols = columns.to_vec();
+            return Ok(());
+        }
+        if let (Some(ttl), Some(cache_key)) = (cache_ttl, &cache_key) {
+            let cached = SCAN_CACHE.with_borrow(|cache| {
+                cache
+                    .get(cache_key)
+                    .filter(|(_, _, read_at)| read_at.elapsed() < ttl)
+                    .map(|(kvs, read_revision, _)| (kvs.clone(), *read_revision))
+            });
+            if let Some((kvs, read_revision)) = cached {
+                if describe_scan(&ScanRequest { cache: Some("cache_ttl"), ..request }) {
+                    return Ok(());
+                }
+                LAST_READ_REVISION.set(read_revision);
+                self.fetch_results = kvs;
+                self.tgt_cols = columns.to_vec();
+                return Ok(());
+            }
+        }
+
+        // Large scans are read in pages of fetch_size keys, the next page is read once the rows of a page are returned
+        // Pages continue after the last key read, so only scans in key order are paged
+        // Cached results must be complete, so cached scans read all keys at once
+        let fetch_size = match options.get("fetch_size") {
+            Some(v) => parse_fetch_size(v)?,
+            None => DEFAULT_FETCH_SIZE,
+        };
+        let paged = fetch_size > 0
+            && cache_key.is_none()
+            && statement_key.is_none()
+            && pushed_sort.is_none_or(|(target, _)| target == SortTarget::Key)
+            && pushed_limit.is_none_or(|n| n > fetch_size);
+        if paged {
+            self.scan_pages = Some(ScanPages {
+                key: key.clone().into_bytes(),
+                range_end: eff_range_end.into_bytes(),
+                descending: pushed_sort == Some((SortTarget::Key, SortOrder::Descend)),
+                options: get_options.clone(),
+                fetch_size,
+                remaining: pushed_limit,
+            });
+            get_options = get_options.with_limit(fetch_size);
+        }
+        let request = ScanRequest {
+            fetch_size: paged.then_some(fetch_size),
+            ..request
+        };
+        if describe_scan(&request) {
+            return Ok(());
+        }
+
+        // Serializable reads can be answered by any member, so they can be hedged
+        let started = std::time::Instant::now();
+        let result = match self.hedge_delay {
+            Some(delay) if serializable && self.config.endpoints.len() > 1 => self.hedged_get(key, get_options, delay),
+            _ => self.rt.block_on(self.client.get(key, Some(get_options))),
+        };
+        let mut result_unwrapped = match result {
+            Ok(x) => x,
+            Err(e) => return Err(fetch_error(e)),
+        };
+        self.count_requests(1, result_unwrapped.kvs(), started);
+        record_read_header(result_unwrapped.header());
+        let read_revision = result_unwrapped.header().map(|h| h.revision()).unwrap_or(0);
+        let read_revision = if revision > 0 { revision } else { read_revision };
+        LAST_READ_REVISION.set(read_revision);
+        let more = result_unwrapped.more();
+        if let Some((key, range_end)) = range_delete {
+            RANGE_DELETE.set(Some(RangeDelete {
+                key,
+                range_end,
+                revision: read_revision,
+                count: result_unwrapped.count(),
+            }));
+        }
+        let result_vec = result_unwrapped.take_kvs();
+        self.continue_pages(&result_vec, more, read_revision);
+        if let Some(cache_key) = cache_key {
+            SCAN_CACHE.with_borrow_mut(|cache| {
+                cache.insert(cache_key, (result_vec.clone(), read_revision, std::time::Instant::now()))
+            });
+        }
+        if let Some(statement_key) = statement_key {
+            STATEMENT_CACHE.with_borrow_mut(|(_, cache)| cache.insert(statement_key, (result_vec.clone(), read_revision)));
+        }
+        self.fetch_results = result_vec;
+        self.tgt_cols = columns.to_vec();
+        Ok(())
+    }
+
+    /// Number of keys of the table and the mean width of their rows
+    /// A single request counts the keys of the table's range and returns a few of them to measure their size
+    fn estimate_rel_size(&mut self, options: &HashMap<String, String>) -> Option<(i64, i32)> {
+        // the keys of several prefixes add up
+        let prefixes = option_prefixes(options);
+        if prefixes.len() > 1 {
+            let mut estimate = (0, 0);
+            for p in prefixes {
+                let mut prefix_options = options.clone();
+                prefix_options.remove("prefixes");
+                prefix_options.insert("prefix".to_string(), p);
+                let (rows, width) = self.estimate_rel_size(&prefix_options)?;
+                estimate = (estimate.0 + rows, estimate.1.max(width));
+            }
+            return Some(estimate);
+        }
+        let mut prefix: Option<String> = None;
+        for p in [options.get("prefix").cloned(), self.role_prefix.clone()].into_iter().flatten() {
+            prefix = match prefix {
+                Some(cur) if p.starts_with(&cur) => Some(p),
+                Some(cur) if cur.starts_with(&p) => Some(cur),
+                Some(_) => return Some((0, 0)),
+                None => Some(p),
+            };
+        }
+        let (key, get_options) = match prefix {
+            Some(p) => (p, GetOptions::new().with_prefix()),
+            None => {
+                let key = options.get("key").cloned().unwrap_or_else(|| "\0".to_string());
+                match options.get("range_end") {
+                    Some(end) => (key, GetOptions::new().with_range(end.clone())),
+                    None => (key, GetOptions::new().with_from_key()),
+                }
+            }
+        };
+        let get_options = get_options.with_limit(ESTIMATE_SAMPLE_SIZE).with_serializable();
+
+        let resp = self.rt.block_on(self.client.get(key, Some(get_options))).ok()?;
+        let sample = resp.kvs();
+        let width = match sample.len() {
+            0 => 0,
+            n => sample.iter().map(|kv| kv.key().len() + kv.value().len()).sum::<usize>() / n,
+        };
+        Some((resp.count(), width.min(i32::MAX as usize) as i32))
+    }
+
+    /// Whether a key is scanned, keys below an `exclude_prefix` and keys not matching `key_regex` are left out
+    fn key_included(&self, key: &[u8]) -> bool {
+        if self.exclude_prefixes.iter().any(|p| key.starts_with(p.as_bytes())) {
+            return false;
+        }
+        match &self.key_regex {
+            Some(regex) => std::str::from_utf8(key).is_ok_and(|k| regex.matches(k, pg_sys::C_COLLATION_OID)),
+            None => true,
+        }
+    }
+
+    /// The next row of the scan, the rows of all prefixes of a table with several ones
+    fn next_row(&mut self, row: &mut Row) -> EtcdFdwResult<Option<()>> {
+        if self.sub_keys {
+            if self.pivoted_rows.is_empty() {
+                return Ok(None);
+            }
+            for (name, cell) in self.pivoted_rows.remove(0) {
+                row.push(&name, cell);
+            }
+            return Ok(Some(()));
+        }
+
+        if self.counted_rows > 0 {
+            self.counted_rows -= 1;
+            return Ok(Some(()));
+        }
+
+        // Go through results row by row and drain the result vector, reading the next page once it is empty
+        loop {
+            if self.fetch_results.is_empty() {
+                if self.scan_pages.is_none() {
+                    // the scan of the next prefix may count its keys as well
+                    return match self.next_prefix_scan()? {
+                        true => self.next_row(row),
+                        false => Ok(None),
+                    };
+                }
+                self.fetch_next_page()?;
+                continue;
+            }
+            let x = self.fetch_results.remove(0);
+            if !self.key_included(x.key()) {
+                continue;
+            }
+            // Unpack x into a row, entries that can't be decoded are skipped with on_scan_error 'warn_skip'
+            match self.decode_entry(&x) {
+                Ok(cells) => {
+                    for (name, cell) in cells {
+                        row.push(&name, cell);
+                    }
+                    return Ok(Some(()));
+                }
+                Err(e) if self.skip_scan_errors => warning!("{}, skipping it", e),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Start the scan of the next prefix of a table with several ones, false once all prefixes were scanned
+    fn next_prefix_scan(&mut self) -> EtcdFdwResult<bool> {
+        let Some(scans) = &mut self.prefix_scans else {
+            return Ok(false);
+        };
+        if scans.options.is_empty() {
+            self.prefix_scans = None;
+            return Ok(false);
+        }
+        let options = scans.options.remove(0);
+        let (quals, columns, sort) = (scans.quals.clone(), scans.columns.clone(), scans.sort.clone());
+        self.scan_range(&quals, &columns, &sort, &None, &options)?;
+        Ok(true)
+    }
+
+    /// Advance the pages of a paged scan past `kvs`, the page just read, or end them after the last page
+    fn continue_pages(&mut self, kvs: &[KeyValue], more: bool, read_revision: i64) {
+        let Some(pages) = &mut self.scan_pages else {
+            return;
+        };
+        pages.remaining = pages.remaining.map(|n| n - kvs.len() as i64);
+        match kvs.last() {
+            Some(last) if more && pages.remaining.is_none_or(|n| n > 0) => {
+                if pages.descending {
+                    pages.range_end = last.key().to_vec();
+                } else {
+                    let mut next = last.key().to_vec();
+                    next.push(0);
+                    pages.key = next;
+                }
+                // all pages are read at the revision of the first one
+                pages.options = pages.options.clone().with_revision(read_revision);
+            }
+            _ => self.scan_pages = None,
+        }
+    }
+
+    /// Read the next page of a paged scan into `fetch_results`
+    fn fetch_next_page(&mut self) -> EtcdFdwResult<()> {
+        let Some(pages) = &self.scan_pages else {
+            return Ok(());
+        };
+        let limit = pages.remaining.map_or(pages.fetch_size, |n| n.min(pages.fetch_size));
+        let options = pages.options.clone().with_range(pages.range_end.clone()).with_limit(limit);
+        let key = pages.key.clone();
+        let started = std::time::Instant::now();
+        let mut resp = self.rt.block_on(self.client.get(key, Some(options))).map_err(fetch_error)?;
+        self.count_requests(1, resp.kvs(), started);
+        record_read_header(resp.header());
+        let read_revision = resp.header().map(|h| h.revision()).unwrap_or(0);
+        let kvs = resp.take_kvs();
+        self.continue_pages(&kvs, resp.more(), read_revision);
+        self.fetch_results = kvs;
+        Ok(())
+    }
+
+    /// Adds requests sent since `started` and the keys they returned to the statistics of the scan
+    fn count_requests(&self, requests: i64, kvs: &[KeyValue], started: std::time::Instant) {
+        let bytes = kvs.iter().map(|kv| (kv.key().len() + kv.value().len()) as i64).sum();
+        self.stats.set(self.stats.get().add(ScanStats {
+            requests,
+            keys: kvs.len() as i64,
+            bytes,
+            time: started.elapsed(),
+        }));
+    }
+
+    /// The value of an entry as the value column sees it, i.e. decompressed with `value_compression 'gzip'`
+    /// Values that aren't gzip streams are taken as they are
+    fn stored_value<'a>(&self, kv: &'a KeyValue) -> Result<std::borrow::Cow<'a, [u8]>, String> {
+        match self.gzip_values && kv.value().starts_with(&GZIP_MAGIC) {
+            true => gunzip(kv.value()).map(std::borrow::Cow::Owned),
+            false => Ok(std::borrow::Cow::Borrowed(kv.value())),
+        }
+    }
+
+    /// Convert a key-value into the cells of the target columns
+    fn decode_entry(&self, kv: &KeyValue) -> EtcdFdwResult<Vec<(String, Option<Cell>)>> {
+        let lossy_key = || String::from_utf8_lossy(kv.key()).into_owned();
+        let value = self.stored_value(kv).map_err(|e| EtcdFdwError::UndecodableEntry(lossy_key(), e))?;
+        let null_value = self.empty_as_null && value.is_empty();
+        // keys_only scans have no values to render
+        let rendered = match self.fetch_value && !null_value {
+            true => self.value_format.render(&value).map_err(|e| EtcdFdwError::UndecodableEntry(lossy_key(), e))?,
+            false => std::borrow::Cow::Borrowed(&*value),
+        };
+        let value_str = || {
+            std::str::from_utf8(&rendered).map_err(|e| EtcdFdwError::UndecodableEntry(lossy_key(), e.to_string()))
+        };
+        let segments = match &self.key_segments {
+            Some(s) => {
+                let key = kv
+                    .key_str()
+                    .map_err(|e| EtcdFdwError::UndecodableEntry(lossy_key(), e.to_string()))?;
+                s.split(key, self.segment_columns.len())
+            }
+            None => Vec::new(),
+        };
+
+        // The value document is parsed once for all the columns projected from it
+        let document = match self.tgt_cols.iter().any(|c| self.jsonpaths.contains_key(&c.name)) {
+            true => {
+                serde_json::from_str(value_str()?)
+                    .map_err(|e| EtcdFdwError::UndecodableEntry(lossy_key(), format!("invalid JSON: {}", e)))?
+            }
+            false => serde_json::Value::Null,
+        };
+
+        let mut cells = Vec::new();
+        for tgt_col in &self.tgt_cols {
+            if let Some(path) = self.jsonpaths.get(&tgt_col.name) {
+                let cell = match path.get(&document).filter(|v| !v.is_null()) {
+                    Some(v) => Some(json_cell(v, tgt_col.type_oid).map_err(|e| EtcdFdwError::UndecodableEntry(lossy_key(), e))?),
+                    None => None,
+                };
+                cells.push((tgt_col.name.clone(), cell));
+                continue;
+            }
+            if let Some(i) = self.segment_columns.iter().position(|c| *c == tgt_col.name) {
+                let cell = match &segments[i] {
+                    Some(segment) => {
+                        Some(text_cell(segment, tgt_col.type_oid).map_err(|e| EtcdFdwError::UndecodableEntry(lossy_key(), e))?)
+                    }
+                    None => None,
+                };
+                cells.push((tgt_col.name.clone(), cell));
+                continue;
+            }
+            if tgt_col.name == self.key_column && tgt_col.type_oid == pg_sys::BYTEAOID && self.key_encoding.is_none() {
+                // bytea keys are passed through as they are, they needn't be UTF-8
+                let bytes = pgrx::varlena::rust_byte_slice_to_bytea(kv.key()).into_pg();
+                cells.push((tgt_col.name.clone(), Some(Cell::Bytea(bytes))));
+            } else if tgt_col.name == self.key_column {
+                let key = kv
+                    .key_str()
+                    .map_err(|e| EtcdFdwError::UndecodableEntry(lossy_key(), e.to_string()))?;
+                let key_cell = match &self.key_encoding {
+                    Some(e) => e.decode(key)?,
+                    None => Cell::String(key.to_string()),
+                };
+                let key_cell = match key_cell {
+                    Cell::String(key) => {
+                        text_cell(&key, tgt_col.type_oid).map_err(|e| EtcdFdwError::UndecodableEntry(lossy_key(), e))?
+                    }
+                    cell => cell,
+                };
+                cells.push((tgt_col.name.clone(), Some(key_cell)));
+            }
+            if tgt_col.name == self.value_column && null_value {
+                cells.push((tgt_col.name.clone(), None));
+            } else if tgt_col.name == self.value_column && tgt_col.type_oid == pg_sys::BYTEAOID {
+                // bytea values are passed through as they are, they needn't be UTF-8
+                let bytes = pgrx::varlena::rust_byte_slice_to_bytea(&value).into_pg();
+                cells.push((tgt_col.name.clone(), Some(Cell::Bytea(bytes))));
+            } else if tgt_col.name == self.value_column {
+                let cell = text_cell(value_str()?, tgt_col.type_oid).map_err(|e| EtcdFdwError::UndecodableEntry(lossy_key(), e))?;
+                cells.push((tgt_col.name.clone(), Some(cell)));
+            }
+            let meta = match tgt_col.name.as_str() {
+                "create_revision" => kv.create_revision(),
+                "mod_revision" => kv.mod_revision(),
+                "version" => kv.version(),
+                "lease" => kv.lease(),
+                "ttl_remaining" | "ttl" => {
+                    let cell = match self.lease_ttl(kv.lease())? {
+                        Some(ttl) => {
+                            Some(number_cell(ttl, tgt_col.type_oid).map_err(|e| EtcdFdwError::UndecodableEntry(lossy_key(), e))?)
+                        }
+                        None => None,
+                    };
+                    cells.push((tgt_col.name.clone(), cell));
+                    continue;
+                }
+                "value_md5" => {
+                    use md5::Digest;
+                    // The checksum of the value as the value column sees it, not of its compressed form
+                    let digest = format!("{:x}", md5::Md5::digest(&*value));
+                    cells.push((tgt_col.name.clone(), Some(Cell::String(digest))));
+                    continue;
+                }
+                "expires_at" => {
+                    let expires_at = self.lease_ttl(kv.lease())?.and_then(|ttl| {
+                        let now = unsafe { pg_sys::GetCurrentTimestamp() };
+                        TimestampWithTimeZone::try_from(now.saturating_add(ttl.saturating_mul(1_000_000))).ok()
+                    });
+                    cells.push((tgt_col.name.clone(), expires_at.map(Cell::Timestamptz)));
+                    continue;
+                }
+                _ => continue,
+            };
+            let cell = number_cell(meta, tgt_col.type_oid).map_err(|e| EtcdFdwError::UndecodableEntry(lossy_key(), e))?;
+            cells.push((tgt_col.name.clone(), Some(cell)));
+        }
+        Ok(cells)
+    }
+
+    /// Remaining seconds of the lease of a key, None without a lease or once it expired
+    /// Each lease is looked up once per scan
+    fn lease_ttl(&self, lease: i64) -> EtcdFdwResult<Option<i64>> {
+        if lease == 0 {
+            return Ok(None);
+        }
+        let cached = self.lease_ttls.borrow().get(&lease).copied();
+        let ttl = match cached {
+            Some(ttl) => ttl,
+            None => {
+                let mut client = self.client.clone();
+                let started = std::time::Instant::now();
+                let ttl = match self.rt.block_on(client.lease_time_to_live(lease, None)) {
+                    Ok(resp) => resp.ttl(),
+                    Err(e) => return Err(EtcdFdwError::FetchError(e.to_string())),
+                };
+                self.count_requests(1, &[], started);
+                self.lease_ttls.borrow_mut().insert(lease, ttl);
+                ttl
+            }
+        };
+        Ok((ttl >= 0).then_some(ttl))
+    }
+
+    /// Client connected to a single endpoint of the server, shared by the backend
+    fn endpoint_client(&self, endpoint: &str) -> Result<Client, Error> {
+        let config = EtcdConfig {
+            endpoints: vec![endpoint.to_string()],
+            pick_first: false,
+            ..self.config.clone()
+        };
+        let cache_key = (config.channel_key(), config.user.clone(), config.password.clone());
+        if let Some(client) = HEDGE_CLIENTS.with_borrow(|clients| clients.get(&cache_key).cloned()) {
+            return Ok(client);
+        }
+        let client = self.rt.block_on(connect_etcd(config))?;
+        HEDGE_CLIENTS.with_borrow_mut(|clients| clients.insert(cache_key, client.clone()));
+        Ok(client)
+    }
+
+    /// Read from two endpoints picked at random, see the `hedge_delay` server option
+    /// Falls back to a regular read if a client for either endpoint can't be created
+    fn hedged_get(&mut self, key: String, options: GetOptions, delay: Duration) -> Result<GetResponse, Error> {
+        use std::hash::BuildHasher;
+
+        let endpoints = &self.config.endpoints;
+        let first = std::collections::hash_map::RandomState::new().hash_one(&key) as usize % endpoints.len();
+        let second = (first + 1) % endpoints.len();
+        match (self.endpoint_client(&endpoints[first]), self.endpoint_client(&endpoints[second])) {
+            (Ok(a), Ok(b)) => self.rt.block_on(hedged_get(a, b, key, options, delay)),
+            _ => self.rt.block_on(self.client.get(key, Some(options))),
+        }
+    }
+
+    /// Look up a single key through the point cache of `prefix`
+    /// Returns the key-value, if the key exists, and the revision of that state
+    fn cached_point_lookup(
+        &mut self,
+        prefix: &str,
+        key: &str,
+        get_options: GetOptions,
+    ) -> EtcdFdwResult<(Option<KeyValue>, i64)> {
+        let cache_key = (self.config.channel_key(), self.config.user.clone(), prefix.to_string());
+        // A cache whose watch ended is replaced by a new one
+        let cache = POINT_CACHES.with_borrow(|caches| {
+            caches
+                .get(&cache_key)
+                .filter(|c| !c.lock().expect("The point cache lock isn't poisoned").broken)
+                .cloned()
+        });
+        if let Some(cache) = &cache {
+            let cache = cache.lock().expect("The point cache lock isn't poisoned");
+            if let Some(entry) = cache.entries.get(key).filter(|_| cache.connected) {
+                return Ok(entry.clone());
+            }
+        }
+
+        // Register the key before reading it, so the watch records changes that happen meanwhile
+        if let Some(cache) = &cache {
+            let mut cache = cache.lock().expect("The point cache lock isn't poisoned");
+            if cache.entries.len() < POINT_CACHE_MAX_KEYS {
+                cache.entries.insert(key.to_string(), (None, 0));
+            }
+        }
+        let started = std::time::Instant::now();
+        let resp = match self.rt.block_on(self.client.get(key, Some(get_options))) {
+            Ok(x) => x,
+            Err(e) => {
+                if let Some(cache) = &cache {
+                    cache.lock().expect("The point cache lock isn't poisoned").entries.remove(key);
+                }
+                return Err(fetch_error(e));
+            }
+        };
+        self.count_requests(1, resp.kvs(), started);
+        record_read_header(resp.header());
+        let revision = resp.header().map(|h| h.revision()).unwrap_or(0);
+        let fetched = (resp.kvs().first().cloned(), revision);
+
+        match cache {
+            Some(cache) => {
+                let mut cache = cache.lock().expect("The point cache lock isn't poisoned");
+                if let Some(entry) = cache.entries.get_mut(key) {
+                    if entry.1 < revision {
+                        *entry = fetched.clone();
+                    }
+                }
+            }
+            None => {
+                // The first lookup starts the watch right after the revision it read
+                let cache = Arc::new(Mutex::new(PointCache::default()));
+                cache
+                    .lock()
+                    .expect("The point cache lock isn't poisoned")
+                    .entries
+                    .insert(key.to_string(), fetched.clone());
+                WATCHES_ACTIVE.fetch_add(1, Ordering::Relaxed);
+                self.rt.spawn(watch_point_cache(
+                    self.client.clone(),
+                    prefix.to_string(),
+                    revision,
+                    cache.clone(),
+                    Backoff::from_settings(),
+                ));
+                POINT_CACHES.with_borrow_mut(|caches| caches.insert(cache_key, cache));
+            }
+        }
+        Ok(fetched)
+    }
+
+    /// Buffer a write of an UPDATE or DELETE, a full batch is sent as one etcd transaction
+    fn batch_write(&mut self, key: &[u8], op: TxnOp) -> EtcdFdwResult<()> {
+        // etcd rejects transactions writing a key twice
+        if self.pending_writes.iter().any(|(k, _)| k == key) {
+            self.flush_writes()?;
+        }
+        self.pending_writes.push((key.to_vec(), op));
+
+        if self.pending_writes.len() >= self.modify_batch_size {
+            self.flush_writes()?;
+        }
+        Ok(())
+    }
+
+    /// Send the buffered writes as one etcd transaction
+    fn flush_writes(&mut self) -> EtcdFdwResult<()> {
+        if self.pending_writes.is_empty() {
+            return Ok(());
+        }
+
+        let (keys, ops): (Vec<Vec<u8>>, Vec<TxnOp>) =
+            std::mem::take(&mut self.pending_writes).into_iter().unzip();
+        for key in &keys {
+            forget_cached_key(key);
+        }
+        // All keys are written if all of them exist, otherwise the keys are read to report the missing one
+        let txn = Txn::new()
+            .when(
+                keys.iter()
+                    .map(|key| Compare::create_revision(key.clone(), CompareOp::Greater, 0))
+                    .collect::<Vec<_>>(),
+            )
+            .and_then(ops)
+            .or_else(
+                keys.iter()
+                    .map(|key| TxnOp::get(key.clone(), Some(GetOptions::new().with_keys_only())))
+                    .collect::<Vec<_>>(),
+            );
+        let resp = match self.rt.block_on(self.client.txn(txn)) {
+            Ok(x) => x,
+            Err(e) => return Err(EtcdFdwError::UpdateError(e.to_string())),
+        };
+
+        if !resp.succeeded() {
+            let missing = keys.iter().zip(resp.op_responses()).find_map(|(key, op)| match op {
+                TxnOpResponse::Get(existing) if existing.kvs().is_empty() => Some(key),
+                _ => None,
+            });
+            let key = missing.unwrap_or(&keys[0]);
+            return Err(EtcdFdwError::KeyDoesntExist(String::from_utf8_lossy(key).into_owned()));
+        }
+        Ok(())
+    }
+
+    /// Buffer a row of an INSERT without RETURNING, the rows are written in one etcd transaction
+    fn buffer_insert(&mut self, key: &[u8], value: Vec<u8>, lease: Option<i64>) -> EtcdFdwResult<()> {
+        // etcd rejects transactions writing a key twice, a repeated key conflicts with the buffered row
+        if let Some(pos) = self.pending_inserts.iter().position(|(k, _, _)| k == key) {
+            return match self.on_conflict {
+                OnConflict::Error => Err(EtcdFdwError::KeyAlreadyExists(String::from_utf8_lossy(key).into_owned())),
+                OnConflict::Ignore => Ok(()),
+                OnConflict::Overwrite => {
+                    self.pending_inserts[pos] = (key.to_vec(), value, lease);
+                    Ok(())
+                }
+            };
+        }
+        self.pending_inserts.push((key.to_vec(), value, lease));
+
+        if self.pending_inserts.len() >= MAX_TXN_OPS {
+            self.flush_inserts()?;
+        }
+        Ok(())
+    }
+
+    /// Write the buffered rows of an INSERT as one etcd transaction
+    fn flush_inserts(&mut self) -> EtcdFdwResult<()> {
+        if self.pending_inserts.is_empty() {
+            return Ok(());
+        }
+
+        let rows = std::mem::take(&mut self.pending_inserts);
+        let write_lease = self.write_lease;
+        let put = |(key, value, lease): &(Vec<u8>, Vec<u8>, Option<i64>)| {
+            let put_options = lease.or(write_lease).map(|lease| PutOptions::new().with_lease(lease));
+            TxnOp::put(key.clone(), value.clone(), put_options)
+        };
+        // The write lease is only attached to created keys, so an abort doesn't delete the keys that were replaced
+        let overwrite = |row: &(Vec<u8>, Vec<u8>, Option<i64>)| match row.2 {
+            None if write_lease.is_some() => TxnOp::txn(
+                Txn::new()
+                    .when([Compare::create_revision(row.0.clone(), CompareOp::Equal, 0)])
+                    .and_then([put(row)])
+                    .or_else([TxnOp::put(row.0.clone(), row.1.clone(), None)]),
+            ),
+            _ => put(row),
+        };
+        let txn = match self.on_conflict {
+            // All rows are put if none of the keys exists, otherwise the keys are read to report the existing one
+            OnConflict::Error => Txn::new()
+                .when(
+                    rows.iter()
+                        .map(|(key, _, _)| Compare::create_revision(key.clone(), CompareOp::Equal, 0))
+                        .collect::<Vec<_>>(),
+                )
+                .and_then(rows.iter().map(put).collect::<Vec<_>>())
+                .or_else(
+                    rows.iter()
+                        .map(|(key, _, _)| TxnOp::get(key.clone(), Some(GetOptions::new().with_keys_only())))
+                        .collect::<Vec<_>>(),
+                ),
+            // Every row is put by a nested transaction checking its own key
+            OnConflict::Ignore => Txn::new().and_then(
+                rows.iter()
+                    .map(|row| {
+                        TxnOp::txn(
+                            Txn::new()
+                                .when([Compare::create_revision(row.0.clone(), CompareOp::Equal, 0)])
+                                .and_then([put(row)]),
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            OnConflict::Overwrite => Txn::new().and_then(rows.iter().map(overwrite).collect::<Vec<_>>()),
+        };
+        let resp = match self.rt.block_on(self.client.txn(txn)) {
+            Ok(x) => x,
+            Err(e) => return Err(EtcdFdwError::UpdateError(e.to_string())),
+        };
+
+        let responses = resp.op_responses();
+        if !resp.succeeded() {
+            let existing = rows.iter().zip(&responses).find_map(|((key, _, _), op)| match op {
+                TxnOpResponse::Get(existing) if !existing.kvs().is_empty() => Some(key),
+                _ => None,
+            });
+            let key = existing.unwrap_or(&rows[0].0);
+            return Err(EtcdFdwError::KeyAlreadyExists(String::from_utf8_lossy(key).into_owned()));
+        }
+
+        if let Some(lease) = self.write_lease {
+            // Keys with a lease of their own aren't detached from it
+            let created = rows.into_iter().zip(&responses).filter_map(|((key, _, lease), op)| match op {
+                _ if lease.is_some() => None,
+                TxnOpResponse::Txn(nested) if !nested.succeeded() => None,
+                _ => Some(key),
+            });
+            with_pending_lease(lease, |keys| keys.extend(created));
+        }
+        Ok(())
+    }
+
+    /// Delete a key for DELETE ... RETURNING, which returns the row of the key as etcd deleted it
+    fn delete_returning(&mut self, key: &[u8]) -> EtcdFdwResult<()> {
+        let resp = match self.rt.block_on(self.client.delete(key, Some(DeleteOptions::new().with_prev_key()))) {
+            Ok(x) => x,
+            Err(e) => return Err(EtcdFdwError::UpdateError(e.to_string())),
+        };
+        let Some(kv) = resp.prev_kvs().first() else {
+            return Err(EtcdFdwError::KeyDoesntExist(String::from_utf8_lossy(key).into_owned()));
+        };
+        self.store_returned(kv)
+    }
+
+    /// Decode the updated or deleted key for the RETURNING clause of an UPDATE or DELETE
+    fn store_returned(&mut self, kv: &KeyValue) -> EtcdFdwResult<()> {
+        let columns = unsafe { relation_columns(self.relid) };
+        self.segment_columns = match self.key_segments {
+            Some(_) => segment_columns(&columns, &self.key_column, &self.value_column),
+            None => Vec::new(),
+        };
+        self.segment_columns.retain(|c| !self.jsonpaths.contains_key(c));
+        self.tgt_cols = unsafe { relation_target_columns(self.relid) };
+        self.fetch_value = true;
+        RETURNED_ROW.set(Some(self.decode_entry(kv)?));
+        Ok(())
+    }
+
+    /// Conditions of the scan of an UPDATE or DELETE that its writes compare again
+    /// Only values stored exactly like the compared text are compared, compressed, jsonb or protobuf values aren't
+    fn write_conditions(&self, quals: &[Qual]) -> WriteConditions {
+        let mod_revision = quals
+            .iter()
+            .filter(|q| q.field == "mod_revision" && q.operator == "=" && !q.use_or)
+            .find_map(qual_number);
+        let comparable = !self.gzip_values
+            && self.jsonpaths.is_empty()
+            && !matches!(self.value_format, ValueFormat::Protobuf(_) | ValueFormat::Kubernetes(_));
+        let value = quals
+            .iter()
+            .filter(|q| comparable && q.field == self.value_column && q.operator == "=" && !q.use_or)
+            .find_map(|q| match &q.value {
+                Value::Cell(cell @ Cell::Bytea(_)) => Some(value_bytes(cell)),
+                Value::Cell(cell @ Cell::String(_)) => self.value_format.parse(value_bytes(cell)).ok(),
+                _ => None,
+            });
+        WriteConditions {
+            relid: self.relid,
+            mod_revision,
+            value,
+        }
+    }
+
+    /// Compares of the conditions of the current UPDATE or DELETE for writing `key`, see `WriteConditions`
+    fn write_compares(&self, key: &[u8]) -> Vec<Compare> {
+        WRITE_CONDITIONS.with_borrow(|conditions| {
+            conditions
+                .as_ref()
+                .filter(|c| c.relid == self.relid)
+                .map(|c| c.compares(key))
+                .unwrap_or_default()
+        })
+    }
+
+    /// Write `key` with `ops` if it exists and `compares` still hold, in one request
+    /// Fails with a serialization error if the key changed, returns the revision of the write and the responses of `ops`
+    fn compared_write(&mut self, key: &[u8], compares: Vec<Compare>, ops: Vec<TxnOp>) -> EtcdFdwResult<(i64, Vec<TxnOpResponse>)> {
+        self.flush_writes()?;
+        let txn = Txn::new()
+            .when(
+                [Compare::create_revision(key, CompareOp::Greater, 0)]
+                    .into_iter()
+                    .chain(compares)
+                    .collect::<Vec<_>>(),
+            )
+            .and_then(ops)
+            .or_else([TxnOp::get(key, Some(GetOptions::new().with_keys_only()))]);
+        let resp = match self.rt.block_on(self.client.txn(txn)) {
+            Ok(x) => x,
+            Err(e) => return Err(EtcdFdwError::UpdateError(e.to_string())),
+        };
+        if !resp.succeeded() {
+            return match resp.op_responses().first() {
+                Some(TxnOpResponse::Get(existing)) if existing.kvs().is_empty() => {
+                    Err(EtcdFdwError::KeyDoesntExist(String::from_utf8_lossy(key).into_owned()))
+                }
+                _ => Err(EtcdFdwError::ConcurrentUpdate(String::from_utf8_lossy(key).into_owned())),
+            };
+        }
+        let revision = resp.header().map(|h| h.revision()).unwrap_or(0);
+        Ok((revision, resp.op_responses()))
+    }
+
+    /// Delete the keys of the DELETE's range with one request if all of them were deleted
+    /// If not, or if the range changed since it was read, the keys are deleted one by one
+    fn flush_range_delete(&mut self) -> EtcdFdwResult<()> {
+        let mut keys = std::mem::take(&mut self.range_deleted);
+        let Some(range) = RANGE_DELETE.take() else {
+            return Ok(());
+        };
+        if keys.is_empty() {
+            return Ok(());
+        }
+        keys.sort();
+        keys.dedup();
+
+        if keys.len() as i64 == range.count {
+            let txn = Txn::new()
+                .when([Compare::mod_revision(range.key.clone(), CompareOp::Less, range.revision + 1)
+                    .with_range(range.range_end.clone())])
+                .and_then([TxnOp::delete(range.key, Some(DeleteOptions::new().with_range(range.range_end)))]);
+            let resp = match self.rt.block_on(self.client.txn(txn)) {
+                Ok(x) => x,
+                Err(e) => return Err(EtcdFdwError::UpdateError(e.to_string())),
+            };
+            // The deletion is committed, keys deleted by others since the scan are gone either way
+            if resp.succeeded() {
+                return Ok(());
+            }
+        }
+
+        for key in keys {
+            self.batch_write(&key, TxnOp::delete(key.clone(), None))?;
+        }
+        self.flush_writes()
+    }
+
+    /// Version of the etcd cluster, queried once per channel of the backend
+    fn server_version(&mut self) -> EtcdFdwResult<String> {
+        let key = self.config.channel_key();
+        if let Some(version) = SERVER_VERSIONS.with_borrow(|versions| versions.get(&key).cloned()) {
+            return Ok(version);
+        }
+        let version = match self.rt.block_on(self.client.status()) {
+            Ok(x) => x.version().to_string(),
+            Err(e) => return Err(fetch_error(e)),
+        };
+        SERVER_VERSIONS.with_borrow_mut(|versions| versions.insert(key, version.clone()));
+        Ok(version)
+    }
+
+    /// Fail with a clear error if `feature` isn't supported by the etcd cluster
+    /// Versions that can't be parsed are assumed to support everything
+    fn require_version(&mut self, feature: &str, min: (u32, u32)) -> EtcdFdwResult<()> {
+        let version = self.server_version()?;
+        match parse_version(&version) {
+            Some(v) if v < min => Err(EtcdFdwError::UnsupportedVersion(
+                feature.to_string(),
+                format!("{}.{}", min.0, min.1),
+                version,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The `namespace` of the server, SQL functions take their keys within it
+    fn server_namespace(&self) -> &str {
+        self.server_options.get("namespace").map(String::as_str).unwrap_or_default()
+    }
+
+    /// The etcd key of a key given to a SQL function, checked against the user mapping's prefix
+    fn server_key(&self, key: &str) -> EtcdFdwResult<String> {
+        let key = format!("{}{}", self.server_namespace(), key);
+        self.check_role_prefix(key.as_bytes())?;
+        Ok(key)
+    }
+
+    /// Writes are restricted to the subtree of the user mapping's `prefix`
+    fn check_role_prefix(&self, key: &[u8]) -> EtcdFdwResult<()> {
+        match &self.role_prefix {
+            Some(p) if !key.starts_with(p.as_bytes()) => Err(EtcdFdwError::KeyOutsideRolePrefix(
+                String::from_utf8_lossy(key).into_owned(),
+                p.clone(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The etcd key of a key column value that is written to or used as rowid
+    /// bytea keys are used as they are, they needn't be UTF-8
+    fn etcd_key(&self, cell: &Cell) -> EtcdFdwResult<Vec<u8>> {
+        match (&self.key_encoding, cell) {
+            (Some(e), cell) => e.encode(cell).map(String::into_bytes),
+            (None, Cell::Bytea(bytes)) => {
+                Ok(unsafe { pgrx::varlena::varlena_to_byte_slice(*bytes as *const pg_sys::varlena) }.to_vec())
+            }
+            (None, cell) => Ok(cell.to_string().trim_matches(|x| x == '\'').as_bytes().to_vec()),
+        }
+    }
+
+    /// The etcd value of a row, compressed with `value_compression 'gzip'`
+    fn row_value(&self, row: &Row) -> EtcdFdwResult<Vec<u8>> {
+        let value = self.uncompressed_row_value(row)?;
+        Ok(match self.gzip_values {
+            true => gzip(&value),
+            false => value,
+        })
+    }
+
+    /// Whether the new row of an UPDATE assigns the value column, the framework only passes assigned columns
+    fn assigns_value(&self, row: &Row) -> bool {
+        row.cols.iter().any(|name| *name == self.value_column)
+    }
+
+    /// Whether the new row of an UPDATE assigns the `lease` or `ttl` column
+    fn assigns_lease(&self, row: &Row) -> bool {
+        row.cols.iter().any(|name| name == "lease" || name == "ttl")
+    }
+
+    /// Whether the new row of an UPDATE assigns any of the `jsonpath` columns
+    fn assigns_jsonpath(&self, row: &Row) -> bool {
+        row.cols.iter().any(|name| self.jsonpaths.contains_key(name))
+    }
+
+    /// The stored entry of a key an UPDATE derives the new value from
+    fn current_entry(&mut self, key: &[u8]) -> EtcdFdwResult<KeyValue> {
+        let resp = self.rt.block_on(self.client.get(key, None)).map_err(fetch_error)?;
+        resp.kvs()
+            .first()
+            .cloned()
+            .ok_or_else(|| EtcdFdwError::KeyDoesntExist(String::from_utf8_lossy(key).into_owned()))
+    }
+
+    /// The value of an updated row that assigns `jsonpath` columns but not the value column
+    /// The assigned fields are set in the stored document, NULL sets them to null
+    fn merged_row_value(&self, current: &KeyValue, row: &Row) -> EtcdFdwResult<Vec<u8>> {
+        let stored = self
+            .stored_value(current)
+            .map_err(|e| EtcdFdwError::UndecodableEntry(String::from_utf8_lossy(current.key()).into_owned(), e))?;
+        let mut document = match stored.is_empty() {
+            true => serde_json::Value::Object(serde_json::Map::new()),
+            false => serde_json::from_slice(&stored).map_err(|e| EtcdFdwError::InvalidJsonValue(e.to_string()))?,
+        };
+        for (name, cell) in row.cols.iter().zip(&row.cells) {
+            if let Some(path) = self.jsonpaths.get(name) {
+                path.set(&mut document, cell.as_ref().map(cell_json).unwrap_or(serde_json::Value::Null));
+            }
+        }
+        let value = document.to_string().into_bytes();
+        Ok(match self.gzip_values {
+            true => gzip(&value),
+            false => value,
+        })
+    }
+
+    /// The value of a row, the `jsonpath` columns are stored in the value document
+    fn uncompressed_row_value(&self, row: &Row) -> EtcdFdwResult<Vec<u8>> {
+        let value_cell = row
+            .cols
+            .iter()
+            .zip(row.cells.clone())
+            .filter(|(name, _cell)| **name == self.value_column)
+            .last();
+        if self.jsonpaths.is_empty() {
+            return match value_cell {
+                // bytea values are stored as they are, value_format renders into the other types
+                Some((_name, Some(cell @ Cell::Bytea(_)))) => Ok(value_bytes(&cell)),
+                Some((_name, Some(cell))) => self
+                    .value_format
+                    .parse(value_bytes(&cell))
+                    .map_err(EtcdFdwError::InvalidValue),
+                // NULL is stored as the empty value, which reads as NULL again
+                Some((_name, None)) if self.empty_as_null => Ok(Vec::new()),
+                Some((_name, None)) => Err(EtcdFdwError::NullValue(self.value_column.clone())),
+                None => Err(EtcdFdwError::MissingColumn(self.value_column.clone())),
+            };
+        }
+
+        // Without a value, the document only holds the fields of the columns
+        let mut document = match value_cell.and_then(|(_name, cell)| cell) {
+            Some(cell) => serde_json::from_slice(&value_bytes(&cell))
+                .map_err(|e| EtcdFdwError::InvalidJsonValue(e.to_string()))?,
+            None => serde_json::Value::Object(serde_json::Map::new()),
+        };
+        for (name, cell) in row.cols.iter().zip(&row.cells) {
+            if let (Some(path), Some(cell)) = (self.jsonpaths.get(name), cell) {
+                path.set(&mut document, cell_json(cell));
+            }
+        }
+        Ok(document.to_string().into_bytes())
+    }
+
+    /// The key of a row of a table with `key_delimiter`, assembled from its segment columns
+    fn segment_key(&self, segments: &KeySegments, row: &Row) -> EtcdFdwResult<String> {
+        let mut columns = segment_columns(&row.cols, &self.key_column, &self.value_column);
+        columns.retain(|c| !self.jsonpaths.contains_key(c));
+        let mut values = Vec::new();
+        for (i, column) in columns.iter().enumerate() {
+            let cell = row
+                .cols
+                .iter()
+                .zip(&row.cells)
+                .find(|(name, _cell)| *name == column)
+                .and_then(|(_name, cell)| cell.as_ref())
+                .ok_or_else(|| EtcdFdwError::MissingColumn(column.clone()))?;
+            let value = cell.to_string().trim_matches('\'').to_string();
+            // only the last segment may span several levels of the hierarchy
+            if i + 1 < columns.len() && value.contains(segments.delimiter.as_str()) {
+                return Err(EtcdFdwError::InvalidKeySegment(column.clone(), value));
+            }
+            values.push(value);
+        }
+        Ok(segments.join(&values))
+    }
+
+    /// etcd field of a column, `"key"` and `"value"` for the key and value columns
+    /// A column named like a renamed one is an ordinary column, etcd has no field for it
+    fn etcd_field<'a>(&self, column: &'a str) -> &'a str {
+        if column == self.key_column {
+            "key"
+        } else if column == self.value_column {
+            "value"
+        } else if column == "key" || column == "value" {
+            ""
+        } else {
+            column
+        }
+    }
+
+    /// Whether etcd's key order matches the order of the key column
+    fn key_order_preserved(&self) -> bool {
+        self.key_order_bytewise && self.key_encoding.as_ref().is_none_or(KeyEncoding::preserves_order)
+    }
+
+    /// Scan of a table with `layout 'sub_keys'`
+    /// The sub-keys below `prefix` are read at once and pivoted into one row per row key,
+    /// only an equality condition on the row key is pushed down
+    fn start_sub_key_scan(
+        &mut self,
+        quals: &[Qual],
+        columns: &[Column],
+        prefix: String,
+        get_options: GetOptions,
+        request: ScanRequest,
+    ) -> EtcdFdwResult<()> {
+        self.tgt_cols = columns.to_vec();
+        self.pivoted_rows = vec![];
+
+        let qual_row = quals
+            .iter()
+            .filter(|q| q.field == self.key_column && q.operator == "=" && !q.use_or)
+            .find_map(|q| qual_key_value(q, None))
+            .map(|row_key| format!("{}/", row_key));
+        let mut read_prefix: Option<String> = None;
+        for p in [Some(prefix.clone()), self.role_prefix.clone(), qual_row].into_iter().flatten() {
+            read_prefix = match read_prefix {
+                Some(cur) if p.starts_with(&cur) => Some(p),
+                Some(cur) if cur.starts_with(&p) => Some(cur),
+                Some(_) => {
+                    describe_scan(&ScanRequest { operation: "none", ..request });
+                    return Ok(());
+                }
+                None => Some(p),
+            };
+        }
+
+        let read_prefix = read_prefix.unwrap_or_default();
+        let request = ScanRequest {
+            key: read_prefix.clone(),
+            prefix: Some(read_prefix.clone()),
+            ..request
+        };
+        if describe_scan(&request) {
+            return Ok(());
+        }
+        let started = std::time::Instant::now();
+        let resp = self
+            .rt
+            .block_on(self.client.get(read_prefix, Some(get_options.with_prefix())))
+            .map_err(fetch_error)?;
+        self.count_requests(1, resp.kvs(), started);
+        record_read_header(resp.header());
+        LAST_READ_REVISION.set(resp.header().map(|h| h.revision()).unwrap_or(0));
+
+        // Sub-keys of a row aren't necessarily adjacent in etcd's order, i.e. with nested row keys
+        let mut rows: BTreeMap<String, HashMap<String, String>> = BTreeMap::new();
+        for kv in resp.kvs() {
+            let lossy_key = || String::from_utf8_lossy(kv.key()).into_owned();
+            let decoded = kv.key_str().and_then(|k| kv.value_str().map(|v| (k, v)));
+            let (key, value) = match decoded {
+                Ok(x) => x,
+                Err(e) if self.skip_scan_errors => {
+                    warning!("{}, skipping it", EtcdFdwError::UndecodableEntry(lossy_key(), e.to_string()));
+                    continue;
+                }
+                Err(e) => return Err(EtcdFdwError::UndecodableEntry(lossy_key(), e.to_string())),
+            };
+            // Keys directly below the prefix don't belong to a row
+            let Some((row_key, column)) = key.rsplit_once('/') else {
+                continue;
+            };
+            if row_key.len() <= prefix.len() {
+                continue;
+            }
+            rows.entry(row_key.to_string())
+                .or_default()
+                .insert(column.to_string(), value.to_string());
+        }
+
+        self.pivoted_rows = Vec::new();
+        for (row_key, mut values) in rows {
+            let row: Result<Vec<_>, String> = self
+                .tgt_cols
+                .iter()
+                .map(|col| {
+                    let text = match col.name == self.key_column {
+                        true => Some(row_key.clone()),
+                        false => values.remove(&col.name).filter(|v| !(self.empty_as_null && v.is_empty())),
+                    };
+                    let cell = text.map(|t| text_cell(&t, col.type_oid)).transpose()?;
+                    Ok((col.name.clone(), cell))
+                })
+                .collect();
+            match row {
+                Ok(row) => self.pivoted_rows.push(row),
+                Err(e) if self.skip_scan_errors => {
+                    warning!("{}, skipping it", EtcdFdwError::UndecodableEntry(row_key, e));
+                }
+                Err(e) => return Err(EtcdFdwError::UndecodableEntry(row_key, e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Acquire the etcd locks `names` for the statement in order, waiting for other holders to release them
+    /// Returns the lease the locks are bound to
+    fn acquire_lock(&mut self, names: Vec<String>) -> EtcdFdwResult<i64> {
+        let lease = match self.rt.block_on(self.client.lease_grant(MODIFY_LOCK_TTL, None)) {
+            Ok(x) => x.id(),
+            Err(e) => return Err(EtcdFdwError::UpdateError(e.to_string())),
+        };
+
+        // Long statements keep the lock, a crashed backend loses it after the TTL
+        let mut client = self.client.clone();
+        let keep_alive = self.rt.spawn(async move {
+            let Ok((mut keeper, mut stream)) = client.lease_keep_alive(lease).await else {
+                return;
+            };
+            while keeper.keep_alive().await.is_ok() && matches!(stream.message().await, Ok(Some(_))) {
+                tokio::time::sleep(Duration::from_secs(MODIFY_LOCK_TTL as u64 / 3)).await;
+            }
+        });
+        // Tracked before waiting, so a canceled wait releases the lease as well
+        track_held_lock(HeldLock {
+            config: self.config.clone(),
+            lease,
+            keep_alive,
+        });
+
+        // The names are sorted, so statements locking overlapping sets of prefixes can't deadlock
+        let mut client = self.client.clone();
+        let mut acquire = self.rt.spawn(async move {
+            for name in names {
+                client.lock(name, Some(LockOptions::new().with_lease(lease))).await?;
+            }
+            Ok::<(), Error>(())
+        });
+        loop {
+            match self.rt.block_on(tokio::time::timeout(Duration::from_millis(100), &mut acquire)) {
+                Ok(Ok(Ok(()))) => return Ok(lease),
+                Ok(Ok(Err(e))) => return Err(EtcdFdwError::UpdateError(e.to_string())),
+                Ok(Err(e)) => return Err(EtcdFdwError::UpdateError(e.to_string())),
+                // Waiting can take long, let the statement be canceled meanwhile
+                Err(_) => pg_sys::check_for_interrupts!(),
+            }
+        }
+    }
+
+    /// etcd key of a row of a table with `layout 'sub_keys'`, checked against the user mapping's prefix
+    /// Sub-keys are named after the row key, so it has to be UTF-8
+    fn sub_key_row(&self, cell: &Cell) -> EtcdFdwResult<String> {
+        let row_key = String::from_utf8(self.etcd_key(cell)?)
+            .map_err(|e| EtcdFdwError::InvalidSubKeyRow(String::from_utf8_lossy(e.as_bytes()).into_owned()))?;
+        self.check_role_prefix(row_key.as_bytes())?;
+        Ok(row_key)
+    }
+
+    /// Insert a row of a table with `layout 'sub_keys'`, all its non-NULL columns are put in one transaction
+    /// The row must not have any sub-keys yet
+    fn insert_sub_keys(&mut self, row: &Row) -> EtcdFdwResult<()> {
+        let key_cell = row
+            .cols
+            .iter()
+            .zip(&row.cells)
+            .filter(|(name, _cell)| **name == self.key_column)
+            .last()
+            .and_then(|(_name, cell)| cell.clone())
+            .ok_or(EtcdFdwError::MissingColumn(self.key_column.clone()))?;
+        let row_key = self.sub_key_row(&key_cell)?;
+
+        let mut put_options = PutOptions::new();
+        if let Some(lease) = self.write_lease {
+            put_options = put_options.with_lease(lease);
+        }
+        let overwrite = self.on_conflict == OnConflict::Overwrite && self.write_lease.is_some();
+        let mut sub_keys = Vec::new();
+        let mut ops = Vec::new();
+        for (name, cell) in row.cols.iter().zip(&row.cells) {
+            let Some(cell) = cell.as_ref().filter(|_| *name != self.key_column) else {
+                continue;
+            };
+            let sub_key = format!("{}/{}", row_key, name);
+            let value = cell.to_string();
+            let value = value.trim_matches(|x| x == '\'');
+            forget_cached_key(sub_key.as_bytes());
+            let put = TxnOp::put(sub_key.as_str(), value, Some(put_options.clone()));
+            // The write lease is only attached to created sub-keys, an abort doesn't delete the replaced ones
+            if overwrite {
+                ops.push(TxnOp::txn(
+                    Txn::new()
+                        .when([Compare::create_revision(sub_key.as_str(), CompareOp::Equal, 0)])
+                        .and_then([put])
+                        .or_else([TxnOp::put(sub_key.as_str(), value, None)]),
+                ));
+            } else {
+                ops.push(put);
+            }
+            sub_keys.push(sub_key);
+        }
+
+        let txn = match self.on_conflict {
+            OnConflict::Overwrite => Txn::new().and_then(ops),
+            _ => Txn::new()
+                .when([Compare::create_revision(format!("{}/", row_key), CompareOp::Equal, 0).with_prefix()])
+                .and_then(ops),
+        };
+        let resp = match self.rt.block_on(self.client.txn(txn)) {
+            Ok(x) => x,
+            Err(e) => return Err(EtcdFdwError::UpdateError(e.to_string())),
+        };
+        if !resp.succeeded() {
+            if self.on_conflict == OnConflict::Ignore {
+                return Ok(());
+            }
+            return Err(EtcdFdwError::KeyAlreadyExists(row_key));
+        }
+        if let Some(lease) = self.write_lease {
+            let created = sub_keys.into_iter().zip(resp.op_responses()).filter_map(|(key, op)| match op {
+                TxnOpResponse::Txn(nested) if !nested.succeeded() => None,
+                _ => Some(key.into_bytes()),
+            });
+            with_pending_lease(lease, |keys| keys.extend(created));
+        }
+        Ok(())
+    }
+
+    /// The new value of the key column of the current UPDATE
+    /// The framework leaves the key out of the new row as it's the rowid
+    fn updated_key(&self) -> Option<Cell> {
+        if !self.rowid_is_key {
+            return None;
+        }
+        UPDATED_ROW.with_borrow(|row| {
+            let row = row.as_ref()?;
+            row.cols
+                .iter()
+                .zip(&row.cells)
+                .find(|(name, _)| **name == self.key_column)
+                .and_then(|(_, cell)| cell.clone())
+        })
+    }
+
+    /// Move the value of a key to a new key in one etcd transaction, as UPDATE of the key column
+    fn rename(
+        &mut self,
+        key: &[u8],
+        new_key: &[u8],
+        value: Vec<u8>,
+        lease: Option<i64>,
+        compares: Vec<Compare>,
+    ) -> EtcdFdwResult<()> {
+        self.check_role_prefix(new_key)?;
+        forget_cached_key(new_key);
+        self.flush_writes()?;
+
+        let put_options = lease.map(|lease| PutOptions::new().with_lease(lease));
+        let compared = !compares.is_empty();
+        let mut ops = vec![TxnOp::put(new_key, value, put_options), TxnOp::delete(key, None)];
+        // RETURNING returns the row of the new key as etcd holds it after the rename
+        if MODIFY_RETURNING.get() {
+            ops.push(TxnOp::get(new_key, None));
+        }
+        let txn = Txn::new()
+            .when(
+                [
+                    Compare::create_revision(key, CompareOp::Greater, 0),
+                    Compare::create_revision(new_key, CompareOp::Equal, 0),
+                ]
+                .into_iter()
+                .chain(compares)
+                .collect::<Vec<_>>(),
+            )
+            .and_then(ops)
+            .or_else([TxnOp::get(new_key, Some(GetOptions::new().with_keys_only()))]);
+        let resp = match self.rt.block_on(self.client.txn(txn)) {
+            Ok(x) => x,
+            Err(e) => return Err(EtcdFdwError::UpdateError(e.to_string())),
+        };
+
+        if !resp.succeeded() {
+            return match resp.op_responses().first() {
+                Some(TxnOpResponse::Get(existing)) if !existing.kvs().is_empty() => {
+                    Err(EtcdFdwError::KeyAlreadyExists(String::from_utf8_lossy(new_key).into_owned()))
+                }
+                _ if compared => Err(EtcdFdwError::ConcurrentUpdate(String::from_utf8_lossy(key).into_owned())),
+                _ => Err(EtcdFdwError::KeyDoesntExist(String::from_utf8_lossy(key).into_owned())),
+            };
+        }
+        // The renamed key stays attached to the write lease until commit like the old one
+        if let Some(lease) = self.write_lease.filter(|l| lease == Some(*l)) {
+            with_pending_lease(lease, |keys| {
+                keys.retain(|k| k != key);
+                keys.push(new_key.to_vec());
+            });
+        }
+        let revision = resp.header().map(|h| h.revision()).unwrap_or(0);
+        LAST_WRITE.set(Some(WrittenKey {
+            created: false,
+            ..replaced_key(None, revision, lease.unwrap_or(0))
+        }));
+        if let Some(TxnOpResponse::Get(renamed)) = resp.op_responses().get(2) {
+            if let Some(kv) = renamed.kvs().first() {
+                self.store_returned(kv)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Insert a key with `on_conflict 'overwrite'`, a plain put replacing any existing value
+    /// The write lease is only attached to a created key, an abort doesn't delete a key that was replaced
+    fn overwrite(&mut self, key: &[u8], value: Vec<u8>, row_lease: Option<i64>) -> EtcdFdwResult<()> {
+        let lease = row_lease.or(self.write_lease);
+        let mut put_options = PutOptions::new().with_prev_key();
+        if let Some(lease) = lease {
+            put_options = put_options.with_lease(lease);
+        }
+        let write_lease = self.write_lease.filter(|_| row_lease.is_none());
+        let txn = match write_lease {
+            Some(_) => Txn::new()
+                .when([Compare::create_revision(key, CompareOp::Equal, 0)])
+                .and_then([TxnOp::put(key, value.clone(), Some(put_options))])
+                .or_else([TxnOp::put(key, value, Some(PutOptions::new().with_prev_key()))]),
+            None => Txn::new().and_then([TxnOp::put(key, value, Some(put_options))]),
+        };
+        let resp = match self.rt.block_on(self.client.txn(txn)) {
+            Ok(x) => x,
+            Err(e) => return Err(EtcdFdwError::UpdateError(e.to_string())),
+        };
+        let lease = match write_lease {
+            Some(lease) if resp.succeeded() => {
+                with_pending_lease(lease, |keys| keys.push(key.to_vec()));
+                Some(lease)
+            }
+            Some(_) => None,
+            None => lease,
+        };
+        let revision = resp.header().map(|h| h.revision()).unwrap_or(0);
+        let previous = match resp.op_responses().into_iter().next() {
+            Some(TxnOpResponse::Put(put)) => put.prev_key().cloned(),
+            _ => None,
+        };
+        LAST_WRITE.set(Some(replaced_key(previous.as_ref(), revision, lease.unwrap_or(0))));
+        Ok(())
+    }
+
+    /// Lease to attach a written key to, from the `lease` or `ttl` column of the row or the `lease_id` table option
+    /// Rows of a statement with the same `ttl` share a lease granted for it
+    fn row_lease(&mut self, row: &Row) -> EtcdFdwResult<Option<i64>> {
+        let column = |column: &str| {
+            row.cols
+                .iter()
+                .zip(&row.cells)
+                .find(|(name, _)| *name == column)
+                .and_then(|(_, cell)| cell.as_ref())
+        };
+        match column("lease") {
+            Some(Cell::I64(lease)) if *lease != 0 => return Ok(Some(*lease)),
+            Some(Cell::I32(lease)) if *lease != 0 => return Ok(Some(i64::from(*lease))),
+            _ => {}
+        }
+        let ttl = match column("ttl") {
+            Some(Cell::I64(ttl)) => *ttl,
+            Some(Cell::I32(ttl)) => i64::from(*ttl),
+            Some(Cell::I16(ttl)) => i64::from(*ttl),
+            _ => return Ok(self.lease_id),
+        };
+        if ttl <= 0 {
+            return Err(EtcdFdwError::InvalidColumnValue("ttl".to_string(), ttl.to_string()));
+        }
+        if let Some(lease) = self.ttl_leases.get(&ttl) {
+            return Ok(Some(*lease));
+        }
+        let lease = match self.rt.block_on(self.client.lease_grant(ttl, None)) {
+            Ok(x) => x.id(),
+            Err(e) => return Err(EtcdFdwError::UpdateError(e.to_string())),
+        };
+        self.ttl_leases.insert(ttl, lease);
+        Ok(Some(lease))
+    }
+
+    /// Update a row of a table with `layout 'sub_keys'` in one transaction
+    /// Non-NULL columns are put, NULL columns are deleted
+    fn update_sub_keys(&mut self, rowid: &Cell, new_row: &Row) -> EtcdFdwResult<()> {
+        let row_key = self.sub_key_row(rowid)?;
+        if let Some(new_key) = self.updated_key() {
+            if self.sub_key_row(&new_key)? != row_key {
+                return Err(EtcdFdwError::SubKeyRename);
+            }
+        }
+
+        let mut ops = Vec::new();
+        for (name, cell) in new_row.cols.iter().zip(&new_row.cells) {
+            if *name == self.key_column {
+                continue;
+            }
+            let sub_key = format!("{}/{}", row_key, name);
+            forget_cached_key(sub_key.as_bytes());
+            let pending_lease = self.write_lease.filter(|lease| {
+                with_pending_lease(*lease, |keys| keys.iter().any(|k| k == sub_key.as_bytes())) == Some(true)
+            });
+            ops.push(match cell {
+                Some(cell) => {
+                    // A plain put would detach a sub-key inserted under the write lease
+                    let put_options = pending_lease.map(|lease| PutOptions::new().with_lease(lease));
+                    let value = cell.to_string();
+                    TxnOp::put(sub_key.as_str(), value.trim_matches(|x| x == '\''), put_options)
+                }
+                None => {
+                    if let Some(lease) = pending_lease {
+                        with_pending_lease(lease, |keys| keys.retain(|k| k != sub_key.as_bytes()));
+                    }
+                    TxnOp::delete(sub_key.as_str(), None)
+                }
+            });
+        }
+
+        // A range comparison without keys compares against a missing key, whose create revision is 0
+        let txn = Txn::new()
+            .when([Compare::create_revision(format!("{}/", row_key), CompareOp::Greater, 0).with_prefix()])
+            .and_then(ops);
+        match self.rt.block_on(self.client.txn(txn)) {
+            Ok(resp) if resp.succeeded() => Ok(()),
+            Ok(_) => Err(EtcdFdwError::KeyDoesntExist(row_key)),
+            Err(e) => Err(EtcdFdwError::UpdateError(e.to_string())),
+        }
+    }
+
+    /// Delete all sub-keys of a row of a table with `layout 'sub_keys'`
+    fn delete_sub_keys(&mut self, rowid: &Cell) -> EtcdFdwResult<()> {
+        let row_key = self.sub_key_row(rowid)?;
+        let row_prefix = format!("{}/", row_key);
+        if let Some(lease) = self.write_lease {
+            with_pending_lease(lease, |keys| keys.retain(|k| !k.starts_with(row_prefix.as_bytes())));
+        }
+
+        let resp = match self
+            .rt
+            .block_on(self.client.delete(row_prefix.as_str(), Some(DeleteOptions::new().with_prefix().with_prev_key())))
+        {
+            Ok(x) => x,
+            Err(e) => return Err(EtcdFdwError::UpdateError(e.to_string())),
+        };
+        for kv in resp.prev_kvs() {
+            forget_cached_key(kv.key());
+        }
+        if resp.deleted() == 0 {
+            return Err(EtcdFdwError::KeyDoesntExist(row_key));
+        }
+        Ok(())
+    }
+}
+
+impl ForeignDataWrapper<EtcdFdwError> for EtcdFdw {
+    fn new(server: ForeignServer) -> EtcdFdwResult<EtcdFdw> {
+        // Open connection to etcd specified through the server parameter
+        let rt = runtime();
+        sync_request_limit();
+
+        let user_mapping = user_mapping_options(server.server_oid);
+        let config = EtcdConfig::from_options(&server.options, &user_mapping)?;
+        let role_prefix = user_mapping.get("prefix").cloned();
+        let hedge_delay = match server.options.get("hedge_delay") {
+            Some(v) => Some(parse_hedge_delay(v)?),
+            None => None,
+        };
+
+        // set by `get_foreign_rel_size` when planning a scan
+        let planned = unsafe { take_planned_table(server.server_oid) };
+
+        // With on_unavailable 'empty' an unreachable cluster is only reported when the scan starts
+        let mut unavailable = None;
+        let client = match rt.block_on(connect_etcd(config.clone())) {
+            Ok(x) => x,
+            Err(e) if planned.empty_when_unavailable && is_unavailable(&e) => {
+                unavailable = Some(e.to_string());
+                match rt.block_on(unavailable_client(e.to_string())) {
+                    Ok(x) => x,
+                    Err(e) => return Err(EtcdFdwError::ClientConnectionError(e.to_string())),
+                }
+            }
+            Err(e) => return Err(EtcdFdwError::ClientConnectionError(e.to_string())),
+        };
+
+        let fetch_results = vec![];
+
+        Ok(Self {
+            client,
+            rt,
+            fetch_results,
+            scan_pages: None,
+            prefix_scans: None,
+            counted_rows: 0,
+            stats: Rc::default(),
+            scan_args: None,
+            tgt_cols: Vec::new(),
+            key_column: "key".to_string(),
+            value_column: "value".to_string(),
+            key_segments: None,
+            segment_columns: Vec::new(),
+            rowid_is_key: true,
+            jsonpaths: HashMap::new(),
+            gzip_values: false,
+            empty_as_null: false,
+            exclude_prefixes: Vec::new(),
+            key_regex: None,
+            value_format: ValueFormat::Text,
+            fetch_key: false,
+            fetch_value: false,
+            server_options: server.options,
+            key_order_bytewise: planned.key_order_bytewise,
+            key_collation: planned.key_collation,
+            value_order_bytewise: planned.value_order_bytewise,
+            key_encoding: None,
+            role_prefix,
+            config,
+            write_lease: None,
+            on_conflict: OnConflict::default(),
+            hedge_delay: hedge_delay,
+            skip_scan_errors: false,
+            unavailable,
+            planned_for_select: planned.select,
+            planned_for_delete: planned.delete,
+            modify_batch_size: MAX_TXN_OPS,
+            pending_writes: Vec::new(),
+            pending_inserts: Vec::new(),
+            lease_id: None,
+            ttl_leases: HashMap::new(),
+            range_deleted: Vec::new(),
+            sub_keys: false,
+            pivoted_rows: Vec::new(),
+            lease_ttls: RefCell::new(HashMap::new()),
+            modify_lock: None,
+            relid: planned.relid,
+            key_range_commuted: planned.key_range_commuted,
+            revision_range_commuted: planned.revision_range_commuted,
+            value_in_conditions: planned.value_in_conditions,
+            conditions: planned.conditions,
+            limit_applies: planned.limit_applies,
+            sort_keys: planned.sort_keys,
+            first_sort_on_table: planned.first_sort_on_table,
+        })
+    }
+
+    fn begin_scan(
+        &mut self,
+        quals: &[Qual],
+        columns: &[Column],
+        sort: &[Sort],
+        limit: &Option<Limit>,
+        options: &std::collections::HashMap<String, String>,
+    ) -> Result<(), EtcdFdwError> {
+        self.scan_args = Some((quals.to_vec(), columns.to_vec(), sort.to_vec(), limit.clone(), options.clone()));
+        self.stats.set(ScanStats::default());
+        BEGUN_SCAN_STATS.set(Some(self.stats.clone()));
+        let empty_when_unavailable = match options.get("on_unavailable") {
+            Some(v) => parse_on_unavailable(v)?,
+            None => false,
+        };
+
+        // Parameterized scans of a nested loop join read the key of the outer row,
+        // nothing before the first outer row and nothing for a NULL
+        let mut quals = quals.to_vec();
+        match JOIN_PARAMS.take() {
+            Some(JoinParams::Values(values)) if values.iter().all(Option::is_some) => {
+                let (key_column, _) = column_names(options);
+                quals.extend(values.into_iter().flatten().map(|cell| Qual {
+                    field: key_column.clone(),
+                    operator: "=".to_string(),
+                    value: Value::Cell(cell),
+                    use_or: false,
+                    param: None,
+                }));
+            }
+            None => {}
+            pending_or_null => {
+                SCAN_REQUEST.set(Some(match pending_or_null {
+                    Some(JoinParams::Pending) => "get of the joined key per outer row".to_string(),
+                    _ => "none".to_string(),
+                }));
+                self.fetch_results = vec![];
+                self.scan_pages = None;
+                self.counted_rows = 0;
+                self.pivoted_rows = vec![];
+                self.tgt_cols = columns.to_vec();
+                return Ok(());
+            }
+        }
+        let quals = &quals[..];
+
+        // Render an empty table instead of failing the whole statement during an etcd outage
+        match self.start_scan(quals, columns, sort, limit, options) {
+            Err(EtcdFdwError::EtcdUnavailable(e)) if empty_when_unavailable => {
+                warning!("etcd is unavailable, scanning no rows: {}", e);
+                self.fetch_results = vec![];
+                self.tgt_cols = columns.to_vec();
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn iter_scan(&mut self, row: &mut Row) -> EtcdFdwResult<Option<()>> {
+        // The default_limit of a table with several prefixes bounds the rows of all of them
+        if self.prefix_scans.as_ref().and_then(|scans| scans.rows_left) == Some(0) {
+            return Ok(None);
+        }
+        let result = self.next_row(row)?;
+        if let (Some(()), Some(scans)) = (result, &mut self.prefix_scans) {
+            scans.rows_left = scans.rows_left.map(|n| n - 1);
+        }
+        Ok(result)
+    }
+
+    fn end_scan(&mut self) -> EtcdFdwResult<()> {
+        self.fetch_results = vec![];
+        self.scan_pages = None;
+        self.prefix_scans = None;
+        self.counted_rows = 0;
+        self.pivoted_rows = vec![];
+        self.fetch_key = false;
+        self.fetch_value = false;
+        Ok(())
+    }
+
+    fn re_scan(&mut self) -> EtcdFdwResult<()> {
+        let Some((quals, columns, sort, limit, options)) = self.scan_args.clone() else {
+            return Ok(());
+        };
+        // The statistics of all scans of a node add up, as the rows of its loops do
+        let stats = self.stats.get();
+        self.end_scan()?;
+        let result = self.begin_scan(&quals, &columns, &sort, &limit, &options);
+        BEGUN_SCAN_STATS.set(None);
+        self.stats.set(stats.add(self.stats.get()));
+        result
+    }
+
+    fn begin_modify(
+        &mut self,
+        options: &std::collections::HashMap<String, String>,
+    ) -> Result<(), EtcdFdwError> {
+        let options = &expand_prefix(&resolve_options(&self.server_options, options))?;
+        self.key_encoding = KeyEncoding::from_options(options)?;
+        (self.key_column, self.value_column) = column_names(options);
+        self.gzip_values = match options.get("value_compression") {
+            Some(v) => parse_value_compression(v)?,
+            None => false,
+        };
+        self.value_format = ValueFormat::from_options(options)?;
+        self.empty_as_null = match options.get("empty_as_null") {
+            Some(v) => parse_empty_as_null(v)?,
+            None => false,
+        };
+        if matches!(self.value_format, ValueFormat::Kubernetes(_)) {
+            return Err(EtcdFdwError::KubernetesReadOnly);
+        }
+        self.jsonpaths = unsafe { column_jsonpaths(self.relid)? };
+        self.key_segments = KeySegments::from_options(options);
+        self.rowid_is_key = options.get("rowid_column") == Some(&self.key_column);
+        self.on_conflict = match options.get("on_conflict") {
+            Some(v) => parse_on_conflict(v)?,
+            None => OnConflict::default(),
+        };
+        self.modify_batch_size = match options.get("modify_batch_size") {
+            Some(v) => parse_modify_batch_size(v)?,
+            None => MAX_TXN_OPS,
+        };
+        self.sub_keys = match options.get("layout") {
+            Some(v) => parse_layout(v)?,
+            None => false,
+        };
+
+        // Competing writers of the table's keys are serialized by a lock named after each of its prefixes
+        let lock = match options.get("modify_lock") {
+            Some(v) => parse_modify_lock(v)?,
+            None => false,
+        };
+        if lock {
+            let mut scopes = option_prefixes(options);
+            if scopes.is_empty() {
+                scopes.push(options.get("key").cloned().unwrap_or_default());
+            }
+            let names = scopes.iter().map(|scope| format!("{}{}", MODIFY_LOCK_PREFIX, scope)).collect();
+            self.modify_lock = Some(self.acquire_lock(names)?);
+        }
+
+        self.lease_id = match options.get("lease_id") {
+            Some(v) => Some(parse_lease_id(v)?),
+            None => None,
+        };
+        self.ttl_leases.clear();
+
+        // Inserts of this statement are attached to a lease until the transaction commits
+        self.write_lease = None;
+        if let Some(ttl) = options.get("write_lease_ttl") {
+            let ttl = parse_write_lease_ttl(ttl)?;
+            // Keys are detached from the lease with IgnoreValue puts
+            self.require_version("option write_lease_ttl", (3, 3))?;
+            let lease = match self.rt.block_on(self.client.lease_grant(ttl, None)) {
+                Ok(x) => x.id(),
+                Err(e) => return Err(EtcdFdwError::UpdateError(e.to_string())),
+            };
+            track_write_lease(self.config.clone(), lease);
+            self.write_lease = Some(lease);
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, row: &Row) -> Result<(), EtcdFdwError> {
+        if self.sub_keys {
+            return self.insert_sub_keys(row);
+        }
+
+        let key_cell = row
+            .cols
+            .iter()
+            .zip(row.cells.clone())
+            .filter(|(name, _cell)| **name == self.key_column)
+            .last();
+        let key_bytes = match (key_cell, &self.key_segments) {
+            (Some((_, Some(cell))), _) => self.etcd_key(&cell)?,
+            // Without a key, it is assembled from the segment columns
+            (_, Some(segments)) => self.segment_key(segments, row)?.into_bytes(),
+            (Some(x), None) => self.etcd_key(&x.1.expect("The key column should be present"))?,
+            (None, None) => return Err(EtcdFdwError::MissingColumn(self.key_column.clone())),
+        };
+        let key = key_bytes.as_slice();
+        let value = self.row_value(row)?;
+        self.check_role_prefix(key)?;
+        forget_cached_key(key);
+
+        let row_lease = self.row_lease(row)?;
+
+        if !MODIFY_RETURNING.get() {
+            return self.buffer_insert(key, value, row_lease);
+        }
+
+        if self.on_conflict == OnConflict::Overwrite {
+            return self.overwrite(key, value, row_lease);
+        }
+
+        // A lease of the row keeps the key attached, the write lease only until commit
+        let lease = row_lease.or(self.write_lease);
+        let mut put_options = PutOptions::new();
+        if let Some(lease) = lease {
+            put_options = put_options.with_lease(lease);
+        }
+
+        // Only put the key if it doesn't exist yet, either way read the key as it is after the txn
+        let txn = Txn::new()
+            .when([Compare::create_revision(key, CompareOp::Equal, 0)])
+            .and_then([TxnOp::put(key, value, Some(put_options)), TxnOp::get(key, None)])
+            .or_else([TxnOp::get(key, None)]);
+        let resp = match self.rt.block_on(self.client.txn(txn)) {
+            Ok(x) => x,
+            Err(e) => return Err(EtcdFdwError::UpdateError(e.to_string())),
+        };
+
+        if resp.succeeded() {
+            // The key didn't exist before, it was created by this put
+            if let (Some(lease), None) = (self.write_lease, row_lease) {
+                with_pending_lease(lease, |keys| keys.push(key.to_vec()));
+            }
+        } else if self.on_conflict != OnConflict::Ignore {
+            return Err(EtcdFdwError::KeyAlreadyExists(String::from_utf8_lossy(key).into_owned()));
+        }
+        if let Some(TxnOpResponse::Get(written)) = resp.op_responses().last() {
+            if let Some(kv) = written.kvs().first() {
+                LAST_WRITE.set(Some(WrittenKey {
+                    create_revision: kv.create_revision(),
+                    mod_revision: kv.mod_revision(),
+                    version: kv.version(),
+                    lease: kv.lease(),
+                    created: resp.succeeded(),
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, rowid: &Cell, new_row: &Row) -> Result<(), EtcdFdwError> {
+        if self.sub_keys {
+            return self.update_sub_keys(rowid, new_row);
+        }
+
+        if self.key_segments.is_some() && !self.rowid_is_key {
+            return Err(EtcdFdwError::SegmentRowid);
+        }
+        let key_bytes = self.etcd_key(rowid)?;
+        let key = key_bytes.as_slice();
+        self.check_role_prefix(key)?;
+        forget_cached_key(key);
+
+        // The existence of the key is compared in the transaction that writes it
+        // RETURNING needs the metadata of every row, so its rows aren't batched
+        // Neither are rows whose conditions are compared again
+        let mut compares = self.write_compares(key);
+
+        // Without the value column the assigned `jsonpath` fields are merged into the stored document,
+        // which mustn't change until the merged one is written
+        let value = if self.assigns_value(new_row) {
+            Some(self.row_value(new_row)?)
+        } else if self.assigns_jsonpath(new_row) {
+            let current = self.current_entry(key)?;
+            compares.push(Compare::mod_revision(key, CompareOp::Equal, current.mod_revision()));
+            Some(self.merged_row_value(&current, new_row)?)
+        } else {
+            None
+        };
+        let batched = !MODIFY_RETURNING.get() && compares.is_empty();
+
+        // Without the lease or ttl column the key stays attached to the lease it has,
+        // be it the lease of the row or the write lease it was inserted under
+        let keeps_lease = !self.assigns_lease(new_row);
+        let lease = match keeps_lease {
+            true => None,
+            false => self.row_lease(new_row)?,
+        };
+
+        if let Some(new_key) = self.updated_key() {
+            let new_key = self.etcd_key(&new_key)?;
+            if new_key != key {
+                // A renamed key keeps its stored value and lease as they are unless the row assigns new ones
+                let (value, lease) = match (value, keeps_lease) {
+                    (Some(value), false) => (value, lease),
+                    (value, keeps_lease) => {
+                        let current = self.current_entry(key)?;
+                        compares.push(Compare::mod_revision(key, CompareOp::Equal, current.mod_revision()));
+                        let lease = match keeps_lease {
+                            true => Some(current.lease()).filter(|lease| *lease != 0),
+                            false => lease,
+                        };
+                        (value.unwrap_or_else(|| current.value().to_vec()), lease)
+                    }
+                };
+                return self.rename(key, &new_key, value, lease, compares);
+            }
+        }
+
+        let mut put_options = match (lease, keeps_lease) {
+            (Some(lease), _) => PutOptions::new().with_lease(lease),
+            (None, true) => PutOptions::new().with_ignore_lease(),
+            (None, false) => PutOptions::new(),
+        };
+        // Updates of the lease alone keep the stored value
+        let value = match value {
+            Some(value) => value,
+            None => {
+                put_options = put_options.with_ignore_value();
+                Vec::new()
+            }
+        };
+
+        if batched {
+            return self.batch_write(key, TxnOp::put(key, value, Some(put_options)));
+        }
+
+        // RETURNING returns the row as etcd holds it after the put, read in the same transaction
+        let put_options = put_options.with_prev_key();
+        let mut ops = vec![TxnOp::put(key, value, Some(put_options))];
+        if MODIFY_RETURNING.get() {
+            ops.push(TxnOp::get(key, None));
+        }
+        let (revision, responses) = self.compared_write(key, compares, ops)?;
+        if let Some(TxnOpResponse::Put(put)) = responses.first() {
+            let lease = match keeps_lease {
+                true => put.prev_key().map(|kv| kv.lease()).unwrap_or(0),
+                false => lease.unwrap_or(0),
+            };
+            LAST_WRITE.set(Some(WrittenKey {
+                created: false,
+                ..replaced_key(put.prev_key(), revision, lease)
+            }));
+        }
+        if let Some(TxnOpResponse::Get(written)) = responses.get(1) {
+            if let Some(kv) = written.kvs().first() {
+                self.store_returned(kv)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, rowid: &Cell) -> Result<(), EtcdFdwError> {
+        if self.sub_keys {
+            return self.delete_sub_keys(rowid);
+        }
+
+        if self.key_segments.is_some() && !self.rowid_is_key {
+            return Err(EtcdFdwError::SegmentRowid);
+        }
+        let key_bytes = self.etcd_key(rowid)?;
+        let key = key_bytes.as_slice();
+        self.check_role_prefix(key)?;
+        forget_cached_key(key);
+
+        // Deleted keys are no longer detached at commit
+        if let Some(lease) = self.write_lease {
+            with_pending_lease(lease, |keys| keys.retain(|k| k != key));
+        }
+
+        let compares = self.write_compares(key);
+        if !compares.is_empty() {
+            let delete_options = DeleteOptions::new().with_prev_key();
+            let (_, responses) = self.compared_write(key, compares, vec![TxnOp::delete(key, Some(delete_options))])?;
+            return match responses.first() {
+                Some(TxnOpResponse::Delete(deleted)) if MODIFY_RETURNING.get() => match deleted.prev_kvs().first() {
+                    Some(kv) => self.store_returned(kv),
+                    None => Err(EtcdFdwError::KeyDoesntExist(String::from_utf8_lossy(key).into_owned())),
+                },
+                _ => Ok(()),
+            };
+        }
+
+        if MODIFY_RETURNING.get() {
+            return self.delete_returning(key);
+        }
+
+        if RANGE_DELETE.with_borrow(|range| range.as_ref().is_some_and(|r| r.contains(key))) {
+            self.range_deleted.push(key.to_vec());
+            return Ok(());
+        }
+
+        // Deletes of keys that don't exist anymore are reported when the batch is sent
+        self.batch_write(key, TxnOp::delete(key, None))
+    }
+
+    fn get_rel_size(
+        &mut self,
+        _quals: &[Qual],
+        _columns: &[Column],
+        _sorts: &[Sort],
+        _limit: &Option<Limit>,
+        options: &std::collections::HashMap<String, String>,
+    ) -> Result<(i64, i32), EtcdFdwError> {
+        // Sticky row count estimate for the planner, set through the `n_rows` table option
+        if let Some(v) = options.get("n_rows") {
+            return Ok((parse_n_rows(v)?, 0));
+        }
+        // Otherwise etcd counts the keys of the table, the planner applies the selectivity of the conditions
+        // An unreachable etcd leaves the estimate to the planner, the scan reports the error
+        if self.unavailable.is_some() {
+            return Ok((0, 0));
+        }
+        let options = &expand_prefix(&resolve_options(&self.server_options, options))?;
+        Ok(self.estimate_rel_size(options).unwrap_or((0, 0)))
+    }
+
+    fn end_modify(&mut self) -> Result<(), EtcdFdwError> {
+        self.flush_inserts()?;
+        self.flush_range_delete()?;
+        self.flush_writes()?;
+        WRITE_CONDITIONS.set(None);
+        match self.modify_lock.take() {
+            Some(lease) => release_lock(lease),
+            None => Ok(()),
+        }
+    }
+
+    fn fdw_routine_hook(routine: &mut supabase_wrappers::FdwRoutine<AllocatedByRust>) {
+        let _ = FRAMEWORK_GET_FOREIGN_REL_SIZE.set(routine.GetForeignRelSize);
+        routine.GetForeignRelSize = Some(get_foreign_rel_size);
+        let _ = FRAMEWORK_GET_FOREIGN_PATHS.set(routine.GetForeignPaths);
+        routine.GetForeignPaths = Some(get_foreign_paths);
+        let _ = FRAMEWORK_GET_FOREIGN_PLAN.set(routine.GetForeignPlan);
+        routine.GetForeignPlan = Some(get_foreign_plan);
+        let _ = FRAMEWORK_BEGIN_FOREIGN_SCAN.set(routine.BeginForeignScan);
+        routine.BeginForeignScan = Some(begin_foreign_scan);
+        let _ = FRAMEWORK_RE_SCAN_FOREIGN_SCAN.set(routine.ReScanForeignScan);
+        routine.ReScanForeignScan = Some(re_scan_foreign_scan);
+        let _ = FRAMEWORK_EXPLAIN_FOREIGN_SCAN.set(routine.ExplainForeignScan);
+        routine.ExplainForeignScan = Some(explain_foreign_scan);
+        let _ = FRAMEWORK_END_FOREIGN_SCAN.set(routine.EndForeignScan);
+        routine.EndForeignScan = Some(end_foreign_scan);
+        let _ = FRAMEWORK_PLAN_FOREIGN_MODIFY.set(routine.PlanForeignModify);
+        routine.PlanForeignModify = Some(plan_foreign_modify);
+        let _ = FRAMEWORK_EXEC_FOREIGN_INSERT.set(routine.ExecForeignInsert);
+        routine.ExecForeignInsert = Some(exec_foreign_insert);
+        let _ = FRAMEWORK_EXEC_FOREIGN_UPDATE.set(routine.ExecForeignUpdate);
+        routine.ExecForeignUpdate = Some(exec_foreign_update);
+        let _ = FRAMEWORK_EXEC_FOREIGN_DELETE.set(routine.ExecForeignDelete);
+        routine.ExecForeignDelete = Some(exec_foreign_delete);
+        #[cfg(not(feature = "pg13"))]
+        {
+            routine.ExecForeignTruncate = Some(exec_foreign_truncate);
+        }
+        let _ = FRAMEWORK_BEGIN_FOREIGN_MODIFY.set(routine.BeginForeignModify);
+        let _ = FRAMEWORK_END_FOREIGN_MODIFY.set(routine.EndForeignModify);
+        routine.BeginForeignInsert = Some(begin_foreign_insert);
+        routine.EndForeignInsert = Some(end_foreign_insert);
+    }
+
+    fn validator(options: Vec<Option<String>>, catalog: Option<pg_sys::Oid>) -> EtcdFdwResult<()> {
+        if let Some(oid) = catalog {
+            if oid == FOREIGN_SERVER_RELATION_ID {
+                check_known_options(&options, &SERVER_OPTIONS)?;
+                check_options_contain(&options, "connstr")?;
+                check_layered_options(&options)?;
+
+                if let Some(load_balancing) = option_value(&options, "load_balancing") {
+                    parse_load_balancing(load_balancing)?;
+                }
+
+                if let Some(max_inflight) = option_value(&options, "max_inflight_requests") {
+                    parse_max_inflight_requests(max_inflight)?;
+                }
+
+                if let Some(hedge_delay) = option_value(&options, "hedge_delay") {
+                    parse_hedge_delay(hedge_delay)?;
+                }
+
+                if let Some(tls_backend) = option_value(&options, "tls_backend") {
+                    parse_tls_backend(tls_backend)?;
+                }
+
+                let cacert_path_exists = check_options_contain(&options, "ssl_ca").is_ok();
+                let cert_path_exists = check_options_contain(&options, "ssl_cert").is_ok();
+
+                require_pair(cacert_path_exists, cert_path_exists, EtcdFdwError::CertKeyMismatch(()))?;
+            } else if oid == FOREIGN_TABLE_RELATION_ID {
+                check_known_options(&options, &TABLE_OPTIONS)?;
+                check_options_contain(&options, "rowid_column")?;
+                check_layered_options(&options)?;
+
+                let prefix_exists = check_options_contain(&options, "prefix").is_ok();
+                let prefixes_exists = check_options_contain(&options, "prefixes").is_ok();
+                let rannge_exists = check_options_contain(&options, "range_end").is_ok();
+                let key_exists = check_options_contain(&options, "key").is_ok();
+
+                if prefix_exists && prefixes_exists {
+                    return Err(EtcdFdwError::ConflictingPrefixAndPrefixes);
+                }
+
+                if (prefix_exists || prefixes_exists) && rannge_exists {
+                    return Err(EtcdFdwError::ConflictingPrefixAndRange);
+                }
+
+                if (prefix_exists || prefixes_exists) && key_exists {
+                    return Err(EtcdFdwError::ConflictingPrefixAndKey);
+                }
+
+                if let Some(prefix) = option_value(&options, "prefix") {
+                    expand_placeholders(prefix, |_| Ok(String::new()))?;
+                }
+
+                if let Some(prefixes) = option_value(&options, "prefixes") {
+                    for prefix in prefixes.split(',') {
+                        expand_placeholders(prefix, |_| Ok(String::new()))?;
+                    }
+                    // keys are encoded, split and grouped below a single prefix
+                    let single_prefix = option_value(&options, "key_pad").is_some()
+                        || option_value(&options, "key_encoding").is_some_and(|v| v != "none")
+                        || option_value(&options, "strip_prefix").is_some_and(|v| v == "true")
+                        || option_value(&options, "key_delimiter").is_some()
+                        || option_value(&options, "layout").is_some_and(|v| v == "sub_keys");
+                    if single_prefix && split_prefixes(prefixes).len() > 1 {
+                        return Err(EtcdFdwError::InvalidOption("prefixes".to_string(), prefixes.to_string()));
+                    }
+                }
+
+                if let Some(empty_as_null) = option_value(&options, "empty_as_null") {
+                    parse_empty_as_null(empty_as_null)?;
+                }
+
+                if let Some(key_regex) = option_value(&options, "key_regex") {
+                    // an invalid regular expression raises its error
+                    KeyPattern::Regex(key_regex.to_string()).matches("", pg_sys::C_COLLATION_OID);
+                }
+
+                if let Some(revision) = option_value(&options, "revision") {
+                    if !revision.parse::<i64>().is_ok_and(|r| r >= 0) {
+                        return Err(EtcdFdwError::InvalidOption("revision".to_string(), revision.to_string()));
+                    }
+                }
+
+                if let Some(n_rows) = option_value(&options, "n_rows") {
+                    parse_n_rows(n_rows)?;
+                }
+
+                if let Some(startup_cost) = option_value(&options, "startup_cost") {
+                    parse_startup_cost(startup_cost)?;
+                }
+
+                if let Some(key_pad) = option_value(&options, "key_pad") {
+                    parse_key_pad(key_pad)?;
+                }
+
+                if let Some(layout) = option_value(&options, "layout") {
+                    // Sub-keys are found below the prefix, row keys aren't encoded
+                    let encoded = option_value(&options, "key_pad").is_some()
+                        || option_value(&options, "key_encoding").is_some_and(|v| v != "none")
+                        || option_value(&options, "strip_prefix").is_some_and(|v| v == "true");
+                    if parse_layout(layout)? && (!prefix_exists || encoded) {
+                        return Err(EtcdFdwError::InvalidOption("layout".to_string(), layout.to_string()));
+                    }
+                }
+
+                if let Some(value_compression) = option_value(&options, "value_compression") {
+                    parse_value_compression(value_compression)?;
+                }
+
+                if let Some(k8s_mode) = option_value(&options, "k8s_mode") {
+                    // Kubernetes objects have a format of their own
+                    if parse_k8s_mode(k8s_mode)? && option_value(&options, "value_format").is_some() {
+                        return Err(EtcdFdwError::InvalidOption("k8s_mode".to_string(), k8s_mode.to_string()));
+                    }
+                    if let Some(descriptor) = option_value(&options, "proto_descriptor") {
+                        proto_pool(descriptor)?;
+                    }
+                }
+
+                if let Some(value_format) = option_value(&options, "value_format") {
+                    parse_value_format(
+                        value_format,
+                        option_value(&options, "proto_descriptor"),
+                        option_value(&options, "proto_message"),
+                    )?;
+                }
+
+                if let Some(key_delimiter) = option_value(&options, "key_delimiter") {
+                    // Segments are split from the keys as they are stored
+                    let encoded = option_value(&options, "key_pad").is_some()
+                        || option_value(&options, "key_encoding").is_some_and(|v| v != "none")
+                        || option_value(&options, "strip_prefix").is_some_and(|v| v == "true")
+                        || option_value(&options, "layout").is_some_and(|v| v == "sub_keys");
+                    if key_delimiter.is_empty() || encoded {
+                        return Err(EtcdFdwError::InvalidOption("key_delimiter".to_string(), key_delimiter.to_string()));
+                    }
+                }
+
+                if let Some(key_encoding) = option_value(&options, "key_encoding") {
+                    if parse_key_encoding(key_encoding)? && option_value(&options, "key_pad").is_some() {
+                        return Err(EtcdFdwError::InvalidOption("key_encoding".to_string(), key_encoding.to_string()));
+                    }
+                }
+
+                if let Some(strip_prefix) = option_value(&options, "strip_prefix") {
+                    // padded and percent-encoded keys are already relative to the prefix
+                    let encoded = option_value(&options, "key_pad").is_some()
+                        || option_value(&options, "key_encoding").is_some_and(|v| v != "none");
+                    if parse_strip_prefix(strip_prefix)? && encoded {
+                        return Err(EtcdFdwError::InvalidOption("strip_prefix".to_string(), strip_prefix.to_string()));
+                    }
+                }
+
+                if let Some(ttl) = option_value(&options, "write_lease_ttl") {
+                    parse_write_lease_ttl(ttl)?;
+                }
+
+                if let Some(lease) = option_value(&options, "lease_id") {
+                    parse_lease_id(lease)?;
+                    // keys attached to the lease would be detached again at commit
+                    if option_value(&options, "write_lease_ttl").is_some() {
+                        return Err(EtcdFdwError::InvalidOption("lease_id".to_string(), lease.to_string()));
+                    }
+                }
+
+                if let Some(on_scan_error) = option_value(&options, "on_scan_error") {
+                    parse_on_scan_error(on_scan_error)?;
+                }
+
+                if let Some(on_unavailable) = option_value(&options, "on_unavailable") {
+                    parse_on_unavailable(on_unavailable)?;
+                }
+
+                if let Some(point_cache) = option_value(&options, "point_cache") {
+                    parse_point_cache(point_cache)?;
+                }
+
+                if let Some(cache_ttl) = option_value(&options, "cache_ttl") {
+                    parse_cache_ttl(cache_ttl)?;
+                }
+
+                if let Some(on_conflict) = option_value(&options, "on_conflict") {
+                    parse_on_conflict(on_conflict)?;
+                }
+
+                if let Some(modify_lock) = option_value(&options, "modify_lock") {
+                    parse_modify_lock(modify_lock)?;
+                }
+
+                if let Some(batch_size) = option_value(&options, "modify_batch_size") {
+                    parse_modify_batch_size(batch_size)?;
+                }
+
+                if let Some(default_limit) = option_value(&options, "default_limit") {
+                    parse_default_limit(default_limit)?;
+                }
+
+                if let Some(default_sort) = option_value(&options, "default_sort") {
+                    parse_default_sort(default_sort)?;
+                }
+            } else if oid == pg_sys::BuiltinOid::AttributeRelationId.value() {
+                check_known_options(&options, &COLUMN_OPTIONS)?;
+
+                if let Some(jsonpath) = option_value(&options, "jsonpath") {
+                    parse_jsonpath(jsonpath)?;
+                }
+            } else if oid == pg_sys::BuiltinOid::UserMappingRelationId.value() {
+                check_known_options(&options, &USER_MAPPING_OPTIONS)?;
+
+                let user_exists = check_options_contain(&options, "user").is_ok();
+                let password_exists = check_options_contain(&options, "password").is_ok();
+
+                require_pair(user_exists, password_exists, EtcdFdwError::UserPassMismatch(()))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Look up a foreign server by name
+fn foreign_server(server_name: &str) -> EtcdFdwResult<ForeignServer> {
+    let name = CString::new(server_name)
+        .map_err(|_| EtcdFdwError::InvalidOption("server".to_string(), server_name.to_string()))?;
+    unsafe {
+        let fserver = pg_sys::GetForeignServerByName(name.as_ptr(), false);
+        Ok(ForeignServer {
+            server_name: server_name.to_string(),
+            server_oid: (*fserver).serverid,
+            options: def_elem_options((*fserver).options),
+            ..Default::default()
+        })
+    }
+}
+
+/// Connect to the etcd cluster of a foreign server with the current user's mapping
+fn connect_server(server_name: &str) -> EtcdFdwResult<EtcdFdw> {
+    EtcdFdw::new(foreign_server(server_name)?)
+}
+
+/// Raise errors of the SQL functions like the ones of the foreign data wrapper
+fn report_error<T>(result: EtcdFdwResult<T>) -> T {
+    match result {
+        Ok(x) => x,
+        Err(e) => {
+            ErrorReport::from(e).report(PgLogLevel::ERROR);
+            unreachable!("ERROR reports don't return")
+        }
+    }
+}
+
+/// Revision of the etcd store the latest scan of an etcd foreign table in this session read at
+#[pg_extern]
+fn etcd_last_read_revision() -> Option<i64> {
+    match LAST_READ_REVISION.get() {
+        0 => None,
+        revision => Some(revision),
+    }
+}
+
+/// Header of the latest response a scan of an etcd foreign table in this session received
+/// The cluster and member IDs are hexadecimal, like etcdctl shows them. Scans answered from a cache don't change it
+#[pg_extern]
+fn etcd_last_read_header() -> TableIterator<
+    'static,
+    (
+        name!(cluster_id, String),
+        name!(member_id, String),
+        name!(raft_term, i64),
+        name!(revision, i64),
+    ),
+> {
+    TableIterator::new(LAST_READ_HEADER.get().into_iter().map(|h| {
+        (format!("{:x}", h.cluster_id), format!("{:x}", h.member_id), h.raft_term as i64, h.revision)
+    }))
+}
+
+/// Version of the etcd cluster of a foreign server, i.e. `3.5.12`
+#[pg_extern]
+fn etcd_server_version(server: &str) -> String {
+    let mut fdw = report_error(connect_server(server));
+    report_error(fdw.server_version())
+}
+
+/// Result of a check of `etcd_fdw_selftest()`: check name, target, passed and details
+type SelftestRow = (String, String, bool, String);
+
+/// Prefixes the foreign tables of a server read, with their placeholders expanded, within their namespace and split
+fn table_prefixes(server: &ForeignServer) -> EtcdFdwResult<Vec<String>> {
+    let query = format!(
+        "SELECT DISTINCT \
+         (SELECT option_value FROM pg_options_to_table(ftoptions) WHERE option_name = 'prefix'), \
+         (SELECT option_value FROM pg_options_to_table(ftoptions) WHERE option_name = 'prefixes'), \
+         (SELECT option_value FROM pg_options_to_table(ftoptions) WHERE option_name = 'namespace') \
+         FROM pg_foreign_table WHERE ftserver = {}",
+        server.server_oid.to_u32()
+    );
+    let tables: Vec<HashMap<String, String>> = Spi::connect(|client| {
+        client.select(query.as_str(), None, &[]).map(|table| {
+            table
+                .map(|row| {
+                    let mut options = HashMap::new();
+                    for (i, name) in [(1, "prefix"), (2, "prefixes"), (3, "namespace")] {
+                        if let Ok(Some(value)) = row.get::<String>(i) {
+                            options.insert(name.to_string(), value);
+                        }
+                    }
+                    options
+                })
+                .collect()
+        })
+    })
+    .map_err(|e| EtcdFdwError::FetchError(e.to_string()))?;
+
+    let mut prefixes = Vec::new();
+    for table in tables {
+        let options = HashMap::from_iter(
+            resolve_options(&server.options, &table)
+                .into_iter()
+                .filter(|(name, _)| name == "prefix" || name == "prefixes" || name == "namespace"),
+        );
+        // tables of single keys don't read a prefix
+        prefixes.extend(option_prefixes(&expand_prefix(&options)?));
+    }
+    Ok(prefixes)
+}
+
+/// Run the checks of `etcd_fdw_selftest()`
+/// Checks that can't run because an earlier one failed are left out
+fn selftest(server_name: &str) -> EtcdFdwResult<Vec<SelftestRow>> {
+    let server = foreign_server(server_name)?;
+    let user_mapping = user_mapping_options(server.server_oid);
+    let config = EtcdConfig::from_options(&server.options, &user_mapping)?;
+    let role_prefix = user_mapping.get("prefix").cloned().unwrap_or_default();
+    let namespace = server.options.get("namespace").cloned().unwrap_or_default();
+    let rt = runtime();
+    sync_request_limit();
+    let mut rows = Vec::new();
+
+    // Every endpoint on its own, so an unreachable member isn't hidden by the load balancing
+    for endpoint in &config.endpoints {
+        let endpoint_config = EtcdConfig {
+            endpoints: vec![endpoint.clone()],
+            user: None,
+            password: None,
+            pick_first: true,
+            ..config.clone()
+        };
+        let started = std::time::Instant::now();
+        let status = rt.block_on(async {
+            let mut client = connect_etcd(endpoint_config).await?;
+            client.status().await
+        });
+        rows.push(match status {
+            Ok(status) if status.leader() == 0 => {
+                ("endpoint".to_string(), endpoint.clone(), false, "member has no leader".to_string())
+            }
+            Ok(status) => (
+                "endpoint".to_string(),
+                endpoint.clone(),
+                true,
+                format!(
+                    "etcd {}, raft term {}, {} ms",
+                    status.version(),
+                    status.raft_term(),
+                    started.elapsed().as_millis()
+                ),
+            ),
+            Err(e) => ("endpoint".to_string(), endpoint.clone(), false, e.to_string()),
+        });
+    }
+
+    let user = config.user.clone().unwrap_or_default();
+    let mut client = match rt.block_on(connect_etcd(config.clone())) {
+        Ok(client) if user.is_empty() => {
+            rows.push((
+                "authentication".to_string(),
+                user,
+                true,
+                "the user mapping has no user, requests are unauthenticated".to_string(),
+            ));
+            client
+        }
+        Ok(client) => {
+            rows.push(("authentication".to_string(), user.clone(), true, format!("authenticated as {}", user)));
+            client
+        }
+        Err(e) => {
+            rows.push(("authentication".to_string(), user, false, e.to_string()));
+            return Ok(rows);
+        }
+    };
+
+    // The prefixes of the foreign tables and the user mapping, the namespace if there are none
+    let scope = function_scope(&namespace, &role_prefix);
+    let mut prefixes = table_prefixes(&server)?;
+    if !role_prefix.is_empty() || prefixes.is_empty() {
+        prefixes.push(scope.clone());
+    }
+    prefixes.sort();
+    prefixes.dedup();
+    for prefix in prefixes {
+        let options = GetOptions::new().with_prefix().with_count_only();
+        rows.push(match rt.block_on(client.get(prefix.as_str(), Some(options))) {
+            Ok(resp) => ("read".to_string(), prefix, true, format!("{} keys", resp.count())),
+            Err(e) => ("read".to_string(), prefix, false, e.to_string()),
+        });
+    }
+
+    // The probe key is attached to a short lease, so it expires even if deleting it fails
+    let probe = format!("{}etcd_fdw_selftest/{}", scope, unsafe { pg_sys::MyProcPid });
+    let write = rt.block_on(async {
+        let lease = client.lease_grant(60, None).await?.id();
+        client
+            .put(probe.as_str(), "", Some(PutOptions::new().with_lease(lease)))
+            .await?;
+        client.delete(probe.as_str(), None).await?;
+        client.lease_revoke(lease).await?;
+        Ok::<(), Error>(())
+    });
+    rows.push(match write {
+        Ok(()) => ("write".to_string(), probe.clone(), true, "written and deleted".to_string()),
+        Err(e) => ("write".to_string(), probe.clone(), false, e.to_string()),
+    });
+
+    // Linearizable reads go through the raft leader, serializable ones are answered by the member
+    for (name, options) in [
+        ("latency linearizable", GetOptions::new().with_count_only()),
+        ("latency serializable", GetOptions::new().with_count_only().with_serializable()),
+    ] {
+        let started = std::time::Instant::now();
+        rows.push(match rt.block_on(client.get(probe.as_str(), Some(options))) {
+            Ok(_) => (name.to_string(), probe.clone(), true, format!("{} ms", started.elapsed().as_millis())),
+            Err(e) => (name.to_string(), probe.clone(), false, e.to_string()),
+        });
+    }
+    Ok(rows)
+}
+
+/// Diagnose the connection to a foreign server: reachability of each endpoint, authentication,
+/// read access to the prefixes in use, write access and latencies
+/// Returns one row per check
+#[pg_extern]
+fn etcd_fdw_selftest(
+    server: &str,
+) -> TableIterator<
+    'static,
+    (
+        name!(check_name, String),
+        name!(target, String),
+        name!(passed, bool),
+        name!(details, String),
+    ),
+> {
+    TableIterator::new(report_error(selftest(server)))
+}
+
+/// Key of a service instance below `etcd_fdw.service_prefix`
+/// Service names can't contain a `/`, as they are one level of the key hierarchy
+fn service_key(name: &str, address: &str) -> EtcdFdwResult<String> {
+    if name.is_empty() || name.contains('/') {
+        return Err(EtcdFdwError::InvalidOption("name".to_string(), name.to_string()));
+    }
+    Ok(format!("{}{}/{}", service_prefix(), name, address))
+}
+
+fn service_prefix() -> String {
+    SERVICE_PREFIX
+        .get()
+        .map(|v| v.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Register an instance of a service at `address` for `ttl` seconds, returns the lease of the registration
+/// Registering the same instance again refreshes the registration
+#[pg_extern]
+fn etcd_service_register(server: &str, name: &str, address: &str, ttl: i64) -> i64 {
+    let mut fdw = report_error(connect_server(server));
+    let key = report_error(service_key(name, address).and_then(|key| fdw.server_key(&key)));
+    report_error(fdw.rt.block_on(register_service(&mut fdw.client, &key, address, ttl)))
+}
+
+/// Remove the registration of a service instance, returns whether it was registered
+#[pg_extern]
+fn etcd_service_deregister(server: &str, name: &str, address: &str) -> bool {
+    let mut fdw = report_error(connect_server(server));
+    let key = report_error(service_key(name, address).and_then(|key| fdw.server_key(&key)));
+    report_error(fdw.rt.block_on(deregister_service(&mut fdw.client, &key)))
+}
+
+/// Number of keys and total bytes of their values, grouped by the first `depth` segments of the keys' paths
+/// Only the keys below the `prefix` of the user mapping are counted
+#[pg_extern]
+fn etcd_keyspace_report(
+    server: &str,
+    depth: i32,
+) -> TableIterator<'static, (name!(prefix, String), name!(keys, i64), name!(value_bytes, i64))> {
+    let mut fdw = report_error(connect_server(server));
+    let namespace = fdw.server_namespace().to_string();
+    let prefix = function_scope(&namespace, fdw.role_prefix.as_deref().unwrap_or_default());
+    let groups = report_error(fdw.rt.block_on(keyspace_report(&mut fdw.client, &namespace, &prefix, depth)));
+    TableIterator::new(groups.into_iter().map(|(group, (keys, bytes))| (group, keys, bytes)))
+}
+
+/// The immediate children of `prefix`, keys and the prefixes of the keys below them up to the next `delimiter`
+/// `prefix` is taken within the `namespace` of the server
+#[pg_extern]
+fn etcd_ls(
+    server: &str,
+    prefix: &str,
+    delimiter: default!(&str, "'/'"),
+) -> TableIterator<'static, (name!(name, String), name!(key, String), name!(is_prefix, bool))> {
+    let mut fdw = report_error(connect_server(server));
+    let namespace = fdw.server_namespace().to_string();
+    let prefix = report_error(fdw.server_key(prefix));
+    let children = report_error(fdw.rt.block_on(list_children(&mut fdw.client, &prefix, delimiter)));
+    TableIterator::new(children.into_iter().map(move |(key, is_prefix)| {
+        let name = key[prefix.len()..].to_string();
+        (name, key[namespace.len()..].to_string(), is_prefix)
+    }))
+}
+
+/// Registered instances of all services, or of the service `name`
+#[pg_extern]
+fn etcd_services(
+    server: &str,
+    name: default!(Option<&str>, "NULL"),
+) -> TableIterator<'static, (name!(name, String), name!(address, String), name!(lease, i64))> {
+    let mut fdw = report_error(connect_server(server));
+    let prefix = match name {
+        Some(name) => report_error(service_key(name, "").and_then(|prefix| fdw.server_key(&prefix))),
+        None => report_error(fdw.server_key(&service_prefix())),
+    };
+    let services_prefix = format!("{}{}", fdw.server_namespace(), service_prefix());
+    let resp = report_error(
+        fdw.rt
+            .block_on(fdw.client.get(prefix.as_str(), Some(GetOptions::new().with_prefix())))
+            .map_err(fetch_error),
+    );
+
+    // Keys that don't follow the <prefix><name>/<address> convention are skipped
+    let services: Vec<_> = resp
+        .kvs()
+        .iter()
+        .filter_map(|kv| {
+            let instance = kv.key_str().ok()?.strip_prefix(services_prefix.as_str())?;
+            let (name, address) = instance.split_once('/')?;
+            Some((name.to_string(), address.to_string(), kv.lease()))
+        })
+        .collect();
+    TableIterator::new(services)
+}
+
+thread_local! {
+    /// Configuration values read by `etcd_config_get()`, with the time they were read
+    static CONFIG_CACHE: RefCell<HashMap<(String, String), (Option<String>, std::time::Instant)>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Foreign server and key of a configuration value
+fn config_key(name: &str) -> EtcdFdwResult<(String, String)> {
+    let server = CONFIG_SERVER
+        .get()
+        .map(|v| v.to_string_lossy().into_owned())
+        .filter(|v| !v.is_empty())
+        .ok_or(EtcdFdwError::NoConfigServer)?;
+    let prefix = CONFIG_PREFIX
+        .get()
+        .map(|v| v.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    Ok((server, format!("{}{}", prefix, name)))
+}
+
+/// Text of a configuration value, cached for `etcd_fdw.config_cache_ttl` seconds
+fn config_value(name: &str) -> EtcdFdwResult<Option<String>> {
+    let key = config_key(name)?;
+    let ttl = Duration::from_secs(CONFIG_CACHE_TTL.get() as u64);
+    let cached = CONFIG_CACHE.with_borrow(|cache| {
+        cache
+            .get(&key)
+            .filter(|(_, read_at)| read_at.elapsed() < ttl)
+            .map(|(value, _)| value.clone())
+    });
+    if let Some(value) = cached {
+        return Ok(value);
+    }
+
+    let mut fdw = connect_server(&key.0)?;
+    let etcd_key = fdw.server_key(&key.1)?;
+    let resp = fdw.rt.block_on(fdw.client.get(etcd_key.as_str(), None)).map_err(fetch_error)?;
+    let value = match resp.kvs().first() {
+        Some(kv) => Some(
+            kv.value_str()
+                .map_err(|e| EtcdFdwError::UndecodableEntry(key.1.clone(), e.to_string()))?
+                .to_string(),
+        ),
+        None => None,
+    };
+    if !ttl.is_zero() {
+        CONFIG_CACHE.with_borrow_mut(|cache| cache.insert(key, (value.clone(), std::time::Instant::now())));
+    }
+    Ok(value)
+}
+
+/// Configuration value `name` as text, NULL if it isn't set
+#[pg_extern(name = "etcd_config_get")]
+fn etcd_config_get_text(name: &str) -> Option<String> {
+    report_error(config_value(name))
+}
+
+/// Configuration value `name` converted to the type of `default`, `default` if it isn't set
+/// i.e. `etcd_config_get('max_workers', 4)` returns an integer
+#[pg_extern]
+fn etcd_config_get(name: &str, default: AnyElement) -> AnyElement {
+    let Some(value) = report_error(config_value(name)) else {
+        return default;
+    };
+    let value = report_error(
+        CString::new(value).map_err(|e| EtcdFdwError::UndecodableEntry(name.to_string(), e.to_string())),
+    );
+    unsafe {
+        let mut input_fn = pg_sys::InvalidOid;
+        let mut io_param = pg_sys::InvalidOid;
+        pg_sys::getTypeInputInfo(default.oid(), &mut input_fn, &mut io_param);
+        let datum = pg_sys::OidInputFunctionCall(input_fn, value.as_ptr().cast_mut(), io_param, -1);
+        AnyElement::from_polymorphic_datum(datum, false, default.oid()).expect("The value isn't null")
+    }
+}
+
+/// Set the configuration value `name` to the text of `value`, NULL removes it
+#[pg_extern]
+fn etcd_config_set(name: &str, value: Option<AnyElement>) {
+    let key = report_error(config_key(name));
+    let text = value.map(|value| unsafe {
+        let mut output_fn = pg_sys::InvalidOid;
+        let mut is_varlena = false;
+        pg_sys::getTypeOutputInfo(value.oid(), &mut output_fn, &mut is_varlena);
+        CStr::from_ptr(pg_sys::OidOutputFunctionCall(output_fn, value.datum()))
+            .to_string_lossy()
+            .into_owned()
+    });
+
+    let mut fdw = report_error(connect_server(&key.0));
+    let etcd_key = report_error(fdw.server_key(&key.1));
+    let result = match &text {
+        Some(text) => fdw.rt.block_on(fdw.client.put(etcd_key.as_str(), text.as_str(), None)).map(|_| ()),
+        None => fdw.rt.block_on(fdw.client.delete(etcd_key.as_str(), None)).map(|_| ()),
+    };
+    report_error(result.map_err(|e| EtcdFdwError::UpdateError(e.to_string())));
+
+    // The backend sees its own writes right away
+    CONFIG_CACHE.with_borrow_mut(|cache| cache.remove(&key));
+}
+
+/// Set `key` to `new_value` if its value is `expected`, returns whether it was set
+/// A NULL `expected` only sets keys that don't exist yet
+#[pg_extern]
+fn etcd_cas(server: &str, key: &str, expected: Option<&str>, new_value: &str) -> bool {
+    let mut fdw = report_error(connect_server(server));
+    let key = &report_error(fdw.server_key(key));
+    let compare = match expected {
+        Some(expected) => Compare::value(key.as_str(), CompareOp::Equal, expected),
+        None => Compare::create_revision(key.as_str(), CompareOp::Equal, 0),
+    };
+    report_error(fdw.rt.block_on(compare_and_write(&mut fdw.client, key, compare, Some(new_value))))
+}
+
+/// Set `key` to `new_value` if it was last modified at `expected_revision`, returns whether it was set
+/// An `expected_revision` of 0 only sets keys that don't exist yet
+#[pg_extern(name = "etcd_cas")]
+fn etcd_cas_revision(server: &str, key: &str, expected_revision: i64, new_value: &str) -> bool {
+    let mut fdw = report_error(connect_server(server));
+    let key = &report_error(fdw.server_key(key));
+    let compare = Compare::mod_revision(key.as_str(), CompareOp::Equal, expected_revision);
+    report_error(fdw.rt.block_on(compare_and_write(&mut fdw.client, key, compare, Some(new_value))))
+}
+
+/// Delete `key` if its value is `expected`, returns whether it was deleted
+#[pg_extern]
+fn etcd_cad(server: &str, key: &str, expected: &str) -> bool {
+    let mut fdw = report_error(connect_server(server));
+    let key = &report_error(fdw.server_key(key));
+    let compare = Compare::value(key.as_str(), CompareOp::Equal, expected);
+    report_error(fdw.rt.block_on(compare_and_write(&mut fdw.client, key, compare, None)))
+}
+
+/// Delete `key` if it was last modified at `expected_revision`, returns whether it was deleted
+#[pg_extern(name = "etcd_cad")]
+fn etcd_cad_revision(server: &str, key: &str, expected_revision: i64) -> bool {
+    let mut fdw = report_error(connect_server(server));
+    let key = &report_error(fdw.server_key(key));
+    let compare = Compare::mod_revision(key.as_str(), CompareOp::Equal, expected_revision);
+    report_error(fdw.rt.block_on(compare_and_write(&mut fdw.client, key, compare, None)))
+}
+
+/// Drop the cached scan results of `table`, or of all tables if it's NULL, see the `cache_ttl` table option
+/// Returns the number of dropped results
+#[pg_extern]
+fn etcd_fdw_flush_cache(table: default!(Option<PgRelation>, "NULL")) -> i64 {
+    flush_scan_cache(table.map(|t| t.oid()))
+}
+
+/// Counters of the watches of the backend, for monitoring
+#[pg_extern]
+fn etcd_watch_stats() -> TableIterator<
+    'static,
+    (
+        name!(active_watches, i64),
+        name!(reconnects, i64),
+        name!(lost_watches, i64),
+    ),
+> {
+    TableIterator::once((
+        WATCHES_ACTIVE.load(Ordering::Relaxed),
+        WATCH_RECONNECTS.load(Ordering::Relaxed),
+        WATCHES_LOST.load(Ordering::Relaxed),
+    ))
+}
+
+/// Keys below `prefix` that were modified or deleted since `since_revision`
+/// Together with `etcd_last_read_revision()` this allows validating reads before committing
+#[pg_extern]
+fn etcd_modified_since(
+    server: &str,
+    prefix: &str,
+    since_revision: i64,
+) -> TableIterator<'static, (name!(key, String), name!(mod_revision, i64), name!(deleted, bool))> {
+    let mut fdw = report_error(connect_server(server));
+    // The watch is synced with progress requests
+    report_error(fdw.require_version("etcd_modified_since()", (3, 4)));
+    let namespace_len = fdw.server_namespace().len();
+    let prefix = report_error(fdw.server_key(prefix));
+    let changes = report_error(fdw.rt.block_on(modified_since(&mut fdw.client, &prefix, since_revision)));
+    TableIterator::new(
+        changes
+            .into_iter()
+            .map(move |(key, (mod_revision, deleted))| (key[namespace_len..].to_string(), mod_revision, deleted)),
+    )
+}
+
+#[cfg(test)]
+pub mod pg_test {
+
+    pub fn setup(_options: Vec<&str>) {
+        // perform one-off initialization when the pg_test framework starts
+    }
+
+    pub fn postgresql_conf_options() -> Vec<&'static str> {
+        // return any postgresql.conf settings that are required for your tests
+        vec![]
+    }
+}
+
+#[pg_schema]
+#[cfg(any(test, feature = "pg_test"))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use etcd_client::Permission;
+    use testcontainers::{
+        core::{IntoContainerPort, WaitFor},
+        runners::SyncRunner,
+        Container, GenericImage, ImageExt,
+    };
+
+    const CMD: [&'static str; 5] = [
+        "/usr/local/bin/etcd",
+        "--listen-client-urls",
+        "http://0.0.0.0:2379",
+        "--advertise-client-urls",
+        "http://0.0.0.0:2379",
+    ];
+
+    const ETCD_USER: &str = "root";
+    const ETCD_PASS: &str = "secret";
+
+    // Setup etcd root role/user and enable authentication
+    async fn etcd_auth_setup(endpoint: String) {
+        let mut client: Client = Client::connect([endpoint], None)
+            .await
+            .expect("connect etcd");
+
+        // add root user and role
+        client.role_add("root").await.expect("add role");
+        client.user_add(ETCD_USER, ETCD_PASS, None)
+            .await
+            .expect("add user");
+
+        client.user_grant_role(ETCD_USER, "root")
+            .await
+            .expect("grant role");
+
+        client.auth_enable().await.expect("enable auth");
+    }
+
+    fn create_container() -> (Container<GenericImage>, String) {
+        let container = GenericImage::new("quay.io/coreos/etcd", "v3.6.4")
+            .with_exposed_port(2379.tcp())
+            .with_wait_for(WaitFor::message_on_either_std(
+                "ready to serve client requests",
+            ))
+            .with_privileged(true)
+            .with_cmd(CMD)
+            .with_startup_timeout(Duration::from_secs(90))
+            .start()
+            .expect("An etcd image was supposed to be started");
+
+        let host = container
+            .get_host()
+            .expect("Host-address should be available");
+
+        let port = container
+            .get_host_port_ipv4(2379.tcp())
+            .expect("Exposed host port should be available");
+
+        let url = format!("{}:{}", host, port);
+        let rt = tokio::runtime::Runtime::new().expect("Tokio runtime should be initialized");
+        rt.block_on(etcd_auth_setup(url.clone()));
+        (container, url)
+    }
+
+    fn create_fdt(url: String) -> () {
+        Spi::run("CREATE FOREIGN DATA WRAPPER etcd_fdw handler etcd_fdw_handler validator etcd_fdw_validator;").expect("FDW should have been created");
+
+        // Create a server
+        Spi::run(
+            format!(
+                "CREATE SERVER etcd_test_server FOREIGN DATA WRAPPER etcd_fdw options(connstr '{}')",
+                url
+            )
+            .as_str(),
+        )
+        .expect("Server should have been created");
+
+        // Create a user mapping
+        Spi::run(
+            format!(
+                "CREATE USER MAPPING FOR CURRENT_USER SERVER etcd_test_server options (user '{}', password '{}')",
+                ETCD_USER, ETCD_PASS
+            )
+            .as_str(),
+        )
+        .expect("User mapping should have been created");
+
+        // Create a foreign table
+        Spi::run("CREATE FOREIGN TABLE test (key text, value text) server etcd_test_server options (rowid_column 'key')").expect("Test table should have been created");
+    }
+
+    #[pg_test]
+    fn test_create_table() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+    }
+    #[pg_test]
+    fn test_insert_select() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        // Insert into the foreign table
+        Spi::run("INSERT INTO test (key, value) VALUES ('foo','bar'),('bar','baz')")
+            .expect("INSERT should work");
+
+        let query_result = Spi::get_two::<String, String>("SELECT * FROM test WHERE key='foo'")
+            .expect("Select should work");
+
+        assert_eq!((Some(format!("foo")), Some(format!("bar"))), query_result);
+        let query_result = Spi::get_two::<String, String>("SELECT * FROM test WHERE key='bar'")
+            .expect("SELECT should work");
+
+        assert_eq!((Some(format!("bar")), Some(format!("baz"))), query_result);
+    }
+
+    #[pg_test]
+    fn test_update() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('foo','bar'),('bar','baz')")
+            .expect("INSERT should work");
+
+        Spi::run("UPDATE test SET value='test_successful'").expect("UPDATE should work");
+
+        let query_result =
+            Spi::get_one::<String>("SELECT value FROM test;").expect("SELECT should work");
+
+        assert_eq!(Some(format!("test_successful")), query_result);
+    }
+
+    #[pg_test]
+    fn test_delete() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('foo','bar'),('bar','baz')")
+            .expect("INSERT should work");
+
+        Spi::run("DELETE FROM test").expect("DELETE should work");
+
+        let query_result = Spi::get_one::<String>("SELECT value FROM test;");
+
+        assert_eq!(Err(spi::SpiError::InvalidPosition), query_result);
+    }
+
+    #[pg_test]
+    fn test_select_value_only_with_key_filter() {
+        // Test for issue #26: selecting only value column with WHERE clause on key
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        // Insert test data
+        Spi::run("INSERT INTO test (key, value) VALUES ('key1','value1'),('key2','value2'),('key3','value3')")
+            .expect("INSERT should work");
+
+        // Test 1: SELECT value WHERE key = 'key2' should return the value
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'key2'")
+            .expect("SELECT value with key filter should work");
+
+        assert_eq!(Some(format!("value2")), query_result);
+
+        // Test 2: SELECT key WHERE key = 'key1' should also work
+        let query_result = Spi::get_one::<String>("SELECT key FROM test WHERE key = 'key1'")
+            .expect("SELECT key with key filter should work");
+
+        assert_eq!(Some(format!("key1")), query_result);
+
+        // Test 3: SELECT * WHERE key = 'key3' should work (baseline)
+        let query_result = Spi::get_two::<String, String>("SELECT * FROM test WHERE key = 'key3'")
+            .expect("SELECT * with key filter should work");
+
+        assert_eq!((Some(format!("key3")), Some(format!("value3"))), query_result);
+    }
+
+    #[pg_test]
+    fn test_update_value_only_with_key_filter() {
+        // Test for issue #26: UPDATE with only value column when key is in WHERE clause
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        // Insert test data
+        Spi::run("INSERT INTO test (key, value) VALUES ('gather/78','original_value'),('gather/84','other_value')")
+            .expect("INSERT should work");
+
+        // Update without including key column in SET clause
+        Spi::run("UPDATE test SET value = 'updated_value' WHERE key = 'gather/84'")
+            .expect("UPDATE with key filter should work");
+
+        // Verify the update worked
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'gather/84'")
+            .expect("SELECT after UPDATE should work");
+
+        assert_eq!(Some(format!("updated_value")), query_result);
+
+        // Verify other row was not affected
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'gather/78'")
+            .expect("SELECT other row should work");
+
+        assert_eq!(Some(format!("original_value")), query_result);
+    }
+
+    #[pg_test]
+    fn test_user_mapping_validation() {
+        let (_container, url) = create_container();
+
+        create_fdt(url.clone());
+
+        // Insert test data
+        Spi::run("INSERT INTO test (key, value) VALUES ('/gather', 'data')")
+            .expect("INSERT should work");
+
+        // Test 1: User mapping with invalid credentials (should fail)
+        Spi::run("ALTER USER MAPPING FOR CURRENT_USER SERVER etcd_test_server OPTIONS (SET password 'wrong_password');")
+            .expect("Alter user mapping should work");
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("SELECT * FROM test;").expect("SELECT should work");
+        });
+
+        assert!(result.is_err(), "Expected SELECT to fail due to invalid user mapping");
+
+        // Setup: create a role and user with limited permissions in etcd
+        let rt = tokio::runtime::Runtime::new().expect("Tokio runtime should be initialized");
+        rt.block_on(
+            async {
+                let mut client: Client = Client::connect([url.clone()], Some(ConnectOptions::new().with_user(ETCD_USER, ETCD_PASS)))
+                    .await
+                    .expect("connect etcd");
+                client.role_add("rw_role").await.expect("add role");
+                // role with read and write permissions on keys starting with "/"
+                client.role_grant_permission("rw_role", Permission::with_from_key(Permission::read_write("/")))
+                    .await
+                    .expect("grant permission");
+                client.user_add("etcd_user", "secret", None)
+                    .await
+                    .expect("add user");
+                client.user_grant_role("etcd_user", "rw_role")
+                    .await
+                    .expect("grant role");
+            }
+        );
+
+        // Alter user mapping to use the new limited permissions user
+        Spi::run("ALTER USER MAPPING FOR CURRENT_USER SERVER etcd_test_server OPTIONS (SET user 'etcd_user', SET password 'secret');")
+            .expect("Alter user mapping should work");
+
+        // Test 2: Selecting a key outside of the user's permissions (should fail)
+        let invalid_result =  std::panic::catch_unwind(|| {
+            Spi::run("SELECT * FROM test;").expect("SELECT should work");
+        });
+
+        assert!(invalid_result.is_err(), "Expected SELECT to fail due to insufficient permissions");
+
+        // Test 3: Selecting a key within the user's permissions
+        let result = Spi::get_two::<String, String>("SELECT * FROM test WHERE key = '/gather'")
+            .expect("SELECT with proper permissions should work");
+
+        assert_eq!((Some(format!("/gather")), Some(format!("data"))), result);
+    }
+
+    #[pg_test]
+    fn test_prepared_statement_key_param() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('key1','value1'),('key2','value2'),('key3','value3')")
+            .expect("INSERT should work");
+
+        // Force a generic plan so the key is only known as $1 at plan time
+        Spi::run("SET plan_cache_mode = force_generic_plan").expect("SET should work");
+        Spi::run("PREPARE get_by_key(text) AS SELECT value FROM test WHERE key = $1")
+            .expect("PREPARE should work");
+
+        let query_result = Spi::get_one::<String>("EXECUTE get_by_key('key2')")
+            .expect("EXECUTE with key parameter should work");
+
+        assert_eq!(Some(format!("value2")), query_result);
+
+        let query_result = Spi::get_one::<String>("EXECUTE get_by_key('key3')")
+            .expect("EXECUTE with another key parameter should work");
+
+        assert_eq!(Some(format!("value3")), query_result);
+    }
+
+    #[pg_test]
+    fn test_key_in_list_multi_get() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('key1','value1'),('key2','value2'),('key3','value3')")
+            .expect("INSERT should work");
+
+        // IN list with a key that doesn't exist
+        let query_result = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE key IN ('key1', 'key3', 'missing')")
+            .expect("SELECT with IN list should work");
+
+        assert_eq!(Some(2), query_result);
+
+        // Keys outside of the other key bounds are not fetched
+        let query_result = Spi::get_one::<String>("SELECT string_agg(value, ',' ORDER BY key) FROM test WHERE key IN ('key1', 'key2', 'key3') AND key > 'key1'")
+            .expect("SELECT with IN list and range should work");
+
+        assert_eq!(Some(format!("value2,value3")), query_result);
+
+        // Semi-join against a constant set
+        let query_result = Spi::get_one::<String>("SELECT string_agg(value, ',' ORDER BY key) FROM test WHERE key IN (VALUES ('key2'), ('key3'))")
+            .expect("SELECT with IN (VALUES ...) should work");
+
+        assert_eq!(Some(format!("value2,value3")), query_result);
+    }
+
+    #[pg_test]
+    fn test_sample_percent() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) SELECT 'key' || i, 'value' || i FROM generate_series(1, 200) i")
+            .expect("INSERT should work");
+
+        // Sampling everything returns all rows
+        Spi::run("SET etcd_fdw.sample_percent = 100").expect("SET should work");
+        let query_result = Spi::get_one::<i64>("SELECT count(*) FROM test")
+            .expect("Sampled SELECT should work");
+
+        assert_eq!(Some(200), query_result);
+
+        // A sample only contains existing rows with their values
+        Spi::run("SET etcd_fdw.sample_percent = 10").expect("SET should work");
+        let query_result = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE value <> 'value' || substr(key, 4)")
+            .expect("Sampled SELECT should work");
+
+        assert_eq!(Some(0), query_result);
+
+        // The sample is the same on every scan
+        let query_result = Spi::get_one::<bool>("SELECT (SELECT array_agg(key ORDER BY key) FROM test) IS NOT DISTINCT FROM (SELECT array_agg(key ORDER BY key) FROM test)")
+            .expect("Sampled SELECT should work");
+
+        assert_eq!(Some(true), query_result);
+
+        Spi::run("RESET etcd_fdw.sample_percent").expect("RESET should work");
+        let query_result = Spi::get_one::<i64>("SELECT count(*) FROM test")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(200), query_result);
+    }
+
+    #[pg_test]
+    fn test_n_rows_estimate() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (ADD n_rows '5000000')")
+            .expect("ALTER FOREIGN TABLE should work");
+
+        let plan = Spi::get_one::<pgrx::Json>("EXPLAIN (FORMAT JSON) SELECT * FROM test")
+            .expect("EXPLAIN should work")
+            .expect("EXPLAIN should return a plan");
+
+        assert_eq!(5000000, plan.0[0]["Plan"]["Plan Rows"].as_i64().unwrap());
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("ALTER FOREIGN TABLE test OPTIONS (SET n_rows '-1')").expect("ALTER should fail");
+        });
+
+        assert!(result.is_err(), "Expected a negative n_rows to be rejected");
+    }
+
+    #[pg_test]
+    fn test_layered_options() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('foo','bar')")
+            .expect("INSERT should work");
+
+        // Server level option applies to all tables
+        Spi::run("ALTER SERVER etcd_test_server OPTIONS (ADD keys_only 'true')")
+            .expect("ALTER SERVER should work");
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'foo'")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(format!("")), query_result);
+
+        // Table level option overrides the server
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (ADD keys_only 'false')")
+            .expect("ALTER FOREIGN TABLE should work");
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'foo'")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(format!("bar")), query_result);
+
+        // Session setting overrides the table
+        Spi::run("SET etcd_fdw.keys_only = 'true'").expect("SET should work");
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'foo'")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(format!("")), query_result);
+    }
+
+    #[pg_test]
+    fn test_key_collation() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('a','1'), ('B','2'), ('c','3')")
+            .expect("INSERT should work");
+
+        // etcd sorts bytewise, a C collated key column can use remote ranges and sort
+        let query_result = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test WHERE key COLLATE \"C\" > 'B' ORDER BY key COLLATE \"C\" LIMIT 2) t",
+        )
+        .expect("SELECT should work");
+
+        assert_eq!(Some(format!("a,c")), query_result);
+
+        // a linguistic collation has to be sorted locally, "a" comes before "B"
+        Spi::run("CREATE COLLATION IF NOT EXISTS test_und (provider = icu, locale = 'und')")
+            .expect("CREATE COLLATION should work");
+        let query_result = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test ORDER BY key COLLATE test_und LIMIT 2) t",
+        )
+        .expect("SELECT should work");
+
+        assert_eq!(Some(format!("a,B")), query_result);
+
+        Spi::run("ALTER FOREIGN TABLE test ALTER COLUMN key TYPE text COLLATE test_und")
+            .expect("ALTER FOREIGN TABLE should work");
+        let query_result = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test WHERE key > 'a' ORDER BY key) t",
+        )
+        .expect("SELECT should work");
+
+        assert_eq!(Some(format!("B,c")), query_result);
+    }
+
+    #[pg_test]
+    fn test_key_pad() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE numbered (key bigint, value text) server etcd_test_server options (rowid_column 'key', prefix '/n/', key_pad '5')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO numbered (key, value) VALUES (2, 'two'), (10, 'ten'), (100, 'hundred')")
+            .expect("INSERT should work");
+
+        // Numbers are stored zero-padded below the prefix
+        let query_result = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',' ORDER BY key) FROM test WHERE key LIKE '/n/%'",
+        )
+        .expect("SELECT should work");
+
+        assert_eq!(Some(format!("/n/00002,/n/00010,/n/00100")), query_result);
+
+        // Numeric ranges map onto the padded key ranges
+        let query_result = Spi::get_one::<String>(
+            "SELECT string_agg(value, ',' ORDER BY key) FROM numbered WHERE key > 5 AND key <= 100",
+        )
+        .expect("SELECT should work");
+
+        assert_eq!(Some(format!("ten,hundred")), query_result);
+
+        Spi::run("UPDATE numbered SET value = 'zwei' WHERE key = 2").expect("UPDATE should work");
+        Spi::run("DELETE FROM numbered WHERE key = 100").expect("DELETE should work");
+
+        let query_result = Spi::get_one::<String>(
+            "SELECT string_agg(key || '=' || value, ',' ORDER BY key) FROM numbered",
+        )
+        .expect("SELECT should work");
+
+        assert_eq!(Some(format!("2=zwei,10=ten")), query_result);
+
+        // Negative numbers have no padded encoding
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("INSERT INTO numbered (key, value) VALUES (-1, 'negative')")
+                .expect("INSERT should work");
+        });
+
+        assert!(result.is_err(), "Expected INSERT of a negative key to fail");
+    }
+
+    #[pg_test]
+    fn test_user_mapping_prefix() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('/alice/a','1'), ('/bob/b','2')")
+            .expect("INSERT should work");
+
+        Spi::run("ALTER USER MAPPING FOR CURRENT_USER SERVER etcd_test_server OPTIONS (ADD prefix '/alice/')")
+            .expect("ALTER USER MAPPING should work");
+
+        // Only the subtree of the user mapping is visible
+        let query_result = Spi::get_one::<String>("SELECT string_agg(key, ',') FROM test")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(format!("/alice/a")), query_result);
+
+        let query_result = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE key = '/bob/b'")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(0), query_result);
+
+        // Writes outside of the subtree are rejected
+        Spi::run("INSERT INTO test (key, value) VALUES ('/alice/c','3')")
+            .expect("INSERT should work");
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("INSERT INTO test (key, value) VALUES ('/bob/c','3')")
+                .expect("INSERT should work");
+        });
+
+        assert!(result.is_err(), "Expected INSERT outside of the prefix to fail");
+    }
+
+    #[pg_test]
+    fn test_write_lease() {
+        let (_container, url) = create_container();
+
+        create_fdt(url.clone());
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('plain','1')")
+            .expect("INSERT should work");
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (ADD write_lease_ttl '60')")
+            .expect("ALTER FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('leased','2')")
+            .expect("INSERT should work");
+        Spi::run("UPDATE test SET value = '3' WHERE key = 'leased'")
+            .expect("UPDATE should work");
+
+        // The test transaction never commits, so inserted keys stay attached to the lease
+        let rt = tokio::runtime::Runtime::new().expect("Tokio runtime should be initialized");
+        let (plain_lease, leased_lease) = rt.block_on(async {
+            let mut client: Client = Client::connect([url.clone()], Some(ConnectOptions::new().with_user(ETCD_USER, ETCD_PASS)))
+                .await
+                .expect("connect etcd");
+            let plain = client.get("plain", None).await.expect("get plain");
+            let leased = client.get("leased", None).await.expect("get leased");
+            (plain.kvs()[0].lease(), leased.kvs()[0].lease())
+        });
+
+        assert_eq!(0, plain_lease);
+        assert_ne!(0, leased_lease);
+
+        // Keys of a rolled back subtransaction are deleted right away
+        Spi::run(
+            "DO $$
+             BEGIN
+                 INSERT INTO test (key, value) VALUES ('rolled_back', '4');
+                 RAISE EXCEPTION 'roll back';
+             EXCEPTION WHEN OTHERS THEN
+                 NULL;
+             END $$",
+        )
+        .expect("DO should work");
+        let rolled_back = rt.block_on(async {
+            let mut client: Client = Client::connect([url.clone()], Some(ConnectOptions::new().with_user(ETCD_USER, ETCD_PASS)))
+                .await
+                .expect("connect etcd");
+            client.get("rolled_back", None).await.expect("get rolled_back").kvs().len()
+        });
+        assert_eq!(0, rolled_back, "The key of the rolled back subtransaction should be deleted");
+
+        // Overwritten keys aren't attached to the lease, an abort would delete them
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (ADD on_conflict 'overwrite')")
+            .expect("ALTER FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('plain','5'), ('fresh','6')")
+            .expect("INSERT should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('plain','7') RETURNING key")
+            .expect("INSERT should work");
+        let (plain_lease, fresh_lease) = rt.block_on(async {
+            let mut client: Client = Client::connect([url.clone()], Some(ConnectOptions::new().with_user(ETCD_USER, ETCD_PASS)))
+                .await
+                .expect("connect etcd");
+            let plain = client.get("plain", None).await.expect("get plain");
+            let fresh = client.get("fresh", None).await.expect("get fresh");
+            (plain.kvs()[0].lease(), fresh.kvs()[0].lease())
+        });
+
+        assert_eq!(0, plain_lease);
+        assert_ne!(0, fresh_lease);
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("ALTER FOREIGN TABLE test OPTIONS (SET write_lease_ttl '0')")
+                .expect("ALTER FOREIGN TABLE should work");
+        });
+
+        assert!(result.is_err(), "Expected a write_lease_ttl of 0 to be rejected");
+    }
+
+    #[pg_test]
+    fn test_modified_since() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('/app/a','1'), ('/app/b','2'), ('/other/c','3')")
+            .expect("INSERT should work");
+
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = '/app/a'")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(format!("1")), query_result);
+
+        let read_revision = Spi::get_one::<i64>("SELECT etcd_last_read_revision()")
+            .expect("SELECT should work")
+            .expect("A scan should set the read revision");
+
+        Spi::run("UPDATE test SET value = '4' WHERE key = '/app/a'").expect("UPDATE should work");
+        Spi::run("DELETE FROM test WHERE key = '/app/b'").expect("DELETE should work");
+        Spi::run("UPDATE test SET value = '5' WHERE key = '/other/c'").expect("UPDATE should work");
+
+        let query_result = Spi::get_one_with_args::<String>(
+            "SELECT string_agg(key || ':' || deleted, ',' ORDER BY key) FROM etcd_modified_since('etcd_test_server', '/app/', $1)",
+            &[read_revision.into()],
+        )
+        .expect("SELECT should work");
+
+        assert_eq!(Some(format!("/app/a:false,/app/b:true")), query_result);
+    }
+
+    #[pg_test]
+    fn test_shared_channel_authentication() {
+        let (_container, url) = create_container();
+
+        create_fdt(url.clone());
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('foo','bar')")
+            .expect("INSERT should work");
+
+        // A second server on the same endpoint shares the channel, but authenticates on its own
+        Spi::run(&format!("CREATE SERVER etcd_second_server FOREIGN DATA WRAPPER etcd_fdw options(connstr '{}')", url))
+            .expect("CREATE SERVER should work");
+        Spi::run(&format!("CREATE USER MAPPING FOR CURRENT_USER SERVER etcd_second_server OPTIONS (user '{}', password 'wrong_password')", ETCD_USER))
+            .expect("CREATE USER MAPPING should work");
+        Spi::run("CREATE FOREIGN TABLE second (key text, value text) server etcd_second_server options (rowid_column 'key')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("SELECT * FROM second").expect("SELECT should work");
+        });
+
+        assert!(result.is_err(), "Expected SELECT to fail due to invalid user mapping");
+
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'foo'")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(format!("bar")), query_result);
+    }
+
+    #[pg_test]
+    fn test_read_only_transaction() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('foo','bar')")
+            .expect("INSERT should work");
+
+        // Scans in read-only transactions default to serializable reads
+        Spi::run("SET transaction_read_only = on").expect("SET should work");
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'foo'")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(format!("bar")), query_result);
+
+        // An explicit consistency still applies
+        Spi::run("SET etcd_fdw.consistency = 'l'").expect("SET should work");
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key IN ('foo', 'baz')")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(format!("bar")), query_result);
+    }
+
+    #[pg_test]
+    fn test_on_scan_error() {
+        let (_container, url) = create_container();
+
+        create_fdt(url.clone());
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('a','1'), ('c','3')")
+            .expect("INSERT should work");
+
+        // A value that isn't valid UTF-8
+        let rt = tokio::runtime::Runtime::new().expect("Tokio runtime should be initialized");
+        rt.block_on(async {
+            let mut client: Client = Client::connect([url.clone()], Some(ConnectOptions::new().with_user(ETCD_USER, ETCD_PASS)))
+                .await
+                .expect("connect etcd");
+            client.put("b", vec![0xffu8, 0xfe], None).await.expect("put invalid value");
+        });
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("SELECT * FROM test").expect("SELECT should work");
+        });
+
+        assert!(result.is_err(), "Expected SELECT to fail on the undecodable value");
+
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (ADD on_scan_error 'warn_skip')")
+            .expect("ALTER FOREIGN TABLE should work");
+        let query_result = Spi::get_one::<String>("SELECT string_agg(key || '=' || value, ',' ORDER BY key) FROM test")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(format!("a=1,c=3")), query_result);
+    }
+
+    #[pg_test]
+    fn test_on_unavailable() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        // Nothing listens on this port
+        Spi::run("CREATE SERVER etcd_down_server FOREIGN DATA WRAPPER etcd_fdw options(connstr '127.0.0.1:1', connect_timeout '1', request_timeout '1')")
+            .expect("CREATE SERVER should work");
+        Spi::run(&format!("CREATE USER MAPPING FOR CURRENT_USER SERVER etcd_down_server OPTIONS (user '{}', password '{}')", ETCD_USER, ETCD_PASS))
+            .expect("CREATE USER MAPPING should work");
+        Spi::run("CREATE FOREIGN TABLE down (key text, value text) server etcd_down_server options (rowid_column 'key')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("SELECT * FROM down").expect("SELECT should work");
+        });
+
+        assert!(result.is_err(), "Expected SELECT to fail while etcd is unavailable");
+
+        Spi::run("ALTER FOREIGN TABLE down OPTIONS (ADD on_unavailable 'empty')")
+            .expect("ALTER FOREIGN TABLE should work");
+        let query_result = Spi::get_one::<i64>("SELECT count(*) FROM down")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(0), query_result);
+    }
+
+    #[pg_test]
+    fn test_default_limit_and_sort() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('a','1'), ('b','2'), ('c','3')")
+            .expect("INSERT should work");
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (ADD default_limit '2', ADD default_sort 'key desc')")
+            .expect("ALTER FOREIGN TABLE should work");
+
+        // Queries without ORDER BY and LIMIT are bounded and ordered
+        let query_result = Spi::get_one::<String>("SELECT string_agg(key, ',') FROM test")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(format!("c,b")), query_result);
+
+        // An explicit ORDER BY and LIMIT take precedence
+        let query_result = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test ORDER BY key COLLATE \"C\" LIMIT 3) t",
+        )
+        .expect("SELECT should work");
+
+        assert_eq!(Some(format!("a,b,c")), query_result);
+
+        // DELETE isn't bounded by the default limit
+        Spi::run("DELETE FROM test").expect("DELETE should work");
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (DROP default_limit)")
+            .expect("ALTER FOREIGN TABLE should work");
+        let query_result = Spi::get_one::<i64>("SELECT count(*) FROM test")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(0), query_result);
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("ALTER FOREIGN TABLE test OPTIONS (SET default_sort 'key sideways')")
+                .expect("ALTER FOREIGN TABLE should work");
+        });
+
+        assert!(result.is_err(), "Expected an invalid default_sort to be rejected");
+    }
+
+    #[pg_test]
+    fn test_insert_returning_metadata() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test_meta (key text, value text, create_revision bigint, mod_revision bigint, version bigint, lease bigint) server etcd_test_server options (rowid_column 'key')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        let inserted = Spi::get_two::<i64, i64>(
+            "INSERT INTO test_meta (key, value) VALUES ('foo', 'bar') RETURNING mod_revision, version",
+        )
+        .expect("INSERT RETURNING should work");
+        let mod_revision = inserted.0.expect("mod_revision should be returned");
+
+        assert_eq!(Some(1), inserted.1);
+
+        // The returned revision is the one etcd reports for the key
+        let query_result = Spi::get_one::<i64>("SELECT mod_revision FROM test_meta WHERE key = 'foo'")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(mod_revision), query_result);
+
+        let query_result = Spi::get_one::<String>("INSERT INTO test_meta (key, value) VALUES ('baz', 'qux') RETURNING key || '=' || value")
+            .expect("INSERT RETURNING should work");
+
+        assert_eq!(Some(format!("baz=qux")), query_result);
+    }
+
+    #[pg_test]
+    fn test_modify_batch_size() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) SELECT 'k' || i, 'v' || i FROM generate_series(1, 10) i")
+            .expect("INSERT should work");
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (ADD modify_batch_size '4')")
+            .expect("ALTER FOREIGN TABLE should work");
+
+        Spi::run("UPDATE test SET value = 'updated'").expect("UPDATE should work");
+        let query_result = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE value = 'updated'")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(10), query_result);
+
+        Spi::run("DELETE FROM test WHERE key <> 'k1'").expect("DELETE should work");
+        let query_result = Spi::get_one::<String>("SELECT string_agg(key, ',') FROM test")
+            .expect("SELECT should work");
+
+        assert_eq!(Some(format!("k1")), query_result);
+    }
+
+    #[pg_test]
+    fn test_alter_validation() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        let invalid_alters = [
+            "ALTER FOREIGN TABLE test OPTIONS (ADD prefx '/foo/')",
+            "ALTER FOREIGN TABLE test OPTIONS (ADD prefix '/foo/', ADD range_end '/zzz')",
+            "ALTER FOREIGN TABLE test OPTIONS (ADD keys_only 'yes')",
+            "ALTER FOREIGN TABLE test OPTIONS (ADD revision '-1')",
+            "ALTER FOREIGN TABLE test OPTIONS (ADD startup_cost 'cheap')",
+            "ALTER FOREIGN TABLE test OPTIONS (DROP rowid_column)",
+            "ALTER SERVER etcd_test_server OPTIONS (ADD consistency 'x')",
+            "ALTER SERVER etcd_test_server OPTIONS (ADD request_timeout 'soon')",
+            "ALTER SERVER etcd_test_server OPTIONS (ADD usr 'root')",
+            "ALTER SERVER etcd_test_server OPTIONS (DROP connstr)",
+        ];
+        for alter in invalid_alters {
+            let result = std::panic::catch_unwind(|| {
+                Spi::run(alter).expect("ALTER should fail");
+            });
+            assert!(result.is_err(), "{} should be rejected", alter);
+        }
+
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (ADD prefix '/foo/', ADD keys_only 'true')")
+            .expect("ALTER FOREIGN TABLE should work");
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (ADD startup_cost '500')")
+            .expect("ALTER FOREIGN TABLE should work");
+        Spi::run("ALTER SERVER etcd_test_server OPTIONS (ADD consistency 's')")
+            .expect("ALTER SERVER should work");
+    }
+
+    #[pg_test]
+    fn test_key_encoding_percent() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE escaped (key text, value text) server etcd_test_server options (rowid_column 'key', prefix '/svc/', key_encoding 'percent')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        Spi::run("INSERT INTO escaped (key, value) VALUES ('/svc/a/b é', 'bar')")
+            .expect("INSERT should work");
+
+        let stored = Spi::get_one::<String>("SELECT key FROM test")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("/svc/a%2Fb%20%C3%A9")), stored);
+
+        let query_result = Spi::get_one::<String>("SELECT value FROM escaped WHERE key = '/svc/a/b é'")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("bar")), query_result);
+
+        Spi::run("UPDATE escaped SET value = 'baz' WHERE key = '/svc/a/b é'").expect("UPDATE should work");
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = '/svc/a%2Fb%20%C3%A9'")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("baz")), query_result);
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("INSERT INTO escaped (key, value) VALUES ('/other/key', 'bar')").expect("INSERT should fail");
+        });
+        assert!(result.is_err(), "Expected keys outside of the prefix to be rejected");
+
+        Spi::run("DELETE FROM escaped").expect("DELETE should work");
+        let query_result = Spi::get_one::<i64>("SELECT count(*) FROM test")
+            .expect("SELECT should work");
+        assert_eq!(Some(0), query_result);
+    }
+
+    #[pg_test]
+    fn test_prefix_placeholders() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE tenant (key text, value text) server etcd_test_server options (rowid_column 'key', prefix '/tenants/{app.tenant}/{current_database}/')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        let database = Spi::get_one::<String>("SELECT current_database()::text")
+            .expect("SELECT should work")
+            .unwrap();
+
+        Spi::run(&format!("INSERT INTO test (key, value) VALUES ('/tenants/a/{0}/x', '1'), ('/tenants/b/{0}/y', '2')", database))
+            .expect("INSERT should work");
+
+        Spi::run("SET app.tenant = 'a'").expect("SET should work");
+        let query_result = Spi::get_one::<String>("SELECT string_agg(value, ',') FROM tenant")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("1")), query_result);
+
+        Spi::run("SET app.tenant = 'b'").expect("SET should work");
+        let query_result = Spi::get_one::<String>("SELECT string_agg(value, ',') FROM tenant")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("2")), query_result);
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("SET app.tenant = 'a/b'").expect("SET should work");
+            Spi::run("SELECT * FROM tenant").expect("SELECT should fail");
+        });
+        assert!(result.is_err(), "Expected placeholder values with a slash to be rejected");
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("ALTER FOREIGN TABLE tenant OPTIONS (SET prefix '/tenants/{app.tenant/')").expect("ALTER should fail");
+        });
+        assert!(result.is_err(), "Expected unclosed placeholders to be rejected");
+    }
+
+    #[pg_test]
+    fn test_server_version() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        let version = Spi::get_one::<String>("SELECT etcd_server_version('etcd_test_server')")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("3.6.4")), version);
+
+        assert_eq!(Some((3, 6)), parse_version("3.6.4"));
+        assert_eq!(Some((3, 10)), parse_version("3.10.0-alpha.0"));
+        assert_eq!(None, parse_version("unknown"));
+    }
+
+    #[pg_test]
+    fn test_on_conflict_ignore() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE loader (key text, value text, created boolean, version bigint) server etcd_test_server options (rowid_column 'key', on_conflict 'ignore')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        let created = Spi::get_two::<bool, i64>("INSERT INTO loader (key, value) VALUES ('foo', 'bar') RETURNING created, version")
+            .expect("INSERT should work");
+        assert_eq!((Some(true), Some(1)), created);
+
+        Spi::run("UPDATE test SET value = 'baz' WHERE key = 'foo'").expect("UPDATE should work");
+
+        let created = Spi::get_two::<bool, i64>("INSERT INTO loader (key, value) VALUES ('foo', 'other') RETURNING created, version")
+            .expect("INSERT of an existing key should be ignored");
+        assert_eq!((Some(false), Some(2)), created);
+
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'foo'")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("baz")), query_result);
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("INSERT INTO test (key, value) VALUES ('foo', 'other')").expect("INSERT should fail");
+        });
+        assert!(result.is_err(), "Expected duplicate keys to fail without on_conflict 'ignore'");
+    }
+
+    #[pg_test]
+    fn test_service_discovery() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        let lease = Spi::get_one::<i64>("SELECT etcd_service_register('etcd_test_server', 'db', '10.0.0.1:5432', 60)")
+            .expect("SELECT should work");
+        Spi::run("SELECT etcd_service_register('etcd_test_server', 'db', '10.0.0.2:5432', 60)")
+            .expect("SELECT should work");
+        Spi::run("SELECT etcd_service_register('etcd_test_server', 'cache', '10.0.0.3:6379', 60)")
+            .expect("SELECT should work");
+
+        let query_result = Spi::get_one::<String>("SELECT string_agg(address, ',' ORDER BY address) FROM etcd_services('etcd_test_server', 'db')")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("10.0.0.1:5432,10.0.0.2:5432")), query_result);
+
+        let query_result = Spi::get_one::<i64>("SELECT lease FROM test WHERE key = '/services/db/10.0.0.1:5432'")
+            .expect("SELECT should work");
+        assert_eq!(lease, query_result);
+
+        // Registering again moves the instance to a new lease
+        let refreshed = Spi::get_one::<i64>("SELECT etcd_service_register('etcd_test_server', 'db', '10.0.0.1:5432', 60)")
+            .expect("SELECT should work");
+        assert_ne!(lease, refreshed);
+
+        let deregistered = Spi::get_one::<bool>("SELECT etcd_service_deregister('etcd_test_server', 'db', '10.0.0.1:5432')")
+            .expect("SELECT should work");
+        assert_eq!(Some(true), deregistered);
+
+        let query_result = Spi::get_one::<String>("SELECT string_agg(name || '=' || address, ',' ORDER BY name) FROM etcd_services('etcd_test_server')")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("cache=10.0.0.3:6379,db=10.0.0.2:5432")), query_result);
+
+        // Registrations are restricted to the prefix of the user mapping like the tables
+        Spi::run("ALTER USER MAPPING FOR CURRENT_USER SERVER etcd_test_server OPTIONS (ADD prefix '/alice/')")
+            .expect("ALTER USER MAPPING should work");
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("SELECT etcd_service_register('etcd_test_server', 'db', '10.0.0.4:5432', 60)")
+                .expect("SELECT should fail");
+        });
+        assert!(result.is_err(), "Expected registrations outside of the prefix to be rejected");
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("SELECT etcd_service_deregister('etcd_test_server', 'cache', '10.0.0.3:6379')")
+                .expect("SELECT should fail");
+        });
+        assert!(result.is_err(), "Expected deregistrations outside of the prefix to be rejected");
+    }
+
+    #[pg_test]
+    fn test_config_store() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("SET etcd_fdw.config_server = 'etcd_test_server'").expect("SET should work");
+
+        Spi::run("SELECT etcd_config_set('max_workers', 8)").expect("SELECT should work");
+        Spi::run("SELECT etcd_config_set('maintenance', true)").expect("SELECT should work");
+
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = '/config/max_workers'")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("8")), query_result);
+
+        let max_workers = Spi::get_one::<i32>("SELECT etcd_config_get('max_workers', 4)")
+            .expect("SELECT should work");
+        assert_eq!(Some(8), max_workers);
+
+        let maintenance = Spi::get_one::<bool>("SELECT etcd_config_get('maintenance', false)")
+            .expect("SELECT should work");
+        assert_eq!(Some(true), maintenance);
+
+        let missing = Spi::get_one::<i32>("SELECT etcd_config_get('missing', 4)")
+            .expect("SELECT should work");
+        assert_eq!(Some(4), missing);
+
+        let text = Spi::get_one::<String>("SELECT etcd_config_get('max_workers')")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("8")), text);
+
+        // Cached values don't see changes of other clients until the TTL ran out
+        Spi::run("SET etcd_fdw.config_cache_ttl = 3600").expect("SET should work");
+        Spi::run("SELECT etcd_config_get('max_workers')").expect("SELECT should work");
+        Spi::run("UPDATE test SET value = '16' WHERE key = '/config/max_workers'").expect("UPDATE should work");
+        let cached = Spi::get_one::<i32>("SELECT etcd_config_get('max_workers', 4)")
+            .expect("SELECT should work");
+        assert_eq!(Some(8), cached);
+
+        Spi::run("SELECT etcd_config_set('max_workers', NULL::int)").expect("SELECT should work");
+        let removed = Spi::get_one::<i32>("SELECT etcd_config_get('max_workers', 4)")
+            .expect("SELECT should work");
+        assert_eq!(Some(4), removed);
+
+        // The configuration store is restricted to the prefix of the user mapping like the tables
+        Spi::run("ALTER USER MAPPING FOR CURRENT_USER SERVER etcd_test_server OPTIONS (ADD prefix '/alice/')")
+            .expect("ALTER USER MAPPING should work");
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("SELECT etcd_config_set('max_workers', 2)").expect("SELECT should fail");
+        });
+        assert!(result.is_err(), "Expected writes outside of the prefix to be rejected");
+    }
+
+    #[pg_test]
+    fn test_load_balancing() {
+        let (_container, url) = create_container();
+
+        create_fdt(url.clone());
+
+        // The unreachable first endpoint is skipped
+        Spi::run(&format!("CREATE SERVER etcd_pinned_server FOREIGN DATA WRAPPER etcd_fdw options(connstr '127.0.0.1:1, {}', load_balancing 'pick_first', connect_timeout '1')", url))
+            .expect("CREATE SERVER should work");
+        Spi::run(&format!("CREATE USER MAPPING FOR CURRENT_USER SERVER etcd_pinned_server OPTIONS (user '{}', password '{}')", ETCD_USER, ETCD_PASS))
+            .expect("CREATE USER MAPPING should work");
+        Spi::run("CREATE FOREIGN TABLE pinned (key text, value text) server etcd_pinned_server options (rowid_column 'key')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('foo', 'bar')").expect("INSERT should work");
+        let query_result = Spi::get_one::<String>("SELECT value FROM pinned WHERE key = 'foo'")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("bar")), query_result);
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("ALTER SERVER etcd_pinned_server OPTIONS (SET load_balancing 'random')").expect("ALTER should fail");
+        });
+        assert!(result.is_err(), "Expected an unknown load_balancing policy to be rejected");
+    }
+
+    #[pg_test]
+    fn test_point_cache() {
+        let (_container, url) = create_container();
+
+        create_fdt(url.clone());
+
+        Spi::run("CREATE FOREIGN TABLE hot (key text, value text, mod_revision bigint) server etcd_test_server options (rowid_column 'key', prefix '/hot/', point_cache 'true')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('/hot/a', '1')").expect("INSERT should work");
+
+        let query_result = Spi::get_one::<String>("SELECT value FROM hot WHERE key = '/hot/a'")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("1")), query_result);
+
+        // Writes of other clients reach the cache through the watch
+        let rt = tokio::runtime::Runtime::new().expect("Tokio runtime should be initialized");
+        rt.block_on(async {
+            let mut client: Client = Client::connect([url.clone()], Some(ConnectOptions::new().with_user(ETCD_USER, ETCD_PASS)))
+                .await
+                .expect("connect etcd");
+            client.put("/hot/a", "2", None).await.expect("put");
+        });
+        let mut query_result = None;
+        for _ in 0..50 {
+            query_result = Spi::get_one::<String>("SELECT value FROM hot WHERE key = '/hot/a'")
+                .expect("SELECT should work");
+            if query_result.as_deref() == Some("2") {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(Some(format!("2")), query_result);
+
+        // Own writes are seen right away
+        Spi::run("UPDATE hot SET value = '3' WHERE key = '/hot/a'").expect("UPDATE should work");
+        let query_result = Spi::get_one::<String>("SELECT value FROM hot WHERE key = '/hot/a'")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("3")), query_result);
+
+        Spi::run("DELETE FROM hot WHERE key = '/hot/a'").expect("DELETE should work");
+        let query_result = Spi::get_one::<i64>("SELECT count(*) FROM hot WHERE key = '/hot/a'")
+            .expect("SELECT should work");
+        assert_eq!(Some(0), query_result);
+    }
+
+    #[pg_test]
+    fn test_hedged_reads() {
+        let (_container, url) = create_container();
+
+        create_fdt(url.clone());
+
+        // An unreachable endpoint is covered by the other one
+        Spi::run(&format!("CREATE SERVER etcd_hedged_server FOREIGN DATA WRAPPER etcd_fdw options(connstr '{0},127.0.0.1:1', hedge_delay '0', consistency 's', connect_timeout '1')", url))
+            .expect("CREATE SERVER should work");
+        Spi::run(&format!("CREATE USER MAPPING FOR CURRENT_USER SERVER etcd_hedged_server OPTIONS (user '{}', password '{}')", ETCD_USER, ETCD_PASS))
+            .expect("CREATE USER MAPPING should work");
+        Spi::run("CREATE FOREIGN TABLE hedged (key text, value text) server etcd_hedged_server options (rowid_column 'key')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('foo', 'bar'), ('baz', 'qux')").expect("INSERT should work");
+        for _ in 0..5 {
+            let query_result = Spi::get_one::<String>("SELECT string_agg(value, ',' ORDER BY value) FROM hedged")
+                .expect("SELECT should work");
+            assert_eq!(Some(format!("bar,qux")), query_result);
+        }
+    }
+
+    #[pg_test]
+    fn test_watch_backoff() {
+        let backoff = Backoff {
+            min: Duration::from_millis(100),
+            max: Duration::from_millis(1000),
+        };
+        // Durations scaled by a float are rounded to the millisecond
+        let millis = |attempt, jitter| (backoff.jittered_delay(attempt, jitter).as_secs_f64() * 1000.0).round();
+        assert_eq!(100.0, millis(0, 1.0));
+        assert_eq!(100.0, millis(3, 0.0));
+        assert_eq!(250.0, millis(2, 0.5));
+        assert_eq!(1000.0, millis(20, 1.0));
+
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        let active_before = Spi::get_one::<i64>("SELECT active_watches FROM etcd_watch_stats()")
+            .expect("SELECT should work");
+
+        Spi::run("CREATE FOREIGN TABLE hot (key text, value text) server etcd_test_server options (rowid_column 'key', point_cache 'true')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("SELECT * FROM hot WHERE key = 'foo'").expect("SELECT should work");
+
+        let active = Spi::get_one::<i64>("SELECT active_watches FROM etcd_watch_stats()")
+            .expect("SELECT should work");
+        assert_eq!(active_before.map(|n| n + 1), active);
+    }
+
+    #[pg_test]
+    fn test_tls_backend() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        let built_with = if cfg!(feature = "tls-openssl") { "openssl" } else { "rustls" };
+        let other = if cfg!(feature = "tls-openssl") { "rustls" } else { "openssl" };
+
+        Spi::run(&format!("ALTER SERVER etcd_test_server OPTIONS (ADD tls_backend '{}')", built_with))
+            .expect("ALTER SERVER should work");
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run(&format!("ALTER SERVER etcd_test_server OPTIONS (SET tls_backend '{}')", other))
+                .expect("ALTER SERVER should fail");
+        });
+        assert!(result.is_err(), "Expected a TLS backend etcd_fdw wasn't built with to be rejected");
+    }
+
+    #[pg_test]
+    fn test_cas() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        let created = Spi::get_one::<bool>("SELECT etcd_cas('etcd_test_server', 'cas', NULL, 'a')")
+            .expect("etcd_cas should work");
+        assert_eq!(created, Some(true));
+        let created_again = Spi::get_one::<bool>("SELECT etcd_cas('etcd_test_server', 'cas', NULL, 'b')")
+            .expect("etcd_cas should work");
+        assert_eq!(created_again, Some(false));
+
+        let stale = Spi::get_one::<bool>("SELECT etcd_cas('etcd_test_server', 'cas', 'x', 'b')")
+            .expect("etcd_cas should work");
+        assert_eq!(stale, Some(false));
+        let swapped = Spi::get_one::<bool>("SELECT etcd_cas('etcd_test_server', 'cas', 'a', 'b')")
+            .expect("etcd_cas should work");
+        assert_eq!(swapped, Some(true));
+
+        let value = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'cas'")
+            .expect("SELECT should work");
+        assert_eq!(value.as_deref(), Some("b"));
+
+        let deleted_stale = Spi::get_one::<bool>("SELECT etcd_cad('etcd_test_server', 'cas', 0::bigint)")
+            .expect("etcd_cad should work");
+        assert_eq!(deleted_stale, Some(false));
+        let deleted = Spi::get_one::<bool>("SELECT etcd_cad('etcd_test_server', 'cas', 'b')")
+            .expect("etcd_cad should work");
+        assert_eq!(deleted, Some(true));
+
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE key = 'cas'")
+            .expect("SELECT should work");
+        assert_eq!(count, Some(0));
+
+        // Keys outside of the prefix of the user mapping can't be written
+        Spi::run("ALTER USER MAPPING FOR CURRENT_USER SERVER etcd_test_server OPTIONS (ADD prefix '/alice/')")
+            .expect("ALTER USER MAPPING should work");
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("SELECT etcd_cas('etcd_test_server', 'cas', NULL, 'a')").expect("etcd_cas should fail");
+        });
+        assert!(result.is_err(), "Expected etcd_cas outside of the prefix to be rejected");
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("SELECT etcd_cad('etcd_test_server', 'cas', 0::bigint)").expect("etcd_cad should fail");
+        });
+        assert!(result.is_err(), "Expected etcd_cad outside of the prefix to be rejected");
+    }
+
+    #[pg_test]
+    fn test_selftest() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        let failed = Spi::get_one::<i64>("SELECT count(*) FROM etcd_fdw_selftest('etcd_test_server') WHERE NOT passed")
+            .expect("etcd_fdw_selftest should work");
+        assert_eq!(failed, Some(0));
+
+        let checks = Spi::get_one::<String>(
+            "SELECT string_agg(DISTINCT check_name, ',' ORDER BY check_name) FROM etcd_fdw_selftest('etcd_test_server')",
+        )
+        .expect("etcd_fdw_selftest should work");
+        assert_eq!(
+            checks.as_deref(),
+            Some("authentication,endpoint,latency linearizable,latency serializable,read,write")
+        );
+    }
+
+    #[pg_test]
+    fn test_unique_key() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        // The joined table can't add or duplicate rows, so the join is removed
+        let plan = Spi::get_one::<pgrx::Json>(
+            "EXPLAIN (FORMAT JSON) SELECT a.key FROM test a LEFT JOIN test b ON a.key = b.key",
+        )
+        .expect("EXPLAIN should work")
+        .expect("EXPLAIN should return a plan");
+        assert_eq!(plan.0[0]["Plan"]["Node Type"].as_str(), Some("Foreign Scan"));
+    }
+
+    #[pg_test]
+    fn test_key_patterns() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run(
+            "INSERT INTO test (key, value) VALUES
+                ('/hosts/a/status', 'up'), ('/hosts/a/url', 'a.example'),
+                ('/hosts/b/status', 'down'), ('/hosts/b/url', 'b.example'),
+                ('/hosts_old', 'x'), ('/other/c/status', 'up')",
+        )
+        .expect("INSERT should work");
+
+        let statuses = Spi::get_one::<String>(
+            "SELECT string_agg(key || '=' || value, ',' ORDER BY key) FROM test WHERE key LIKE '/hosts/%/status'",
+        )
+        .expect("SELECT should work");
+        assert_eq!(statuses.as_deref(), Some("/hosts/a/status=up,/hosts/b/status=down"));
+
+        // `_` is a wildcard, not part of the prefix
+        let single = Spi::get_one::<String>("SELECT string_agg(key, ',' ORDER BY key) FROM test WHERE key LIKE '/hosts_a/url'")
+            .expect("SELECT should work");
+        assert_eq!(single, None);
+        let escaped = Spi::get_one::<String>("SELECT string_agg(key, ',' ORDER BY key) FROM test WHERE key LIKE '/hosts\\_%'")
+            .expect("SELECT should work");
+        assert_eq!(escaped.as_deref(), Some("/hosts_old"));
+
+        let urls = Spi::get_one::<String>(
+            "SELECT string_agg(value, ',' ORDER BY key) FROM test WHERE key ~ '^/hosts/[ab]/url$'",
+        )
+        .expect("SELECT should work");
+        assert_eq!(urls.as_deref(), Some("a.example,b.example"));
+    }
+
+    #[pg_test]
+    fn test_sub_key_layout() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE hosts (key text, url text, status text) server etcd_test_server options (rowid_column 'key', prefix '/hosts/', layout 'sub_keys')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO hosts (key, url, status) VALUES ('/hosts/pg-eu-01', 'pg1.example', 'up'), ('/hosts/pg-eu-02', 'pg2.example', NULL)")
+            .expect("INSERT should work");
+
+        // Every column is its own key
+        let status = Spi::get_one::<String>("SELECT value FROM test WHERE key = '/hosts/pg-eu-01/status'")
+            .expect("SELECT should work");
+        assert_eq!(status.as_deref(), Some("up"));
+        let sub_keys = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE key LIKE '/hosts/%'")
+            .expect("SELECT should work");
+        assert_eq!(sub_keys, Some(3));
+
+        let rows = Spi::get_one::<String>(
+            "SELECT string_agg(key || ' ' || url || ' ' || coalesce(status, '-'), ',' ORDER BY key) FROM hosts",
+        )
+        .expect("SELECT should work");
+        assert_eq!(rows.as_deref(), Some("/hosts/pg-eu-01 pg1.example up,/hosts/pg-eu-02 pg2.example -"));
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("INSERT INTO hosts (key, url) VALUES ('/hosts/pg-eu-01', 'other.example')")
+                .expect("INSERT should fail");
+        });
+        assert!(result.is_err(), "Expected an insert of an existing row to fail");
+
+        Spi::run("UPDATE hosts SET status = NULL, url = 'pg1.internal' WHERE key = '/hosts/pg-eu-01'")
+            .expect("UPDATE should work");
+        let row = Spi::get_two::<String, String>("SELECT url, status FROM hosts WHERE key = '/hosts/pg-eu-01'")
+            .expect("SELECT should work");
+        assert_eq!(row, (Some("pg1.internal".to_string()), None));
+
+        Spi::run("DELETE FROM hosts WHERE key = '/hosts/pg-eu-02'").expect("DELETE should work");
+        let remaining = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE key LIKE '/hosts/%'")
+            .expect("SELECT should work");
+        assert_eq!(remaining, Some(1));
+
+        // Sub-keys are named after the row key, which has to be UTF-8
+        Spi::run("CREATE FOREIGN TABLE hosts_bytea (key bytea, url text) server etcd_test_server options (rowid_column 'key', prefix '/hosts/', layout 'sub_keys')")
+            .expect("CREATE FOREIGN TABLE should work");
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("INSERT INTO hosts_bytea (key, url) VALUES ('\\x2f686f7374732fff'::bytea, 'pg3.example')")
+                .expect("INSERT should fail");
+        });
+        assert!(result.is_err(), "Expected an insert of a row key that isn't UTF-8 to fail");
+    }
+
+    #[pg_test]
+    fn test_modify_lock() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (ADD prefix '/etl/', ADD modify_lock 'true')")
+            .expect("ALTER FOREIGN TABLE should work");
+
+        // A lock that isn't released would block the second statement
+        Spi::run("INSERT INTO test (key, value) VALUES ('/etl/a', '1'), ('/etl/b', '2')").expect("INSERT should work");
+        Spi::run("UPDATE test SET value = '3' WHERE key = '/etl/a'").expect("UPDATE should work");
+
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (DROP prefix)").expect("ALTER FOREIGN TABLE should work");
+        let locks = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE key LIKE '/etcd_fdw/locks%'")
+            .expect("SELECT should work");
+        assert_eq!(locks, Some(0));
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("ALTER FOREIGN TABLE test OPTIONS (SET modify_lock 'yes')").expect("ALTER should fail");
+        });
+        assert!(result.is_err(), "Expected an invalid modify_lock to be rejected");
+    }
+
+    #[pg_test]
+    fn test_max_inflight_requests() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("SET etcd_fdw.max_inflight_requests = 1").expect("SET should work");
+        Spi::run("ALTER SERVER etcd_test_server OPTIONS (ADD max_inflight_requests '1')")
+            .expect("ALTER SERVER should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('a', '1'), ('b', '2')").expect("INSERT should work");
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM test").expect("SELECT should work");
+        assert_eq!(count, Some(2));
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("ALTER SERVER etcd_test_server OPTIONS (SET max_inflight_requests '-1')")
+                .expect("ALTER SERVER should fail");
+        });
+        assert!(result.is_err(), "Expected a negative max_inflight_requests to be rejected");
+    }
+
+    #[pg_test]
+    fn test_cache_ttl() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('a', '1')").expect("INSERT should work");
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (ADD cache_ttl '3600s')").expect("ALTER should work");
+        let value = Spi::get_one::<String>("SELECT value FROM test WHERE key >= 'a'").expect("SELECT should work");
+        assert_eq!(value.as_deref(), Some("1"));
+
+        // a write of another client isn't seen until the cache is flushed
+        Spi::run("SELECT etcd_cas('etcd_test_server', 'a', '1', '2')").expect("etcd_cas should work");
+        let value = Spi::get_one::<String>("SELECT value FROM test WHERE key >= 'a'").expect("SELECT should work");
+        assert_eq!(value.as_deref(), Some("1"));
+
+        let flushed = Spi::get_one::<i64>("SELECT etcd_fdw_flush_cache('test')").expect("flush should work");
+        assert_eq!(flushed, Some(1));
+        let value = Spi::get_one::<String>("SELECT value FROM test WHERE key >= 'a'").expect("SELECT should work");
+        assert_eq!(value.as_deref(), Some("2"));
+
+        // writes through the backend drop the cache
+        Spi::run("UPDATE test SET value = '3' WHERE key = 'a'").expect("UPDATE should work");
+        let value = Spi::get_one::<String>("SELECT value FROM test WHERE key >= 'a'").expect("SELECT should work");
+        assert_eq!(value.as_deref(), Some("3"));
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("ALTER FOREIGN TABLE test OPTIONS (SET cache_ttl 'soon')").expect("ALTER should fail");
+        });
+        assert!(result.is_err(), "Expected an invalid cache_ttl to be rejected");
+    }
+
+    #[pg_test]
+    fn test_keyspace_report() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('/app/a', '12'), ('/app/b/c', '345'), ('/db/x', '6'), ('top', '78')")
+            .expect("INSERT should work");
+
+        let report = Spi::get_one::<String>(
+            "SELECT string_agg(format('%s=%s/%s', prefix, keys, value_bytes), ',' ORDER BY prefix) FROM etcd_keyspace_report('etcd_test_server', 1)",
+        )
+        .expect("etcd_keyspace_report should work");
+        assert_eq!(report.as_deref(), Some("/app/=2/5,/db/=1/1,top=1/2"));
+
+        let total = Spi::get_one::<i64>("SELECT sum(keys)::bigint FROM etcd_keyspace_report('etcd_test_server', 0)")
+            .expect("etcd_keyspace_report should work");
+        assert_eq!(total, Some(4));
+    }
+
+    #[pg_test]
+    fn test_keyset_pagination() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("ALTER FOREIGN TABLE test ALTER COLUMN key TYPE text COLLATE \"C\"").expect("ALTER should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('a', '1'), ('b', '2'), ('c', '3'), ('d', '4')")
+            .expect("INSERT should work");
+
+        // no local sort between the limit and the scan
+        let plan = Spi::get_one::<pgrx::Json>("EXPLAIN (FORMAT JSON) SELECT * FROM test WHERE key > 'a' ORDER BY key LIMIT 2")
+            .expect("EXPLAIN should work")
+            .expect("EXPLAIN should return a plan");
+        assert_eq!(plan.0[0]["Plan"]["Node Type"].as_str(), Some("Limit"));
+        assert_eq!(plan.0[0]["Plan"]["Plans"][0]["Node Type"].as_str(), Some("Foreign Scan"));
+
+        let page = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test WHERE key > 'a' ORDER BY key LIMIT 2) p",
+        )
+        .expect("SELECT should work");
+        assert_eq!(page.as_deref(), Some("b,c"));
+
+        let page = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test WHERE key < 'd' ORDER BY key DESC LIMIT 2) p",
+        )
+        .expect("SELECT should work");
+        assert_eq!(page.as_deref(), Some("c,b"));
+
+        let keys = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test WHERE key IN ('a', 'c') ORDER BY key DESC) p",
+        )
+        .expect("SELECT should work");
+        assert_eq!(keys.as_deref(), Some("c,a"));
+    }
+
+    #[pg_test]
+    fn test_statement_cache() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test2 (key text, value text) server etcd_test_server options (rowid_column 'key')")
+            .expect("Second table should have been created");
+        Spi::run("INSERT INTO test (key, value) VALUES ('a', '1'), ('b', '2')").expect("INSERT should work");
+        Spi::run("SET etcd_fdw.statement_cache = on").expect("SET should work");
+
+        let joined = Spi::get_one::<i64>("SELECT count(*) FROM test t1 JOIN test2 t2 USING (key)")
+            .expect("SELECT should work");
+        assert_eq!(joined, Some(2));
+
+        // the next statement reads again
+        Spi::run("UPDATE test SET value = '3' WHERE key = 'a'").expect("UPDATE should work");
+        let values = Spi::get_one::<String>(
+            "SELECT string_agg(t1.value || t2.value, ',' ORDER BY key) FROM test t1 JOIN test2 t2 USING (key)",
+        )
+        .expect("SELECT should work");
+        assert_eq!(values.as_deref(), Some("33,22"));
+    }
+
+    #[pg_test]
+    fn test_point_get() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('/gather/7', '1'), ('/gather/78', '2'), ('/gather/780', '3')")
+            .expect("INSERT should work");
+
+        let value = Spi::get_one::<String>("SELECT value FROM test WHERE key = '/gather/78'").expect("SELECT should work");
+        assert_eq!(value.as_deref(), Some("2"));
+
+        let missing = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE key = '/gather/79'").expect("SELECT should work");
+        assert_eq!(missing, Some(0));
+
+        // keys outside of the table's prefix aren't read
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (ADD prefix '/other/')").expect("ALTER should work");
+        let outside = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE key = '/gather/78'").expect("SELECT should work");
+        assert_eq!(outside, Some(0));
+    }
+
+    #[pg_test]
+    fn test_like_prefix() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('/config/a', '1'), ('/config/b', '2'), ('/configs', '3'), ('/data/a', '4')")
+            .expect("INSERT should work");
+
+        let config = Spi::get_one::<String>("SELECT string_agg(value, ',' ORDER BY key) FROM test WHERE key LIKE '/config/%'")
+            .expect("SELECT should work");
+        assert_eq!(config.as_deref(), Some("1,2"));
+
+        let exact = Spi::get_one::<String>("SELECT string_agg(value, ',' ORDER BY key) FROM test WHERE key LIKE '/config/b'")
+            .expect("SELECT should work");
+        assert_eq!(exact.as_deref(), Some("2"));
+
+        let none = Spi::get_one::<String>("SELECT string_agg(value, ',') FROM test WHERE key LIKE '/config'")
+            .expect("SELECT should work");
+        assert_eq!(none, None);
+    }
+
+    #[pg_test]
+    fn test_key_range() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("ALTER FOREIGN TABLE test ALTER COLUMN key TYPE text COLLATE \"C\"").expect("ALTER should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('/a', '1'), ('/a/x', '2'), ('/b', '3'), ('/c', '4')")
+            .expect("INSERT should work");
+
+        let half_open = Spi::get_one::<String>("SELECT string_agg(value, ',' ORDER BY key) FROM test WHERE key >= '/a' AND key < '/b'")
+            .expect("SELECT should work");
+        assert_eq!(half_open.as_deref(), Some("1,2"));
+
+        let between = Spi::get_one::<String>("SELECT string_agg(value, ',' ORDER BY key) FROM test WHERE key BETWEEN '/a/x' AND '/b'")
+            .expect("SELECT should work");
+        assert_eq!(between.as_deref(), Some("2,3"));
+
+        // the key on the right of the comparison
+        let commuted = Spi::get_one::<String>("SELECT string_agg(value, ',' ORDER BY key) FROM test WHERE '/b' > key")
+            .expect("SELECT should work");
+        assert_eq!(commuted.as_deref(), Some("1,2"));
+    }
+
+    #[pg_test]
+    fn test_implicit_keys_only() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('a', repeat('x', 100000)), ('b', '2')").expect("INSERT should work");
+
+        let keys = Spi::get_one::<String>("SELECT string_agg(key, ',' ORDER BY key) FROM test").expect("SELECT should work");
+        assert_eq!(keys.as_deref(), Some("a,b"));
+
+        // values are still read when a condition needs them
+        let filtered = Spi::get_one::<String>("SELECT string_agg(key, ',') FROM test WHERE value = '2'").expect("SELECT should work");
+        assert_eq!(filtered.as_deref(), Some("b"));
+    }
+
+    #[pg_test]
+    fn test_count_only() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) SELECT '/k/' || i, i::text FROM generate_series(1, 50) i").expect("INSERT should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('/other', 'x')").expect("INSERT should work");
+
+        let all = Spi::get_one::<i64>("SELECT count(*) FROM test").expect("SELECT should work");
+        assert_eq!(all, Some(51));
+
+        // only the key is needed for the prefix, it's rechecked along with the other rows
+        let prefixed = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE key LIKE '/k/%'").expect("SELECT should work");
+        assert_eq!(prefixed, Some(50));
+
+        let filtered = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE value = 'x'").expect("SELECT should work");
+        assert_eq!(filtered, Some(1));
+    }
+
+    #[pg_test]
+    fn test_limit_offset() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("ALTER FOREIGN TABLE test ALTER COLUMN key TYPE text COLLATE \"C\"").expect("ALTER should work");
+        Spi::run("INSERT INTO test (key, value) SELECT lpad(i::text, 3, '0'), 'v' || i FROM generate_series(1, 30) i")
+            .expect("INSERT should work");
+
+        let page = Spi::get_one::<String>(
+            "SELECT string_agg(key || '=' || value, ',') FROM (SELECT * FROM test ORDER BY key LIMIT 3 OFFSET 10) p",
+        )
+        .expect("SELECT should work");
+        assert_eq!(page.as_deref(), Some("011=v11,012=v12,013=v13"));
+
+        let descending = Spi::get_one::<String>(
+            "SELECT string_agg(value, ',') FROM (SELECT * FROM test ORDER BY key DESC LIMIT 2 OFFSET 5) p",
+        )
+        .expect("SELECT should work");
+        assert_eq!(descending.as_deref(), Some("v25,v24"));
+
+        let past_end = Spi::get_one::<i64>("SELECT count(*) FROM (SELECT * FROM test ORDER BY key LIMIT 5 OFFSET 40) p")
+            .expect("SELECT should work");
+        assert_eq!(past_end, Some(0));
+    }
+
+    #[pg_test]
+    fn test_multi_column_sort() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("ALTER FOREIGN TABLE test ALTER COLUMN key TYPE text COLLATE \"C\", ALTER COLUMN value TYPE text COLLATE \"C\"")
+            .expect("ALTER should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('a', '2'), ('b', '1'), ('c', '2'), ('d', '1')").expect("INSERT should work");
+
+        let sorted = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test ORDER BY value DESC, key LIMIT 3) s",
+        )
+        .expect("SELECT should work");
+        assert_eq!(sorted.as_deref(), Some("a,c,b"));
+
+        let by_key = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test ORDER BY key DESC, value LIMIT 2) s",
+        )
+        .expect("SELECT should work");
+        assert_eq!(by_key.as_deref(), Some("d,c"));
+
+        // an expression etcd can't sort by is sorted locally
+        let local = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test ORDER BY upper(key) DESC LIMIT 2) s",
+        )
+        .expect("SELECT should work");
+        assert_eq!(local.as_deref(), Some("d,c"));
+    }
+
+    #[pg_test]
+    fn test_sort_by_metadata() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test_meta (key text, value text, create_revision bigint, mod_revision bigint, version bigint, lease bigint) server etcd_test_server options (rowid_column 'key')")
+            .expect("Test table should have been created");
+        Spi::run("INSERT INTO test (key, value) VALUES ('a', '1')").expect("INSERT should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('b', '1')").expect("INSERT should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('c', '1')").expect("INSERT should work");
+        Spi::run("UPDATE test SET value = '2' WHERE key = 'a'").expect("UPDATE should work");
+
+        let recent = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test_meta ORDER BY mod_revision DESC LIMIT 2) r",
+        )
+        .expect("SELECT should work");
+        assert_eq!(recent.as_deref(), Some("a,c"));
+
+        let oldest = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test_meta ORDER BY create_revision LIMIT 2) r",
+        )
+        .expect("SELECT should work");
+        assert_eq!(oldest.as_deref(), Some("a,b"));
+
+        let versions = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test_meta ORDER BY version DESC, key LIMIT 2) r",
+        )
+        .expect("SELECT should work");
+        assert_eq!(versions.as_deref(), Some("a,b"));
+
+        let by_lease = Spi::get_one::<i64>("SELECT count(*) FROM (SELECT key FROM test_meta ORDER BY lease LIMIT 2) r")
+            .expect("SELECT should work");
+        assert_eq!(by_lease, Some(2));
+    }
+
+    #[pg_test]
+    fn test_revision_filters() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test_meta (key text, value text, create_revision bigint, mod_revision bigint) server etcd_test_server options (rowid_column 'key')")
+            .expect("Test table should have been created");
+        Spi::run("INSERT INTO test (key, value) VALUES ('a', '1'), ('b', '1')").expect("INSERT should work");
+        let since = Spi::get_one::<i64>("SELECT max(mod_revision) FROM test_meta")
+            .expect("SELECT should work")
+            .expect("keys should have a revision");
+        Spi::run("UPDATE test SET value = '2' WHERE key = 'b'").expect("UPDATE should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('c', '1')").expect("INSERT should work");
+
+        let changed = Spi::get_one::<String>(&format!(
+            "SELECT string_agg(key, ',' ORDER BY key) FROM test_meta WHERE mod_revision > {}",
+            since
+        ))
+        .expect("SELECT should work");
+        assert_eq!(changed.as_deref(), Some("b,c"));
+
+        let created = Spi::get_one::<String>(&format!(
+            "SELECT string_agg(key, ',' ORDER BY key) FROM test_meta WHERE create_revision <= {}",
+            since
+        ))
+        .expect("SELECT should work");
+        assert_eq!(created.as_deref(), Some("a,b"));
+
+        let commuted = Spi::get_one::<String>(&format!(
+            "SELECT string_agg(key, ',' ORDER BY key) FROM test_meta WHERE {} < mod_revision",
+            since
+        ))
+        .expect("SELECT should work");
+        assert_eq!(commuted.as_deref(), Some("b,c"));
+    }
+
+    #[pg_test]
+    fn test_read_revision() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test_meta (key text, value text, mod_revision bigint) server etcd_test_server options (rowid_column 'key')")
+            .expect("Test table should have been created");
+        Spi::run("INSERT INTO test (key, value) VALUES ('a', '1')").expect("INSERT should work");
+        let before = Spi::get_one::<i64>("SELECT mod_revision FROM test_meta WHERE key = 'a'")
+            .expect("SELECT should work")
+            .expect("key should have a revision");
+        Spi::run("UPDATE test SET value = '2' WHERE key = 'a'").expect("UPDATE should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('b', '1')").expect("INSERT should work");
+
+        Spi::run(&format!("SET etcd_fdw.read_revision = '{}'", before)).expect("SET should work");
+        let old = Spi::get_one::<String>("SELECT string_agg(key || '=' || value, ',' ORDER BY key) FROM test")
+            .expect("SELECT should work");
+        assert_eq!(old.as_deref(), Some("a=1"));
+
+        Spi::run("SET etcd_fdw.read_revision = '0'").expect("SET should work");
+        let latest = Spi::get_one::<String>("SELECT string_agg(key || '=' || value, ',' ORDER BY key) FROM test")
+            .expect("SELECT should work");
+        assert_eq!(latest.as_deref(), Some("a=2,b=1"));
+    }
+
+    #[pg_test]
+    fn test_limit_with_local_conditions() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('a', '1'), ('b', '2'), ('c', '2'), ('d', '1')")
+            .expect("INSERT should work");
+
+        let filtered = Spi::get_one::<String>("SELECT key FROM test WHERE value = '2' ORDER BY key LIMIT 1")
+            .expect("SELECT should work");
+        assert_eq!(filtered.as_deref(), Some("b"));
+
+        let unextracted = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test WHERE key = 'z' OR value = '1' ORDER BY key LIMIT 2) t",
+        )
+        .expect("SELECT should work");
+        assert_eq!(unextracted.as_deref(), Some("a,d"));
+
+        let pushed = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test WHERE key > 'a' ORDER BY key LIMIT 2) t",
+        )
+        .expect("SELECT should work");
+        assert_eq!(pushed.as_deref(), Some("b,c"));
+    }
+
+    #[pg_test]
+    fn test_limit_not_pushed() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('a', '1'), ('b', '1'), ('c', '2')")
+            .expect("INSERT should work");
+        Spi::run("CREATE TABLE local_keys (k text)").expect("CREATE TABLE should work");
+        Spi::run("INSERT INTO local_keys VALUES ('c')").expect("INSERT should work");
+
+        let joined = Spi::get_one::<String>("SELECT t.key FROM test t JOIN local_keys l ON l.k = t.key ORDER BY t.key LIMIT 1")
+            .expect("SELECT should work");
+        assert_eq!(joined.as_deref(), Some("c"));
+
+        let distinct = Spi::get_one::<String>(
+            "SELECT string_agg(value, ',') FROM (SELECT DISTINCT value FROM test ORDER BY value LIMIT 2) t",
+        )
+        .expect("SELECT should work");
+        assert_eq!(distinct.as_deref(), Some("1,2"));
+
+        let ties = Spi::get_one::<i64>(
+            "SELECT count(*) FROM (SELECT key FROM test ORDER BY value FETCH FIRST 1 ROWS WITH TIES) t",
+        )
+        .expect("SELECT should work");
+        assert_eq!(ties, Some(2));
+    }
+
+    #[pg_test]
+    fn test_key_bounds() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('a', '1'), ('b', '2'), ('c', '3'), ('d', '4'), ('e', '5')")
+            .expect("INSERT should work");
+
+        let keys = |condition: &str| {
+            Spi::get_one::<String>(&format!(
+                "SELECT string_agg(key, ',' ORDER BY key) FROM test WHERE {}",
+                condition
+            ))
+            .expect("SELECT should work")
+        };
+        assert_eq!(keys("key >= 'b' AND key <= 'd'").as_deref(), Some("b,c,d"));
+        assert_eq!(keys("key > 'b' AND key < 'd'").as_deref(), Some("c"));
+        assert_eq!(keys("key >= 'b' AND key < 'd'").as_deref(), Some("b,c"));
+        assert_eq!(keys("key > 'b' AND key <= 'd'").as_deref(), Some("c,d"));
+        assert_eq!(keys("key BETWEEN 'b' AND 'c'").as_deref(), Some("b,c"));
+        assert_eq!(keys("key > 'a' AND key > 'c' AND key < 'e' AND key < 'd'").as_deref(), None);
+        assert_eq!(keys("key >= 'c' AND key >= 'a' AND key <= 'e' AND key <= 'd'").as_deref(), Some("c,d"));
+        assert_eq!(keys("key > 'd' AND key < 'b'").as_deref(), None);
+        assert_eq!(keys("key = 'c' AND key >= 'b' AND key < 'e'").as_deref(), Some("c"));
+        assert_eq!(keys("key = 'c' AND key > 'c'").as_deref(), None);
+
+        let page = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test WHERE key > 'a' AND key < 'e' AND key >= 'b' ORDER BY key LIMIT 2) t",
+        )
+        .expect("SELECT should work");
+        assert_eq!(page.as_deref(), Some("b,c"));
+    }
+
+    #[pg_test]
+    fn test_starts_with() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('/a/1', '1'), ('/a/2', '2'), ('/ab', '3'), ('/b/1', '4')")
+            .expect("INSERT should work");
+
+        let operator = Spi::get_one::<String>("SELECT string_agg(key, ',' ORDER BY key) FROM test WHERE key ^@ '/a/'")
+            .expect("SELECT should work");
+        assert_eq!(operator.as_deref(), Some("/a/1,/a/2"));
+
+        let function =
+            Spi::get_one::<String>("SELECT string_agg(key, ',' ORDER BY key) FROM test WHERE starts_with(key, '/a')")
+                .expect("SELECT should work");
+        assert_eq!(function.as_deref(), Some("/a/1,/a/2,/ab"));
+
+        let disjoint = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',' ORDER BY key) FROM test WHERE key ^@ '/a/' AND starts_with(key, '/b')",
+        )
+        .expect("SELECT should work");
+        assert_eq!(disjoint, None);
+
+        let commuted = Spi::get_one::<String>("SELECT string_agg(key, ',' ORDER BY key) FROM test WHERE '/a/1/x' ^@ key")
+            .expect("SELECT should work");
+        assert_eq!(commuted.as_deref(), Some("/a/1"));
+
+        let page = Spi::get_one::<String>(
+            "SELECT string_agg(key, ',') FROM (SELECT key FROM test WHERE starts_with(key, '/a') ORDER BY key LIMIT 2) t",
+        )
+        .expect("SELECT should work");
+        assert_eq!(page.as_deref(), Some("/a/1,/a/2"));
+    }
+
+    #[pg_test]
+    fn test_dynamic_prefix() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('/config/a', '1'), ('/config/b', '2'), ('/flags/a', '3')")
+            .expect("INSERT should work");
+        Spi::run("SET plan_cache_mode = force_generic_plan").expect("SET should work");
+        Spi::run("PREPARE subtree(text) AS SELECT string_agg(key, ',' ORDER BY key) FROM test WHERE key ^@ $1")
+            .expect("PREPARE should work");
+
+        let config = Spi::get_one::<String>("EXECUTE subtree('/config/')").expect("EXECUTE should work");
+        assert_eq!(config.as_deref(), Some("/config/a,/config/b"));
+
+        let flags = Spi::get_one::<String>("EXECUTE subtree('/flags/')").expect("EXECUTE should work");
+        assert_eq!(flags.as_deref(), Some("/flags/a"));
+
+        let missing = Spi::get_one::<String>("EXECUTE subtree('/jobs/')").expect("EXECUTE should work");
+        assert_eq!(missing, None);
+    }
+
+    #[pg_test]
+    fn test_fetch_size() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('a', '1'), ('b', '2'), ('c', '3'), ('d', '4'), ('e', '5')")
+            .expect("INSERT should work");
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (ADD fetch_size '2')").expect("ALTER should work");
+
+        let all = Spi::get_one::<String>("SELECT string_agg(key, ',') FROM test").expect("SELECT should work");
+        assert_eq!(all.as_deref(), Some("a,b,c,d,e"));
+
+        let descending = Spi::get_one::<String>("SELECT string_agg(key, ',') FROM (SELECT key FROM test ORDER BY key DESC) t")
+            .expect("SELECT should work");
+        assert_eq!(descending.as_deref(), Some("e,d,c,b,a"));
+
+        let limited = Spi::get_one::<String>("SELECT string_agg(key, ',') FROM (SELECT key FROM test ORDER BY key LIMIT 3) t")
+            .expect("SELECT should work");
+        assert_eq!(limited.as_deref(), Some("a,b,c"));
+
+        let range = Spi::get_one::<String>("SELECT string_agg(key || '=' || value, ',') FROM test WHERE key > 'a' AND key < 'e'")
+            .expect("SELECT should work");
+        assert_eq!(range.as_deref(), Some("b=2,c=3,d=4"));
+
+        Spi::run("SET etcd_fdw.fetch_size = '0'").expect("SET should work");
+        let unpaged = Spi::get_one::<i64>("SELECT count(key) FROM test").expect("SELECT should work");
+        assert_eq!(unpaged, Some(5));
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("ALTER FOREIGN TABLE test OPTIONS (SET fetch_size '-1')").expect("ALTER should fail");
+        });
+
+        assert!(result.is_err(), "Expected a negative fetch_size to be rejected");
+    }
+
+    #[pg_test]
+    fn test_rel_size_estimate() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) SELECT 'k' || i, repeat('x', 100) FROM generate_series(1, 500) i")
+            .expect("INSERT should work");
+
+        let plan = Spi::get_one::<pgrx::Json>("EXPLAIN (FORMAT JSON) SELECT * FROM test")
+            .expect("EXPLAIN should work")
+            .expect("EXPLAIN should return a plan");
+        assert_eq!(500, plan.0[0]["Plan"]["Plan Rows"].as_i64().unwrap());
+        assert!(plan.0[0]["Plan"]["Plan Width"].as_i64().unwrap() >= 100);
+
+        let plan = Spi::get_one::<pgrx::Json>("EXPLAIN (FORMAT JSON) SELECT * FROM test WHERE key = 'k1'")
+            .expect("EXPLAIN should work")
+            .expect("EXPLAIN should return a plan");
+        assert!(plan.0[0]["Plan"]["Plan Rows"].as_i64().unwrap() < 500);
+    }
+
+    #[pg_test]
+    fn test_scan_cost() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) SELECT 'k' || i, 'v' FROM generate_series(1, 5000) i")
+            .expect("INSERT should work");
+
+        let cost = |query: &str| {
+            let plan = Spi::get_one::<pgrx::Json>(&format!("EXPLAIN (FORMAT JSON) {}", query))
+                .expect("EXPLAIN should work")
+                .expect("EXPLAIN should return a plan");
+            plan.0[0]["Plan"]["Total Cost"].as_f64().unwrap()
+        };
+        let full = cost("SELECT * FROM test");
+        let point = cost("SELECT * FROM test WHERE key = 'k1'");
+        let value = cost("SELECT * FROM test WHERE value = 'v'");
+        assert!(point < full, "a point get should be cheaper than a full scan");
+        assert!(value > 4.0 * point, "conditions on the value don't reduce the keys read");
+    }
+    #[pg_test]
+    fn test_parameterized_join() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) SELECT 'k' || i, 'v' || i FROM generate_series(1, 100) i")
+            .expect("INSERT should work");
+        Spi::run("CREATE TABLE names (name text)").expect("CREATE TABLE should work");
+        Spi::run("INSERT INTO names VALUES ('k1'), ('k42'), ('missing'), (NULL)").expect("INSERT should work");
+        Spi::run("ANALYZE names").expect("ANALYZE should work");
+        Spi::run("SET enable_hashjoin = off").expect("SET should work");
+        Spi::run("SET enable_mergejoin = off").expect("SET should work");
+
+        let query = "SELECT string_agg(e.value, ',' ORDER BY e.value) FROM names n JOIN test e ON e.key = n.name";
+        let plan = Spi::get_one::<pgrx::Json>(&format!("EXPLAIN (FORMAT JSON) {}", query))
+            .expect("EXPLAIN should work")
+            .expect("EXPLAIN should return a plan");
+        assert!(plan.0.to_string().contains("Nested Loop"), "{}", plan.0);
+
+        let values = Spi::get_one::<String>(query).expect("SELECT should work");
+        assert_eq!(values, Some("v1,v42".to_string()));
+    }
+    #[pg_test]
+    fn test_explain_request() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('/a/1', 'x'), ('/a/2', 'y'), ('/b/1', 'z')")
+            .expect("INSERT should work");
+
+        let request = |query: &str| {
+            let plan = Spi::get_one::<pgrx::Json>(&format!("EXPLAIN (VERBOSE, FORMAT JSON) {}", query))
+                .expect("EXPLAIN should work")
+                .expect("EXPLAIN should return a plan");
+            let mut node = &plan.0[0]["Plan"];
+            while !node.is_null() && node["Node Type"] != "Foreign Scan" {
+                node = &node["Plans"][0];
+            }
+            node["etcd Request"].as_str().unwrap_or_default().to_string()
+        };
+        assert!(request("SELECT * FROM test WHERE key = '/a/1'").starts_with("get key \"/a/1\""));
+        let range = request("SELECT * FROM test WHERE key ^@ '/a/' ORDER BY key DESC LIMIT 1");
+        assert!(range.starts_with("range key \"/a/\" range_end \"/a0\""), "{}", range);
+        assert!(range.contains("limit 1") && range.contains("sort key descend"), "{}", range);
+        assert!(request("SELECT key FROM test").contains("keys_only"));
+        assert_eq!(request("SELECT * FROM test WHERE key > '/b' AND key < '/a'"), "none");
+    }
+    #[pg_test]
+    fn test_explain_analyze_stats() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) SELECT 'k' || i, 'value' FROM generate_series(1, 10) i")
+            .expect("INSERT should work");
+
+        let plan = Spi::get_one::<pgrx::Json>("EXPLAIN (ANALYZE, FORMAT JSON) SELECT * FROM test")
+            .expect("EXPLAIN should work")
+            .expect("EXPLAIN should return a plan");
+        let scan = &plan.0[0]["Plan"];
+        assert_eq!(scan["etcd Requests"], 1);
+        assert_eq!(scan["etcd Keys"], 10);
+        assert!(scan["etcd Bytes"].as_i64().unwrap() >= 10 * 7, "{}", scan);
+        assert!(scan["etcd Time"].as_f64().is_some(), "{}", scan);
+
+        Spi::run("SET etcd_fdw.fetch_size = 4").expect("SET should work");
+        let plan = Spi::get_one::<pgrx::Json>("EXPLAIN (ANALYZE, FORMAT JSON) SELECT * FROM test")
+            .expect("EXPLAIN should work")
+            .expect("EXPLAIN should return a plan");
+        assert_eq!(plan.0[0]["Plan"]["etcd Requests"], 3);
+    }
+    #[pg_test]
+    fn test_min_max_key() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("ALTER FOREIGN TABLE test ALTER COLUMN key TYPE text COLLATE \"C\"").expect("ALTER should work");
+        Spi::run("INSERT INTO test (key, value) SELECT '/jobs/' || i, 'v' FROM generate_series(1, 9) i")
+            .expect("INSERT should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('/other', 'v')").expect("INSERT should work");
+
+        let max = Spi::get_one::<String>("SELECT max(key) FROM test WHERE key LIKE '/jobs/%'").expect("SELECT should work");
+        assert_eq!(max.as_deref(), Some("/jobs/9"));
+        let min = Spi::get_one::<String>("SELECT min(key) FROM test WHERE key LIKE '/jobs/%'").expect("SELECT should work");
+        assert_eq!(min.as_deref(), Some("/jobs/1"));
+
+        let plan = Spi::get_one::<pgrx::Json>("EXPLAIN (ANALYZE, FORMAT JSON) SELECT max(key) FROM test WHERE key LIKE '/jobs/%'")
+            .expect("EXPLAIN should work")
+            .expect("EXPLAIN should return a plan");
+        let mut node = &plan.0[0]["Plan"];
+        while !node.is_null() && node["Node Type"] != "Foreign Scan" {
+            node = &node["Plans"][0];
+        }
+        assert_eq!(node["etcd Requests"], 1, "{}", plan.0);
+        assert_eq!(node["etcd Keys"], 1, "{}", plan.0);
+    }
+    #[pg_test]
+    fn test_jsonb_value() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test_json (key text, value jsonb) server etcd_test_server options (rowid_column 'key')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run(r#"INSERT INTO test_json (key, value) VALUES ('/a', '{"status": "ok", "n": [1, 2]}')"#)
+            .expect("INSERT should work");
+
+        let status = Spi::get_one::<String>("SELECT value->>'status' FROM test_json WHERE key = '/a'").expect("SELECT should work");
+        assert_eq!(status.as_deref(), Some("ok"));
+        let stored = Spi::get_one::<String>("SELECT value FROM test WHERE key = '/a'").expect("SELECT should work");
+        assert_eq!(stored.as_deref(), Some(r#"{"n":[1,2],"status":"ok"}"#));
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('/b', 'not json')").expect("INSERT should work");
+        let result = std::panic::catch_unwind(|| {
+            Spi::get_one::<pgrx::JsonB>("SELECT value FROM test_json WHERE key = '/b'")
+        });
+        assert!(result.is_err(), "invalid JSON should be reported");
+    }
+    #[pg_test]
+    fn test_bytea_value() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test_bin (key text, value bytea) server etcd_test_server options (rowid_column 'key')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test_bin (key, value) VALUES ('/bin', '\\x00ff10')").expect("INSERT should work");
+
+        let value = Spi::get_one::<Vec<u8>>("SELECT value FROM test_bin WHERE key = '/bin'").expect("SELECT should work");
+        assert_eq!(value, Some(vec![0x00, 0xff, 0x10]));
+
+        Spi::run("UPDATE test_bin SET value = '\\xfe' WHERE key = '/bin'").expect("UPDATE should work");
+        let value = Spi::get_one::<Vec<u8>>("SELECT value FROM test_bin WHERE key = '/bin'").expect("SELECT should work");
+        assert_eq!(value, Some(vec![0xfe]));
+
+        // A text column reports the value instead of crashing
+        let result = std::panic::catch_unwind(|| Spi::get_one::<String>("SELECT value FROM test WHERE key = '/bin'"));
+        assert!(result.is_err(), "invalid UTF-8 should be reported");
+    }
+
+    #[pg_test]
+    fn test_bytea_key() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test_bytea_key (key bytea, value text) server etcd_test_server options (rowid_column 'key')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        Spi::run("INSERT INTO test_bytea_key VALUES ('\\x00ff', 'binary'), ('/text', 'plain')").expect("INSERT should work");
+        let value = Spi::get_one::<String>("SELECT value FROM test_bytea_key WHERE key = '\\x00ff'").expect("SELECT should work");
+        assert_eq!(value, Some("binary".to_string()));
+        let key = Spi::get_one::<Vec<u8>>("SELECT key FROM test_bytea_key WHERE value = 'binary'").expect("SELECT should work");
+        assert_eq!(key, Some(vec![0x00, 0xff]));
+
+        Spi::run("UPDATE test_bytea_key SET value = 'changed' WHERE key = '\\x00ff'").expect("UPDATE should work");
+        let value = Spi::get_one::<String>("SELECT value FROM test_bytea_key WHERE key IN ('\\x00ff', '\\x01')").expect("SELECT should work");
+        assert_eq!(value, Some("changed".to_string()));
+
+        Spi::run("DELETE FROM test_bytea_key WHERE key = '\\x00ff'").expect("DELETE should work");
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM test_bytea_key").expect("SELECT should work");
+        assert_eq!(count, Some(1));
+    }
+
+    #[pg_test]
+    fn test_key_value_column_options() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test_renamed (etcd_key text, config_value text, mod_revision bigint) server etcd_test_server options (rowid_column 'etcd_key', key_column 'etcd_key', value_column 'config_value')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test_renamed (etcd_key, config_value) VALUES ('/renamed/a', 'one'), ('/renamed/b', 'two')")
+            .expect("INSERT should work");
+
+        let value = Spi::get_one::<String>("SELECT config_value FROM test_renamed WHERE etcd_key = '/renamed/b'")
+            .expect("SELECT should work");
+        assert_eq!(value, Some("two".to_string()));
+        let first = Spi::get_one::<String>("SELECT etcd_key FROM test_renamed ORDER BY etcd_key COLLATE \"C\" DESC LIMIT 1")
+            .expect("SELECT should work");
+        assert_eq!(first, Some("/renamed/b".to_string()));
+
+        Spi::run("UPDATE test_renamed SET config_value = 'uno' WHERE etcd_key = '/renamed/a'").expect("UPDATE should work");
+        let value = Spi::get_one::<String>("SELECT config_value FROM test_renamed WHERE etcd_key = '/renamed/a'")
+            .expect("SELECT should work");
+        assert_eq!(value, Some("uno".to_string()));
+
+        Spi::run("DELETE FROM test_renamed WHERE etcd_key = '/renamed/b'").expect("DELETE should work");
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM test_renamed").expect("SELECT should work");
+        assert_eq!(count, Some(1));
+    }
+
+    #[pg_test]
+    fn test_key_delimiter() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test_segments (tenant text, resource text, id text, value text) server etcd_test_server options (rowid_column 'id', key_delimiter '/')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test_segments VALUES ('acme', 'hosts', '42', 'pg-eu-01'), ('acme', 'dbs', '7', 'orders'), ('other', 'hosts', '1', 'x')")
+            .expect("INSERT should work");
+
+        // the key is assembled from the segments
+        let value = Spi::get_one::<String>("SELECT value FROM test WHERE key = '/acme/hosts/42'").expect("SELECT should work");
+        assert_eq!(value, Some("pg-eu-01".to_string()));
+
+        let id = Spi::get_one::<String>("SELECT id FROM test_segments WHERE tenant = 'acme' AND resource = 'hosts'")
+            .expect("SELECT should work");
+        assert_eq!(id, Some("42".to_string()));
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM test_segments WHERE tenant = 'acme'").expect("SELECT should work");
+        assert_eq!(count, Some(2));
+
+        // only the last segment may contain the delimiter
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("INSERT INTO test_segments VALUES ('a/b', 'hosts', '1', 'x')").expect("INSERT should fail");
+        });
+        assert!(result.is_err(), "a segment containing the delimiter should be rejected");
+    }
+
+    #[pg_test]
+    fn test_jsonpath_columns() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test_hosts (key text, host text OPTIONS (jsonpath '$.host_name'), port int OPTIONS (jsonpath '$.net.port')) server etcd_test_server options (rowid_column 'key', prefix '/hosts/')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('/hosts/1', '{\"host_name\": \"pg-eu-01\", \"net\": {\"port\": 5432}, \"extra\": true}')")
+            .expect("INSERT should work");
+
+        let host = Spi::get_one::<String>("SELECT host FROM test_hosts WHERE port = 5432").expect("SELECT should work");
+        assert_eq!(host, Some("pg-eu-01".to_string()));
+
+        // fields are written into the document, the other fields stay
+        Spi::run("UPDATE test_hosts SET port = 6432 WHERE key = '/hosts/1'").expect("UPDATE should work");
+        let value = Spi::get_one::<pgrx::JsonB>("SELECT value::jsonb FROM test WHERE key = '/hosts/1'").expect("SELECT should work");
+        assert_eq!(
+            value.map(|v| v.0),
+            Some(serde_json::json!({"host_name": "pg-eu-01", "net": {"port": 6432}, "extra": true}))
+        );
+
+        Spi::run("INSERT INTO test_hosts VALUES ('/hosts/2', 'pg-us-01', 5433)").expect("INSERT should work");
+        let value = Spi::get_one::<String>("SELECT value FROM test WHERE key = '/hosts/2'").expect("SELECT should work");
+        assert_eq!(value, Some("{\"host_name\":\"pg-us-01\",\"net\":{\"port\":5433}}".to_string()));
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("ALTER FOREIGN TABLE test_hosts ALTER COLUMN host OPTIONS (SET jsonpath 'host_name')").expect("ALTER should fail");
+        });
+        assert!(result.is_err(), "an invalid jsonpath should be rejected");
+    }
+
+    #[pg_test]
+    fn test_typed_value() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test_int (key text, value bigint) server etcd_test_server options (rowid_column 'key', prefix '/int/')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("CREATE FOREIGN TABLE test_flag (key text, value boolean) server etcd_test_server options (rowid_column 'key', prefix '/flag/')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test_int VALUES ('/int/a', 9), ('/int/b', 10)").expect("INSERT should work");
+        Spi::run("INSERT INTO test_flag VALUES ('/flag/a', true)").expect("INSERT should work");
+
+        // the numbers sort by value, not by their text
+        let max = Spi::get_one::<String>("SELECT key FROM test_int ORDER BY value DESC LIMIT 1").expect("SELECT should work");
+        assert_eq!(max, Some("/int/b".to_string()));
+        let sum = Spi::get_one::<i64>("SELECT sum(value)::bigint FROM test_int").expect("SELECT should work");
+        assert_eq!(sum, Some(19));
+        let flag = Spi::get_one::<bool>("SELECT value FROM test_flag WHERE key = '/flag/a'").expect("SELECT should work");
+        assert_eq!(flag, Some(true));
+
+        // values the type rejects are reported as an FDW error
+        Spi::run("INSERT INTO test VALUES ('/int/c', 'not a number')").expect("INSERT should work");
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("SELECT * FROM test_int").expect("SELECT should fail");
+        });
+        assert!(result.is_err(), "a value that isn't a number should be reported");
+    }
+
+    #[pg_test]
+    fn test_lease_expiration_columns() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test_expiring (key text, value text, ttl_remaining bigint, expires_at timestamptz) server etcd_test_server options (rowid_column 'key', write_lease_ttl '60')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test VALUES ('/plain', 'x')").expect("INSERT should work");
+        // until the transaction of the test commits, the key is attached to the write lease
+        Spi::run("INSERT INTO test_expiring (key, value) VALUES ('/leased', 'y')").expect("INSERT should work");
+
+        let ttl = Spi::get_one::<i64>("SELECT ttl_remaining FROM test_expiring WHERE key = '/leased'").expect("SELECT should work");
+        assert!(ttl.is_some_and(|ttl| ttl > 0 && ttl <= 60), "unexpected TTL {:?}", ttl);
+        let expiring = Spi::get_one::<bool>(
+            "SELECT expires_at BETWEEN now() AND now() + interval '61 seconds' FROM test_expiring WHERE key = '/leased'",
+        )
+        .expect("SELECT should work");
+        assert_eq!(expiring, Some(true));
+
+        let ttl = Spi::get_one::<i64>("SELECT ttl_remaining FROM test_expiring WHERE key = '/plain'").expect("SELECT should work");
+        assert_eq!(ttl, None);
+    }
+
+    #[pg_test]
+    fn test_last_read_header() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("INSERT INTO test VALUES ('/header', 'x')").expect("INSERT should work");
+        Spi::run("SELECT * FROM test").expect("SELECT should work");
+
+        let revision = Spi::get_one::<i64>("SELECT etcd_last_read_revision()").expect("SELECT should work");
+        let header_revision = Spi::get_one::<i64>("SELECT revision FROM etcd_last_read_header()").expect("SELECT should work");
+        assert_eq!(header_revision, revision);
+        let member = Spi::get_one::<String>("SELECT member_id FROM etcd_last_read_header()").expect("SELECT should work");
+        assert!(member.is_some_and(|m| !m.is_empty() && m.chars().all(|c| c.is_ascii_hexdigit())));
+    }
+
+    #[pg_test]
+    fn test_value_compression() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test_gzip (key text, value text) server etcd_test_server options (rowid_column 'key', prefix '/gz/', value_compression 'gzip')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("CREATE FOREIGN TABLE test_raw (key text, value bytea) server etcd_test_server options (rowid_column 'key', prefix '/gz/')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test_gzip VALUES ('/gz/a', repeat('compressible ', 100))").expect("INSERT should work");
+        Spi::run("INSERT INTO test VALUES ('/gz/plain', 'not compressed')").expect("INSERT should work");
+
+        // etcd holds a gzip stream, smaller than the value
+        let raw = Spi::get_one::<Vec<u8>>("SELECT value FROM test_raw WHERE key = '/gz/a'").expect("SELECT should work");
+        assert!(raw.as_ref().is_some_and(|r| r.starts_with(&[0x1f, 0x8b]) && r.len() < 1300));
+
+        let value = Spi::get_one::<String>("SELECT value FROM test_gzip WHERE key = '/gz/a'").expect("SELECT should work");
+        assert_eq!(value, Some("compressible ".repeat(100)));
+        let value = Spi::get_one::<String>("SELECT value FROM test_gzip WHERE key = '/gz/plain'").expect("SELECT should work");
+        assert_eq!(value, Some("not compressed".to_string()));
+
+        // The checksum is the one of the decompressed value
+        Spi::run("CREATE FOREIGN TABLE test_gzip_sums (key text, value_md5 text) server etcd_test_server options (rowid_column 'key', prefix '/gz/', value_compression 'gzip')")
+            .expect("CREATE FOREIGN TABLE should work");
+        let matching = Spi::get_one::<bool>("SELECT value_md5 = md5(repeat('compressible ', 100)) FROM test_gzip_sums WHERE key = '/gz/a'")
+            .expect("SELECT should work");
+        assert_eq!(Some(true), matching);
+    }
 
-        // Determine the effective key to start the scan
-        // If a prefix is provided, use it as the base key and enable prefix-based scanning
-        // Otherwise, use the effective key start
-        let key = match &eff_prefix {
-            Some(p) => {
-                get_options = get_options.with_prefix();
-                // Ensure the key starts from the larger of the prefix or the effective key start
-                std::cmp::max(eff_key_start.clone(), p.clone())
-            }
-            None => eff_key_start.clone(),
-        };
+    #[pg_test]
+    fn test_value_format() {
+        let (_container, url) = create_container();
 
-        get_options = get_options.with_range(eff_range_end);
+        create_fdt(url);
 
-        // sort pushdown
-        if let Some(first_sort) = sort.first() {
-            let field_name = first_sort.field.to_ascii_uppercase();
+        Spi::run("CREATE FOREIGN TABLE test_hex (key text, value text) server etcd_test_server options (rowid_column 'key', prefix '/bin/', value_format 'hex')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("CREATE FOREIGN TABLE test_base64 (key text, value text) server etcd_test_server options (rowid_column 'key', prefix '/bin/', value_format 'base64')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("CREATE FOREIGN TABLE test_json (key text, value text) server etcd_test_server options (rowid_column 'key', prefix '/bin/', value_format 'json')")
+            .expect("CREATE FOREIGN TABLE should work");
 
-            if let Some(target) = SortTarget::from_str_name(&field_name) {
-                let order = if first_sort.reversed {
-                    SortOrder::Descend
-                } else {
-                    SortOrder::Ascend
-                };
+        // binary values aren't UTF-8
+        Spi::run("INSERT INTO test_hex VALUES ('/bin/a', '00ff10')").expect("INSERT should work");
+        let value = Spi::get_one::<String>("SELECT value FROM test_hex WHERE key = '/bin/a'").expect("SELECT should work");
+        assert_eq!(value, Some("00ff10".to_string()));
+        let value = Spi::get_one::<String>("SELECT value FROM test_base64 WHERE key = '/bin/a'").expect("SELECT should work");
+        assert_eq!(value, Some("AP8Q".to_string()));
 
-                get_options = get_options.with_sort(target, order);
-            } else {
-                return Err(EtcdFdwError::InvalidSortField(first_sort.field.clone()));
-            }
-        }
+        Spi::run("INSERT INTO test_base64 VALUES ('/bin/b', 'aGVsbG8=')").expect("INSERT should work");
+        let value = Spi::get_one::<String>("SELECT value FROM test WHERE key = '/bin/b'").expect("SELECT should work");
+        assert_eq!(value, Some("hello".to_string()));
 
-        // Check if columns contains key and value
-        let colnames: Vec<String> = columns.iter().map(|x| x.name.clone()).collect();
-        self.fetch_key = colnames.contains(&String::from("key"));
-        self.fetch_value = colnames.contains(&String::from("value"));
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("INSERT INTO test_hex VALUES ('/bin/c', 'xyz')").expect("INSERT should fail");
+        });
+        assert!(result.is_err(), "Expected a value that isn't hex to be rejected");
 
-        let result = self
-            .rt
-            .block_on(self.client.get(key, Some(get_options)));
-        let mut result_unwrapped = match result {
-            Ok(x) => x,
-            Err(e) => return Err(EtcdFdwError::FetchError(e.to_string())),
-        };
-        let result_vec = result_unwrapped.take_kvs();
-        self.fetch_results = result_vec;
-        self.tgt_cols = columns.to_vec();
-        Ok(())
+        // Non-ASCII text is rejected like other digits that aren't hex
+        Spi::run(
+            "CREATE FUNCTION insert_error(stmt text) RETURNS text LANGUAGE plpgsql AS $$
+             BEGIN
+                 EXECUTE stmt;
+                 RETURN NULL;
+             EXCEPTION WHEN OTHERS THEN
+                 RETURN SQLERRM;
+             END $$",
+        )
+        .expect("CREATE FUNCTION should work");
+        let error = Spi::get_one::<String>("SELECT insert_error($q$INSERT INTO test_hex VALUES ('/bin/d', 'aéb')$q$)")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("The value doesn't fit the table's value_format: invalid hex: not a hex digit")), error);
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("INSERT INTO test_json VALUES ('/bin/c', '{not json')").expect("INSERT should fail");
+        });
+        assert!(result.is_err(), "Expected a value that isn't JSON to be rejected");
+
+        Spi::run("INSERT INTO test_json VALUES ('/bin/c', '{\"a\": 1}')").expect("INSERT should work");
+        let value = Spi::get_one::<String>("SELECT value FROM test_json WHERE key = '/bin/c'").expect("SELECT should work");
+        assert_eq!(value, Some("{\"a\": 1}".to_string()));
     }
 
-    fn iter_scan(&mut self, row: &mut Row) -> EtcdFdwResult<Option<()>> {
-        // Go through results row by row and drain the result vector
-        if self.fetch_results.is_empty() {
-            Ok(None)
-        } else {
-            Ok(self.fetch_results.drain(0..1).last().map(|x| {
-                // Unpack x into a row
-                let key = x.key_str().expect("Expected a key, but the key was empty");
-                let value = x
-                    .value_str()
-                    .expect("Expected a value, but the value was empty");
-                for tgt_col in &self.tgt_cols {
-                    if tgt_col.name == "key" {
-                        row.push(&tgt_col.name, Some(Cell::String(key.to_string())));
-                    }
-                    if tgt_col.name == "value" {
-                        row.push(&tgt_col.name, Some(Cell::String(value.to_string())));
-                    }
-                }
-            }))
-        }
+    #[pg_test]
+    fn test_value_format_protobuf() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        // message test.Item { string name = 1; int32 count = 2; }
+        let descriptor = "CkwKCnRlc3QucHJvdG8SBHRlc3QiMAoESXRlbRISCgRuYW1lGAEgASgJUgRuYW1lEhQKBWNvdW50GAIgASgFUgVjb3VudGIGcHJvdG8z";
+        Spi::run(&format!("CREATE FOREIGN TABLE test_proto (key text, value jsonb) server etcd_test_server options (rowid_column 'key', prefix '/items/', value_format 'protobuf', proto_descriptor '{}', proto_message 'test.Item')", descriptor))
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("CREATE FOREIGN TABLE test_hex (key text, value text) server etcd_test_server options (rowid_column 'key', prefix '/items/', value_format 'hex')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run(&format!("CREATE FOREIGN TABLE test_proto_unknown (key text, value jsonb) server etcd_test_server options (value_format 'protobuf', proto_descriptor '{}', proto_message 'test.Missing')", descriptor)).expect("CREATE FOREIGN TABLE should fail");
+        });
+        assert!(result.is_err(), "Expected a message missing from the descriptor to be rejected");
+
+        Spi::run("INSERT INTO test_hex VALUES ('/items/a', '0a01611003')").expect("INSERT should work");
+        let value = Spi::get_one::<JsonB>("SELECT value FROM test_proto WHERE key = '/items/a'").expect("SELECT should work");
+        assert_eq!(value.map(|v| v.0), Some(serde_json::json!({"name": "a", "count": 3})));
+
+        Spi::run("INSERT INTO test_proto VALUES ('/items/b', '{\"name\": \"b\", \"count\": 1}')").expect("INSERT should work");
+        let value = Spi::get_one::<String>("SELECT value FROM test_hex WHERE key = '/items/b'").expect("SELECT should work");
+        assert_eq!(value, Some("0a01621001".to_string()));
     }
 
-    fn end_scan(&mut self) -> EtcdFdwResult<()> {
-        self.fetch_results = vec![];
-        self.fetch_key = false;
-        self.fetch_value = false;
-        Ok(())
+    #[pg_test]
+    fn test_k8s_mode() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        // message k8s.io.api.core.v1.ConfigMap { optional string name = 1; }
+        let descriptor = "CkkKCmNvcmUucHJvdG8SEms4cy5pby5hcGkuY29yZS52MSIfCglDb25maWdNYXASEgoEbmFtZRgBIAEoCVIEbmFtZWIGcHJvdG8y";
+        Spi::run(&format!("CREATE FOREIGN TABLE test_k8s (key text, value jsonb) server etcd_test_server options (rowid_column 'key', prefix '/registry/', k8s_mode 'true', proto_descriptor '{}')", descriptor))
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("CREATE FOREIGN TABLE test_k8s_envelope (key text, value jsonb) server etcd_test_server options (rowid_column 'key', prefix '/registry/', k8s_mode 'true')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("CREATE FOREIGN TABLE test_hex (key text, value text) server etcd_test_server options (rowid_column 'key', prefix '/registry/', value_format 'hex')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("CREATE FOREIGN TABLE test_k8s_hex (key text, value jsonb) server etcd_test_server options (k8s_mode 'true', value_format 'hex')").expect("CREATE FOREIGN TABLE should fail");
+        });
+        assert!(result.is_err(), "Expected k8s_mode with another value_format to be rejected");
+
+        // a v1 ConfigMap named cm in its protobuf envelope
+        Spi::run("INSERT INTO test_hex VALUES ('/registry/configmaps/default/cm', '6b3873000a0f0a0276311209436f6e6669674d617012040a02636d22236170706c69636174696f6e2f766e642e6b756265726e657465732e70726f746f627566')")
+            .expect("INSERT should work");
+        Spi::run("INSERT INTO test VALUES ('/registry/widgets/default/w', '{\"apiVersion\": \"example.com/v1\", \"kind\": \"Widget\"}')")
+            .expect("INSERT should work");
+
+        let value = Spi::get_one::<JsonB>("SELECT value FROM test_k8s WHERE key = '/registry/configmaps/default/cm'").expect("SELECT should work");
+        assert_eq!(value.map(|v| v.0), Some(serde_json::json!({"apiVersion": "v1", "kind": "ConfigMap", "name": "cm"})));
+        let value = Spi::get_one::<JsonB>("SELECT value FROM test_k8s_envelope WHERE key = '/registry/configmaps/default/cm'").expect("SELECT should work");
+        assert_eq!(
+            value.map(|v| v.0),
+            Some(serde_json::json!({"apiVersion": "v1", "kind": "ConfigMap", "contentType": "application/vnd.kubernetes.protobuf", "raw": "CgJjbQ=="}))
+        );
+        let kind = Spi::get_one::<String>("SELECT value->>'kind' FROM test_k8s WHERE key = '/registry/widgets/default/w'").expect("SELECT should work");
+        assert_eq!(kind, Some("Widget".to_string()));
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("INSERT INTO test_k8s VALUES ('/registry/x', '{}')").expect("INSERT should fail");
+        });
+        assert!(result.is_err(), "Expected tables with k8s_mode to be read-only");
     }
 
-    fn begin_modify(
-        &mut self,
-        _options: &std::collections::HashMap<String, String>,
-    ) -> Result<(), EtcdFdwError> {
-        // This currently does nothing
-        Ok(())
+    #[pg_test]
+    fn test_strip_prefix() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test_relative (key text, value text) server etcd_test_server options (rowid_column 'key', prefix '/config/app1/', strip_prefix 'true')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        Spi::run("INSERT INTO test_relative VALUES ('db_host', 'localhost'), ('db_port', '5432')").expect("INSERT should work");
+        let value = Spi::get_one::<String>("SELECT value FROM test WHERE key = '/config/app1/db_host'").expect("SELECT should work");
+        assert_eq!(value, Some("localhost".to_string()));
+
+        let keys = Spi::get_one::<String>("SELECT string_agg(key, ',' ORDER BY key) FROM test_relative").expect("SELECT should work");
+        assert_eq!(keys, Some("db_host,db_port".to_string()));
+        let value = Spi::get_one::<String>("SELECT value FROM test_relative WHERE key = 'db_port'").expect("SELECT should work");
+        assert_eq!(value, Some("5432".to_string()));
+
+        Spi::run("UPDATE test_relative SET value = '5433' WHERE key = 'db_port'").expect("UPDATE should work");
+        Spi::run("DELETE FROM test_relative WHERE key = 'db_host'").expect("DELETE should work");
+        let value = Spi::get_one::<String>("SELECT value FROM test WHERE key = '/config/app1/db_port'").expect("SELECT should work");
+        assert_eq!(value, Some("5433".to_string()));
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE key = '/config/app1/db_host'").expect("SELECT should work");
+        assert_eq!(count, Some(0));
     }
 
-    fn insert(&mut self, row: &Row) -> Result<(), EtcdFdwError> {
-        let key_string = match row
-            .cols
-            .iter()
-            .zip(row.cells.clone())
-            .filter(|(name, _cell)| *name == "key")
-            .last()
-        {
-            Some(x) => x.1.expect("The key column should be present").to_string(),
-            None => return Err(EtcdFdwError::MissingColumn("key".to_string())),
-        };
-        let value_string = match row
-            .cols
-            .iter()
-            .zip(row.cells.clone())
-            .filter(|(name, _cell)| *name == "value")
-            .last()
-        {
-            Some(x) => x.1.expect("The value column should be present").to_string(),
-            None => return Err(EtcdFdwError::MissingColumn("value".to_string())),
-        };
-        let key = key_string.trim_matches(|x| x == '\'');
-        let value = value_string.trim_matches(|x| x == '\'');
+    #[pg_test]
+    fn test_namespace() {
+        let (_container, url) = create_container();
 
-        // See if key already exists. Error if it does
-        match self.rt.block_on(self.client.get(key, None)) {
-            Ok(x) => {
-                if let Some(y) = x.kvs().first() {
-                    if y.key_str().expect("There should be a key string") == key {
-                        return Err(EtcdFdwError::KeyAlreadyExists(format!("{}", key)));
-                    }
-                }
-            }
-            Err(e) => return Err(EtcdFdwError::FetchError(e.to_string())),
-        }
+        create_fdt(url.clone());
 
-        match self
-            .rt
-            .block_on(self.client.put(key, value, Some(PutOptions::new())))
-        {
-            Ok(_) => Ok(()),
-            Err(e) => return Err(EtcdFdwError::UpdateError(e.to_string())),
-        }
+        Spi::run(&format!("CREATE SERVER etcd_tenant_server FOREIGN DATA WRAPPER etcd_fdw options(connstr '{}', namespace '/tenants/a/')", url))
+            .expect("CREATE SERVER should work");
+        Spi::run(&format!("CREATE USER MAPPING FOR CURRENT_USER SERVER etcd_tenant_server OPTIONS (user '{}', password '{}')", ETCD_USER, ETCD_PASS))
+            .expect("CREATE USER MAPPING should work");
+        Spi::run("CREATE FOREIGN TABLE tenant_all (key text, value text) server etcd_tenant_server options (rowid_column 'key')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("CREATE FOREIGN TABLE tenant_config (key text, value text) server etcd_tenant_server options (rowid_column 'key', prefix 'config/')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        Spi::run("INSERT INTO tenant_config VALUES ('config/db_host', 'localhost')").expect("INSERT should work");
+        Spi::run("INSERT INTO test VALUES ('/tenants/b/config/db_host', 'elsewhere'), ('/tenants/a/other', 'x')").expect("INSERT should work");
+
+        let value = Spi::get_one::<String>("SELECT value FROM test WHERE key = '/tenants/a/config/db_host'").expect("SELECT should work");
+        assert_eq!(value, Some("localhost".to_string()));
+        let keys = Spi::get_one::<String>("SELECT string_agg(key, ',' ORDER BY key) FROM tenant_config").expect("SELECT should work");
+        assert_eq!(keys, Some("config/db_host".to_string()));
+        let keys = Spi::get_one::<String>("SELECT string_agg(key, ',' ORDER BY key) FROM tenant_all").expect("SELECT should work");
+        assert_eq!(keys, Some("config/db_host,other".to_string()));
+        let value = Spi::get_one::<String>("SELECT value FROM tenant_all WHERE key = 'other'").expect("SELECT should work");
+        assert_eq!(value, Some("x".to_string()));
+
+        Spi::run("DELETE FROM tenant_all WHERE key = 'other'").expect("DELETE should work");
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE key LIKE '/tenants/%'").expect("SELECT should work");
+        assert_eq!(count, Some(2));
+
+        // The SQL functions take their keys within the namespace as well
+        let swapped = Spi::get_one::<bool>("SELECT etcd_cas('etcd_tenant_server', 'config/db_host', 'localhost', 'db1')")
+            .expect("etcd_cas should work");
+        assert_eq!(Some(true), swapped);
+        let value = Spi::get_one::<String>("SELECT value FROM test WHERE key = '/tenants/a/config/db_host'").expect("SELECT should work");
+        assert_eq!(Some(format!("db1")), value);
+
+        Spi::run("SELECT etcd_service_register('etcd_tenant_server', 'db', '10.0.0.1:5432', 60)").expect("SELECT should work");
+        let services = Spi::get_one::<String>("SELECT string_agg(name || '=' || address, ',') FROM etcd_services('etcd_tenant_server')")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("db=10.0.0.1:5432")), services);
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE key LIKE '/tenants/a//services/%'").expect("SELECT should work");
+        assert_eq!(Some(1), count);
+
+        let report = Spi::get_one::<String>(
+            "SELECT string_agg(format('%s=%s', prefix, keys), ',' ORDER BY prefix) FROM etcd_keyspace_report('etcd_tenant_server', 1)",
+        )
+        .expect("etcd_keyspace_report should work");
+        assert_eq!(Some(format!("/services/=1,config/=1")), report);
     }
 
-    fn update(&mut self, rowid: &Cell, new_row: &Row) -> Result<(), EtcdFdwError> {
-        let key_string = rowid.to_string();
-        let key = key_string.trim_matches(|x| x == '\'');
+    #[pg_test]
+    fn test_etcd_ls() {
+        let (_container, url) = create_container();
 
-        match self.rt.block_on(self.client.get(key, None)) {
-            Ok(x) => {
-                if let Some(y) = x.kvs().first() {
-                    if y.key_str().expect("There should be a key string") != key {
-                        return Err(EtcdFdwError::KeyDoesntExist(format!("{}", key)));
-                    }
-                }
-            }
-            Err(e) => return Err(EtcdFdwError::FetchError(e.to_string())),
-        }
+        create_fdt(url);
 
-        let value_string = match new_row
-            .cols
-            .iter()
-            .zip(new_row.cells.clone())
-            .filter(|(name, _cell)| *name == "value")
-            .last()
-        {
-            Some(x) => x.1.expect("The value column should be present").to_string(),
-            None => return Err(EtcdFdwError::MissingColumn("value".to_string())),
-        };
-        let value = value_string.trim_matches(|x| x == '\'');
+        Spi::run("INSERT INTO test (key, value) VALUES ('/config/app1/a', '1'), ('/config/app1/b/c', '2'), ('/config/app2/x', '3'), ('/config/version', '4'), ('/other/y', '5')")
+            .expect("INSERT should work");
 
-        match self.rt.block_on(self.client.put(key, value, None)) {
-            Ok(_) => Ok(()),
-            Err(e) => return Err(EtcdFdwError::UpdateError(e.to_string())),
-        }
+        let listing = Spi::get_one::<String>(
+            "SELECT string_agg(format('%s=%s/%s', name, key, is_prefix), ',') FROM etcd_ls('etcd_test_server', '/config/')",
+        )
+        .expect("etcd_ls should work");
+        assert_eq!(listing.as_deref(), Some("app1/=/config/app1//true,app2/=/config/app2//true,version=/config/version/false"));
+
+        let listing = Spi::get_one::<String>("SELECT string_agg(name, ',') FROM etcd_ls('etcd_test_server', '/config/app1/')")
+            .expect("etcd_ls should work");
+        assert_eq!(listing.as_deref(), Some("a,b/"));
     }
 
-    fn delete(&mut self, rowid: &Cell) -> Result<(), EtcdFdwError> {
-        let key_string = rowid.to_string();
-        let key = key_string.trim_matches(|x| x == '\'');
+    #[pg_test]
+    fn test_value_md5_column() {
+        let (_container, url) = create_container();
 
-        let delete_options = DeleteOptions::new();
+        create_fdt(url);
 
-        match self.rt.block_on(self.client.get(key, None)) {
-            Ok(x) => {
-                if let Some(y) = x.kvs().first() {
-                    if y.key_str().expect("There should be a key string") != key {
-                        return Err(EtcdFdwError::KeyDoesntExist(format!("{}", key)));
-                    }
-                }
-            }
-            Err(e) => return Err(EtcdFdwError::FetchError(e.to_string())),
-        }
+        Spi::run("CREATE FOREIGN TABLE test_sums (key text, value_md5 text) server etcd_test_server options (rowid_column 'key')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test VALUES ('a', 'some value'), ('b', '')").expect("INSERT should work");
 
-        match self
-            .rt
-            .block_on(self.client.delete(key, Some(delete_options)))
-        {
-            Ok(x) => {
-                if x.deleted() == 0 {
-                    return Err(EtcdFdwError::UpdateError(format!(
-                        "Deletion seemingly successful, but deleted count is {}",
-                        x.deleted()
-                    )));
-                }
-                Ok(())
-            }
-            Err(e) => Err(EtcdFdwError::UpdateError(e.to_string())),
-        }
+        let checksum = Spi::get_one::<String>("SELECT value_md5 FROM test_sums WHERE key = 'a'").expect("SELECT should work");
+        assert_eq!(checksum, Some("5946210c9e93ae37891dfe96c3e39614".to_string()));
+        let matching = Spi::get_one::<bool>("SELECT bool_and(s.value_md5 = md5(t.value)) FROM test_sums s JOIN test t USING (key)")
+            .expect("SELECT should work");
+        assert_eq!(matching, Some(true));
     }
 
-    // fn get_rel_size(
-    //     &mut self,
-    //     _quals: &[Qual],
-    //     _columns: &[Column],
-    //     _sorts: &[Sort],
-    //     _limit: &Option<Limit>,
-    //     _options: &std::collections::HashMap<String, String>,
-    // ) -> Result<(i64, i32), EtcdFdwError> {
-    //     todo!("Get rel size is not yet implemented")
-    // }
+    #[pg_test]
+    fn test_multiple_prefixes() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
 
-    fn end_modify(&mut self) -> Result<(), EtcdFdwError> {
-        // This currently also does nothing
-        Ok(())
+        Spi::run("CREATE FOREIGN TABLE test_settings (key text, value text) server etcd_test_server options (rowid_column 'key', prefixes '/feature_flags/,/config/')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("CREATE FOREIGN TABLE test_settings_relative (key text, value text) server etcd_test_server options (rowid_column 'key', prefixes '/a/,/b/', strip_prefix 'true')")
+                .expect("CREATE FOREIGN TABLE should fail");
+        });
+        assert!(result.is_err(), "strip_prefix with several prefixes should be rejected");
+
+        Spi::run("INSERT INTO test VALUES ('/config/a', '1'), ('/feature_flags/b', '2'), ('/other/c', '3'), ('/config/d', '4')")
+            .expect("INSERT should work");
+
+        let keys = Spi::get_one::<String>("SELECT string_agg(key, ',') FROM test_settings").expect("SELECT should work");
+        assert_eq!(keys, Some("/config/a,/config/d,/feature_flags/b".to_string()));
+        let keys = Spi::get_one::<String>("SELECT string_agg(key, ',') FROM (SELECT key FROM test_settings ORDER BY key DESC) t")
+            .expect("SELECT should work");
+        assert_eq!(keys, Some("/feature_flags/b,/config/d,/config/a".to_string()));
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM test_settings").expect("SELECT should work");
+        assert_eq!(count, Some(3));
+        let keys = Spi::get_one::<String>("SELECT string_agg(key, ',') FROM (SELECT key FROM test_settings LIMIT 2) t").expect("SELECT should work");
+        assert_eq!(keys, Some("/config/a,/config/d".to_string()));
+        let keys = Spi::get_one::<String>("SELECT string_agg(key, ',') FROM (SELECT key FROM test_settings LIMIT 1 OFFSET 1) t").expect("SELECT should work");
+        assert_eq!(keys, Some("/config/d".to_string()));
+
+        Spi::run("CREATE FOREIGN TABLE test_settings_limited (key text, value text) server etcd_test_server options (rowid_column 'key', prefixes '/feature_flags/,/config/', default_limit '2')")
+            .expect("CREATE FOREIGN TABLE should work");
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM (SELECT key FROM test_settings_limited) t").expect("SELECT should work");
+        assert_eq!(count, Some(2));
+        let value = Spi::get_one::<String>("SELECT value FROM test_settings WHERE key = '/feature_flags/b'").expect("SELECT should work");
+        assert_eq!(value, Some("2".to_string()));
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM test_settings WHERE key = '/other/c'").expect("SELECT should work");
+        assert_eq!(count, Some(0));
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("CREATE FOREIGN TABLE test_settings_both (key text, value text) server etcd_test_server options (rowid_column 'key', prefix '/a/', prefixes '/b/,/c/')")
+                .expect("CREATE FOREIGN TABLE should fail");
+        });
+        assert!(result.is_err(), "prefix and prefixes together should be rejected");
+
+        // a prefix is a single one, commas included
+        Spi::run("CREATE FOREIGN TABLE test_comma (key text, value text) server etcd_test_server options (rowid_column 'key', prefix '/config/,/feature_flags/')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test VALUES ('/config/,/feature_flags/e', '5')").expect("INSERT should work");
+        let keys = Spi::get_one::<String>("SELECT string_agg(key, ',') FROM test_comma").expect("SELECT should work");
+        assert_eq!(keys, Some("/config/,/feature_flags/e".to_string()));
+
+        // placeholder values can't add prefixes to the list
+        Spi::run("CREATE FOREIGN TABLE test_tenants (key text, value text) server etcd_test_server options (rowid_column 'key', prefixes '/config/{app.tenant},/feature_flags/')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("SET app.tenant = 'a,b'").expect("SET should work");
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("SELECT * FROM test_tenants").expect("SELECT should fail");
+        });
+        assert!(result.is_err(), "a placeholder value with a comma should be rejected");
     }
 
-    fn validator(options: Vec<Option<String>>, catalog: Option<pg_sys::Oid>) -> EtcdFdwResult<()> {
-        if let Some(oid) = catalog {
-            if oid == FOREIGN_SERVER_RELATION_ID {
-                check_options_contain(&options, "connstr")?;
+    #[pg_test]
+    fn test_exclude_prefix_and_key_regex() {
+        let (_container, url) = create_container();
 
-                let cacert_path_exists = check_options_contain(&options, "ssl_ca").is_ok();
-                let cert_path_exists = check_options_contain(&options, "ssl_cert").is_ok();
+        create_fdt(url);
 
-                require_pair(cacert_path_exists, cert_path_exists, EtcdFdwError::CertKeyMismatch(()))?;
-            } else if oid == FOREIGN_TABLE_RELATION_ID {
-                check_options_contain(&options, "rowid_column")?;
+        Spi::run("CREATE FOREIGN TABLE test_state (key text, value text) server etcd_test_server options (rowid_column 'key', prefix '/registry/', exclude_prefix '/registry/events/,/registry/leases/')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("CREATE FOREIGN TABLE test_pods (key text, value text) server etcd_test_server options (rowid_column 'key', prefix '/registry/', key_regex '^/registry/pods/[^/]+/web-')")
+            .expect("CREATE FOREIGN TABLE should work");
 
-                let prefix_exists = check_options_contain(&options, "prefix").is_ok();
-                let rannge_exists = check_options_contain(&options, "range_end").is_ok();
-                let key_exists = check_options_contain(&options, "key").is_ok();
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("CREATE FOREIGN TABLE test_bad_regex (key text, value text) server etcd_test_server options (rowid_column 'key', key_regex '(')")
+                .expect("CREATE FOREIGN TABLE should fail");
+        });
+        assert!(result.is_err(), "an invalid key_regex should be rejected");
 
-                if prefix_exists && rannge_exists {
-                    return Err(EtcdFdwError::ConflictingPrefixAndRange);
-                }
+        Spi::run("INSERT INTO test VALUES ('/registry/events/e1', '1'), ('/registry/leases/l1', '2'), ('/registry/pods/default/web-1', '3'), ('/registry/pods/default/db-1', '4')")
+            .expect("INSERT should work");
 
-                if prefix_exists && key_exists {
-                    return Err(EtcdFdwError::ConflictingPrefixAndKey);
-                }
-            } else if oid == pg_sys::BuiltinOid::UserMappingRelationId.value() {
-                let user_exists = check_options_contain(&options, "user").is_ok();
-                let password_exists = check_options_contain(&options, "password").is_ok();
+        let keys = Spi::get_one::<String>("SELECT string_agg(key, ',' ORDER BY key) FROM test_state").expect("SELECT should work");
+        assert_eq!(keys, Some("/registry/pods/default/db-1,/registry/pods/default/web-1".to_string()));
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM test_state").expect("SELECT should work");
+        assert_eq!(count, Some(2));
+        let keys = Spi::get_one::<String>("SELECT string_agg(key, ',') FROM (SELECT key FROM test_pods LIMIT 1) t").expect("SELECT should work");
+        assert_eq!(keys, Some("/registry/pods/default/web-1".to_string()));
+    }
 
-                require_pair(user_exists, password_exists, EtcdFdwError::UserPassMismatch(()))?;
-            }
-        }
+    #[pg_test]
+    fn test_empty_as_null() {
+        let (_container, url) = create_container();
 
-        Ok(())
+        create_fdt(url.clone());
+
+        Spi::run("CREATE FOREIGN TABLE test_nulls (key text, value jsonb) server etcd_test_server options (rowid_column 'key', empty_as_null 'true')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        // An empty value, as `etcdctl put empty ''` writes it
+        let rt = tokio::runtime::Runtime::new().expect("Tokio runtime should be initialized");
+        rt.block_on(async {
+            let mut client: Client = Client::connect([url.clone()], Some(ConnectOptions::new().with_user(ETCD_USER, ETCD_PASS)))
+                .await
+                .expect("connect etcd");
+            client.put("empty", "", None).await.expect("put empty value");
+        });
+
+        let value = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'empty'").expect("SELECT should work");
+        assert_eq!(value, Some(String::new()));
+        let is_null = Spi::get_one::<bool>("SELECT value IS NULL FROM test_nulls WHERE key = 'empty'").expect("SELECT should work");
+        assert_eq!(is_null, Some(true));
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("INSERT INTO test VALUES ('null', NULL)").expect("INSERT should fail");
+        });
+        assert!(result.is_err(), "a NULL value should be rejected without empty_as_null");
+
+        Spi::run("INSERT INTO test_nulls VALUES ('null', NULL)").expect("INSERT should work");
+        let value = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'null'").expect("SELECT should work");
+        assert_eq!(value, Some(String::new()));
     }
-}
 
-#[cfg(test)]
-pub mod pg_test {
+    #[pg_test]
+    fn test_typed_key_and_metadata() {
+        let (_container, url) = create_container();
 
-    pub fn setup(_options: Vec<&str>) {
-        // perform one-off initialization when the pg_test framework starts
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE test_ports (port integer, value text, version integer, mod_revision numeric) server etcd_test_server options (key_column 'port', rowid_column 'port')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test VALUES ('8080', 'http'), ('443', 'https')").expect("INSERT should work");
+
+        let ports = Spi::get_one::<String>("SELECT string_agg(port::text, ',' ORDER BY port) FROM test_ports").expect("SELECT should work");
+        assert_eq!(ports, Some("443,8080".to_string()));
+        let value = Spi::get_one::<String>("SELECT value FROM test_ports WHERE port = 443").expect("SELECT should work");
+        assert_eq!(value, Some("https".to_string()));
+        let version = Spi::get_one::<i32>("SELECT version FROM test_ports WHERE port = 8080").expect("SELECT should work");
+        assert_eq!(version, Some(1));
+        let newer = Spi::get_one::<bool>("SELECT max(mod_revision) > 0 FROM test_ports").expect("SELECT should work");
+        assert_eq!(newer, Some(true));
+
+        // keys that aren't numbers can't be read as such
+        Spi::run("INSERT INTO test VALUES ('http', 'x')").expect("INSERT should work");
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("SELECT * FROM test_ports").expect("SELECT should fail");
+        });
+        assert!(result.is_err(), "a key that doesn't parse as the key column's type should be rejected");
     }
 
-    pub fn postgresql_conf_options() -> Vec<&'static str> {
-        // return any postgresql.conf settings that are required for your tests
-        vec![]
+    #[pg_test]
+    fn test_on_conflict_overwrite() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
+
+        Spi::run("CREATE FOREIGN TABLE upsert (key text, value text, created boolean, version bigint) server etcd_test_server options (rowid_column 'key', on_conflict 'overwrite')")
+            .expect("CREATE FOREIGN TABLE should work");
+
+        let created = Spi::get_two::<bool, i64>("INSERT INTO upsert (key, value) VALUES ('foo', 'bar') RETURNING created, version")
+            .expect("INSERT should work");
+        assert_eq!((Some(true), Some(1)), created);
+
+        let created = Spi::get_two::<bool, i64>("INSERT INTO upsert (key, value) VALUES ('foo', 'baz') RETURNING created, version")
+            .expect("INSERT of an existing key should overwrite it");
+        assert_eq!((Some(false), Some(2)), created);
+
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'foo'")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("baz")), query_result);
     }
-}
 
-#[pg_schema]
-#[cfg(any(test, feature = "pg_test"))]
-mod tests {
-    use std::time::Duration;
+    #[pg_test]
+    fn test_atomic_insert() {
+        let (_container, url) = create_container();
+
+        create_fdt(url);
 
-    use super::*;
-    use etcd_client::Permission;
-    use testcontainers::{
-        core::{IntoContainerPort, WaitFor},
-        runners::SyncRunner,
-        Container, GenericImage, ImageExt,
-    };
+        Spi::run("INSERT INTO test (key, value) VALUES ('b', 'existing')").expect("INSERT should work");
 
-    const CMD: [&'static str; 5] = [
-        "/usr/local/bin/etcd",
-        "--listen-client-urls",
-        "http://0.0.0.0:2379",
-        "--advertise-client-urls",
-        "http://0.0.0.0:2379",
-    ];
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("INSERT INTO test (key, value) VALUES ('a', '1'), ('b', '2'), ('c', '3')").expect("INSERT should fail");
+        });
+        assert!(result.is_err(), "Expected the INSERT of an existing key to fail");
 
-    const ETCD_USER: &str = "root";
-    const ETCD_PASS: &str = "secret";
+        let query_result = Spi::get_one::<i64>("SELECT count(*) FROM test").expect("SELECT should work");
+        assert_eq!(Some(1), query_result, "No row of the failed INSERT should be written");
 
-    // Setup etcd root role/user and enable authentication
-    async fn etcd_auth_setup(endpoint: String) {
-        let mut client: Client = Client::connect([endpoint], None)
-            .await
-            .expect("connect etcd");
+        Spi::run("INSERT INTO test (key, value) VALUES ('a', '1'), ('c', '3')").expect("INSERT should work");
 
-        // add root user and role
-        client.role_add("root").await.expect("add role");
-        client.user_add(ETCD_USER, ETCD_PASS, None)
-            .await
-            .expect("add user");
+        let query_result = Spi::get_one::<i64>("SELECT count(*) FROM test").expect("SELECT should work");
+        assert_eq!(Some(3), query_result);
 
-        client.user_grant_role(ETCD_USER, "root")
-            .await
-            .expect("grant role");
+        // Overwriting rows are written in the same transaction, the last row of a repeated key wins
+        Spi::run("CREATE FOREIGN TABLE upsert (key text, value text) server etcd_test_server options (rowid_column 'key', on_conflict 'overwrite')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO upsert (key, value) VALUES ('b', '2'), ('d', '4'), ('d', '5')").expect("INSERT should work");
 
-        client.auth_enable().await.expect("enable auth");
+        let query_result = Spi::get_one::<String>("SELECT string_agg(key || '=' || value, ',' ORDER BY key) FROM test")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("a=1,b=2,c=3,d=5")), query_result);
     }
 
-    fn create_container() -> (Container<GenericImage>, String) {
-        let container = GenericImage::new("quay.io/coreos/etcd", "v3.6.4")
-            .with_exposed_port(2379.tcp())
-            .with_wait_for(WaitFor::message_on_either_std(
-                "ready to serve client requests",
-            ))
-            .with_privileged(true)
-            .with_cmd(CMD)
-            .with_startup_timeout(Duration::from_secs(90))
-            .start()
-            .expect("An etcd image was supposed to be started");
+    #[pg_test]
+    fn test_copy_from() {
+        let (_container, url) = create_container();
 
-        let host = container
-            .get_host()
-            .expect("Host-address should be available");
+        create_fdt(url);
 
-        let port = container
-            .get_host_port_ipv4(2379.tcp())
-            .expect("Exposed host port should be available");
+        let path = std::env::temp_dir().join("etcd_fdw_copy_from.csv");
+        let rows: String = (0..300).map(|i| format!("key{:03},value{}\n", i, i)).collect();
+        std::fs::write(&path, rows).expect("Writing the CSV file should work");
 
-        let url = format!("{}:{}", host, port);
-        let rt = tokio::runtime::Runtime::new().expect("Tokio runtime should be initialized");
-        rt.block_on(etcd_auth_setup(url.clone()));
-        (container, url)
+        Spi::run(&format!("COPY test FROM '{}' WITH (FORMAT csv)", path.display())).expect("COPY should work");
+
+        let query_result = Spi::get_two::<i64, String>("SELECT count(*), max(value) FILTER (WHERE key = 'key299') FROM test")
+            .expect("SELECT should work");
+        assert_eq!((Some(300), Some(format!("value299"))), query_result);
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run(&format!("COPY test FROM '{}' WITH (FORMAT csv)", path.display())).expect("COPY should fail");
+        });
+        assert!(result.is_err(), "Expected COPY of existing keys to fail");
     }
 
-    fn create_fdt(url: String) -> () {
-        Spi::run("CREATE FOREIGN DATA WRAPPER etcd_fdw handler etcd_fdw_handler validator etcd_fdw_validator;").expect("FDW should have been created");
+    #[pg_test]
+    fn test_copy_to() {
+        let (_container, url) = create_container();
 
-        // Create a server
-        Spi::run(
-            format!(
-                "CREATE SERVER etcd_test_server FOREIGN DATA WRAPPER etcd_fdw options(connstr '{}')",
-                url
-            )
-            .as_str(),
-        )
-        .expect("Server should have been created");
+        create_fdt(url);
 
-        // Create a user mapping
-        Spi::run(
-            format!(
-                "CREATE USER MAPPING FOR CURRENT_USER SERVER etcd_test_server options (user '{}', password '{}')",
-                ETCD_USER, ETCD_PASS
-            )
-            .as_str(),
-        )
-        .expect("User mapping should have been created");
+        Spi::run("ALTER FOREIGN TABLE test OPTIONS (ADD fetch_size '2')").expect("ALTER FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('a', '1'), ('b', '2'), ('c', '3'), ('d', '4'), ('e', '5')")
+            .expect("INSERT should work");
 
-        // Create a foreign table
-        Spi::run("CREATE FOREIGN TABLE test (key text, value text) server etcd_test_server options (rowid_column 'key')").expect("Test table should have been created");
+        let path = std::env::temp_dir().join("etcd_fdw_copy_to.csv");
+        Spi::run(&format!("COPY (SELECT key, value FROM test) TO '{}' WITH (FORMAT csv)", path.display()))
+            .expect("COPY should work");
+
+        let exported = std::fs::read_to_string(&path).expect("Reading the CSV file should work");
+        assert_eq!("a,1\nb,2\nc,3\nd,4\ne,5\n", exported);
     }
 
     #[pg_test]
-    fn test_create_table() {
+    fn test_update_key() {
         let (_container, url) = create_container();
 
         create_fdt(url);
+
+        Spi::run("INSERT INTO test (key, value) VALUES ('/old', 'foo'), ('/taken', 'bar')").expect("INSERT should work");
+
+        Spi::run("UPDATE test SET key = '/new' WHERE key = '/old'").expect("UPDATE should work");
+
+        let query_result = Spi::get_one::<String>("SELECT string_agg(key || '=' || value, ',' ORDER BY key) FROM test")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("/new=foo,/taken=bar")), query_result);
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("UPDATE test SET key = '/taken' WHERE key = '/new'").expect("UPDATE should fail");
+        });
+        assert!(result.is_err(), "Expected renaming to an existing key to fail");
+
+        // The stored value is moved as it is, without being decoded
+        Spi::run("CREATE FOREIGN TABLE test_bytes (key text, value bytea) server etcd_test_server options (rowid_column 'key')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test_bytes (key, value) VALUES ('/binary', '\\x00ff')").expect("INSERT should work");
+        Spi::run("UPDATE test_bytes SET key = '/moved' WHERE key = '/binary'").expect("UPDATE should work");
+
+        let query_result = Spi::get_one::<String>("SELECT encode(value, 'hex') FROM test_bytes WHERE key = '/moved'")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("00ff")), query_result);
     }
+
     #[pg_test]
-    fn test_insert_select() {
+    fn test_delete_range() {
         let (_container, url) = create_container();
 
         create_fdt(url);
 
-        // Insert into the foreign table
-        Spi::run("INSERT INTO test (key, value) VALUES ('foo','bar'),('bar','baz')")
+        Spi::run("INSERT INTO test (key, value) VALUES ('/tmp/a', '1'), ('/tmp/b', '2'), ('/tmp/c', '3'), ('/keep', '4')")
             .expect("INSERT should work");
 
-        let query_result = Spi::get_two::<String, String>("SELECT * FROM test WHERE key='foo'")
-            .expect("Select should work");
+        Spi::run("DELETE FROM test WHERE key LIKE '/tmp/%'").expect("DELETE should work");
 
-        assert_eq!((Some(format!("foo")), Some(format!("bar"))), query_result);
-        let query_result = Spi::get_two::<String, String>("SELECT * FROM test WHERE key='bar'")
+        let query_result = Spi::get_one::<String>("SELECT string_agg(key, ',' ORDER BY key) FROM test")
             .expect("SELECT should work");
+        assert_eq!(Some(format!("/keep")), query_result);
 
-        assert_eq!((Some(format!("bar")), Some(format!("baz"))), query_result);
+        // Conditions PostgreSQL evaluates leave keys of the range in place
+        Spi::run("INSERT INTO test (key, value) VALUES ('/tmp/a', '1'), ('/tmp/b', '2')").expect("INSERT should work");
+        Spi::run("DELETE FROM test WHERE key LIKE '/tmp/%' AND value = '1'").expect("DELETE should work");
+
+        let query_result = Spi::get_one::<String>("SELECT string_agg(key, ',' ORDER BY key) FROM test")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("/keep,/tmp/b")), query_result);
     }
 
+    #[cfg(not(feature = "pg13"))]
     #[pg_test]
-    fn test_update() {
+    fn test_truncate() {
         let (_container, url) = create_container();
 
         create_fdt(url);
 
-        Spi::run("INSERT INTO test (key, value) VALUES ('foo','bar'),('bar','baz')")
+        Spi::run("CREATE FOREIGN TABLE test_sessions (key text, value text) server etcd_test_server options (rowid_column 'key', prefix '/sessions/')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('/sessions/a', '1'), ('/sessions/b', '2'), ('/keep', '3')")
             .expect("INSERT should work");
 
-        Spi::run("UPDATE test SET value='test_successful'").expect("UPDATE should work");
+        Spi::run("TRUNCATE test_sessions").expect("TRUNCATE should work");
 
-        let query_result =
-            Spi::get_one::<String>("SELECT value FROM test;").expect("SELECT should work");
+        let query_result = Spi::get_one::<String>("SELECT string_agg(key, ',' ORDER BY key) FROM test")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("/keep")), query_result);
 
-        assert_eq!(Some(format!("test_successful")), query_result);
+        // Truncated keys are neither served from the point cache nor detached from the write lease at commit
+        Spi::run("CREATE FOREIGN TABLE test_leased (key text, value text) server etcd_test_server options (rowid_column 'key', prefix '/leased/', point_cache 'true', write_lease_ttl '60')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test_leased (key, value) VALUES ('/leased/a', '1')").expect("INSERT should work");
+        let query_result = Spi::get_one::<String>("SELECT value FROM test_leased WHERE key = '/leased/a'")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("1")), query_result);
+
+        Spi::run("TRUNCATE test_leased").expect("TRUNCATE should work");
+
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM test_leased WHERE key = '/leased/a'").expect("SELECT should work");
+        assert_eq!(Some(0), count);
+        let pending = PENDING_LEASES.with_borrow(|pending| pending.iter().map(|lease| lease.keys.iter().count()).sum::<usize>());
+        assert_eq!(0, pending, "The truncated key should no longer be pending");
+
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("TRUNCATE test").expect("TRUNCATE should fail");
+        });
+        assert!(result.is_err(), "Expected TRUNCATE of a table without prefix to fail");
     }
 
     #[pg_test]
-    fn test_delete() {
+    fn test_update_delete_returning() {
         let (_container, url) = create_container();
 
         create_fdt(url);
 
-        Spi::run("INSERT INTO test (key, value) VALUES ('foo','bar'),('bar','baz')")
+        Spi::run("CREATE FOREIGN TABLE test_meta (key text, value text, mod_revision bigint, version bigint) server etcd_test_server options (rowid_column 'key')")
+            .expect("CREATE FOREIGN TABLE should work");
+        let inserted = Spi::get_one::<i64>("INSERT INTO test_meta (key, value) VALUES ('foo', 'bar') RETURNING mod_revision")
+            .expect("INSERT should work")
+            .expect("The mod_revision should be returned");
+
+        let updated = Spi::get_two::<i64, i64>("UPDATE test_meta SET value = 'baz' WHERE key = 'foo' RETURNING mod_revision, version")
+            .expect("UPDATE should work");
+        assert_eq!((Some(inserted + 1), Some(2)), updated);
+
+        // The columns the UPDATE doesn't assign are returned as well
+        let updated = Spi::get_two::<String, String>("UPDATE test_meta SET value = 'qux' WHERE key = 'foo' RETURNING key, value")
+            .expect("UPDATE should work");
+        assert_eq!((Some(format!("foo")), Some(format!("qux"))), updated);
+        let renamed = Spi::get_two::<String, i64>("UPDATE test_meta SET key = 'moved' WHERE key = 'foo' RETURNING value, version")
+            .expect("UPDATE should work");
+        assert_eq!((Some(format!("qux")), Some(1)), renamed);
+
+        let deleted = Spi::get_two::<String, i64>("DELETE FROM test_meta WHERE key = 'moved' RETURNING value, version")
+            .expect("DELETE should work");
+        assert_eq!((Some(format!("qux")), Some(1)), deleted);
+    }
+
+    #[pg_test]
+    fn test_lease_column() {
+        let (_container, url) = create_container();
+
+        create_fdt(url.clone());
+
+        let rt = tokio::runtime::Runtime::new().expect("Tokio runtime should be initialized");
+        let lease = rt.block_on(async {
+            let mut client: Client = Client::connect([url.clone()], Some(ConnectOptions::new().with_user(ETCD_USER, ETCD_PASS)))
+                .await
+                .expect("connect etcd");
+            client.lease_grant(60, None).await.expect("grant lease").id()
+        });
+
+        Spi::run("CREATE FOREIGN TABLE test_leased (key text, value text, lease bigint) server etcd_test_server options (rowid_column 'key')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run(&format!("INSERT INTO test_leased (key, value, lease) VALUES ('/services/db', 'up', {})", lease))
             .expect("INSERT should work");
+        Spi::run("UPDATE test_leased SET value = 'down' WHERE key = '/services/db'").expect("UPDATE should work");
 
-        Spi::run("DELETE FROM test").expect("DELETE should work");
+        let query_result = Spi::get_one::<i64>("SELECT lease FROM test_leased WHERE key = '/services/db'")
+            .expect("SELECT should work");
+        assert_eq!(Some(lease), query_result, "The key should stay attached to the lease");
 
-        let query_result = Spi::get_one::<String>("SELECT value FROM test;");
+        // A renamed key keeps its lease, a key whose lease alone is updated keeps its value
+        Spi::run("UPDATE test_leased SET key = '/services/pg' WHERE key = '/services/db'").expect("UPDATE should work");
+        let query_result = Spi::get_one::<i64>("SELECT lease FROM test_leased WHERE key = '/services/pg'")
+            .expect("SELECT should work");
+        assert_eq!(Some(lease), query_result, "The renamed key should stay attached to the lease");
 
-        assert_eq!(Err(spi::SpiError::InvalidPosition), query_result);
+        Spi::run("UPDATE test_leased SET lease = 0 WHERE key = '/services/pg'").expect("UPDATE should work");
+        let query_result = Spi::get_two::<String, i64>("SELECT value, lease FROM test_leased WHERE key = '/services/pg'")
+            .expect("SELECT should work");
+        assert_eq!((Some(format!("down")), Some(0)), query_result);
+
+        Spi::run(&format!("ALTER FOREIGN TABLE test OPTIONS (ADD lease_id '{}')", lease))
+            .expect("ALTER FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('/services/web', 'up')").expect("INSERT should work");
+
+        let query_result = Spi::get_one::<i64>("SELECT lease FROM test_leased WHERE key = '/services/web'")
+            .expect("SELECT should work");
+        assert_eq!(Some(lease), query_result);
     }
 
     #[pg_test]
-    fn test_select_value_only_with_key_filter() {
-        // Test for issue #26: selecting only value column with WHERE clause on key
+    fn test_ttl_column() {
         let (_container, url) = create_container();
 
         create_fdt(url);
 
-        // Insert test data
-        Spi::run("INSERT INTO test (key, value) VALUES ('key1','value1'),('key2','value2'),('key3','value3')")
+        Spi::run("CREATE FOREIGN TABLE test_sessions (key text, value text, ttl bigint, lease bigint) server etcd_test_server options (rowid_column 'key')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test_sessions (key, value, ttl) VALUES ('/sessions/a', 'x', 300), ('/sessions/b', 'y', 300)")
             .expect("INSERT should work");
 
-        // Test 1: SELECT value WHERE key = 'key2' should return the value
-        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'key2'")
-            .expect("SELECT value with key filter should work");
-
-        assert_eq!(Some(format!("value2")), query_result);
+        let ttl = Spi::get_one::<i64>("SELECT ttl FROM test_sessions WHERE key = '/sessions/a'").expect("SELECT should work");
+        assert!(matches!(ttl, Some(ttl) if ttl > 0 && ttl <= 300), "The key should be attached to a 300 second lease");
 
-        // Test 2: SELECT key WHERE key = 'key1' should also work
-        let query_result = Spi::get_one::<String>("SELECT key FROM test WHERE key = 'key1'")
-            .expect("SELECT key with key filter should work");
+        let leases = Spi::get_one::<i64>("SELECT count(DISTINCT lease) FROM test_sessions WHERE lease <> 0")
+            .expect("SELECT should work");
+        assert_eq!(Some(1), leases, "Rows with the same TTL should share a lease");
 
-        assert_eq!(Some(format!("key1")), query_result);
+        Spi::run("INSERT INTO test_sessions (key, value) VALUES ('/sessions/c', 'z')").expect("INSERT should work");
+        let ttl = Spi::get_one::<i64>("SELECT ttl FROM test_sessions WHERE key = '/sessions/c'").expect("SELECT should work");
+        assert_eq!(None, ttl, "Keys without a TTL shouldn't get a lease");
 
-        // Test 3: SELECT * WHERE key = 'key3' should work (baseline)
-        let query_result = Spi::get_two::<String, String>("SELECT * FROM test WHERE key = 'key3'")
-            .expect("SELECT * with key filter should work");
+        // Updates of other columns keep the key attached to its lease
+        Spi::run("UPDATE test_sessions SET value = 'x2' WHERE key = '/sessions/a'").expect("UPDATE should work");
+        let ttl = Spi::get_one::<i64>("SELECT ttl FROM test_sessions WHERE key = '/sessions/a'").expect("SELECT should work");
+        assert!(matches!(ttl, Some(ttl) if ttl > 0 && ttl <= 300), "The updated key should stay attached to its lease");
 
-        assert_eq!((Some(format!("key3")), Some(format!("value3"))), query_result);
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("INSERT INTO test_sessions (key, value, ttl) VALUES ('/sessions/d', 'w', 0)").expect("INSERT should fail");
+        });
+        assert!(result.is_err(), "Expected a TTL of 0 to be rejected");
     }
 
     #[pg_test]
-    fn test_update_value_only_with_key_filter() {
-        // Test for issue #26: UPDATE with only value column when key is in WHERE clause
+    fn test_update_mod_revision() {
         let (_container, url) = create_container();
 
         create_fdt(url);
 
-        // Insert test data
-        Spi::run("INSERT INTO test (key, value) VALUES ('gather/78','original_value'),('gather/84','other_value')")
-            .expect("INSERT should work");
+        Spi::run("CREATE FOREIGN TABLE test_meta (key text, value text, mod_revision bigint) server etcd_test_server options (rowid_column 'key')")
+            .expect("CREATE FOREIGN TABLE should work");
+        Spi::run("INSERT INTO test_meta (key, value) VALUES ('/leader', 'node1')").expect("INSERT should work");
+        let revision = Spi::get_one::<i64>("SELECT mod_revision FROM test_meta WHERE key = '/leader'")
+            .expect("SELECT should work")
+            .expect("The key should exist");
+
+        Spi::run(&format!("UPDATE test_meta SET value = 'node2' WHERE key = '/leader' AND mod_revision = {}", revision))
+            .expect("UPDATE with the current revision should work");
+        let query_result = Spi::get_one::<String>("SELECT value FROM test_meta WHERE key = '/leader'").expect("SELECT should work");
+        assert_eq!(Some(format!("node2")), query_result);
+
+        // A trigger changes the key between the scan and the write, like a concurrent writer
+        let revision = Spi::get_one::<i64>("SELECT mod_revision FROM test_meta WHERE key = '/leader'")
+            .expect("SELECT should work")
+            .expect("The key should exist");
+        Spi::run(
+            "CREATE FUNCTION concurrent_write() RETURNS trigger LANGUAGE plpgsql AS $$
+             BEGIN
+                 PERFORM etcd_cas('etcd_test_server', OLD.key, OLD.value, 'concurrent');
+                 RETURN NEW;
+             END $$",
+        )
+        .expect("CREATE FUNCTION should work");
+        Spi::run("CREATE TRIGGER concurrent_write BEFORE UPDATE ON test_meta FOR EACH ROW EXECUTE FUNCTION concurrent_write()")
+            .expect("CREATE TRIGGER should work");
+        let result = std::panic::catch_unwind(|| {
+            Spi::run(&format!("UPDATE test_meta SET value = 'node3' WHERE key = '/leader' AND mod_revision = {}", revision))
+                .expect("UPDATE should fail");
+        });
+        assert!(result.is_err(), "Expected the UPDATE of a concurrently changed key to fail");
 
-        // Update without including key column in SET clause
-        Spi::run("UPDATE test SET value = 'updated_value' WHERE key = 'gather/84'")
-            .expect("UPDATE with key filter should work");
+        let query_result = Spi::get_one::<String>("SELECT value FROM test_meta WHERE key = '/leader'").expect("SELECT should work");
+        assert_eq!(Some(format!("concurrent")), query_result, "The concurrent write shouldn't be overwritten");
+    }
 
-        // Verify the update worked
-        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'gather/84'")
-            .expect("SELECT after UPDATE should work");
+    #[pg_test]
+    fn test_update_value_condition() {
+        let (_container, url) = create_container();
 
-        assert_eq!(Some(format!("updated_value")), query_result);
+        create_fdt(url);
 
-        // Verify other row was not affected
-        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = 'gather/78'")
-            .expect("SELECT other row should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('/leader', 'node1')").expect("INSERT should work");
+        Spi::run("UPDATE test SET value = 'node2' WHERE key = '/leader' AND value = 'node1'").expect("UPDATE should work");
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = '/leader'").expect("SELECT should work");
+        assert_eq!(Some(format!("node2")), query_result);
 
-        assert_eq!(Some(format!("original_value")), query_result);
+        // A trigger changes the value between the scan and the write, like a concurrent writer
+        Spi::run(
+            "CREATE FUNCTION concurrent_write() RETURNS trigger LANGUAGE plpgsql AS $$
+             BEGIN
+                 PERFORM etcd_cas('etcd_test_server', OLD.key, OLD.value, 'concurrent');
+                 RETURN OLD;
+             END $$",
+        )
+        .expect("CREATE FUNCTION should work");
+        Spi::run("CREATE TRIGGER concurrent_write BEFORE DELETE ON test FOR EACH ROW EXECUTE FUNCTION concurrent_write()")
+            .expect("CREATE TRIGGER should work");
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("DELETE FROM test WHERE key = '/leader' AND value = 'node2'").expect("DELETE should fail");
+        });
+        assert!(result.is_err(), "Expected the DELETE of a concurrently changed value to fail");
+
+        let query_result = Spi::get_one::<String>("SELECT value FROM test WHERE key = '/leader'").expect("SELECT should work");
+        assert_eq!(Some(format!("concurrent")), query_result, "The concurrently written key shouldn't be deleted");
     }
 
     #[pg_test]
-    fn test_user_mapping_validation() {
+    fn test_batched_modify() {
         let (_container, url) = create_container();
 
-        create_fdt(url.clone());
+        create_fdt(url);
 
-        // Insert test data
-        Spi::run("INSERT INTO test (key, value) VALUES ('/gather', 'data')")
+        Spi::run("INSERT INTO test (key, value) SELECT 'k' || i, 'v' || i FROM generate_series(1, 300) i")
             .expect("INSERT should work");
 
-        // Test 1: User mapping with invalid credentials (should fail)
-        Spi::run("ALTER USER MAPPING FOR CURRENT_USER SERVER etcd_test_server OPTIONS (SET password 'wrong_password');")
-            .expect("Alter user mapping should work");
+        Spi::run("UPDATE test SET value = 'updated' WHERE key <> 'k1'").expect("UPDATE should work");
+        let query_result = Spi::get_one::<i64>("SELECT count(*) FROM test WHERE value = 'updated'")
+            .expect("SELECT should work");
+        assert_eq!(Some(299), query_result, "Every batch of the UPDATE should be written");
 
-        let result = std::panic::catch_unwind(|| {
-            Spi::run("SELECT * FROM test;").expect("SELECT should work");
-        });
+        Spi::run("DELETE FROM test WHERE value = 'updated'").expect("DELETE should work");
+        let query_result = Spi::get_one::<String>("SELECT string_agg(key, ',') FROM test")
+            .expect("SELECT should work");
+        assert_eq!(Some("k1".to_string()), query_result, "Every batch of the DELETE should be written");
+    }
 
-        assert!(result.is_err(), "Expected SELECT to fail due to invalid user mapping");
+    #[pg_test]
+    fn test_update_deleted_key() {
+        let (_container, url) = create_container();
 
-        // Setup: create a role and user with limited permissions in etcd
-        let rt = tokio::runtime::Runtime::new().expect("Tokio runtime should be initialized");
-        rt.block_on(
-            async {
-                let mut client: Client = Client::connect([url.clone()], Some(ConnectOptions::new().with_user(ETCD_USER, ETCD_PASS)))
-                    .await
-                    .expect("connect etcd");
-                client.role_add("rw_role").await.expect("add role");
-                // role with read and write permissions on keys starting with "/"
-                client.role_grant_permission("rw_role", Permission::with_from_key(Permission::read_write("/")))
-                    .await
-                    .expect("grant permission");
-                client.user_add("etcd_user", "secret", None)
-                    .await
-                    .expect("add user");
-                client.user_grant_role("etcd_user", "rw_role")
-                    .await
-                    .expect("grant role");
-            }
-        );
+        create_fdt(url);
 
-        // Alter user mapping to use the new limited permissions user
-        Spi::run("ALTER USER MAPPING FOR CURRENT_USER SERVER etcd_test_server OPTIONS (SET user 'etcd_user', SET password 'secret');")
-            .expect("Alter user mapping should work");
+        Spi::run("INSERT INTO test (key, value) VALUES ('/a', '1'), ('/b', '2')").expect("INSERT should work");
 
-        // Test 2: Selecting a key outside of the user's permissions (should fail)
-        let invalid_result =  std::panic::catch_unwind(|| {
-            Spi::run("SELECT * FROM test;").expect("SELECT should work");
-        });
+        // A trigger deletes the key between the scan and the write, like a concurrent writer
+        Spi::run(
+            "CREATE FUNCTION concurrent_delete() RETURNS trigger LANGUAGE plpgsql AS $$
+             BEGIN
+                 PERFORM etcd_cad('etcd_test_server', OLD.key, OLD.value);
+                 RETURN NEW;
+             END $$",
+        )
+        .expect("CREATE FUNCTION should work");
+        Spi::run("CREATE TRIGGER concurrent_delete BEFORE UPDATE ON test FOR EACH ROW WHEN (OLD.key = '/b') EXECUTE FUNCTION concurrent_delete()")
+            .expect("CREATE TRIGGER should work");
 
-        assert!(invalid_result.is_err(), "Expected SELECT to fail due to insufficient permissions");
+        let result = std::panic::catch_unwind(|| {
+            Spi::run("UPDATE test SET value = 'updated'").expect("UPDATE should fail");
+        });
+        assert!(result.is_err(), "Expected the UPDATE of a deleted key to fail");
 
-        // Test 3: Selecting a key within the user's permissions
-        let result = Spi::get_two::<String, String>("SELECT * FROM test WHERE key = '/gather'")
-            .expect("SELECT with proper permissions should work");
+        let query_result = Spi::get_one::<String>("SELECT string_agg(key || '=' || value, ',') FROM test")
+            .expect("SELECT should work");
+        assert_eq!(Some(format!("/a=1")), query_result, "The deleted key shouldn't be recreated");
 
-        assert_eq!((Some(format!("/gather")), Some(format!("data"))), result);
+        let query_result = Spi::get_one::<String>("UPDATE test SET value = 'updated' WHERE key = '/a' RETURNING value")
+            .expect("UPDATE ... RETURNING of an existing key should work");
+        assert_eq!(Some(format!("updated")), query_result);
     }
 }